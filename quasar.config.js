@@ -108,7 +108,7 @@ module.exports = configure(function (/* ctx */) {
             // directives: [],
 
             // Quasar plugins
-            plugins: [],
+            plugins: ['Notify'],
         },
 
         // animations: 'all', // --- includes all animations
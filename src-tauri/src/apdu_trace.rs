@@ -0,0 +1,193 @@
+//! Module for the APDU traces of the cards.
+//!
+//! A failed remote authentication is hard to investigate from the application log, which only tells that
+//! the server gave up. Every command sent to the card on behalf of the server and its response (or the error)
+//! is recorded into the trace of the card: the last `apdu_trace.max_entries` commands are kept in memory,
+//! and with `apdu_trace.to_file` they are also appended to `apdu_trace/<card number>.log` in the data folder.
+//! The support engineers download the trace with `export_apdu_trace`.
+//!
+//! The commands are numbered by the authentication session, so the commands of the failed one are easily found.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::config::{get_apdu_trace_config, get_data_dir, retry_io};
+use crate::hooks::ConnectionHooks;
+use crate::protocol::ApduTransport;
+use crate::timestamp::Timestamp;
+
+/// Name of the folder with the trace files inside the data folder.
+const APDU_TRACE_DIR_NAME: &str = "apdu_trace";
+/// Size of the trace file after which it is moved to `<card number>.log.1`, replacing the previous one.
+const MAX_TRACE_FILE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Command sent to the card and its result.
+///
+/// # Fields
+///
+/// * `session` - Number of the authentication session of the card since the start of the application.
+/// * `command` - The command APDU in hex.
+/// * `response` - The response APDU in hex, empty if the command failed.
+/// * `status_word` - The status word of the response (e.g. "9000"), empty if the command failed.
+/// * `error` - The error of the card or the reader, `None` if the card responded.
+/// * `duration_ms` - How long the card took to respond.
+#[derive(Serialize, Clone, Debug)]
+pub struct TraceEntry {
+    pub timestamp: Timestamp,
+    pub session: u32,
+    pub command: String,
+    pub response: String,
+    pub status_word: String,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Trace of the card: the last commands and the number of the current session.
+#[derive(Default)]
+struct CardTrace {
+    session: u32,
+    entries: VecDeque<TraceEntry>,
+}
+
+lazy_static! {
+    /// Traces by the card number.
+    static ref TRACES: Mutex<HashMap<String, CardTrace>> = Mutex::new(HashMap::new());
+}
+
+/// Card which records the commands sent through it into the trace of the card.
+pub struct TracedCard<'a, T> {
+    card: &'a T,
+    cardnumber: &'a str,
+}
+
+impl<'a, T> TracedCard<'a, T> {
+    pub fn new(card: &'a T, cardnumber: &'a str) -> Self {
+        TracedCard { card, cardnumber }
+    }
+}
+
+impl<T: ApduTransport> ApduTransport for TracedCard<'_, T>
+where
+    T::Error: Display,
+{
+    type Error = T::Error;
+
+    fn transmit_hex(&self, apdu_hex: &str) -> Result<String, Self::Error> {
+        let started = Instant::now();
+        let result = self.card.transmit_hex(apdu_hex);
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(response) => record(self.cardnumber, apdu_hex, response, None, duration_ms),
+            Err(e) => record(self.cardnumber, apdu_hex, "", Some(e.to_string()), duration_ms),
+        }
+        result
+    }
+}
+
+/// Adds the command to the trace of the card and to the trace file if it is enabled.
+fn record(cardnumber: &str, command: &str, response: &str, error: Option<String>, duration_ms: u64) {
+    let config = get_apdu_trace_config();
+    let status_word = response.get(response.len().saturating_sub(4)..).unwrap_or_default().to_lowercase();
+
+    let mut traces = TRACES.lock().unwrap();
+    let trace = traces.entry(cardnumber.to_string()).or_default();
+    let entry = TraceEntry {
+        timestamp: Timestamp::now(),
+        session: trace.session,
+        command: command.to_lowercase(),
+        response: response.to_lowercase(),
+        status_word,
+        error,
+        duration_ms,
+    };
+    trace.entries.push_back(entry.clone());
+    while trace.entries.len() > config.max_entries {
+        trace.entries.pop_front();
+    }
+    drop(traces);
+
+    if config.to_file {
+        if let Err(e) = append_to_file(cardnumber, &entry) {
+            log::warn!("{} | Failed to write the APDU trace: {}", cardnumber, e);
+        }
+    }
+}
+
+/// Path of the trace file of the card. The card number is reduced to the characters safe in the file names.
+fn trace_file_path(cardnumber: &str) -> io::Result<PathBuf> {
+    let name: String = cardnumber
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let mut path = get_data_dir()?;
+    path.push(APDU_TRACE_DIR_NAME);
+    fs::create_dir_all(&path)?;
+    path.push(format!("{}.log", name));
+    Ok(path)
+}
+
+/// Appends the entry to the trace file of the card as a JSON line, the full file is rotated first.
+fn append_to_file(cardnumber: &str, entry: &TraceEntry) -> io::Result<()> {
+    let path = trace_file_path(cardnumber)?;
+    if fs::metadata(&path).map(|metadata| metadata.len() >= MAX_TRACE_FILE_BYTES).unwrap_or(false) {
+        retry_io(|| fs::rename(&path, path.with_extension("log.1")))?;
+    }
+    let line = serde_json::to_string(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    retry_io(|| {
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", line)
+    })
+}
+
+/// Starts the new session in the traces of the finished authentication sessions.
+pub struct ApduTraceHooks;
+
+impl ConnectionHooks for ApduTraceHooks {
+    fn on_session_finished(&self, cardnumber: &str, _apdu_count: u32) {
+        if let Some(trace) = TRACES.lock().unwrap().get_mut(cardnumber) {
+            trace.session += 1;
+        }
+    }
+}
+
+/// Exported trace of the card.
+#[derive(Serialize)]
+struct ApduTraceExport {
+    card_number: String,
+    exported_at: Timestamp,
+    app_version: String,
+    entries: Vec<TraceEntry>,
+}
+
+/// Public function to export the APDU trace of the card, e.g. for the investigation of a failed authentication.
+/// This function is a Tauri command that is called from the frontend, which saves the trace to a file.
+///
+/// # Arguments
+///
+/// * `card_number` - The number of the card.
+///
+/// # Returns
+///
+/// * `Result<String, String>` - The trace in JSON, or the error message if there is no trace of the card.
+#[tauri::command]
+pub fn export_apdu_trace(card_number: String) -> Result<String, String> {
+    let entries: Vec<TraceEntry> = match TRACES.lock().unwrap().get(&card_number) {
+        Some(trace) if !trace.entries.is_empty() => trace.entries.iter().cloned().collect(),
+        _ => return Err(format!("There is no APDU trace of the card {}", card_number)),
+    };
+    let export = ApduTraceExport {
+        card_number,
+        exported_at: Timestamp::now(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        entries,
+    };
+    serde_json::to_string_pretty(&export).map_err(|e| e.to_string())
+}
@@ -3,13 +3,12 @@
 //! This module provides functionality for creating and managing MQTT connections.
 
 // Standard library imports
+use std::collections::HashMap; // For the registry of the running connections.
 use std::io::ErrorKind; // For categorizing I/O errors.
 use std::time::Duration; // For specifying time durations.
 
-// MQTT client library imports
-use rumqttc::v5::ConnectionError; // For handling MQTT connection errors.
-use rumqttc::v5::StateError::{self, AwaitPingResp, ServerDisconnect}; // Specific error for server disconnection.
-use rumqttc::v5::{AsyncClient, Event, Incoming, MqttOptions}; // Core MQTT async client and options.
+use lazy_static::lazy_static;
+use tauri::async_runtime::{self, JoinHandle}; // Async runtime and task join handles for Tauri apps.
 
 // Serialization/Deserialization library imports
 use serde_json::Value; // For working with JSON data structures.
@@ -20,44 +19,224 @@ use serde_json::Value; // For working with JSON data structures.
 /// to the MQTT server in case of connection loss.
 const SLEEP_DURATION_SECS: u64 = 10;
 
+/// Time in seconds the old application connection is given to send the DISCONNECT before it is aborted.
+const DISCONNECT_TIMEOUT_SECS: u64 = 3;
+
 // Importing specific functionality from local modules
-use crate::config::get_from_cache; // Function to get data from cache for syncing server data.
+use crate::config::{get_accounts, get_card_account_name, AccountConfig}; // Connections of the flespi accounts.
 use crate::config::split_host_to_parts; // Function to split the host into parts for MQTT connection.
-use crate::config::CacheSection; // Enum for cache sections for getting data from cache.
+use crate::mqtt_client::{create_client, ConnectionErrorKind, EventLoop, MqttClient, MqttEvent, MqttOptions, QoS}; // MQTT client of both protocol versions.
+use crate::maintenance::{handle_maintenance_message, is_maintenance_active}; // Maintenance windows announced by the server.
+use crate::security_log::SecurityEvent; // Audit of the remote interactions.
+use crate::remote_commands; // Commands of the server side to the bridge.
+use crate::connection_state::{self, link_phase, lost_phase, ConnectionKind, ConnectionPhase}; // Live states of the connections.
+use crate::global_app_handle::StateReason;
+
+/// Running application connection of the account.
+struct AppConnection {
+    /// The settings the connection is established with.
+    account: AccountConfig,
+    client: MqttClient,
+    handle: JoinHandle<()>,
+}
+
+lazy_static! {
+    /// Running application connections by the account name. The lock serializes the changes of the connections,
+    /// so the concurrent changes of the configuration don't leave the connection with the old ident.
+    static ref APP_CONNECTIONS: tokio::sync::Mutex<HashMap<String, AppConnection>> = tokio::sync::Mutex::new(HashMap::new());
+}
 
-/// Ensures an MQTT connection for the specified client ID.
+/// Ensures the MQTT application connections of all accounts from the configuration.
+/// The bridge serving several flespi accounts keeps a connection with the ident of every account.
 pub async fn app_connection() {
-    // Getting server data from the cache
-    let full_host = get_from_cache(CacheSection::Server, "host");
-    let (host, port) = match split_host_to_parts(&full_host) {
-        Ok((host, port)) => {
-            // log::debug!("Server data from cache: {:?}:{}", host, port);
-            (host, port)
+    let mut connections = APP_CONNECTIONS.lock().await;
+    for (name, account) in get_accounts() {
+        log::info!("Starting the application connection of the account '{}'", name);
+        if let Some(connection) = start_account_connection(account) {
+            connections.insert(name, connection);
+        }
+    }
+}
+
+/// Applies the changed accounts (e.g. the ident changed with `update_server`) to the running connections.
+///
+/// The connection of the changed account is disconnected cleanly and established again with the new settings,
+/// and the card connections of the account are re-established, so their topics use the new ident. The change of
+/// the ident is recorded to the security log.
+pub async fn apply_account_changes() {
+    let mut connections = APP_CONNECTIONS.lock().await;
+    let accounts = get_accounts();
+
+    let mut changed_accounts: Vec<String> = Vec::new();
+    for (name, account) in accounts.iter() {
+        let old_account = match connections.get(name) {
+            Some(connection) if connection.account == *account => continue,
+            Some(connection) => Some(connection.account.clone()),
+            None => None,
+        };
+        if let Some(old_connection) = connections.remove(name) {
+            stop_account_connection(old_connection).await;
+        }
+        if let Some(old_account) = old_account {
+            if old_account.ident != account.ident {
+                log::info!("The ident of the account '{}' is changed from '{}' to '{}'", name, old_account.ident, account.ident);
+                crate::security_log::record(
+                    SecurityEvent::IdentRotated,
+                    None,
+                    "update_server",
+                    &format!("account: {}, ident: {} -> {}", name, old_account.ident, account.ident),
+                );
+            }
+            changed_accounts.push(name.clone());
+        }
+        log::info!("Starting the application connection of the account '{}'", name);
+        if let Some(connection) = start_account_connection(account.clone()) {
+            connections.insert(name.clone(), connection);
+        }
+    }
+    // The accounts removed from the configuration
+    let removed: Vec<String> = connections
+        .keys()
+        .filter(|name| !accounts.iter().any(|(account_name, _)| account_name == *name))
+        .cloned()
+        .collect();
+    for name in removed {
+        if let Some(old_connection) = connections.remove(&name) {
+            stop_account_connection(old_connection).await;
+        }
+    }
+    drop(connections);
+
+    // The shared card connections of the changed and removed accounts are closed, their cards are reconnected
+    let mut cards = crate::multiplex::close_unconfigured();
+    // The card connections are re-established with the topics and the server of the changed account
+    cards.extend(
+        crate::smart_card::TASK_POOL
+            .lock()
+            .await
+            .iter()
+            .map(|task| task.client_id.clone())
+            .filter(|cardnumber| changed_accounts.contains(&get_card_account_name(cardnumber))),
+    );
+    cards.sort();
+    cards.dedup();
+    if !cards.is_empty() {
+        log::info!("Reconnecting the cards of the changed accounts: {:?}", cards);
+        crate::mqtt::remove_connections(cards).await;
+        // The errors of the PC/SC service are logged by the sync
+        let _ = crate::smart_card::manual_sync_cards().await;
+    }
+}
+
+/// Publishes the pending tombstones with the running application connections (see `migration::publish_tombstones`).
+pub async fn publish_tombstones() {
+    let connections = APP_CONNECTIONS.lock().await;
+    for connection in connections.values() {
+        crate::migration::publish_tombstones(&connection.client).await;
+    }
+}
+
+/// Publishes the heartbeat of every account with its application connection (see `heartbeat`).
+/// The connections which are not established are skipped, the heartbeat would only wait in their queues.
+pub async fn publish_heartbeats() {
+    let connected: Vec<(String, MqttClient)> = APP_CONNECTIONS
+        .lock()
+        .await
+        .values()
+        .filter(|connection| is_app_connected(&connection.account.ident))
+        .map(|connection| (connection.account.ident.clone(), connection.client.clone()))
+        .collect();
+    for (ident, client) in connected {
+        let payload = match serde_json::to_string(&crate::heartbeat::heartbeat(&ident)) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("{} | Failed to serialize the heartbeat: {}", ident, e);
+                continue;
+            }
+        };
+        if let Err(e) = client.publish(crate::heartbeat::heartbeat_topic(&ident), QoS::AtMostOnce, false, payload).await {
+            log::warn!("{} | Failed to publish the heartbeat: {:?}", ident, e);
+        }
+    }
+}
+
+/// Checks if the application connection with the ident is established.
+fn is_app_connected(ident: &str) -> bool {
+    connection_state::snapshot().iter().any(|state| {
+        state.kind == ConnectionKind::App
+            && state.id == ident
+            && matches!(state.state, ConnectionPhase::Connected | ConnectionPhase::Degraded)
+    })
+}
+
+/// Disconnects the application connection cleanly, it is aborted if the DISCONNECT is not sent in time.
+async fn stop_account_connection(connection: AppConnection) {
+    let AppConnection { account, client, mut handle } = connection;
+    let disconnected = tokio::time::timeout(Duration::from_secs(DISCONNECT_TIMEOUT_SECS), async {
+        if let Err(e) = client.disconnect().await {
+            log::warn!("{} | Failed to disconnect the application connection: {:?}", account.ident, e);
         }
+        (&mut handle).await.is_ok()
+    })
+    .await;
+    if disconnected != Ok(true) {
+        handle.abort();
+    }
+    connection_state::remove(ConnectionKind::App, &account.ident);
+    log::info!("{} | The application connection is closed", account.ident);
+}
+
+/// Starts the MQTT connection of the account, `None` if it can't be established with the settings of the account.
+fn start_account_connection(account: AccountConfig) -> Option<AppConnection> {
+    let ident = account.ident.clone();
+    // The server the broker has redirected the connections to (see the server_redirect module)
+    let mqtt_options = match app_mqtt_options(&account, &crate::server_redirect::current_host(&account.host)) {
+        Ok(mqtt_options) => mqtt_options,
         Err(e) => {
-            log::error!("Error: {}", e);
-            return;
+            log::error!("{} | The application connection can't be established: {}", ident, e);
+            return None;
         }
     };
 
-    // Getting the flespi token from the cache
-    let ident = get_from_cache(CacheSection::Ident, "ident");
+    // Create a new asynchronous MQTT client and its associated event loop
+    // `mqtt_options` specifies the configuration for the MQTT connection
+    // `10` is the capacity of the internal channel used by the event loop for buffering operations
+    let (client, mut eventloop) = create_client(mqtt_options, 10);
+    account.tuning.apply_connection_timeout(&mut eventloop);
+    let handle = async_runtime::spawn(account_connection(account.clone(), ident, client.clone(), eventloop));
+    Some(AppConnection { account, client, handle })
+}
+
+/// Builds the options of the application connection of the account to the server.
+///
+/// # Arguments
+///
+/// * `account` - The account of the connection.
+/// * `full_host` - The server, 'host:port': the configured one or the one of the redirect.
+fn app_mqtt_options(account: &AccountConfig, full_host: &str) -> Result<MqttOptions, String> {
+    let (host, port) = split_host_to_parts(full_host)?;
 
     //////////////////////////////////////////////////
     //  Create a new client ID for the MQTT connection
     //////////////////////////////////////////////////
-    let mut mqtt_options = MqttOptions::new(ident.clone(), &host, port);
-    // mqtt_options.set_credentials(flespi_token, "");
-    mqtt_options.set_keep_alive(Duration::from_secs(300));
+    let mut mqtt_options = MqttOptions::new(account.mqtt_version, &account.ident, &host, port);
+    account.apply_credentials(&mut mqtt_options);
+    account.apply_tls(&mut mqtt_options)?;
+    crate::proxy::apply_proxy(&mut mqtt_options)?;
+    mqtt_options.set_keep_alive(account.tuning.app_keep_alive());
+    account.tuning.apply_packet_size(&mut mqtt_options);
+    mqtt_options.set_user_properties(crate::installation::bridge_properties());
     // log::debug!("mqtt_options: {:?}", mqtt_options);
+    Ok(mqtt_options)
+}
 
-    // Create a new asynchronous MQTT client and its associated event loop
-    // `mqtt_options` specifies the configuration for the MQTT connection
-    // `10` is the capacity of the internal channel used by the event loop for buffering operations
-    let (_, mut eventloop) = AsyncClient::new(mqtt_options, 10);
-
+/// Polls the MQTT connection of the account.
+async fn account_connection(account: AccountConfig, ident: String, client: MqttClient, mut eventloop: EventLoop) {
     let log_header: String = format!("{} |", ident);
+    // The connection has been established before, the lost one is being reconnected
+    let mut has_connected = false;
 
+    connection_state::report(ConnectionKind::App, &ident, ConnectionPhase::Connecting, None, None);
     // create async task for the mqtt client
     loop {
         match eventloop.poll().await {
@@ -65,7 +244,7 @@ pub async fn app_connection() {
                 log::debug!("{} Notification: {:?}", log_header, notification);
 
                 match notification {
-                    Event::Incoming(Incoming::Publish(publish)) => {
+                    MqttEvent::Publish(publish) => {
                         // Extracting the topic from the incoming data
                         // let topic_str = match std::str::from_utf8(&publish.topic) {
                         //     Ok(str) => str,
@@ -81,40 +260,107 @@ pub async fn app_connection() {
                         // Card number and parcel ID. So we just change the initial topic
                         // let topic_ack = topic.replace("request", "response");
 
+                        // The bridge has been moved to another machine
+                        if publish.topic == crate::migration::own_tombstone_topic().as_bytes() {
+                            crate::migration::handle_tombstone_message(&publish.payload).await;
+                            continue;
+                        }
+
+                        // The command of the server side, not awaited in the polling loop as it publishes the result.
+                        // It is recorded to the security log by the remote_commands module
+                        if publish.topic == remote_commands::commands_topic(&ident).as_bytes() {
+                            async_runtime::spawn(remote_commands::handle_command(client.clone(), ident.clone(), publish.payload));
+                            continue;
+                        }
+
                         // serializable data to interpret it as json
                         match serde_json::from_slice::<Value>(&publish.payload) {
                             Ok(json_payload) => {
                                 println!("Parsed JSON payload: {:?}", json_payload);
-                                // The "hex" parameter contains the apdu instruction that needs to be transferred to the card
+                                // Planned backend downtime announcement
+                                if let Some(maintenance) = json_payload.get("maintenance") {
+                                    handle_maintenance_message(maintenance);
+                                }
                             }
                             Err(e) => {
                                 log::error!("{} parsing JSON payload issue: {:?}", log_header, e);
                             }
                         }
                     }
-                    Event::Incoming(Incoming::ConnAck(..)) => {
+                    MqttEvent::ConnAck { .. } => {
                         log::info!(
                             "{} Сonnection to the server has been successfully established.",
                             log_header
-                        )
+                        );
+                        has_connected = true;
+                        connection_state::report(ConnectionKind::App, &ident, link_phase(), None, None);
+                        // Not awaited in the polling loop, the requests are sent by the event loop
+                        let client = client.clone();
+                        let commands_topic = remote_commands::commands_topic(&ident);
+                        async_runtime::spawn(async move {
+                            // The tombstone of this installation is cleared before the subscription,
+                            // so the reactivated bridge doesn't receive it again
+                            crate::migration::publish_tombstones(&client).await;
+                            if let Err(e) = client.subscribe(crate::migration::own_tombstone_topic(), QoS::AtLeastOnce).await {
+                                log::warn!("Failed to subscribe to the tombstone of the installation: {:?}", e);
+                            }
+                            if let Err(e) = client.subscribe(commands_topic, QoS::AtLeastOnce).await {
+                                log::warn!("Failed to subscribe to the remote commands: {:?}", e);
+                            }
+                        });
+                    }
+                    MqttEvent::Disconnect => {
+                        log::info!("{} The connection is closed", log_header);
+                        return;
                     }
                     _ => {} // This handles any other events that you haven't explicitly matched above
                 }
             }
             Err(e) => {
-                match e {
-                    ConnectionError::Io(ref io_err) => match io_err.kind() {
+                let reason = if is_maintenance_active() {
+                    StateReason::ScheduledOffline
+                } else if e.kind() == ConnectionErrorKind::CredentialsRejected {
+                    StateReason::CredentialsRejected
+                } else {
+                    StateReason::BrokerUnreachable
+                };
+                connection_state::report(ConnectionKind::App, &ident, lost_phase(has_connected, reason), Some(reason), Some(e.to_string()));
+
+                // Connection losses are expected during the maintenance, so they are not reported as warnings
+                if is_maintenance_active() {
+                    log::debug!("{} Connection error during the maintenance window: {:?}", log_header, e);
+                    tokio::time::sleep(Duration::from_secs(SLEEP_DURATION_SECS)).await;
+                    continue;
+                }
+
+                match e.kind() {
+                    ConnectionErrorKind::ServerMoved => {
+                        // The connection is moved to the referenced server if it is allowed
+                        if let Some(target) = crate::server_redirect::follow_redirect(&account, &log_header).await {
+                            match app_mqtt_options(&account, &target) {
+                                Ok(mqtt_options) => {
+                                    eventloop.set_options(mqtt_options);
+                                    account.tuning.apply_connection_timeout(&mut eventloop);
+                                    continue;
+                                }
+                                Err(e) => log::error!("{} The connection can't be moved to {}: {}", log_header, target, e),
+                            }
+                        }
+                    }
+                    ConnectionErrorKind::Io(kind) => match kind {
                         ErrorKind::ConnectionAborted => log::warn!("{} Can't establish a connection to a remote server.", log_header),
                         ErrorKind::ConnectionReset => log::warn!("{} The connection could not be established. Check the server address in the configuration.", log_header),
                         ErrorKind::TimedOut => log::warn!("{} Connection timeout. The server may be down or the network is unstable.", log_header),
                         _ => log::error!("{} An IO error occurred.", log_header),
                     },
-                    ConnectionError::MqttState(ServerDisconnect { .. }) => log::warn!("{} The connection was terminated on the server side. Most likely the user has turned off the channel/device.", log_header),
-                    ConnectionError::MqttState(AwaitPingResp { .. }) => {
+                    ConnectionErrorKind::ServerDisconnect => log::warn!("{} The connection was terminated on the server side. Most likely the user has turned off the channel/device.", log_header),
+                    ConnectionErrorKind::AwaitPingResp => {
                         log::warn!("{} Awaiting PING response from the server. The connection might be unstable.", log_header);
                         // Implement your reconnection or handling strategy here
                     },
-                    ConnectionError::MqttState(StateError::Io{ .. }) => {
+                    ConnectionErrorKind::Proxy => crate::proxy::report_proxy_error(&format!("Failed to connect through the proxy: {}", e)),
+                    ConnectionErrorKind::CredentialsRejected => log::error!("{} The broker has rejected the credentials. Check the username and password in the configuration.", log_header),
+                    ConnectionErrorKind::StateIo => {
                         log::warn!("{} MQTT state IO error: Connection closed by peer", log_header);
                     },
                     _ => {
@@ -0,0 +1,138 @@
+//! Module for the parsing of the card ATRs (ISO/IEC 7816-3).
+//!
+//! The ATR tells the convention, the protocols and the transmission parameters of the card, it is decoded for
+//! the diagnostics view and the protocol override check. The tachograph card the ATR belongs to (its generation,
+//! manufacturer and model) is identified by the table of the `known_cards` module.
+
+use hex::encode;
+
+/// Clock rate conversion factors Fi by the FI nibble of TA1, `None` for the reserved values.
+const FI_TABLE: [Option<u16>; 16] = [
+    Some(372), Some(372), Some(558), Some(744), Some(1116), Some(1488), Some(1860), None,
+    None, Some(512), Some(768), Some(1024), Some(1536), Some(2048), None, None,
+];
+
+/// Baud rate adjustment factors Di by the DI nibble of TA1, `None` for the reserved values.
+const DI_TABLE: [Option<u8>; 16] = [
+    None, Some(1), Some(2), Some(4), Some(8), Some(16), Some(32), Some(64),
+    Some(12), Some(20), None, None, None, None, None, None,
+];
+
+/// Decoded ATR of the card (ISO/IEC 7816-3).
+///
+/// # Fields
+///
+/// * `convention` - "direct" or "inverse", from the TS byte.
+/// * `protocols` - Protocols offered by the card ("T=0", "T=1", ...), the first one is the default protocol.
+/// * `fi` - Clock rate conversion factor from TA1, `None` if TA1 is absent (the default 372 is used) or reserved.
+/// * `di` - Baud rate adjustment factor from TA1, `None` if TA1 is absent (the default 1 is used) or reserved.
+/// * `historical_bytes` - Historical bytes in hex (card issuer data).
+/// * `checksum_valid` - Whether the TCK byte is correct. `None` if the ATR has no TCK (only T=0 is offered).
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct AtrSummary {
+    pub convention: String,
+    pub protocols: Vec<String>,
+    pub fi: Option<u16>,
+    pub di: Option<u8>,
+    pub historical_bytes: String,
+    pub checksum_valid: Option<bool>,
+}
+
+/// Parses the ATR and finds the protocol the card works with by default.
+///
+/// # Arguments
+///
+/// * `atr` - The ATR bytes.
+///
+/// # Returns
+///
+/// * `Result<(String, AtrSummary), String>` - The default protocol and the decoded ATR, or the error message if the ATR is malformed.
+pub fn parse_atr_and_get_protocol(atr: &[u8]) -> Result<(String, AtrSummary), String> {
+    let convention = match atr.first() {
+        Some(0x3B) => "direct",
+        Some(0x3F) => "inverse",
+        Some(ts) => return Err(format!("Invalid TS byte: {:02X}", ts)),
+        None => return Err("ATR is empty".to_string()),
+    };
+    let t0 = *atr.get(1).ok_or("ATR is too short")?;
+    let historical_count = (t0 & 0x0F) as usize;
+
+    // TA1 (the first interface byte) holds the transmission parameters
+    let (fi, di) = match (t0 & 0x10 != 0).then(|| atr.get(2)).flatten() {
+        Some(ta1) => (FI_TABLE[(ta1 >> 4) as usize], DI_TABLE[(ta1 & 0x0F) as usize]),
+        None => (None, None),
+    };
+
+    // Interface bytes: every TD byte tells which bytes follow and the protocol
+    let mut protocols: Vec<u8> = Vec::new();
+    let mut indicator = t0 >> 4;
+    let mut pos = 2;
+    loop {
+        // TA, TB and TC are present if the corresponding bits are set
+        pos += (indicator & 0x07).count_ones() as usize;
+        if indicator & 0x08 == 0 {
+            break;
+        }
+        let td = *atr.get(pos).ok_or("ATR is truncated in the interface bytes")?;
+        let protocol = td & 0x0F;
+        if !protocols.contains(&protocol) {
+            protocols.push(protocol);
+        }
+        indicator = td >> 4;
+        pos += 1;
+    }
+    if protocols.is_empty() {
+        protocols.push(0);
+    }
+
+    let historical_bytes = atr
+        .get(pos..pos + historical_count)
+        .ok_or("ATR is truncated in the historical bytes")?;
+    pos += historical_count;
+
+    // TCK is present if any protocol other than T=0 is offered. XOR of T0..TCK must be zero.
+    let checksum_valid = if protocols.iter().any(|protocol| *protocol != 0) {
+        if atr.len() <= pos {
+            Some(false)
+        } else {
+            Some(atr[1..=pos].iter().fold(0, |acc, byte| acc ^ byte) == 0)
+        }
+    } else {
+        None
+    };
+
+    let protocols: Vec<String> = protocols.iter().map(|protocol| format!("T={}", protocol)).collect();
+    let summary = AtrSummary {
+        convention: convention.to_string(),
+        protocols: protocols.clone(),
+        fi,
+        di,
+        historical_bytes: encode(historical_bytes),
+        checksum_valid,
+    };
+    Ok((protocols[0].clone(), summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_generation_atr_is_parsed() {
+        let atr = hex::decode("3bff9600008131fe4380318065b0846566fb12017882900085").unwrap();
+        let (protocol, summary) = parse_atr_and_get_protocol(&atr).unwrap();
+        assert_eq!(protocol, "T=1");
+        assert_eq!(summary.convention, "direct");
+        assert_eq!(summary.fi, Some(512));
+        assert_eq!(summary.di, Some(32));
+        assert_eq!(summary.historical_bytes, "80318065b0846566fb120178829000");
+        assert_eq!(summary.checksum_valid, Some(true));
+
+        // Only T=0 without TA1: the default parameters and no TCK
+        let (protocol, summary) = parse_atr_and_get_protocol(&[0x3B, 0x02, 0x14, 0x50]).unwrap();
+        assert_eq!(protocol, "T=0");
+        assert_eq!((summary.fi, summary.di, summary.checksum_valid), (None, None, None));
+
+        assert!(parse_atr_and_get_protocol(&[0x3B, 0xFF, 0x96]).is_err());
+    }
+}
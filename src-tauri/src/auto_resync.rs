@@ -0,0 +1,96 @@
+//! Module for the automatic resync of the readers with the failing cards.
+//!
+//! When the card returns errors on several APDU commands in a row, the reader is resynced the same way as with
+//! the manual sync: the connection of the card is removed, the reader is removed from the pool and its state is
+//! read again, so the card is connected from scratch. The resyncs of the same card are separated by the cool-down,
+//! which is doubled after every resync (up to `max_cooldown_secs`), so a broken card doesn't get into a reset loop.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::config::get_auto_resync_config;
+use crate::global_app_handle::emit_notification;
+
+/// Resyncs of the card.
+struct ResyncHistory {
+    /// Number of the resyncs in a row, each of them doubles the cool-down.
+    count: u32,
+    last: Instant,
+}
+
+lazy_static! {
+    /// Resyncs by the card number.
+    static ref RESYNC_HISTORY: Mutex<HashMap<String, ResyncHistory>> = Mutex::new(HashMap::new());
+}
+
+/// Checks if the number of the consecutive APDU failures of the card reached the threshold from the configuration.
+pub fn is_failure_threshold_reached(consecutive_failures: u32) -> bool {
+    let config = get_auto_resync_config();
+    config.enabled && config.failure_threshold > 0 && consecutive_failures >= config.failure_threshold
+}
+
+/// Resyncs the reader with the failing card, unless the card is in the cool-down after the previous resync.
+///
+/// The resync runs in a separate task, because it removes the connection of the card, i.e. the task of the caller.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+/// * `reader_name` - The reader the card is inserted to.
+/// * `consecutive_failures` - The number of the failed APDU commands in a row.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the resync is started, `false` if the card is in the cool-down.
+pub fn request_resync(cardnumber: &str, reader_name: CString, consecutive_failures: u32) -> bool {
+    let config = get_auto_resync_config();
+    let now = Instant::now();
+    let max_cooldown = Duration::from_secs(config.max_cooldown_secs);
+
+    {
+        let mut history = RESYNC_HISTORY.lock().unwrap();
+        let entry = history.entry(cardnumber.to_string()).or_insert(ResyncHistory { count: 0, last: now });
+        if entry.count > 0 {
+            let elapsed = now.duration_since(entry.last);
+            // The card has worked long enough since the last resync, so the cool-down starts over
+            if elapsed > max_cooldown * 2 {
+                entry.count = 0;
+            } else {
+                let cooldown = cooldown(config.cooldown_secs, entry.count).min(max_cooldown);
+                if elapsed < cooldown {
+                    log::warn!(
+                        "{} | The reader is not resynced, the card is in the cool-down for {} more seconds",
+                        cardnumber,
+                        (cooldown - elapsed).as_secs()
+                    );
+                    return false;
+                }
+            }
+        }
+        entry.count += 1;
+        entry.last = now;
+    }
+
+    let message = format!(
+        "The card {} has failed {} commands in a row, the reader is resynced",
+        cardnumber, consecutive_failures
+    );
+    log::warn!("{}", message);
+    // The notification is kept in the event store with the other notifications
+    emit_notification("warning", &message);
+
+    tauri::async_runtime::spawn(async move {
+        crate::smart_card::resync_reader(&reader_name).await;
+    });
+    true
+}
+
+/// Cool-down after the resyncs in a row: the base cool-down doubled after every resync.
+fn cooldown(base_secs: u64, resyncs: u32) -> Duration {
+    let factor = 2u64.saturating_pow(resyncs.saturating_sub(1));
+    Duration::from_secs(base_secs.saturating_mul(factor))
+}
@@ -0,0 +1,141 @@
+//! Module for broadcasting card states to the local network.
+//!
+//! Some depots use a wall display that shows the state of the company cards and authentications.
+//! When enabled in the configuration, every card state sent to the frontend is also sent as a JSON
+//! datagram to the UDP multicast group, so any display in the LAN can listen to it without access to the application.
+//! The broadcast is started, stopped or moved to another group when its settings change.
+//!
+//! Anybody in the LAN can read the messages, so they don't carry the token of the displays. They carry the identifier
+//! derived from it instead, HMAC-SHA256 of `SOURCE_ID_CONTEXT` with the token as the key (see `source_id`),
+//! the display configured with the token computes the same identifier to check the source.
+
+// Standard library imports
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use ring::hmac;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::config::{get_broadcast_config, get_disclosed_atr, watch_broadcast_config, BroadcastConfig};
+use crate::global_app_handle::CardStatePayload;
+
+/// Capacity of the channel between the card state emitters and the broadcasting task.
+/// If the task can't keep up, the messages are dropped instead of blocking the card processing.
+const BROADCAST_CHANNEL_CAPACITY: usize = 64;
+
+/// Message the source identifier is computed for (see `source_id`).
+const SOURCE_ID_CONTEXT: &[u8] = b"tba-broadcast";
+
+lazy_static! {
+    static ref BROADCAST_SENDER: Mutex<Option<mpsc::Sender<String>>> = Mutex::new(None);
+}
+
+/// Runs the broadcasting task: the card states are broadcast while the broadcast is enabled in the configuration,
+/// the task follows the changes of the settings.
+pub async fn start_broadcast() {
+    let mut config_rx = watch_broadcast_config();
+    loop {
+        let config = config_rx.borrow_and_update().clone();
+        let stopped = tokio::select! {
+            _ = broadcast(config) => true,
+            _ = config_rx.changed() => false,
+        };
+        *BROADCAST_SENDER.lock().unwrap() = None;
+        // The broadcast which is disabled or has failed waits for the new settings
+        if stopped && config_rx.changed().await.is_err() {
+            return;
+        }
+        log::info!("The broadcast settings are changed");
+    }
+}
+
+/// Broadcasts the card states with the settings, returns if the broadcast is disabled or fails to start.
+async fn broadcast(config: Option<BroadcastConfig>) {
+    let config = match config {
+        Some(config) if config.enabled => config,
+        _ => return,
+    };
+
+    let address: SocketAddr = match config.address.parse() {
+        Ok(address) => address,
+        Err(e) => {
+            log::error!("Invalid broadcast address '{}': {}", config.address, e);
+            return;
+        }
+    };
+
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("Failed to create the broadcast socket: {}", e);
+            return;
+        }
+    };
+    // The messages shouldn't leave the local network
+    if let Err(e) = socket.set_multicast_ttl_v4(1) {
+        log::warn!("Failed to set the multicast TTL for the broadcast socket: {}", e);
+    }
+
+    let (sender, mut receiver) = mpsc::channel::<String>(BROADCAST_CHANNEL_CAPACITY);
+    *BROADCAST_SENDER.lock().unwrap() = Some(sender);
+    log::info!("Card states are broadcast to {}", address);
+
+    while let Some(message) = receiver.recv().await {
+        if let Err(e) = socket.send_to(message.as_bytes(), address).await {
+            log::warn!("Failed to broadcast the card state: {}", e);
+        }
+    }
+}
+
+/// Sends the card state to the local network if the broadcast is running.
+pub fn broadcast_card_state(payload: &CardStatePayload) {
+    let sender = match BROADCAST_SENDER.lock().unwrap().clone() {
+        Some(sender) => sender,
+        None => return,
+    };
+    let token = get_broadcast_config().map(|config| config.token).unwrap_or_default();
+
+    // The ATR is disclosed according to the card settings, the hidden one is sent as the empty string
+    let data = CardStatePayload {
+        atr: get_disclosed_atr(&payload.card_number, &payload.atr).unwrap_or_default(),
+        ..payload.clone()
+    };
+
+    let message = serde_json::json!({
+        "source": source_id(&token),
+        "event": "card-state",
+        "data": data,
+    })
+    .to_string();
+
+    if let Err(e) = sender.try_send(message) {
+        log::debug!("Card state is not broadcast: {}", e);
+    }
+}
+
+/// Returns the identifier of the messages derived from the token of the displays: HMAC-SHA256 of `SOURCE_ID_CONTEXT`
+/// in hex, the empty string if the token is not configured.
+pub fn source_id(token: &str) -> String {
+    if token.is_empty() {
+        return String::new();
+    }
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    hex::encode(hmac::sign(&key, SOURCE_ID_CONTEXT).as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_id_is_derived_from_token() {
+        let id = source_id("display-token");
+        assert_eq!(id.len(), 64);
+        assert!(!id.contains("display-token"));
+        assert_eq!(id, source_id("display-token"));
+        assert_ne!(id, source_id("another-token"));
+        assert_eq!(source_id(""), "");
+    }
+}
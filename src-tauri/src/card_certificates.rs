@@ -0,0 +1,741 @@
+//! Module for the local check of the card certificates.
+//!
+//! The server rejects the authentication of the company card whose certificate is expired, revoked or doesn't
+//! chain up to the European root (ERCA), and the user learns it only from the failed download. So the certificates
+//! are read from the chip when the card is inserted and checked locally, and the user is warned in advance:
+//! * Gen1 (Annex 1B): EF Card_Certificate and EF CA_Certificate (the member state) are RSA-1024 certificates with
+//!   the message recovery (ISO/IEC 9796-2), the content is recovered with the public key of the signer;
+//! * Gen2 (Annex 1C): EF Card_MA_Certificate and EF CA_Certificate of DF Tachograph_G2 are the card verifiable
+//!   certificates with the ECDSA signatures.
+//!
+//! The root keys are replaced from time to time, so they are not built in: the paths to the European public key
+//! of the first generation (EUR.PK) and to the root certificate of the second generation are configured in the
+//! `certificates` section, as well as the revoked certificates. Without the root key the Gen2 chain is checked up
+//! to the member state certificate, and the Gen1 certificates can't be recovered at all. The signatures on
+//! the Brainpool curves can't be verified locally (only the NIST curves are supported), such a certificate is
+//! checked for the validity and the revocation only.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use num_bigint::BigUint;
+use ring::digest;
+use ring::signature::{self, UnparsedPublicKey};
+use serde::Serialize;
+
+use crate::card_identification::decode_time;
+use crate::config::{get_certificates_config, retry_io, CertificatesConfig};
+use crate::global_app_handle::emit_notification;
+use crate::known_cards::CardGeneration;
+use crate::protocol::{transmit_chained, ApduTransport};
+use crate::timestamp::Timestamp;
+
+/// Select the applications by their names "TACHO" (Gen1) and "SMRDT" (Gen2).
+const SELECT_DF_TACHOGRAPH_APDU: &str = "00a4040c06ff544143484f";
+const SELECT_DF_TACHOGRAPH_G2_APDU: &str = "00a4040c06ff534d524454";
+/// Select EF Card_Certificate (Gen1) or EF Card_MA_Certificate (Gen2), FID C100.
+const SELECT_EF_CARD_CERTIFICATE_APDU: &str = "00a4020c02c100";
+/// Select EF CA_Certificate, the certificate of the member state, FID C108.
+const SELECT_EF_CA_CERTIFICATE_APDU: &str = "00a4020c02c108";
+/// Select the MF, so the card is left as it has been before the reading.
+const SELECT_MF_APDU: &str = "00a4000c023f00";
+
+/// Length of the Gen1 certificate: the signature, the non-recoverable part of the content and the CAR.
+const GEN1_CERTIFICATE_LENGTH: usize = 194;
+/// Length of the Gen1 content: CPI, CAR, CHA, EOV, CHR, the modulus and the exponent.
+const GEN1_CONTENT_LENGTH: usize = 164;
+/// Length of the RSA modulus and the signature of the Gen1 keys.
+const GEN1_MODULUS_LENGTH: usize = 128;
+/// Length of the EUR.PK file: the key identifier, the modulus and the exponent.
+const GEN1_ROOT_KEY_LENGTH: usize = 144;
+/// Maximum length of the Gen2 certificate (with the 512-bit curve it is about 340 bytes).
+const GEN2_CERTIFICATE_MAX_LENGTH: usize = 512;
+/// Maximum length of the data read with one READ BINARY.
+const READ_CHUNK_LENGTH: usize = 200;
+
+/// OIDs of the domain parameters (the curves) of the Gen2 keys.
+const OID_NIST_P256: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const OID_NIST_P384: &[u8] = &[0x2B, 0x81, 0x04, 0x00, 0x22];
+const OID_NIST_P521: &[u8] = &[0x2B, 0x81, 0x04, 0x00, 0x23];
+const OID_BRAINPOOL_P256: &[u8] = &[0x2B, 0x24, 0x03, 0x03, 0x02, 0x08, 0x01, 0x01, 0x07];
+const OID_BRAINPOOL_P384: &[u8] = &[0x2B, 0x24, 0x03, 0x03, 0x02, 0x08, 0x01, 0x01, 0x0B];
+const OID_BRAINPOOL_P512: &[u8] = &[0x2B, 0x24, 0x03, 0x03, 0x02, 0x08, 0x01, 0x01, 0x0D];
+
+/// Result of the check of the card certificates.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CertificateStatus {
+    /// The chain is verified up to the root and the certificates are valid.
+    Valid,
+    /// The certificates are valid as far as they are checked, but the chain is not verified up to the root.
+    Unverified,
+    /// The certificate of the card or of the member state has expired.
+    Expired,
+    /// The certificate of the card or of the member state is not valid yet.
+    NotYetValid,
+    /// The certificate of the card or of the member state is in the revocation list of the configuration.
+    Revoked,
+    /// A signature of the chain is wrong: the certificate is damaged or the card is not genuine.
+    InvalidSignature,
+}
+
+/// Check of the certificates of the card, for the frontend.
+///
+/// # Fields
+///
+/// * `holder_reference` - The certificate holder reference (CHR) of the card certificate in hex,
+///   `None` if the certificate can't be recovered.
+/// * `authority_reference` - The reference of the member state key the card certificate is signed with (CAR) in hex.
+/// * `valid_from`, `valid_until` - The validity of the card certificate, `None` if it is unknown or unlimited.
+/// * `detail` - Description of the status for the UI, `None` for the valid certificates.
+#[derive(Serialize, Clone, Debug)]
+pub struct CertificateCheck {
+    pub card_number: String,
+    pub generation: CardGeneration,
+    pub status: CertificateStatus,
+    pub detail: Option<String>,
+    pub holder_reference: Option<String>,
+    pub authority_reference: String,
+    pub valid_from: Option<Timestamp>,
+    pub valid_until: Option<Timestamp>,
+    pub checked_at: Timestamp,
+}
+
+/// Public key of the certificate.
+#[derive(Clone, Debug, PartialEq)]
+enum PublicKey {
+    Rsa { modulus: Vec<u8>, exponent: Vec<u8> },
+    Ec { curve: Vec<u8>, point: Vec<u8> },
+}
+
+/// Decoded certificate.
+#[derive(Clone, Debug)]
+struct Certificate {
+    /// Certificate authority reference, the key the certificate is signed with.
+    car: Vec<u8>,
+    /// Certificate holder reference, the key of the certificate.
+    chr: Vec<u8>,
+    valid_from: Option<Timestamp>,
+    valid_until: Option<Timestamp>,
+    public_key: PublicKey,
+    /// The signed data and the signature of the Gen2 certificate, empty for Gen1.
+    body: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// The problem of the chain: the status and its description.
+type Problem = (CertificateStatus, String);
+
+/// Decoded certificates of the card and the member state, and the problem of their chain.
+struct Chain {
+    card: Option<Certificate>,
+    authority: Option<Certificate>,
+    problem: Option<Problem>,
+}
+
+lazy_static! {
+    /// The last checks by the card number.
+    static ref CHECKS: Mutex<HashMap<String, CertificateCheck>> = Mutex::new(HashMap::new());
+    /// The cards and the statuses the user has been warned about, the warning is shown once per run.
+    static ref WARNED: Mutex<HashSet<(String, CertificateStatus)>> = Mutex::new(HashSet::new());
+}
+
+/// Reads the certificates of the card and checks them. The card is left in the MF.
+///
+/// # Arguments
+///
+/// * `card` - The card.
+/// * `generation` - The generation of the card (see `smart_card::detect_card_generation`).
+/// * `card_number` - The number of the card.
+///
+/// # Returns
+///
+/// * `Result<CertificateCheck, String>` - The check, or the error message if the certificates can't be read.
+pub fn check<T: ApduTransport<Error = Box<dyn Error>>>(
+    card: &T,
+    generation: CardGeneration,
+    card_number: &str,
+) -> Result<CertificateCheck, String> {
+    let certificates = read_certificates(card, generation);
+    if let Err(e) = card.transmit_hex(SELECT_MF_APDU) {
+        log::warn!("Failed to select the MF after the certificates are read: {}", e);
+    }
+    let (card_data, ca_data) = certificates?;
+    Ok(evaluate(card_number, generation, &card_data, &ca_data, &get_certificates_config()))
+}
+
+/// Reads the certificate of the card and the certificate of the member state.
+fn read_certificates<T: ApduTransport<Error = Box<dyn Error>>>(
+    card: &T,
+    generation: CardGeneration,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let select_application = match generation {
+        CardGeneration::Gen1 => SELECT_DF_TACHOGRAPH_APDU,
+        CardGeneration::Gen2 => SELECT_DF_TACHOGRAPH_G2_APDU,
+    };
+    command(card, select_application, "The tachograph application selection")?;
+    let mut certificates = Vec::new();
+    for (select, name) in [
+        (SELECT_EF_CARD_CERTIFICATE_APDU, "EF Card_Certificate"),
+        (SELECT_EF_CA_CERTIFICATE_APDU, "EF CA_Certificate"),
+    ] {
+        command(card, select, &format!("{} selection", name))?;
+        let data = match generation {
+            CardGeneration::Gen1 => read_binary(card, 0, GEN1_CERTIFICATE_LENGTH)?,
+            CardGeneration::Gen2 => read_tlv_file(card)?,
+        };
+        certificates.push(data);
+    }
+    let ca_data = certificates.pop().unwrap_or_default();
+    let card_data = certificates.pop().unwrap_or_default();
+    Ok((card_data, ca_data))
+}
+
+/// Sends the command and checks that the status is 9000.
+fn command<T: ApduTransport<Error = Box<dyn Error>>>(card: &T, apdu: &str, name: &str) -> Result<(), String> {
+    let response = card.transmit_hex(apdu).map_err(|e| format!("{} has failed: {}", name, e))?;
+    if response.ends_with("9000") {
+        Ok(())
+    } else {
+        Err(format!("{} has failed with the status {}", name, response))
+    }
+}
+
+/// Reads the part of the selected file. The end of the file (6282) is accepted, the data is shorter then.
+fn read_binary<T: ApduTransport<Error = Box<dyn Error>>>(card: &T, offset: usize, length: usize) -> Result<Vec<u8>, String> {
+    let apdu = format!("00b0{:04x}{:02x}", offset, length);
+    let response = transmit_chained(card, &apdu).map_err(|e| format!("The certificate reading has failed: {}", e))?;
+    let data = response
+        .strip_suffix("9000")
+        .or_else(|| response.strip_suffix("6282"))
+        .ok_or_else(|| format!("The certificate reading has failed with the status {}", response))?;
+    hex::decode(data).map_err(|e| e.to_string())
+}
+
+/// Reads the selected file with the single TLV object, the Gen2 certificate.
+fn read_tlv_file<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> Result<Vec<u8>, String> {
+    // The tag of two bytes and the length of up to three bytes
+    let mut data = read_binary(card, 0, 5)?;
+    let (_, header_length, value_length) = parse_tlv_header(&data)?;
+    let length = header_length + value_length;
+    if length > GEN2_CERTIFICATE_MAX_LENGTH {
+        return Err(format!("The certificate is too long: {} bytes", length));
+    }
+    while data.len() < length {
+        let chunk = read_binary(card, data.len(), (length - data.len()).min(READ_CHUNK_LENGTH))?;
+        if chunk.is_empty() {
+            return Err("The certificate file is shorter than the certificate".to_string());
+        }
+        data.extend(chunk);
+    }
+    data.truncate(length);
+    Ok(data)
+}
+
+/// Checks the certificates read from the card.
+fn evaluate(
+    card_number: &str,
+    generation: CardGeneration,
+    card_data: &[u8],
+    ca_data: &[u8],
+    config: &CertificatesConfig,
+) -> CertificateCheck {
+    let chain = match generation {
+        CardGeneration::Gen1 => gen1_chain(card_data, ca_data, config),
+        CardGeneration::Gen2 => gen2_chain(card_data, ca_data, config),
+    };
+    let authority_reference = match (&chain.card, generation) {
+        (Some(card), _) => hex::encode(&card.car),
+        // The CAR of the Gen1 certificate is not encrypted
+        (None, CardGeneration::Gen1) => hex::encode(card_data.get(GEN1_CERTIFICATE_LENGTH - 8..).unwrap_or_default()),
+        (None, CardGeneration::Gen2) => String::new(),
+    };
+
+    let now = Timestamp::now().epoch;
+    let certificates: Vec<&Certificate> = chain.card.iter().chain(chain.authority.iter()).collect();
+    let revoked = certificates
+        .iter()
+        .find(|certificate| config.revoked.iter().any(|revoked| revoked.eq_ignore_ascii_case(&hex::encode(&certificate.chr))));
+    let expired = certificates
+        .iter()
+        .find(|certificate| certificate.valid_until.as_ref().map(|until| until.epoch < now).unwrap_or(false));
+    let not_yet_valid = certificates
+        .iter()
+        .find(|certificate| certificate.valid_from.as_ref().map(|from| from.epoch > now).unwrap_or(false));
+
+    let (status, detail) = match (chain.problem, revoked, expired, not_yet_valid) {
+        (Some((CertificateStatus::InvalidSignature, detail)), _, _, _) => (CertificateStatus::InvalidSignature, Some(detail)),
+        (_, Some(certificate), _, _) => (
+            CertificateStatus::Revoked,
+            Some(format!("The certificate {} is revoked", hex::encode(&certificate.chr))),
+        ),
+        (_, None, Some(certificate), _) => (
+            CertificateStatus::Expired,
+            Some(format!(
+                "The certificate {} has expired on {}",
+                hex::encode(&certificate.chr),
+                certificate.valid_until.as_ref().map(|until| until.date()).unwrap_or_default()
+            )),
+        ),
+        (_, None, None, Some(certificate)) => (
+            CertificateStatus::NotYetValid,
+            Some(format!(
+                "The certificate {} is valid from {}",
+                hex::encode(&certificate.chr),
+                certificate.valid_from.as_ref().map(|from| from.date()).unwrap_or_default()
+            )),
+        ),
+        (Some((status, detail)), None, None, None) => (status, Some(detail)),
+        (None, None, None, None) => (CertificateStatus::Valid, None),
+    };
+
+    CertificateCheck {
+        card_number: card_number.to_string(),
+        generation,
+        status,
+        detail,
+        holder_reference: chain.card.as_ref().map(|card| hex::encode(&card.chr)),
+        authority_reference,
+        valid_from: chain.card.as_ref().and_then(|card| card.valid_from.clone()),
+        valid_until: chain.card.as_ref().and_then(|card| card.valid_until.clone()),
+        checked_at: Timestamp::now(),
+    }
+}
+
+/// Reads the root key file, the binary or the hex content.
+fn read_root_file(path: &str) -> Result<Vec<u8>, String> {
+    let contents = retry_io(|| fs::read(path)).map_err(|e| format!("Failed to read the root key {}: {}", path, e))?;
+    let text: String = String::from_utf8_lossy(&contents).split_whitespace().collect();
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        hex::decode(&text).map_err(|e| format!("Failed to decode the root key {}: {}", path, e))
+    } else {
+        Ok(contents)
+    }
+}
+
+/// Recovers the Gen1 certificates: the member state one with the European key, the card one with the member state key.
+fn gen1_chain(card_data: &[u8], ca_data: &[u8], config: &CertificatesConfig) -> Chain {
+    let recovered = load_gen1_root(config).and_then(|root| {
+        let authority = recover_gen1(ca_data, &root)?;
+        let card = recover_gen1(card_data, &authority)?;
+        Ok((card, authority))
+    });
+    match recovered {
+        Ok((card, authority)) => Chain {
+            card: Some(card),
+            authority: Some(authority),
+            problem: None,
+        },
+        Err(problem) => Chain {
+            card: None,
+            authority: None,
+            problem: Some(problem),
+        },
+    }
+}
+
+/// Loads EUR.PK: the key identifier, the modulus and the exponent. It is returned as the certificate of the key.
+fn load_gen1_root(config: &CertificatesConfig) -> Result<Certificate, Problem> {
+    let unverified = |detail: String| (CertificateStatus::Unverified, detail);
+    let path = config
+        .erca_gen1_key
+        .as_deref()
+        .ok_or_else(|| unverified("The European public key of the first generation is not configured".to_string()))?;
+    let key = read_root_file(path).map_err(unverified)?;
+    if key.len() != GEN1_ROOT_KEY_LENGTH {
+        return Err(unverified(format!("The European public key has {} bytes instead of {}", key.len(), GEN1_ROOT_KEY_LENGTH)));
+    }
+    Ok(Certificate {
+        car: key[..8].to_vec(),
+        chr: key[..8].to_vec(),
+        valid_from: None,
+        valid_until: None,
+        public_key: PublicKey::Rsa {
+            modulus: key[8..8 + GEN1_MODULUS_LENGTH].to_vec(),
+            exponent: key[8 + GEN1_MODULUS_LENGTH..].to_vec(),
+        },
+        body: Vec::new(),
+        signature: Vec::new(),
+    })
+}
+
+/// Recovers the content of the Gen1 certificate with the key of the signer (ISO/IEC 9796-2, SHA-1).
+fn recover_gen1(data: &[u8], signer: &Certificate) -> Result<Certificate, Problem> {
+    let invalid = |detail: String| (CertificateStatus::InvalidSignature, detail);
+    if data.len() != GEN1_CERTIFICATE_LENGTH {
+        return Err(invalid(format!("The certificate has {} bytes instead of {}", data.len(), GEN1_CERTIFICATE_LENGTH)));
+    }
+    let (signature, rest) = data.split_at(GEN1_MODULUS_LENGTH);
+    let (non_recoverable, car) = rest.split_at(rest.len() - 8);
+    if car != signer.chr.as_slice() {
+        return Err((
+            CertificateStatus::Unverified,
+            format!("The certificate is signed with the key {}, not with {}", hex::encode(car), hex::encode(&signer.chr)),
+        ));
+    }
+    let (modulus, exponent) = match &signer.public_key {
+        PublicKey::Rsa { modulus, exponent } => (modulus, exponent),
+        PublicKey::Ec { .. } => return Err(invalid("The certificate is signed with the key of another generation".to_string())),
+    };
+
+    let recovered = mod_pow(signature, exponent, modulus)
+        .ok_or_else(|| invalid(format!("The signature of the certificate signed with {} is wrong", hex::encode(car))))?;
+    let recoverable_length = GEN1_MODULUS_LENGTH - 22;
+    if recovered.first() != Some(&0x6A) || recovered.last() != Some(&0xBC) {
+        return Err(invalid(format!("The signature of the certificate signed with {} is wrong", hex::encode(car))));
+    }
+    let mut content = recovered[1..1 + recoverable_length].to_vec();
+    content.extend_from_slice(non_recoverable);
+    let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &content);
+    if hash.as_ref() != &recovered[1 + recoverable_length..GEN1_MODULUS_LENGTH - 1] || content.len() != GEN1_CONTENT_LENGTH {
+        return Err(invalid(format!("The hash of the certificate signed with {} is wrong", hex::encode(car))));
+    }
+
+    // CPI (1), CAR (8), CHA (7), EOV (4), CHR (8), the modulus (128) and the exponent (8)
+    Ok(Certificate {
+        car: content[1..9].to_vec(),
+        chr: content[20..28].to_vec(),
+        valid_from: None,
+        valid_until: decode_time(&content[16..20]),
+        public_key: PublicKey::Rsa {
+            modulus: content[28..28 + GEN1_MODULUS_LENGTH].to_vec(),
+            exponent: content[28 + GEN1_MODULUS_LENGTH..].to_vec(),
+        },
+        body: Vec::new(),
+        signature: Vec::new(),
+    })
+}
+
+/// Parses the Gen2 certificates and verifies the card one with the member state one, and the member state one
+/// with the root certificate.
+fn gen2_chain(card_data: &[u8], ca_data: &[u8], config: &CertificatesConfig) -> Chain {
+    let malformed = |name: &str, e: String| Chain {
+        card: None,
+        authority: None,
+        problem: Some((CertificateStatus::InvalidSignature, format!("The {} certificate is malformed: {}", name, e))),
+    };
+    let card = match parse_cv_certificate(card_data) {
+        Ok(card) => card,
+        Err(e) => return malformed("card", e),
+    };
+    let authority = match parse_cv_certificate(ca_data) {
+        Ok(authority) => authority,
+        Err(e) => return malformed("member state", e),
+    };
+    let problem = verify_cv_certificate(&card, &authority).err().or_else(|| match load_gen2_root(config) {
+        Ok(root) => verify_cv_certificate(&authority, &root).err(),
+        Err(problem) => Some(problem),
+    });
+    Chain {
+        card: Some(card),
+        authority: Some(authority),
+        problem,
+    }
+}
+
+/// Loads the root certificate of the second generation.
+fn load_gen2_root(config: &CertificatesConfig) -> Result<Certificate, Problem> {
+    let unverified = |detail: String| (CertificateStatus::Unverified, detail);
+    let path = config
+        .erca_gen2_root
+        .as_deref()
+        .ok_or_else(|| unverified("The European root certificate of the second generation is not configured".to_string()))?;
+    let data = read_root_file(path).map_err(unverified)?;
+    parse_cv_certificate(&data).map_err(|e| unverified(format!("The root certificate {} is malformed: {}", path, e)))
+}
+
+/// Verifies the signature of the Gen2 certificate with the key of the signer.
+fn verify_cv_certificate(certificate: &Certificate, signer: &Certificate) -> Result<(), Problem> {
+    if certificate.car != signer.chr {
+        return Err((
+            CertificateStatus::Unverified,
+            format!(
+                "The certificate {} is signed with the key {}, not with {}",
+                hex::encode(&certificate.chr),
+                hex::encode(&certificate.car),
+                hex::encode(&signer.chr)
+            ),
+        ));
+    }
+    let (curve, point) = match &signer.public_key {
+        PublicKey::Ec { curve, point } => (curve, point),
+        PublicKey::Rsa { .. } => {
+            return Err((CertificateStatus::InvalidSignature, "The certificate is signed with the key of another generation".to_string()))
+        }
+    };
+    // The hash follows the size of the curve of the signer
+    let algorithm: &'static dyn signature::VerificationAlgorithm = match curve.as_slice() {
+        OID_NIST_P256 => &signature::ECDSA_P256_SHA256_FIXED,
+        OID_NIST_P384 => &signature::ECDSA_P384_SHA384_FIXED,
+        curve => {
+            return Err((
+                CertificateStatus::Unverified,
+                format!("The signatures on the curve {} can't be verified locally", curve_name(curve)),
+            ))
+        }
+    };
+    UnparsedPublicKey::new(algorithm, point)
+        .verify(&certificate.body, &certificate.signature)
+        .map_err(|_| {
+            (
+                CertificateStatus::InvalidSignature,
+                format!("The signature of the certificate {} is wrong", hex::encode(&certificate.chr)),
+            )
+        })
+}
+
+fn curve_name(oid: &[u8]) -> String {
+    match oid {
+        OID_NIST_P256 => "NIST P-256".to_string(),
+        OID_NIST_P384 => "NIST P-384".to_string(),
+        OID_NIST_P521 => "NIST P-521".to_string(),
+        OID_BRAINPOOL_P256 => "brainpoolP256r1".to_string(),
+        OID_BRAINPOOL_P384 => "brainpoolP384r1".to_string(),
+        OID_BRAINPOOL_P512 => "brainpoolP512r1".to_string(),
+        oid => hex::encode(oid),
+    }
+}
+
+/// Parses the header of the BER-TLV object: the tag, the length of the header and the length of the value.
+fn parse_tlv_header(data: &[u8]) -> Result<(u16, usize, usize), String> {
+    let byte = |index: usize| data.get(index).copied().map(usize::from).ok_or("The TLV object is truncated");
+    let (tag, mut position) = if byte(0)? & 0x1F == 0x1F {
+        ((byte(0)? << 8 | byte(1)?) as u16, 2)
+    } else {
+        (byte(0)? as u16, 1)
+    };
+    let length = match byte(position)? {
+        length if length < 0x80 => length,
+        0x81 => {
+            position += 1;
+            byte(position)?
+        }
+        0x82 => {
+            position += 2;
+            byte(position - 1)? << 8 | byte(position)?
+        }
+        length => return Err(format!("Unsupported length of the TLV object: {:02x}", length)),
+    };
+    Ok((tag, position + 1, length))
+}
+
+/// TLV object: the tag, the value and the whole object.
+type TlvObject<'a> = (u16, &'a [u8], &'a [u8]);
+
+/// Splits the data into the TLV objects.
+fn parse_tlv(mut data: &[u8]) -> Result<Vec<TlvObject<'_>>, String> {
+    let mut objects = Vec::new();
+    while !data.is_empty() {
+        let (tag, header_length, value_length) = parse_tlv_header(data)?;
+        let end = header_length + value_length;
+        if data.len() < end {
+            return Err(format!("The TLV object {:04x} is truncated", tag));
+        }
+        objects.push((tag, &data[header_length..end], &data[..end]));
+        data = &data[end..];
+    }
+    Ok(objects)
+}
+
+/// Finds the value of the TLV object with the tag.
+fn find_tlv<'a>(objects: &[TlvObject<'a>], tag: u16) -> Result<&'a [u8], String> {
+    objects
+        .iter()
+        .find(|(object_tag, _, _)| *object_tag == tag)
+        .map(|(_, value, _)| *value)
+        .ok_or_else(|| format!("The TLV object {:04x} is missing", tag))
+}
+
+/// Parses the Gen2 card verifiable certificate (Annex 1C, Appendix 11).
+fn parse_cv_certificate(data: &[u8]) -> Result<Certificate, String> {
+    let certificate = find_tlv(&parse_tlv(data)?, 0x7F21)?;
+    let parts = parse_tlv(certificate)?;
+    let body = parts
+        .iter()
+        .find(|(tag, _, _)| *tag == 0x7F4E)
+        .ok_or("The certificate body is missing")?;
+    let fields = parse_tlv(body.1)?;
+    let public_key = parse_tlv(find_tlv(&fields, 0x7F49)?)?;
+    let time = |tag: u16| -> Result<Option<Timestamp>, String> {
+        let value = find_tlv(&fields, tag)?;
+        if value.len() != 4 {
+            return Err(format!("The date {:04x} has {} bytes", tag, value.len()));
+        }
+        Ok(decode_time(value))
+    };
+    Ok(Certificate {
+        car: find_tlv(&fields, 0x42)?.to_vec(),
+        chr: find_tlv(&fields, 0x5F20)?.to_vec(),
+        valid_from: time(0x5F25)?,
+        valid_until: time(0x5F24)?,
+        public_key: PublicKey::Ec {
+            curve: find_tlv(&public_key, 0x06)?.to_vec(),
+            point: find_tlv(&public_key, 0x86)?.to_vec(),
+        },
+        body: body.2.to_vec(),
+        signature: find_tlv(&parts, 0x5F37)?.to_vec(),
+    })
+}
+
+/// Computes `base ^ exponent mod modulus` of the unsigned big-endian numbers, the result has the length of the modulus.
+///
+/// # Returns
+///
+/// * `Option<Vec<u8>>` - The result, or `None` if the base is not below the modulus: such a number is not
+///   a signature made with the key.
+fn mod_pow(base: &[u8], exponent: &[u8], modulus: &[u8]) -> Option<Vec<u8>> {
+    let base = BigUint::from_bytes_be(base);
+    let modulus_number = BigUint::from_bytes_be(modulus);
+    if base >= modulus_number {
+        return None;
+    }
+    let result = base.modpow(&BigUint::from_bytes_be(exponent), &modulus_number).to_bytes_be();
+    let mut bytes = vec![0u8; modulus.len() - result.len()];
+    bytes.extend(result);
+    Some(bytes)
+}
+
+/// Keeps the check of the card and warns the user (once per card and status) about the card the server will reject.
+pub fn record(check: CertificateCheck) {
+    let problem = match check.status {
+        CertificateStatus::Valid => None,
+        CertificateStatus::Unverified => {
+            log::info!("{} | The certificates are not verified: {}", check.card_number, check.detail.as_deref().unwrap_or_default());
+            None
+        }
+        CertificateStatus::Expired => Some("has an expired certificate"),
+        CertificateStatus::NotYetValid => Some("has a certificate which is not valid yet"),
+        CertificateStatus::Revoked => Some("has a revoked certificate"),
+        CertificateStatus::InvalidSignature => Some("has a certificate with a wrong signature"),
+    };
+    if let Some(problem) = problem {
+        let message = format!(
+            "The card {} {}, the server will reject its authentication. {}",
+            check.card_number,
+            problem,
+            check.detail.as_deref().unwrap_or_default()
+        );
+        log::warn!("{}", message);
+        if WARNED.lock().unwrap().insert((check.card_number.clone(), check.status)) {
+            emit_notification("warning", &message);
+        }
+    }
+    CHECKS.lock().unwrap().insert(check.card_number.clone(), check);
+}
+
+/// Public function to get the checks of the certificates of the cards inserted since the start.
+/// This function is a Tauri command that is called from the frontend, e.g. to show the certificate status of the card.
+#[tauri::command]
+pub fn get_card_certificates() -> Vec<CertificateCheck> {
+    CHECKS.lock().unwrap().values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test chains in the formats of the ERCA test certificates, see `tests/certificates/tachograph/generate.py`.
+    const GEN1_MS_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/tachograph/gen1_ms.crt");
+    const GEN1_CARD_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/tachograph/gen1_card.crt");
+    const GEN2_MS_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/tachograph/gen2_ms.crt");
+    const GEN2_CARD_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/tachograph/gen2_card.crt");
+
+    fn test_roots() -> CertificatesConfig {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certificates/tachograph");
+        CertificatesConfig {
+            erca_gen1_key: Some(format!("{}/gen1_eur.pk", dir)),
+            erca_gen2_root: Some(format!("{}/gen2_root.crt", dir)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn modular_power_of_big_numbers() {
+        assert_eq!(mod_pow(&[4], &[13], &[0x01, 0xF1]), Some(vec![0x01, 0xBD]));
+        // The result keeps the leading zeros of the modulus length
+        let modulus = [0x1F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(mod_pow(&[7], &[2], &modulus), Some(vec![0, 0, 0, 0, 0, 0, 0, 49]));
+        // The number above the modulus is not a signature of the key
+        assert_eq!(mod_pow(&[0xFF; 8], &[2], &modulus), None);
+        assert_eq!(mod_pow(&[1], &[3], &[0]), None);
+    }
+
+    #[test]
+    fn gen1_chain_is_verified() {
+        let chain = gen1_chain(GEN1_CARD_CERTIFICATE, GEN1_MS_CERTIFICATE, &test_roots());
+        assert_eq!(chain.problem, None);
+        let authority = chain.authority.unwrap();
+        assert_eq!(hex::encode(&authority.car), "fd45432001ffff01");
+        assert_eq!(hex::encode(&authority.chr), "fd54535401ffff01");
+        let card = chain.card.unwrap();
+        assert_eq!(hex::encode(&card.car), "fd54535401ffff01");
+        assert_eq!(hex::encode(&card.chr), "0d12345678900501");
+        assert_eq!(card.valid_until.as_ref().map(|until| until.epoch), Some(2_051_222_400));
+    }
+
+    #[test]
+    fn tampered_gen1_certificate_is_rejected() {
+        let mut card = GEN1_CARD_CERTIFICATE.to_vec();
+        // A byte of the modulus in the non-recoverable part
+        card[150] ^= 0x01;
+        let problem = gen1_chain(&card, GEN1_MS_CERTIFICATE, &test_roots()).problem.unwrap();
+        assert_eq!(problem.0, CertificateStatus::InvalidSignature);
+
+        // The card certificate signed by another member state key
+        let problem = gen1_chain(GEN1_MS_CERTIFICATE, GEN1_MS_CERTIFICATE, &test_roots()).problem.unwrap();
+        assert_eq!(problem.0, CertificateStatus::Unverified);
+    }
+
+    #[test]
+    fn gen2_chain_is_verified() {
+        let chain = gen2_chain(GEN2_CARD_CERTIFICATE, GEN2_MS_CERTIFICATE, &test_roots());
+        assert_eq!(chain.problem, None);
+        let card = chain.card.unwrap();
+        assert_eq!(hex::encode(&card.chr), "0d12345678900501");
+        assert_eq!(card.valid_from.as_ref().map(|from| from.epoch), Some(1_577_836_800));
+        assert_eq!(card.valid_until.as_ref().map(|until| until.epoch), Some(2_051_222_400));
+
+        let mut card = GEN2_CARD_CERTIFICATE.to_vec();
+        // A byte of the signature
+        let last = card.len() - 1;
+        card[last] ^= 0x01;
+        let problem = gen2_chain(&card, GEN2_MS_CERTIFICATE, &test_roots()).problem.unwrap();
+        assert_eq!(problem.0, CertificateStatus::InvalidSignature);
+    }
+
+    #[test]
+    fn tampered_certificate_is_rejected() {
+        let field = |tag: &str, value: &str| format!("{}{:02x}{}", tag, value.len() / 2, value);
+        let point = format!("04{}", "11".repeat(64));
+        let public_key = field("7f49", &(field("06", &hex::encode(OID_NIST_P256)) + &field("86", &point)));
+        let body_fields = [
+            field("5f29", "00"),
+            field("42", "fd45432001ffff01"),
+            field("5f4c", "ff53"),
+            public_key,
+            field("5f20", "0d1234567890ab01"),
+            field("5f25", "5e0be100"),
+            field("5f24", "7a42b280"),
+        ]
+        .concat();
+        let body = format!("7f4e81{:02x}{}", body_fields.len() / 2, body_fields);
+        let inner = body + &field("5f37", &"22".repeat(64));
+        let data = hex::decode(format!("7f2181{:02x}{}", inner.len() / 2, inner)).unwrap();
+
+        let certificate = parse_cv_certificate(&data).unwrap();
+        assert_eq!(hex::encode(&certificate.chr), "0d1234567890ab01");
+        assert_eq!(certificate.valid_from.as_ref().map(|from| from.epoch), Some(1_577_836_800));
+        assert_eq!(certificate.valid_until.as_ref().map(|until| until.epoch), Some(2_051_191_424));
+
+        // The certificate is self-signed with the point which is not on the curve
+        let mut self_signed = certificate.clone();
+        self_signed.car = self_signed.chr.clone();
+        let problem = verify_cv_certificate(&self_signed, &self_signed).unwrap_err();
+        assert_eq!(problem.0, CertificateStatus::InvalidSignature);
+        let problem = verify_cv_certificate(&certificate, &self_signed).unwrap_err();
+        assert_eq!(problem.0, CertificateStatus::Unverified);
+    }
+}
@@ -0,0 +1,497 @@
+//! Module for the identification data of the tachograph cards.
+//!
+//! The card number is entered by the user when the card is paired, so a typo or a swapped card is only noticed
+//! when the server rejects the authentication. The chip has the identification of the card in the EF Identification
+//! file of the tachograph application (Annex 1B/1C): the issuing member state, the card number, the issuing
+//! authority, the validity and the holder (the company for the company cards). It is read when the card is inserted,
+//! kept by the reader and sent to the frontend with the `global-card-details` event.
+//!
+//! The expiry date of the paired card is saved to its settings (`CardConfig.expire`), so it is not entered manually.
+//! The expiry dates of all the paired cards are checked periodically (see `check_expiry`) and the user is warned
+//! when the card expires soon, once for every number of days of `notifications.expiry_warning_days`.
+//!
+//! The PIN policy of the card is checked at the same time (see `PinPolicy`): the workshop cards require the PIN
+//! for the authentication, the company cards don't, so the server and the frontend can warn about the card
+//! which can't be authenticated remotely before the session fails at the PIN step.
+//!
+//! The holder data is not taken from the card certificate (EF Card_Certificate): it can be recovered only with
+//! the public key of the member state, and EF Identification already has it in the plain form. The certificates
+//! are checked by the `card_certificates` module.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::config::{get_card_config, get_card_config_snapshot, get_notifications_config};
+use crate::config_writer::ConfigMutation;
+use crate::global_app_handle::{emit_event_of_kind, emit_notification, EventKind};
+use crate::protocol::ApduTransport;
+use crate::smart_card::detect_card_type;
+use crate::timestamp::Timestamp;
+
+/// Name of the event with the identification data of the card.
+pub const CARD_DETAILS_EVENT: &str = "global-card-details";
+
+/// Select EF Identification (FID 0520).
+const SELECT_EF_IDENTIFICATION_APDU: &str = "00a4020c020520";
+/// VERIFY without the data: the card reports the status of the PIN without the attempt to verify it (ISO/IEC 7816-4).
+const VERIFY_STATUS_APDU: &str = "00200000";
+/// Select the MF, so the card is left as it has been before the reading.
+const SELECT_MF_APDU: &str = "00a4000c023f00";
+
+/// Interval in seconds of the checks of the expiry dates of the paired cards.
+pub const EXPIRY_CHECK_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Length of CardIdentification, the first part of EF Identification.
+const CARD_IDENTIFICATION_LENGTH: usize = 65;
+/// Length of the Name data type: the code page and 35 characters.
+const NAME_LENGTH: usize = 36;
+
+/// Type of the tachograph card (EquipmentType of EF Application_Identification).
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TachographCardType {
+    Driver,
+    Workshop,
+    Control,
+    Company,
+}
+
+impl TachographCardType {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(TachographCardType::Driver),
+            2 => Some(TachographCardType::Workshop),
+            3 => Some(TachographCardType::Control),
+            4 => Some(TachographCardType::Company),
+            _ => None,
+        }
+    }
+
+    /// Length of the holder identification which follows CardIdentification in EF Identification.
+    fn holder_identification_length(self) -> usize {
+        match self {
+            // holder surname and first names, birth date, preferred language
+            TachographCardType::Driver => 2 * NAME_LENGTH + 4 + 2,
+            // workshop or control body name and address, holder surname and first names, preferred language
+            TachographCardType::Workshop | TachographCardType::Control => 4 * NAME_LENGTH + 2,
+            // company name and address, preferred language
+            TachographCardType::Company => 2 * NAME_LENGTH + 2,
+        }
+    }
+}
+
+/// PIN policy of the card, detected with the status words of VERIFY without the data.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "pin", content = "tries_left")]
+pub enum PinPolicy {
+    /// The card doesn't have the PIN (the company cards) or the PIN is already verified.
+    NotRequired,
+    /// The card requires the PIN, with the number of the tries left if the card reports it.
+    Required(Option<u8>),
+    /// The PIN is blocked, the card can't be authenticated.
+    Blocked,
+    /// The status words are not recognized.
+    Unknown,
+}
+
+impl PinPolicy {
+    /// Detects the policy by the status words of VERIFY without the data.
+    pub fn from_status(status: &str) -> Self {
+        let status = status.to_ascii_uppercase();
+        match status.as_str() {
+            "9000" => PinPolicy::NotRequired,
+            // Referenced data not found, incorrect P1-P2, the instruction or the function is not supported
+            "6A88" | "6A86" | "6A81" | "6D00" | "6E00" => PinPolicy::NotRequired,
+            // Security status not satisfied, wrong length: the PIN is there, the tries are not reported
+            "6982" | "6700" => PinPolicy::Required(None),
+            // Authentication method blocked, reference data not usable
+            "6983" | "6984" | "63C0" => PinPolicy::Blocked,
+            _ => match status.strip_prefix("63C").and_then(|tries| u8::from_str_radix(tries, 16).ok()) {
+                Some(tries) => PinPolicy::Required(Some(tries)),
+                None => PinPolicy::Unknown,
+            },
+        }
+    }
+}
+
+/// Identification of the card read from the chip.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CardIdentification {
+    pub card_type: TachographCardType,
+    /// The card number on the chip, e.g. "D000000012345600" (the owner and the consecutive, replacement
+    /// and renewal indexes).
+    pub card_number: String,
+    /// Distinguishing sign of the issuing member state, e.g. "D", or the numeric code if it is unknown.
+    pub issuing_member_state: String,
+    pub issuing_authority: String,
+    pub issue_date: Option<Timestamp>,
+    pub validity_begin: Option<Timestamp>,
+    pub expiry_date: Option<Timestamp>,
+    /// The company for the company cards, the workshop or the control body for their cards,
+    /// the surname and the first names for the driver cards.
+    pub holder_name: String,
+    pub pin_policy: PinPolicy,
+}
+
+/// Identification of the card in the reader, for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct CardDetails {
+    pub reader_name: String,
+    /// The number the card is paired with, empty if the card is not paired.
+    pub card_number: String,
+    #[serde(flatten)]
+    pub identification: CardIdentification,
+}
+
+lazy_static! {
+    /// Identification of the cards by the reader name.
+    static ref DETAILS: Mutex<HashMap<String, CardDetails>> = Mutex::new(HashMap::new());
+    /// Cards the user has been warned about the expiry of, by the card number: the expiry date and the number of days
+    /// of the last warning (`-1` for the expired card). The warning is shown once per run for every number of days.
+    static ref EXPIRY_WARNED: Mutex<HashMap<String, (String, i64)>> = Mutex::new(HashMap::new());
+}
+
+/// Reads the identification of the card from EF Identification.
+pub fn read<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> Result<CardIdentification, String> {
+    let result = read_files(card);
+    // The card is left in the MF, as the server expects it after the insertion
+    if let Err(e) = card.transmit_hex(SELECT_MF_APDU) {
+        log::warn!("Failed to select the MF after the identification is read: {}", e);
+    }
+    result
+}
+
+fn read_files<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> Result<CardIdentification, String> {
+    // The tachograph application stays selected after the detection
+    let card_type = detect_card_type(card).map_err(|e| e.to_string())?;
+
+    command(card, SELECT_EF_IDENTIFICATION_APDU, "EF Identification selection")?;
+    let length = CARD_IDENTIFICATION_LENGTH + card_type.holder_identification_length();
+    let data = command(card, &format!("00b00000{:02x}", length), "EF Identification reading")?;
+    let mut identification = parse(card_type, &data)?;
+    identification.pin_policy = read_pin_policy(card);
+    Ok(identification)
+}
+
+/// Checks the PIN policy of the card, the tachograph application has to be selected.
+fn read_pin_policy<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> PinPolicy {
+    match card.transmit_hex(VERIFY_STATUS_APDU) {
+        Ok(response) if response.len() >= 4 => PinPolicy::from_status(&response[response.len() - 4..]),
+        Ok(response) => {
+            log::debug!("Unexpected response to the PIN status check: {}", response);
+            PinPolicy::Unknown
+        }
+        Err(e) => {
+            log::debug!("Failed to check the PIN status of the card: {}", e);
+            PinPolicy::Unknown
+        }
+    }
+}
+
+/// Sends the command and returns the data of the response without the status, if the status is 9000.
+fn command<T: ApduTransport<Error = Box<dyn Error>>>(card: &T, apdu: &str, name: &str) -> Result<Vec<u8>, String> {
+    let response = card.transmit_hex(apdu).map_err(|e| format!("{} has failed: {}", name, e))?;
+    let data = response
+        .strip_suffix("9000")
+        .ok_or_else(|| format!("{} has failed with the status {}", name, response))?;
+    hex::decode(data).map_err(|e| e.to_string())
+}
+
+/// Parses EF Identification of the card of the type.
+pub fn parse(card_type: TachographCardType, data: &[u8]) -> Result<CardIdentification, String> {
+    let expected = CARD_IDENTIFICATION_LENGTH + card_type.holder_identification_length();
+    if data.len() < expected {
+        return Err(format!("EF Identification is too short: {} of {} bytes", data.len(), expected));
+    }
+    let holder = &data[CARD_IDENTIFICATION_LENGTH..];
+    let holder_name = match card_type {
+        TachographCardType::Driver => join_names(&[decode_name(&holder[NAME_LENGTH..2 * NAME_LENGTH]), decode_name(&holder[..NAME_LENGTH])]),
+        _ => decode_name(&holder[..NAME_LENGTH]),
+    };
+    Ok(CardIdentification {
+        card_type,
+        card_number: decode_ia5(&data[1..17]),
+        issuing_member_state: nation_sign(data[0]),
+        issuing_authority: decode_name(&data[17..17 + NAME_LENGTH]),
+        issue_date: decode_time(&data[53..57]),
+        validity_begin: decode_time(&data[57..61]),
+        expiry_date: decode_time(&data[61..65]),
+        holder_name,
+        pin_policy: PinPolicy::Unknown,
+    })
+}
+
+fn join_names(names: &[String]) -> String {
+    names
+        .iter()
+        .filter(|name| !name.is_empty())
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Decodes the IA5String (ASCII) padded with the spaces.
+fn decode_ia5(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| if byte.is_ascii() && *byte >= 0x20 { *byte as char } else { '?' })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Decodes the Name data type: the code page (the part of ISO/IEC 8859) and the characters padded with the spaces.
+/// Latin-1 and Cyrillic are decoded, the other code pages are decoded as ASCII.
+fn decode_name(bytes: &[u8]) -> String {
+    let (code_page, characters) = match bytes.split_first() {
+        Some(split) => split,
+        None => return String::new(),
+    };
+    characters
+        .iter()
+        .map(|byte| match (*code_page, *byte) {
+            (_, 0x00) | (_, 0xFF) => ' ',
+            (_, byte) if byte < 0x80 => byte as char,
+            (1, byte) => byte as char,
+            // ISO/IEC 8859-5: Ё..Џ, А..я, ё..џ
+            (5, byte @ 0xA1..=0xAC) | (5, byte @ 0xAE..=0xEF) | (5, byte @ 0xF1..=0xFC) | (5, byte @ 0xFE..=0xFF) => {
+                char::from_u32(byte as u32 - 0xA0 + 0x400).unwrap_or('?')
+            }
+            _ => '?',
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Decodes TimeReal: the seconds since 1970-01-01 00:00 UTC. Zero means the date is not set.
+pub fn decode_time(bytes: &[u8]) -> Option<Timestamp> {
+    let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    if seconds == 0 || seconds == u32::MAX {
+        return None;
+    }
+    Some(Timestamp::from_epoch(seconds as i64))
+}
+
+/// Distinguishing sign of the member state by its NationNumeric code.
+fn nation_sign(code: u8) -> String {
+    const SIGNS: [&str; 0x38] = [
+        "", "A", "AL", "AND", "ARM", "AZ", "B", "BG", "BIH", "BY", "CH", "CY", "CZ", "D", "DK", "E", "EST", "F", "FIN",
+        "FL", "FR", "UK", "GE", "GR", "H", "HR", "I", "IRL", "IS", "KZ", "L", "LT", "LV", "M", "MC", "MD", "MK", "N",
+        "NL", "P", "PL", "RO", "RSM", "RUS", "S", "SK", "SLO", "TM", "TR", "UA", "V", "YU", "MNE", "SRB", "UZ", "TJ",
+    ];
+    match code {
+        0xFD => "EC".to_string(),
+        0xFE => "EUR".to_string(),
+        0xFF => "WLD".to_string(),
+        code => match SIGNS.get(code as usize) {
+            Some(sign) if !sign.is_empty() => sign.to_string(),
+            _ => format!("{:02X}", code),
+        },
+    }
+}
+
+/// Keeps the identification of the card in the reader and sends it to the frontend.
+pub fn record(details: CardDetails) {
+    if !details.card_number.is_empty() && details.card_number != details.identification.card_number {
+        log::warn!(
+            "The card {} in the reader {} has the number {} on the chip",
+            details.card_number,
+            details.reader_name,
+            details.identification.card_number
+        );
+    }
+    if !details.card_number.is_empty() && details.identification.pin_policy != PinPolicy::NotRequired {
+        log::warn!(
+            "The card {} reports the PIN policy {:?}, the remote authentication may fail at the PIN step",
+            details.card_number,
+            details.identification.pin_policy
+        );
+    }
+    if let (false, Some(expiry_date)) = (details.card_number.is_empty(), &details.identification.expiry_date) {
+        update_expiry(&details.card_number, expiry_date);
+    }
+    DETAILS.lock().unwrap().insert(details.reader_name.clone(), details.clone());
+    if let Err(e) = emit_event_of_kind(EventKind::CardState, CARD_DETAILS_EVENT, details) {
+        log::warn!("Failed to emit the card details: {}", e);
+    }
+}
+
+/// Saves the expiry date read from the chip to the settings of the paired card and warns about the card
+/// which expires soon.
+fn update_expiry(cardnumber: &str, expiry_date: &Timestamp) {
+    let expire = expiry_date.date().to_string();
+    warn_expiry(cardnumber, &expire);
+
+    let saved = get_card_config(cardnumber).and_then(|card_config| card_config.expire);
+    if saved.as_deref() != Some(expire.as_str()) {
+        log::info!("{} | The expiry date of the card is {}", cardnumber, expire);
+        let mutation = ConfigMutation::SetCardExpire {
+            cardnumber: cardnumber.to_string(),
+            expire,
+        };
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = crate::config_writer::apply(mutation).await {
+                log::warn!("Failed to save the expiry date of the card: {}", e);
+            }
+        });
+    }
+}
+
+/// Checks the expiry dates of the paired cards saved in their settings and warns about the cards which expire soon.
+/// This function is a periodic job of the scheduler.
+pub fn check_expiry() {
+    for entry in get_card_config_snapshot() {
+        if let Some(expire) = &entry.config.expire {
+            warn_expiry(&entry.card_number, expire);
+        }
+    }
+}
+
+/// Warns about the card which expires soon, unless the user has already been warned at the same number of days.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+/// * `expire` - The expiry date of the card, "YYYY-MM-DD".
+fn warn_expiry(cardnumber: &str, expire: &str) {
+    let expiry_date = match NaiveDate::parse_from_str(expire, "%Y-%m-%d") {
+        Ok(expiry_date) => expiry_date,
+        Err(e) => {
+            log::warn!("{} | Invalid expiry date '{}' of the card: {}", cardnumber, expire, e);
+            return;
+        }
+    };
+    let days_left = (expiry_date - Utc::now().date_naive()).num_days();
+    let threshold = match expiry_warning_threshold(days_left, &get_notifications_config().expiry_warning_days) {
+        Some(threshold) => threshold,
+        None => return,
+    };
+
+    {
+        let mut warned = EXPIRY_WARNED.lock().unwrap();
+        let previous = warned.get(cardnumber).filter(|(date, _)| date == expire).map(|(_, previous)| *previous);
+        if previous.map_or(false, |previous| previous <= threshold) {
+            return;
+        }
+        warned.insert(cardnumber.to_string(), (expire.to_string(), threshold));
+    }
+
+    let message = if days_left < 0 {
+        format!("The card {} has expired on {}", cardnumber, expire)
+    } else {
+        format!("The card {} expires on {}, in {} day(s)", cardnumber, expire, days_left)
+    };
+    log::warn!("{}", message);
+    emit_notification("warning", &message);
+}
+
+/// Returns the number of days of the warning about the card which expires in `days_left` days: the smallest
+/// of `warning_days` the card has reached, `-1` if the card has expired.
+///
+/// # Returns
+///
+/// * `Option<i64>` - The number of days, `None` if it is too early to warn or the warnings are turned off.
+fn expiry_warning_threshold(days_left: i64, warning_days: &[u32]) -> Option<i64> {
+    if warning_days.is_empty() {
+        return None;
+    }
+    if days_left < 0 {
+        return Some(-1);
+    }
+    warning_days
+        .iter()
+        .map(|days| i64::from(*days))
+        .filter(|days| *days >= days_left)
+        .min()
+}
+
+/// Returns the PIN policy of the card with the number, `None` if the card has not been read.
+pub fn pin_policy(cardnumber: &str) -> Option<PinPolicy> {
+    DETAILS
+        .lock()
+        .unwrap()
+        .values()
+        .find(|details| details.card_number == cardnumber)
+        .map(|details| details.identification.pin_policy)
+}
+
+/// Forgets the identification of the card removed from the reader.
+pub fn forget(reader_name: &str) {
+    DETAILS.lock().unwrap().remove(reader_name);
+}
+
+/// Public function to get the identification data of the cards in the readers.
+/// This function is a Tauri command that is called from the frontend, e.g. when it is loaded after the cards.
+#[tauri::command]
+pub fn get_card_details() -> Vec<CardDetails> {
+    DETAILS.lock().unwrap().values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(code_page: u8, text: &[u8]) -> Vec<u8> {
+        let mut name = vec![code_page];
+        name.extend_from_slice(text);
+        name.resize(NAME_LENGTH, b' ');
+        name
+    }
+
+    #[test]
+    fn expiry_warning_threshold_is_the_nearest_reached() {
+        let warning_days = [30, 14, 3];
+        assert_eq!(expiry_warning_threshold(45, &warning_days), None);
+        assert_eq!(expiry_warning_threshold(30, &warning_days), Some(30));
+        assert_eq!(expiry_warning_threshold(10, &warning_days), Some(14));
+        assert_eq!(expiry_warning_threshold(0, &warning_days), Some(3));
+        assert_eq!(expiry_warning_threshold(-1, &warning_days), Some(-1));
+        assert_eq!(expiry_warning_threshold(-1, &[]), None);
+    }
+
+    #[test]
+    fn simulated_card_is_identified() {
+        let identification = read(&crate::simulated_card::SimulatedCard::default()).unwrap();
+        assert_eq!(identification.card_type, TachographCardType::Company);
+        assert_eq!(identification.card_number, "SIM0000000000001");
+        assert_eq!(identification.holder_name, "Simulated Transport Company");
+        assert_eq!(identification.pin_policy, PinPolicy::NotRequired);
+    }
+
+    #[test]
+    fn company_card_identification_is_parsed() {
+        let mut data = vec![0x0D];
+        data.extend_from_slice(b"D000000012345600");
+        data.extend(name(1, b"KBA"));
+        data.extend_from_slice(&1_600_000_000u32.to_be_bytes());
+        data.extend_from_slice(&1_600_000_000u32.to_be_bytes());
+        data.extend_from_slice(&1_757_000_000u32.to_be_bytes());
+        data.extend(name(5, &[0xBE, 0xBE, 0xBE, b' ', b'T', b'r', b'a', b'n', b's']));
+        data.extend(name(1, b"Berlin"));
+        data.extend_from_slice(b"de");
+
+        let identification = parse(TachographCardType::Company, &data).unwrap();
+        assert_eq!(identification.card_number, "D000000012345600");
+        assert_eq!(identification.issuing_member_state, "D");
+        assert_eq!(identification.issuing_authority, "KBA");
+        assert_eq!(identification.holder_name, "ООО Trans");
+        assert_eq!(identification.expiry_date.unwrap().epoch, 1_757_000_000);
+        assert!(parse(TachographCardType::Driver, &data).is_err());
+    }
+
+    #[test]
+    fn pin_policy_is_detected_by_status() {
+        assert_eq!(PinPolicy::from_status("6A88"), PinPolicy::NotRequired);
+        assert_eq!(PinPolicy::from_status("9000"), PinPolicy::NotRequired);
+        assert_eq!(PinPolicy::from_status("63c3"), PinPolicy::Required(Some(3)));
+        assert_eq!(PinPolicy::from_status("63C0"), PinPolicy::Blocked);
+        assert_eq!(PinPolicy::from_status("6982"), PinPolicy::Required(None));
+        assert_eq!(PinPolicy::from_status("6F00"), PinPolicy::Unknown);
+    }
+}
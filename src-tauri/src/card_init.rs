@@ -0,0 +1,286 @@
+//! Module for the initialization of the inserted cards.
+//!
+//! Connecting to the card and reading its ICCID can take seconds with a slow card or reader, and the monitor
+//! of the readers must not wait for it, otherwise the state changes of all the other readers are delayed.
+//! So every card is initialized in its own task with a timeout, and the result is sent back to the monitor,
+//! which connects the card to the server. The result of the stale initialization (the card has been removed
+//! or replaced meanwhile) is dropped.
+//!
+//! The initialization also detects the type of the card: only the company cards (and the workshop and control cards,
+//! which can download the data too) are connected, the driver card inserted by mistake is reported to the user.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::card_identification::{CardDetails, TachographCardType};
+use crate::config::{get_card_by_iccid, get_card_config, get_certificates_config};
+use crate::config_writer::ConfigMutation;
+use crate::known_cards::find_known_card;
+use crate::global_app_handle::{emit_card_state, emit_notification, CardStatePayload, StateReason};
+use crate::mqtt::ensure_connection;
+use crate::reader_pool::{set_card_generation, set_card_iccid, set_card_type};
+use crate::smart_card::{remember_iccid, remember_unpaired_iccid, ManagedCard, ReaderId, TASK_POOL};
+use crate::timestamp::Timestamp;
+
+/// Maximum time of the card initialization.
+const CARD_INIT_TIMEOUT_SECS: u64 = 15;
+
+/// Maximum waiting of the monitor for the reader changes while the cards are initialized,
+/// so the initialized cards are connected without waiting for the next change.
+pub const CARD_INIT_POLL_MS: u64 = 250;
+
+/// Result of the card initialization, sent back to the monitor.
+struct CardInitEvent {
+    reader_name: CString,
+    /// Number of the initialization in the reader, the results of the previous ones are stale.
+    generation: u64,
+    card_number: String,
+    atr: String,
+    result: Result<ManagedCard, String>,
+}
+
+lazy_static! {
+    /// Channel of the initialization results from the tasks to the monitor.
+    static ref EVENTS: (UnboundedSender<CardInitEvent>, Mutex<UnboundedReceiver<CardInitEvent>>) = {
+        let (sender, receiver) = unbounded_channel();
+        (sender, Mutex::new(receiver))
+    };
+    /// The last initialization of every reader.
+    static ref GENERATIONS: Mutex<HashMap<CString, u64>> = Mutex::new(HashMap::new());
+}
+
+/// Number of the initialization tasks which haven't sent their results yet.
+static IN_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Checks if some cards are being initialized, the monitor has to check for their results then.
+pub fn in_progress() -> bool {
+    IN_PROGRESS.load(Ordering::SeqCst) > 0
+}
+
+/// Starts the initialization of the card in the reader. The previous initialization of the reader becomes stale,
+/// so the state of the reader without the card (or without the card number) just cancels it.
+///
+/// # Arguments
+///
+/// * `reader_name` - The name of the reader.
+/// * `card_number` - The number of the card, the card without the number is not connected.
+/// * `atr` - The ATR of the card in hex.
+pub fn start(reader_name: &CStr, card_number: String, atr: String) {
+    let generation = {
+        let mut generations = GENERATIONS.lock().unwrap();
+        let generation = generations.entry(reader_name.to_owned()).or_insert(0);
+        *generation += 1;
+        *generation
+    };
+    if card_number.is_empty() {
+        // The card is not connected, but its identification helps to pair it
+        if !atr.is_empty() {
+            let reader_name = reader_name.to_owned();
+            tauri::async_runtime::spawn_blocking(move || match ManagedCard::create_card(&reader_name, "") {
+                Ok(card) => {
+                    // The ICCID is checked when the card is paired
+                    match card.iccid() {
+                        Ok(iccid) => remember_unpaired_iccid(&atr, iccid),
+                        Err(e) => log::debug!("Failed to read the ICCID of the unpaired card: {}", e),
+                    }
+                    read_details(&card, &reader_name, "")
+                }
+                Err(e) => log::debug!(
+                    "Failed to connect to the unpaired card in the reader {}: {}",
+                    ReaderId::from_name(&reader_name.to_string_lossy()),
+                    e
+                ),
+            });
+        }
+        return;
+    }
+
+    let reader_name = reader_name.to_owned();
+    IN_PROGRESS.fetch_add(1, Ordering::SeqCst);
+    tauri::async_runtime::spawn(async move {
+        // The card connected through the same reader is not initialized again. The card connected through
+        // another reader is initialized, it may be the same card in the renamed reader (see `ensure_connection`).
+        let connected = TASK_POOL
+            .lock()
+            .await
+            .iter()
+            .any(|task| task.client_id == card_number && task.reader_name == reader_name);
+        if !connected {
+            let blocking_reader = reader_name.clone();
+            let blocking_card_number = card_number.clone();
+            let initialization = tokio::task::spawn_blocking(move || initialize(&blocking_reader, &blocking_card_number));
+            let result = match tokio::time::timeout(Duration::from_secs(CARD_INIT_TIMEOUT_SECS), initialization).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => Err(format!("The initialization of the card has failed: {}", e)),
+                Err(_) => Err(format!("The card has not responded within {} seconds", CARD_INIT_TIMEOUT_SECS)),
+            };
+            let event = CardInitEvent {
+                reader_name,
+                generation,
+                card_number,
+                atr,
+                result,
+            };
+            if EVENTS.0.send(event).is_err() {
+                log::error!("Failed to send the result of the card initialization to the monitor");
+            }
+        }
+        IN_PROGRESS.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Connects to the card, reads its ICCID, detects its type and generation and checks its certificates. The card is connected even if the ICCID can't be read,
+/// it is read again at the authentication, and even if its type can't be detected.
+fn initialize(reader_name: &CStr, card_number: &str) -> Result<ManagedCard, String> {
+    let card = ManagedCard::create_card(reader_name, card_number).map_err(|e| e.to_string())?;
+    match card.iccid() {
+        Ok(iccid) => {
+            // The same card paired with two numbers would start the connection of the wrong one
+            if let Some(other) = get_card_by_iccid(iccid).map(|(other, _)| other).filter(|other| other != card_number) {
+                return Err(format!(
+                    "The card with the ICCID {} is also paired with the number {}, remove one of them and pair the card again",
+                    iccid, other
+                ));
+            }
+            remember_iccid(card_number, iccid);
+            update_iccid(card_number, iccid);
+        }
+        Err(e) => log::warn!("{} | Failed to read the ICCID of the card: {}", card_number, e),
+    }
+    match card.card_type() {
+        Ok(card_type) => log::debug!("{} | The card type is {:?}", card_number, card_type),
+        Err(e) => log::info!("{} | Failed to detect the type of the card: {}", card_number, e),
+    }
+    match card.card_generation() {
+        Ok(card_generation) => log::info!("{} | The card generation is {:?}", card_number, card_generation),
+        Err(e) => log::info!("{} | Failed to detect the generation of the card: {}", card_number, e),
+    }
+    read_details(&card, reader_name, card_number);
+    if get_certificates_config().enabled {
+        match card.check_certificates(card_number) {
+            Ok(check) => crate::card_certificates::record(check),
+            Err(e) => log::info!("{} | Failed to check the certificates of the card: {}", card_number, e),
+        }
+    }
+    Ok(card)
+}
+
+/// Saves the ICCID read from the chip to the settings of the paired card.
+fn update_iccid(card_number: &str, iccid: &str) {
+    let saved = get_card_config(card_number).and_then(|card_config| card_config.iccid);
+    if saved.as_deref() == Some(iccid) {
+        return;
+    }
+    let mutation = ConfigMutation::SetCardIccid {
+        cardnumber: card_number.to_string(),
+        iccid: iccid.to_string(),
+    };
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = crate::config_writer::apply(mutation).await {
+            log::warn!("Failed to save the ICCID of the card: {}", e);
+        }
+    });
+}
+
+/// Reads the identification of the card for the frontend (see `card_identification`).
+fn read_details(card: &ManagedCard, reader_name: &CStr, card_number: &str) {
+    match card.identification() {
+        Ok(identification) => crate::card_identification::record(CardDetails {
+            reader_name: reader_name.to_string_lossy().into(),
+            card_number: card_number.to_string(),
+            identification,
+        }),
+        Err(e) => log::info!(
+            "The identification of the card in the reader {} is not read: {}",
+            ReaderId::from_name(&reader_name.to_string_lossy()),
+            e
+        ),
+    }
+}
+
+/// Reports the driver card, which is not connected: the remote download is authenticated with the company card.
+fn report_wrong_card_type(reader_id: &ReaderId, card_number: String, atr: String) {
+    let message = format!(
+        "The card {} in the reader {} is a driver card. Insert the company card to download the data remotely.",
+        card_number,
+        reader_id.label()
+    );
+    log::warn!("{}", message);
+    emit_notification("warning", &message);
+    let card_model = find_known_card(&atr).map(|known| known.model());
+    if let Err(e) = emit_card_state(CardStatePayload {
+        atr,
+        reader_name: reader_id.name.clone(),
+        reader_label: reader_id.label(),
+        card_state: "PRESENT".into(),
+        card_number,
+        online: Some(false),
+        updated_at: Timestamp::now(),
+        reason: Some(StateReason::WrongCardType),
+        detail: Some(message),
+        card_type: Some(TachographCardType::Driver),
+        card_model,
+        ..Default::default()
+    }) {
+        log::warn!("Failed to emit card state: {}", e);
+    }
+}
+
+/// Connects the initialized cards to the server. Called by the monitor of the readers.
+pub async fn connect_initialized_cards() {
+    let events: Vec<CardInitEvent> = {
+        let mut receiver = EVENTS.1.lock().unwrap();
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    };
+
+    for event in events {
+        let current = GENERATIONS.lock().unwrap().get(&event.reader_name).copied();
+        if current != Some(event.generation) {
+            log::debug!("{} | The card has been removed during the initialization", event.card_number);
+            continue;
+        }
+        match event.result {
+            Ok(card) => {
+                let reader_id = ReaderId::from_name(&event.reader_name.to_string_lossy());
+                if let Some(iccid) = card.cached_iccid() {
+                    set_card_iccid(&reader_id, &event.card_number, iccid);
+                }
+                if let Some(card_generation) = card.cached_card_generation() {
+                    set_card_generation(&reader_id, &event.card_number, card_generation);
+                }
+                if let Some(card_type) = card.cached_card_type() {
+                    set_card_type(&reader_id, &event.card_number, card_type);
+                    if card_type == TachographCardType::Driver {
+                        report_wrong_card_type(&reader_id, event.card_number, event.atr);
+                        continue;
+                    }
+                }
+                ensure_connection(&event.reader_name, event.card_number, event.atr, card).await
+            }
+            Err(e) => {
+                let reader_name = event.reader_name.to_string_lossy().to_string();
+                let reader_label = ReaderId::from_name(&reader_name).label();
+                log::error!("{} | Failed to initialize the card in the reader {}: {}", event.card_number, reader_label, e);
+                if let Err(e) = emit_card_state(CardStatePayload {
+                    atr: event.atr,
+                    reader_label,
+                    reader_name,
+                    card_state: "PRESENT".into(),
+                    card_number: event.card_number,
+                    updated_at: Timestamp::now(),
+                    reason: Some(StateReason::CardMute),
+                    detail: Some(e),
+                    ..Default::default()
+                }) {
+                    log::warn!("Failed to emit card state: {}", e);
+                }
+            }
+        }
+    }
+}
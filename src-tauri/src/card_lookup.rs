@@ -0,0 +1,95 @@
+//! Module for the lookup of the company cards by the card number or the ICCID.
+//!
+//! The offices with dozens of company cards search the card in the UI by the number printed on the card
+//! or by the ICCID reported by the server, and get everything known about it in one record.
+//! The cards with the label (e.g. "Depot Vilnius, drawer 3") are also found by it.
+
+use serde::Serialize;
+
+use crate::config::{find_card_by_label, get_card_account_name, get_card_config, CardConfig};
+use crate::event_store::{card_errors, card_statistics, last_card_event, CardError, CardStatistics, StoredEntry};
+use crate::global_app_handle::CardStatePayload;
+use crate::mqtt::get_active_session;
+use crate::reader_pool::{find_card, ReaderInfo};
+use crate::smart_card::{known_iccid, TASK_POOL};
+
+/// Everything known about the company card.
+#[derive(Serialize, Clone, Debug)]
+pub struct CardRecord {
+    pub card_number: String,
+    /// ICCID of the card, `None` if it has not been read since the start.
+    pub iccid: Option<String>,
+    /// Settings of the card, `None` if the card is not in the configuration.
+    pub config: Option<CardConfig>,
+    /// Name of the account the card belongs to.
+    pub account: String,
+    /// The reader the card is inserted to, `None` if the card is not in any reader.
+    pub reader: Option<ReaderInfo>,
+    /// The MQTT connection of the card is running.
+    pub connected: bool,
+    /// Topic of the request of the authentication in progress.
+    pub authentication: Option<String>,
+    /// The last state of the card from the history.
+    pub last_event: Option<StoredEntry<CardStatePayload>>,
+    /// The last errors of the card, the oldest first.
+    pub last_errors: Vec<StoredEntry<CardError>>,
+    pub statistics: CardStatistics,
+}
+
+/// Public function to find the company card by the card number or the ICCID.
+/// This function is a Tauri command that is called from the search box of the frontend.
+///
+/// # Arguments
+///
+/// * `iccid_or_number` - The card number, the ICCID or the label of the card.
+///
+/// # Returns
+///
+/// * `Result<CardRecord, String>` - The record of the card, or the error if the card is not known.
+#[tauri::command]
+pub async fn lookup_card(iccid_or_number: String) -> Result<CardRecord, String> {
+    let query = iccid_or_number.trim();
+    if query.is_empty() {
+        return Err("The card number or the ICCID is empty".to_string());
+    }
+
+    let connected_cards: Vec<String> = TASK_POOL.lock().await.iter().map(|task| task.client_id.clone()).collect();
+    let is_card_number = get_card_config(query).is_some()
+        || find_card(query).is_some()
+        || connected_cards.iter().any(|id| id == query);
+    let card_number = if is_card_number {
+        query.to_string()
+    } else {
+        crate::smart_card::find_card_by_iccid(query)
+            .or_else(|| find_card_by_label(query))
+            .ok_or_else(|| format!("The card {} is not found", query))?
+    };
+
+    Ok(CardRecord {
+        iccid: known_iccid(&card_number),
+        config: get_card_config(&card_number),
+        account: get_card_account_name(&card_number),
+        reader: find_card(&card_number),
+        connected: connected_cards.contains(&card_number),
+        authentication: get_active_session(&card_number),
+        last_event: last_card_event(&card_number),
+        last_errors: card_errors(&card_number),
+        statistics: card_statistics(&card_number),
+        card_number,
+    })
+}
+
+/// Public function to find the number of the card by its ICCID, e.g. the ICCID reported by the server.
+/// This function is a Tauri command that is called from the frontend.
+///
+/// # Arguments
+///
+/// * `iccid` - The ICCID of the card.
+///
+/// # Returns
+///
+/// * `Option<String>` - The number of the card, `None` if the card with the ICCID is not known.
+#[tauri::command]
+pub fn find_card_by_iccid(iccid: String) -> Option<String> {
+    crate::smart_card::find_card_by_iccid(iccid.trim())
+}
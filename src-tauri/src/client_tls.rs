@@ -0,0 +1,270 @@
+//! Module for the TLS connections to the broker.
+//!
+//! The connection uses TLS if the server (or the account) has the client certificate or the custom CA bundle.
+//! The broker which requires the x509 client certificate gets the certificate and the private key
+//! from the PEM files set in the `client_cert` and `client_key` settings. The server certificate is verified with
+//! the root certificates of the OS and with the CA bundle from the `ca_cert` setting, for the self-hosted brokers
+//! with the private PKI. The `insecure_skip_hostname_verification` setting accepts the server certificate issued
+//! for another name (the chain is still verified), it is only for the brokers which are reached by the IP address
+//! and is loudly logged.
+//! The key of the client certificate can be on the hardware token instead of the file (the `client_token` setting,
+//! see the `hardware_token` module).
+//! The invalid or expired certificate is reported to the user once, the connection is not established then.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufReader, Cursor};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use rumqttc::{TlsConfiguration, Transport};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+
+use crate::config::AccountConfig;
+use crate::global_app_handle::emit_notification;
+use crate::mqtt_client::MqttOptions;
+
+lazy_static! {
+    /// Certificates already reported to the user, so every card connection does not repeat the error.
+    static ref REPORTED_ERRORS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    /// Hosts already warned about the skipped hostname verification.
+    static ref WARNED_HOSTS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Sets the TLS transport to the MQTT connection, if TLS is configured for the account.
+///
+/// # Arguments
+///
+/// * `mqtt_options` - The options of the MQTT connection.
+/// * `account` - The account with the TLS settings.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - `Ok` if the transport is set or TLS is not configured, otherwise the error,
+///   which is reported to the user.
+pub fn apply_tls(mqtt_options: &mut MqttOptions, account: &AccountConfig) -> Result<(), String> {
+    let reported_key = format!("{}|{:?}|{:?}|{:?}", account.host, account.client_cert, account.client_token, account.ca_cert);
+    match load_tls(account) {
+        Ok(Some(config)) => {
+            mqtt_options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(Arc::new(config))));
+            REPORTED_ERRORS.lock().unwrap().remove(&reported_key);
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            if REPORTED_ERRORS.lock().unwrap().insert(reported_key) {
+                emit_notification("error", &e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Builds the TLS configuration of the account, e.g. for the TLS handshake of the network diagnostics.
+///
+/// # Returns
+///
+/// * `Result<Option<ClientConfig>, String>` - The configuration, or `None` if TLS is not configured for the account.
+pub fn load_tls(account: &AccountConfig) -> Result<Option<ClientConfig>, String> {
+    let client_cert = match (&account.client_cert, &account.client_key) {
+        (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+        (None, None) => None,
+        _ => return Err("Both client_cert and client_key must be set for the mutual TLS".to_string()),
+    };
+    if client_cert.is_some() && account.client_token.is_some() {
+        return Err("Only one of client_cert and client_token can be set for the mutual TLS".to_string());
+    }
+    if client_cert.is_none() && account.client_token.is_none() && account.ca_cert.is_none() {
+        return Ok(None);
+    }
+
+    let mut roots = RootCertStore::empty();
+    let native_certs = rustls_native_certs::load_native_certs()
+        .map_err(|e| format!("Failed to load the root certificates of the OS: {}", e))?;
+    for cert in native_certs {
+        // The certificates which are not supported by rustls are skipped
+        let _ = roots.add(&Certificate(cert.0));
+    }
+    if let Some(ca_path) = &account.ca_cert {
+        load_ca_bundle(&mut roots, ca_path).map_err(|e| format!("CA bundle {}: {}", ca_path, e))?;
+    }
+    let roots = Arc::new(roots);
+
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots.clone());
+    let mut config = match (client_cert, &account.client_token) {
+        (_, Some(token)) => builder.with_client_cert_resolver(crate::hardware_token::client_cert_resolver(token)?),
+        (Some((cert_path, key_path)), None) => {
+            let (certs, key) = load_client_cert(cert_path, key_path).map_err(|e| format!("Client certificate {}: {}", cert_path, e))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| format!("Client certificate {}: the certificate doesn't match the private key: {}", cert_path, e))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+    };
+
+    if account.insecure_skip_hostname_verification {
+        if WARNED_HOSTS.lock().unwrap().insert(account.host.clone()) {
+            log::warn!(
+                "!!! The hostname verification of {} is DISABLED by insecure_skip_hostname_verification, \
+                 the connection is not protected from the man-in-the-middle with a certificate of the same CA !!!",
+                account.host
+            );
+        }
+        config.dangerous().set_certificate_verifier(Arc::new(SkipHostnameVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+        }));
+    }
+    Ok(Some(config))
+}
+
+/// Reads the client certificate (with its chain) and the private key.
+fn load_client_cert(cert_path: &str, key_path: &str) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    let certs: Vec<Certificate> = read_pem_items(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    let certificate = certs
+        .first()
+        .ok_or_else(|| "no certificate is found in the file".to_string())?;
+    check_validity(&certificate.0, Utc::now())?;
+
+    let key = read_pem_items(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key is found in {}", key_path))?;
+    Ok((certs, key))
+}
+
+/// Adds the certificates of the CA bundle to the trusted roots.
+fn load_ca_bundle(roots: &mut RootCertStore, ca_path: &str) -> Result<(), String> {
+    let mut added = 0;
+    for item in read_pem_items(ca_path)? {
+        if let Item::X509Certificate(der) = item {
+            roots
+                .add(&Certificate(der))
+                .map_err(|e| format!("the certificate can't be used as the root: {}", e))?;
+            added += 1;
+        }
+    }
+    if added == 0 {
+        return Err("no certificate is found in the file".to_string());
+    }
+    log::info!("{} certificate(s) of the CA bundle {} are trusted", added, ca_path);
+    Ok(())
+}
+
+/// Verifier of the server certificate which accepts the certificate issued for another name.
+/// The chain of the certificate and its validity are verified as usual.
+struct SkipHostnameVerifier {
+    inner: WebPkiVerifier,
+}
+
+impl ServerCertVerifier for SkipHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // The name is checked after the chain, so this error means the chain is trusted
+        match self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+}
+
+fn read_pem_items(path: &str) -> Result<Vec<Item>, String> {
+    let contents = fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(Cursor::new(contents)))
+        .map_err(|e| format!("failed to parse {}: {}", path, e))
+}
+
+/// Checks that the certificate is valid at the given time.
+pub fn check_validity(der: &[u8], now: DateTime<Utc>) -> Result<(), String> {
+    let (not_before, not_after) =
+        certificate_validity(der).ok_or_else(|| "the certificate can't be parsed".to_string())?;
+    if now < not_before {
+        return Err(format!("the certificate is not valid before {}", not_before));
+    }
+    if now > not_after {
+        return Err(format!("the certificate has expired on {}", not_after));
+    }
+    Ok(())
+}
+
+/// Reads the validity period of the DER encoded x509 certificate.
+///
+/// Certificate ::= SEQUENCE { tbsCertificate, ... }, where
+/// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer, validity, ... }
+fn certificate_validity(der: &[u8]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (_, certificate, _) = read_der(der)?;
+    let (_, tbs, _) = read_der(certificate)?;
+
+    let (tag, _, mut rest) = read_der(tbs)?;
+    if tag != 0xA0 {
+        // There is no version, the first field is the serial number
+        rest = tbs;
+    }
+    for _ in 0..3 {
+        // serialNumber, signature, issuer
+        rest = read_der(rest)?.2;
+    }
+    let (_, validity, _) = read_der(rest)?;
+    let (not_before_tag, not_before, rest) = read_der(validity)?;
+    let (not_after_tag, not_after, _) = read_der(rest)?;
+    Some((
+        parse_der_time(not_before_tag, not_before)?,
+        parse_der_time(not_after_tag, not_after)?,
+    ))
+}
+
+/// Reads the DER element: the tag, the contents and the rest of the data.
+pub fn read_der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first_length_byte = *data.get(1)? as usize;
+    let (length, header) = if first_length_byte < 0x80 {
+        (first_length_byte, 2)
+    } else {
+        let count = first_length_byte & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, 2 + count)
+    };
+    let contents = data.get(header..header + length)?;
+    Some((tag, contents, &data[header + length..]))
+}
+
+/// Parses UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ).
+fn parse_der_time(tag: u8, value: &[u8]) -> Option<DateTime<Utc>> {
+    let value = std::str::from_utf8(value).ok()?;
+    let format = match tag {
+        0x17 => "%y%m%d%H%M%SZ",
+        0x18 => "%Y%m%d%H%M%SZ",
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(value, format)
+        .ok()
+        .map(|time| DateTime::from_naive_utc_and_offset(time, Utc))
+}
@@ -1,18 +1,23 @@
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_yaml;
+use tokio::sync::watch;
 
+use crate::config_writer::{self, ConfigMutation, ConfigYaml};
+use crate::mqtt_client::{EventLoop, MqttOptions, MqttVersion};
+
+use sha2::{Digest, Sha256};
 use tauri::Manager;
 
-use log::{debug, error};
+use log::error;
 use std::fs;
 
 
@@ -25,13 +30,903 @@ pub struct ConfigurationFile {
     appearance: Option<AppearanceConfig>,          // Optional UI configuration settings.
     ident: Option<String>,                  // Optional ident for the application.
     server: Option<ServerConfig>,           // Optional server configuration settings.
-    cards: Option<HashMap<String, String>>, // Optional mapping of card ATRs to card numbers.
+    #[serde(default, deserialize_with = "deserialize_cards")]
+    cards: Option<HashMap<String, CardConfig>>, // Optional mapping of card numbers to card settings.
+    #[serde(default)]
+    broadcast: Option<BroadcastConfig>,     // Optional LAN broadcast of the card states.
+    #[serde(default)]
+    security_log: Option<SecurityLogConfig>, // Optional settings of the security log.
+    #[serde(default)]
+    readers: Option<ReadersConfig>,         // Optional filter of the readers and mapping of reader names to reader settings.
+    #[serde(default)]
+    retention: Option<RetentionConfig>,     // Optional limits of the event stores.
+    #[serde(default)]
+    protocol: Option<ProtocolConfig>,       // Optional settings of the server protocol parsing.
+    #[serde(default)]
+    accounts: Option<HashMap<String, AccountConfig>>, // Optional additional flespi accounts, by the account name.
+    #[serde(default)]
+    reader_debounce: Option<ReaderDebounceConfig>, // Optional debouncing of the reader state changes.
+    #[serde(default)]
+    scheduler: Option<HashMap<String, ScheduledJobConfig>>, // Optional settings of the periodic jobs, by the job name.
+    #[serde(default)]
+    auto_resync: Option<AutoResyncConfig>,  // Optional automatic resync of the readers with the failing cards.
+    #[serde(default)]
+    known_atrs: Option<Vec<KnownAtrConfig>>, // Optional ATR patterns of the tachograph cards in addition to the built-in ones.
+    #[serde(default)]
+    power_saving: Option<PowerSavingConfig>, // Optional powering off of the idle cards.
+    #[serde(default)]
+    proxy: Option<ProxyConfig>,             // Optional proxy of the MQTT connections.
+    #[serde(default)]
+    connection_stagger: Option<ConnectionStaggerConfig>, // Optional staggering of the card connections.
+    #[serde(default)]
+    session: Option<SessionConfig>,         // Optional persistent MQTT sessions of the card clients.
+    #[serde(default)]
+    topics: Option<TopicsConfig>,           // Optional topic templates of the card connections (see the topics module).
+    #[serde(default)]
+    integrity: Option<IntegrityConfig>,     // Optional integrity check of the application at the start.
+    #[serde(default)]
+    apdu_trace: Option<ApduTraceConfig>,    // Optional limits and file of the APDU traces of the cards.
+    #[serde(default)]
+    certificates: Option<CertificatesConfig>, // Optional root keys and revocation list for the check of the card certificates.
+    #[serde(default)]
+    backups: Option<BackupsConfig>,         // Optional number of the kept backups of the configuration file.
+    #[serde(default)]
+    notifications: Option<NotificationsConfig>, // Optional warnings about the expiring company cards.
+}
+
+// Integrity Configuration structure, part of ConfigurationFile that contains the settings of the integrity check
+// of the executable and the bundled resources against the signed manifest (see the integrity module).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IntegrityConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Path to the manifest, `integrity.json` next to the executable if it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<String>,
+}
+
+// Topics Configuration structure, part of ConfigurationFile that contains the topic templates of the card connections
+// for the brokers other than flespi, e.g. `{ident}/{cardnumber}/request/#` and `{ident}/{cardnumber}/response/{suffix}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TopicsConfig {
+    /// Topic filter the card client subscribes to for the requests.
+    pub request: String,
+    /// Topic of the responses.
+    pub response: String,
+}
+
+// Session Configuration structure, part of ConfigurationFile that contains the settings of the MQTT sessions of the card clients.
+// With the persistent session the broker keeps the subscriptions and the pending QoS 1/2 requests of the card
+// during the short network blips, so they are delivered after the reconnection instead of being lost.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionConfig {
+    #[serde(default)]
+    pub persistent: bool,
+    /// How long the broker keeps the session after the disconnection (MQTT 5 only).
+    #[serde(default = "default_session_expiry_secs")]
+    pub expiry_secs: u64,
+    /// All cards of the account share one broker connection, the requests are routed to the cards by the topics
+    /// (see the multiplex module). Requires the `topics` templates, as the cards subscribe to their requests.
+    #[serde(default)]
+    pub multiplexed: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            persistent: false,
+            expiry_secs: default_session_expiry_secs(),
+            multiplexed: false,
+        }
+    }
+}
+
+fn default_session_expiry_secs() -> u64 {
+    300
+}
+
+// Connection Stagger Configuration structure, part of ConfigurationFile that contains the delays of the card connections.
+// When the bridge with many readers starts, all the cards connect at once and some brokers rate-limit the burst.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConnectionStaggerConfig {
+    /// Minimum interval between two card connections.
+    #[serde(default)]
+    pub interval_ms: u64,
+    /// The connections within this window after the start of the application are delayed by a random time
+    /// up to the window.
+    #[serde(default)]
+    pub startup_window_ms: u64,
+}
+
+// Proxy Configuration structure, part of ConfigurationFile that contains the proxy of all the MQTT connections.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProxyConfig {
+    /// `http://host:port`, `https://host:port` or `socks5://host:port`.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+// Power Saving Configuration structure, part of ConfigurationFile that contains the settings of powering off the idle cards.
+// Some readers and cards run hot when they are powered all the time, so the card without the authentication
+// for the idle period is powered off and connected again on the first request from the server.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PowerSavingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Time without the requests after which the card is powered off.
+    #[serde(default = "default_power_saving_idle_secs")]
+    pub idle_secs: u64,
+}
+
+impl Default for PowerSavingConfig {
+    fn default() -> Self {
+        PowerSavingConfig {
+            enabled: false,
+            idle_secs: default_power_saving_idle_secs(),
+        }
+    }
+}
+
+fn default_power_saving_idle_secs() -> u64 {
+    600
+}
+
+// Certificates Configuration structure, part of ConfigurationFile that contains the settings of the local check
+// of the card certificates (see the card_certificates module). The root keys are published by the JRC, the files
+// are configured by the path, in the binary form or in hex.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CertificatesConfig {
+    #[serde(default = "default_certificates_enabled")]
+    pub enabled: bool,
+    /// Path to the European public key of the first generation (EUR.PK).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub erca_gen1_key: Option<String>,
+    /// Path to the European root certificate of the second generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub erca_gen2_root: Option<String>,
+    /// Certificate holder references (in hex) of the revoked card and member state certificates.
+    #[serde(default)]
+    pub revoked: Vec<String>,
+}
+
+impl Default for CertificatesConfig {
+    fn default() -> Self {
+        CertificatesConfig {
+            enabled: default_certificates_enabled(),
+            erca_gen1_key: None,
+            erca_gen2_root: None,
+            revoked: Vec::new(),
+        }
+    }
+}
+
+fn default_certificates_enabled() -> bool {
+    true
+}
+
+// APDU Trace Configuration structure, part of ConfigurationFile that contains the limits of the APDU traces
+// of the cards (see the apdu_trace module). The trace is always kept in memory, the file is written on demand
+// while an issue is investigated, because it grows with every authentication.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApduTraceConfig {
+    /// Maximum number of the last commands kept in memory for every card.
+    #[serde(default = "default_apdu_trace_max_entries")]
+    pub max_entries: usize,
+    /// Whether the commands are also written to the trace files in the data folder.
+    #[serde(default)]
+    pub to_file: bool,
+}
+
+impl Default for ApduTraceConfig {
+    fn default() -> Self {
+        ApduTraceConfig {
+            max_entries: default_apdu_trace_max_entries(),
+            to_file: false,
+        }
+    }
+}
+
+fn default_apdu_trace_max_entries() -> usize {
+    1000
+}
+
+// Backups Configuration structure, part of ConfigurationFile that contains the number of the backups of the configuration
+// file. The backup is written to the `backups` folder of the data folder before every change of the file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupsConfig {
+    /// Number of the last backups kept, the older ones are removed. 0 turns the backups off.
+    #[serde(default = "default_backups_keep")]
+    pub keep: usize,
+}
+
+impl Default for BackupsConfig {
+    fn default() -> Self {
+        BackupsConfig {
+            keep: default_backups_keep(),
+        }
+    }
+}
+
+fn default_backups_keep() -> usize {
+    20
+}
+
+// Notifications Configuration structure, part of ConfigurationFile that contains the settings of the warnings
+// about the company cards which expire soon (see `CardConfig.expire`).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationsConfig {
+    /// Numbers of days before the expiry of the card when the user is warned, once for every number.
+    /// Empty turns the warnings off.
+    #[serde(default = "default_expiry_warning_days")]
+    pub expiry_warning_days: Vec<u32>,
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            expiry_warning_days: default_expiry_warning_days(),
+        }
+    }
+}
+
+fn default_expiry_warning_days() -> Vec<u32> {
+    vec![30, 14, 3]
+}
+
+// Auto Resync Configuration structure, part of ConfigurationFile that contains the settings of the automatic resync
+// of the reader when the card fails several APDU commands in a row.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AutoResyncConfig {
+    #[serde(default = "default_auto_resync_enabled")]
+    pub enabled: bool,
+    /// Number of the failed APDU commands in a row after which the reader is resynced.
+    #[serde(default = "default_auto_resync_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Cool-down after the first resync of the card, doubled after every next one.
+    #[serde(default = "default_auto_resync_cooldown_secs")]
+    pub cooldown_secs: u64,
+    #[serde(default = "default_auto_resync_max_cooldown_secs")]
+    pub max_cooldown_secs: u64,
+}
+
+impl Default for AutoResyncConfig {
+    fn default() -> Self {
+        AutoResyncConfig {
+            enabled: default_auto_resync_enabled(),
+            failure_threshold: default_auto_resync_failure_threshold(),
+            cooldown_secs: default_auto_resync_cooldown_secs(),
+            max_cooldown_secs: default_auto_resync_max_cooldown_secs(),
+        }
+    }
+}
+
+fn default_auto_resync_enabled() -> bool {
+    true
+}
+
+fn default_auto_resync_failure_threshold() -> u32 {
+    3
+}
+
+fn default_auto_resync_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_auto_resync_max_cooldown_secs() -> u64 {
+    1800
+}
+
+// Scheduled Job Configuration structure, part of ConfigurationFile that contains the settings of a periodic job.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduledJobConfig {
+    #[serde(default = "default_job_enabled")]
+    pub enabled: bool,
+    /// Overrides the default interval of the job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_secs: Option<u64>,
+}
+
+fn default_job_enabled() -> bool {
+    true
+}
+
+/// Name of the account of the `server` and `ident` settings.
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+// Account Configuration structure, part of ConfigurationFile that contains the connection to an additional flespi account.
+// The bridge serving the cards of several customers has an account per customer, the cards are assigned to them
+// with the `account` setting of the card.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccountConfig {
+    pub host: String,
+    pub ident: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>, // Optional MQTT username (or flespi token) of the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>, // Optional MQTT password of the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>, // Optional path to the PEM client certificate for the mutual TLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>, // Optional path to the PEM private key of the client certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_token: Option<HardwareTokenConfig>, // Optional hardware token with the client certificate and its key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>, // Optional path to the PEM CA bundle of the broker with the private PKI.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub insecure_skip_hostname_verification: bool, // DANGEROUS: accept the broker certificate issued for another name.
+    #[serde(default)]
+    pub mqtt_version: MqttVersion, // Version of the MQTT protocol of the account broker.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_redirect_hosts: Vec<String>, // Hosts (or `*.domain`) the broker may redirect the connections to.
+    #[serde(default, flatten)]
+    pub tuning: ConnectionTuning, // Keep-alive, timeout and packet size of the account connections.
+}
+
+// Hardware Token Configuration structure, part of ServerConfig and AccountConfig that contains the hardware token
+// with the client certificate of the mutual TLS: the token of the PKCS#11 module (including the TPM through its
+// PKCS#11 module) or the PIV smart card in the reader. The private key never leaves the token,
+// the handshake is signed by the token.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HardwareTokenConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pkcs11_module: Option<String>, // Path of the PKCS#11 module of the token, e.g. libtpm2_pkcs11.so for the TPM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_label: Option<String>, // Label of the PKCS#11 token, any token with the certificate if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_label: Option<String>, // Label of the certificate on the PKCS#11 token, the first one with its key if not set.
+    #[serde(default)]
+    pub reader: String, // Part of the name of the reader with the PIV token, used without the PKCS#11 module.
+    #[serde(default = "default_token_slot")]
+    pub slot: String, // PIV slot of the key and the certificate: 9a (authentication, default), 9c, 9d or 9e.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin_env: Option<String>, // Environment variable with the PIN of the token, if the key requires it.
+}
+
+fn default_token_slot() -> String {
+    "9a".to_string()
+}
+
+/// Default keep-alive of the card and application connections.
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 300;
+
+/// Minimum keep-alive allowed by the MQTT client.
+const MIN_KEEP_ALIVE_SECS: u64 = 5;
+
+// Connection Tuning structure, part of ServerConfig and AccountConfig that contains the settings of the MQTT connections
+// for the operators on the high-latency satellite or cellular links. The defaults are used for the unset settings.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ConnectionTuning {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_alive_secs: Option<u64>, // Optional keep-alive of the card connections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_keep_alive_secs: Option<u64>, // Optional keep-alive of the application connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_timeout_secs: Option<u64>, // Optional timeout of the TCP/TLS connection and the CONNACK.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_packet_size: Option<u32>, // Optional maximum size of the MQTT packets in bytes.
+}
+
+impl ConnectionTuning {
+    /// Returns the keep-alive of the card connections.
+    pub fn card_keep_alive(&self) -> Duration {
+        keep_alive(self.keep_alive_secs)
+    }
+
+    /// Returns the keep-alive of the application connection.
+    pub fn app_keep_alive(&self) -> Duration {
+        keep_alive(self.app_keep_alive_secs)
+    }
+
+    /// Sets the maximum packet size to the MQTT connection, if it is configured.
+    pub fn apply_packet_size(&self, mqtt_options: &mut MqttOptions) {
+        if let Some(max_packet_size) = self.max_packet_size {
+            mqtt_options.set_max_packet_size(max_packet_size);
+        }
+    }
+
+    /// Sets the connection timeout to the event loop of the MQTT connection, if it is configured.
+    pub fn apply_connection_timeout(&self, eventloop: &mut EventLoop) {
+        if let Some(timeout) = self.connection_timeout_secs {
+            eventloop.set_connection_timeout(Duration::from_secs(timeout));
+        }
+    }
+}
+
+/// The dangerous settings are not written to the configuration file while they are off.
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn keep_alive(secs: Option<u64>) -> Duration {
+    Duration::from_secs(secs.unwrap_or(DEFAULT_KEEP_ALIVE_SECS).max(MIN_KEEP_ALIVE_SECS))
+}
+
+impl AccountConfig {
+    /// Sets the credentials of the account to the MQTT connection, if the username is configured.
+    /// The broker with the token authentication gets the token as the username and the empty password.
+    pub fn apply_credentials(&self, mqtt_options: &mut MqttOptions) {
+        if let Some(username) = &self.username {
+            mqtt_options.set_credentials(username.clone(), self.password.clone().unwrap_or_default());
+        }
+    }
+
+    /// Sets the TLS transport to the MQTT connection, if the client certificate or the CA bundle is configured.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - The error if the certificates can't be used, the connection must not be established then.
+    pub fn apply_tls(&self, mqtt_options: &mut MqttOptions) -> Result<(), String> {
+        crate::client_tls::apply_tls(mqtt_options, self)
+    }
+}
+
+/// How the APDU requests with deviations from the protocol are handled.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolMode {
+    /// Unknown fields are ignored, the request is processed if it can be.
+    #[default]
+    Lenient,
+    /// The request with unknown, missing or invalid fields is rejected with the error response.
+    Strict,
+}
+
+// Protocol Configuration structure, part of ConfigurationFile that contains the settings of the server protocol parsing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProtocolConfig {
+    #[serde(default)]
+    pub mode: ProtocolMode,
+    /// Number of seconds without the requests after which the authentication session is considered abandoned
+    /// by the tracker: the card is reset and is free for a new session. `0` disables the timeout.
+    #[serde(default = "default_session_timeout_secs")]
+    pub session_timeout_secs: u64,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        ProtocolConfig {
+            mode: ProtocolMode::default(),
+            session_timeout_secs: default_session_timeout_secs(),
+        }
+    }
+}
+
+fn default_session_timeout_secs() -> u64 {
+    60
+}
+
+// Retention Configuration structure, part of ConfigurationFile that contains the limits of the in-memory
+// stores of the events, notifications and statistics.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionConfig {
+    /// Maximum number of the card state events kept in the history.
+    #[serde(default = "default_max_events")]
+    pub max_events: usize,
+    /// Maximum number of the notifications kept in the notification center.
+    #[serde(default = "default_max_notifications")]
+    pub max_notifications: usize,
+    /// Maximum number of the last errors kept for every card.
+    #[serde(default = "default_max_card_errors")]
+    pub max_card_errors: usize,
+    /// Number of days the events, notifications and statistics are kept.
+    #[serde(default = "default_max_age_days")]
+    pub max_age_days: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            max_events: default_max_events(),
+            max_notifications: default_max_notifications(),
+            max_card_errors: default_max_card_errors(),
+            max_age_days: default_max_age_days(),
+        }
+    }
+}
+
+fn default_max_events() -> usize {
+    1000
+}
+
+fn default_max_notifications() -> usize {
+    200
+}
+
+fn default_max_card_errors() -> usize {
+    10
+}
+
+fn default_max_age_days() -> u32 {
+    30
+}
+
+/// How the card is opened in the reader.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CardShareMode {
+    /// The card is shared with the other applications all the time.
+    #[default]
+    Shared,
+    /// The card is opened exclusively for the duration of the authentication session and shared otherwise.
+    Exclusive,
+}
+
+// Reader Configuration structure, part of ConfigurationFile that contains the settings of a reader.
+// The reader is identified by the full PC/SC name or by the label shown in the UI.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReaderConfig {
+    #[serde(default)]
+    pub share_mode: CardShareMode,
+    /// The reader is used even if it matches the virtual reader patterns or the `deny` patterns,
+    /// e.g. a real reader whose name contains "Remote".
+    #[serde(default)]
+    pub force_enable: bool,
+    /// Name of the reader shown in the UI and in the log instead of the PC/SC name, e.g. "Front desk".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+// Readers Configuration structure, part of ConfigurationFile that contains the filter of the readers
+// (e.g. the built-in readers of the kiosk PCs are never used) and the settings of the readers by the reader name.
+// The patterns match the full PC/SC name or the label, case-insensitively, with `*` for any text and `?` for any character.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReadersConfig {
+    /// Patterns of the readers which are used, all the readers are used if it is empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Patterns of the readers which are never used, they take precedence over `allow`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+    /// Regular expressions of the virtual readers (e.g. the Microsoft virtual smart cards and the readers redirected
+    /// by the remote desktop), which are never used. The built-in expressions are used if it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtual_patterns: Option<Vec<String>>,
+    /// Compiled `virtual_patterns`, see `compile_virtual_patterns`.
+    #[serde(skip)]
+    pub virtual_regexes: Vec<Regex>,
+    #[serde(flatten)]
+    pub settings: HashMap<String, ReaderConfig>,
+}
+
+impl ReadersConfig {
+    /// Compiles the virtual reader patterns, the invalid ones are reported and skipped.
+    fn compile_virtual_patterns(&mut self) {
+        let default_patterns = default_virtual_reader_patterns();
+        let patterns = self.virtual_patterns.as_ref().unwrap_or(&default_patterns);
+        self.virtual_regexes = patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    log::error!("Invalid virtual reader pattern '{}' in the configuration: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+    }
+}
+
+fn default_virtual_reader_patterns() -> Vec<String> {
+    vec![
+        "(?i)^microsoft (ivsc|virtual)".to_string(),
+        "(?i)virtual smart ?card".to_string(),
+        "(?i)^windows hello".to_string(),
+        "(?i)remote desktop".to_string(),
+    ]
+}
+
+// Reader Debounce Configuration structure, part of ConfigurationFile that contains the protection against the readers
+// which appear and disappear all the time (e.g. connected with a faulty USB cable).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReaderDebounceConfig {
+    /// Number of seconds the reader state must be stable before it is processed.
+    #[serde(default = "default_debounce_stable_secs")]
+    pub stable_secs: u64,
+    /// The same period in milliseconds, it replaces `stable_secs` if it is set. The card wiggled in the reader
+    /// makes the changes within milliseconds, a fraction of the second is enough to skip them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stable_ms: Option<u64>,
+    /// Number of the reader state changes within `flapping_window_secs` after which the reader is paused.
+    #[serde(default = "default_debounce_flapping_threshold")]
+    pub flapping_threshold: u32,
+    #[serde(default = "default_debounce_flapping_window_secs")]
+    pub flapping_window_secs: u64,
+    /// Number of seconds the flapping reader is paused.
+    #[serde(default = "default_debounce_pause_secs")]
+    pub pause_secs: u64,
+    /// Number of seconds the reader must stay EMPTY before the card is considered removed. The card reset makes
+    /// the reader EMPTY for a moment, and the session of the card is kept if the card is back within this period.
+    #[serde(default = "default_debounce_removal_grace_secs")]
+    pub removal_grace_secs: u64,
+}
+
+impl Default for ReaderDebounceConfig {
+    fn default() -> Self {
+        ReaderDebounceConfig {
+            stable_secs: default_debounce_stable_secs(),
+            stable_ms: None,
+            flapping_threshold: default_debounce_flapping_threshold(),
+            flapping_window_secs: default_debounce_flapping_window_secs(),
+            pause_secs: default_debounce_pause_secs(),
+            removal_grace_secs: default_debounce_removal_grace_secs(),
+        }
+    }
+}
+
+impl ReaderDebounceConfig {
+    /// The period the reader state must be stable before it is processed.
+    pub fn stable_period(&self) -> Duration {
+        match self.stable_ms {
+            Some(stable_ms) => Duration::from_millis(stable_ms),
+            None => Duration::from_secs(self.stable_secs),
+        }
+    }
+}
+
+fn default_debounce_stable_secs() -> u64 {
+    2
+}
+
+fn default_debounce_flapping_threshold() -> u32 {
+    10
+}
+
+fn default_debounce_flapping_window_secs() -> u64 {
+    60
+}
+
+fn default_debounce_pause_secs() -> u64 {
+    300
+}
+
+fn default_debounce_removal_grace_secs() -> u64 {
+    10
+}
+
+// Security Log Configuration structure, part of ConfigurationFile that contains the settings of the audit log
+// of the remote interactions.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecurityLogConfig {
+    /// Number of days the security log records are kept.
+    #[serde(default = "default_security_log_retention_days")]
+    pub retention_days: u32,
+}
+
+impl Default for SecurityLogConfig {
+    fn default() -> Self {
+        SecurityLogConfig {
+            retention_days: default_security_log_retention_days(),
+        }
+    }
+}
+
+fn default_security_log_retention_days() -> u32 {
+    365
+}
+
+// Broadcast Configuration structure, part of ConfigurationFile that contains the settings of the LAN broadcast
+// of card states for the external displays (e.g. the wall display in the depot).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BroadcastConfig {
+    pub enabled: bool,
+    /// UDP multicast group and port the card states are sent to.
+    #[serde(default = "default_broadcast_address")]
+    pub address: String,
+    /// Read-only token of the displays. The messages carry the identifier derived from it (see `broadcast::source_id`),
+    /// so the displays can check where the message came from, the token itself is not sent.
+    #[serde(default)]
+    pub token: String,
+}
+
+fn default_broadcast_address() -> String {
+    "239.255.70.70:47800".to_string()
+}
+
+/// What the bridge does when the server sends a request while the card is not in the reader.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AbsentCardBehavior {
+    /// Respond immediately with the "card not present" message.
+    #[default]
+    Reject,
+    /// Wait for the card to be inserted back for `hold_secs` of the card, then process the request or reject it.
+    Hold,
+    /// Keep the request and process it as soon as the card is inserted back.
+    Queue,
+}
+
+/// Whether the card serves the authentication requests. The office takes the card offline intentionally
+/// by pausing or disabling it, the server then gets the "card unavailable" response instead of the silence.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CardAvailability {
+    #[default]
+    Active,
+    /// Taken offline for a while, the server may retry later.
+    Paused,
+    /// Taken offline until it is enabled again, the server should not retry.
+    Disabled,
+}
+
+impl CardAvailability {
+    fn is_active(&self) -> bool {
+        *self == CardAvailability::Active
+    }
+}
+
+/// Protocol the card is connected with, instead of the one negotiated by the reader.
+/// Some old Gen1 cards misbehave with the protocol derived from their ATR.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ForceProtocol {
+    T0,
+    T1,
+    #[serde(rename = "ANY")]
+    Any,
+}
+
+/// ATR pattern of the tachograph cards from the `known_atrs` section, either the bare pattern or the pattern
+/// with the card model, e.g. `{ pattern: "3bff9600008131fe4380318065b0*", manufacturer: "...", model: "..." }`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum KnownAtrConfig {
+    Pattern(String),
+    Card {
+        pattern: String,
+        #[serde(default)]
+        manufacturer: Option<String>,
+        #[serde(default)]
+        model: Option<String>,
+    },
+}
+
+impl KnownAtrConfig {
+    /// The ATR pattern (see the `known_cards` module for the syntax).
+    pub fn pattern(&self) -> &str {
+        match self {
+            KnownAtrConfig::Pattern(pattern) | KnownAtrConfig::Card { pattern, .. } => pattern,
+        }
+    }
+}
+
+/// How the ATR of the card is disclosed in the status and telemetry messages.
+/// The ATR requested by the server during the authentication is always sent as is.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AtrDisclosure {
+    /// The ATR is sent as is.
+    #[default]
+    Full,
+    /// The SHA-256 digest of the ATR is sent instead, so the card can be distinguished but not fingerprinted.
+    Anonymized,
+    /// The ATR is not sent.
+    Hidden,
+}
+
+impl AtrDisclosure {
+    /// The value of the ATR for the status and telemetry messages, `None` if it is hidden.
+    pub fn disclose(self, atr: &str) -> Option<String> {
+        match self {
+            AtrDisclosure::Full => Some(atr.to_string()),
+            AtrDisclosure::Anonymized => Some(format!("sha256:{}", hex::encode(Sha256::digest(atr.as_bytes())))),
+            AtrDisclosure::Hidden => None,
+        }
+    }
+}
+
+// Card Configuration structure, part of ConfigurationFile that contains the settings of a single company card.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CardConfig {
+    pub atr: String,
+    #[serde(default)]
+    pub absent_card: AbsentCardBehavior,
+    /// Time in seconds the request waits for the card in the `Hold` mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_secs: Option<u64>,
+    /// Overrides the share mode of the reader the card is inserted to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_mode: Option<CardShareMode>,
+    /// Name of the account the card belongs to, the default server is used if it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// How the ATR is disclosed in the status and telemetry messages.
+    #[serde(default)]
+    pub atr_disclosure: AtrDisclosure,
+    /// Overrides the protocol negotiated with the card.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_protocol: Option<ForceProtocol>,
+    /// Expiry date of the card, "YYYY-MM-DD". Filled from the chip when the card is inserted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expire: Option<String>,
+    /// The paused or disabled card rejects the requests with the "card unavailable" response.
+    #[serde(default, skip_serializing_if = "CardAvailability::is_active")]
+    pub availability: CardAvailability,
+    /// Time in seconds after which the server may retry the request to the paused card.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    /// QoS of the subscription to the requests of the card and of its responses: 0, 1 or 2, 1 if not set
+    /// (the other values are treated as 1). The change is applied to the running connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qos: Option<u8>,
+    /// Name of the card shown in the UI, e.g. "Depot Vilnius, drawer 3".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Free text about the card for the office staff.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// ICCID of the card, filled from the chip when the card is paired or inserted. One card (ICCID) can be paired
+    /// only with one card number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iccid: Option<String>,
+}
+
+/// Deserializes the cards section.
+/// Supports the legacy format, where the section was a plain mapping of card ATRs to card numbers,
+/// and converts it to the current one, where card numbers are mapped to the card settings.
+fn deserialize_cards<'de, D>(deserializer: D) -> Result<Option<HashMap<String, CardConfig>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // The legacy value is kept as a YAML value, because card numbers consisting of digits are parsed as numbers
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum CardEntry {
+        Config(CardConfig),
+        Legacy(serde_yaml::Value),
+    }
+
+    let entries: HashMap<String, CardEntry> = match Option::deserialize(deserializer)? {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+
+    let mut cards = HashMap::new();
+    for (key, entry) in entries {
+        match entry {
+            CardEntry::Config(card) => {
+                cards.insert(key, card);
+            }
+            // legacy: ATR => card number
+            CardEntry::Legacy(value) => {
+                let cardnumber = match value {
+                    serde_yaml::Value::String(number) => number,
+                    serde_yaml::Value::Number(number) => number.to_string(),
+                    _ => return Err(serde::de::Error::custom(format!("invalid card number for the ATR {}", key))),
+                };
+                cards.insert(cardnumber, CardConfig { atr: key, ..Default::default() });
+            }
+        }
+    }
+
+    Ok(Some(cards))
 }
 
 // Server Configuration structure, part of ConfigurationFile that contains data about the server.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct ServerConfig {
     pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>, // Optional MQTT username (or flespi token) for the brokers that require authentication.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>, // Optional MQTT password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>, // Optional path to the PEM client certificate for the brokers that require the mutual TLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>, // Optional path to the PEM private key of the client certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_token: Option<HardwareTokenConfig>, // Optional hardware token with the client certificate and its key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>, // Optional path to the PEM CA bundle for the self-hosted brokers with the private PKI.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub insecure_skip_hostname_verification: bool, // DANGEROUS: accept the broker certificate issued for another name.
+    #[serde(default)]
+    pub mqtt_version: MqttVersion, // Version of the MQTT protocol, `v311` for the legacy brokers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_redirect_hosts: Vec<String>, // Hosts (or `*.domain`) the MQTT 5 broker may redirect the connections to.
+    #[serde(default, flatten)]
+    pub tuning: ConnectionTuning, // Keep-alive, timeout and packet size of the connections.
 }
 
 // Dark Theme enum, part of AppearanceConfig that contains data about the theme.
@@ -47,14 +942,75 @@ pub struct AppearanceConfig {
     pub dark_theme: DarkTheme,
 }
 
-/// Retrieves the configuration file path.
-/// This function constructs the path to the configuration file, creating the necessary directories if they do not exist.
-///
-/// # Returns
-///
-/// * `Result<PathBuf>` - The path to the configuration file or an error if the path could not be created.
-pub fn get_config_path() -> io::Result<PathBuf> {
-    let mut config_path = PathBuf::new();
+/// Name of the application folder inside the Documents / app data folders.
+const APP_DIR_NAME: &str = "tba";
+/// Name of the configuration file.
+const CONFIG_FILE_NAME: &str = "config.yaml";
+/// Name of the log file and of the previous log file (see `logger`).
+pub const LOG_FILE_NAME: &str = "log.txt";
+pub const OLD_LOG_FILE_NAME: &str = "log.old.txt";
+/// Name of the file left in the legacy data folder after the migration. Contains the path to the new folder.
+const MOVED_POINTER_FILE_NAME: &str = "MOVED.txt";
+/// Suffix of the temporary file while it is copied to the new folder.
+const MIGRATION_TMP_SUFFIX: &str = ".migrating";
+/// Name of the folder with the backups of the configuration file, next to the file.
+const CONFIG_BACKUPS_DIR_NAME: &str = "backups";
+/// Name of the backup is the prefix, the time of the backup and the suffix.
+const CONFIG_BACKUP_PREFIX: &str = "config_";
+const CONFIG_BACKUP_SUFFIX: &str = ".yaml";
+
+/// Number of attempts for the file operations that fail because the file is locked by another process.
+const IO_RETRY_ATTEMPTS: u32 = 5;
+/// Delay before the first retry of the file operation. Doubles on each attempt.
+const IO_RETRY_INITIAL_DELAY_MS: u64 = 100;
+
+/// Folder names of the known cloud sync services. Files in these folders are locked by the sync client from time to time.
+const CLOUD_SYNC_MARKERS: [&str; 6] = [
+    "onedrive",
+    "dropbox",
+    "google drive",
+    "icloud drive",
+    "mobile documents",
+    "com~apple~clouddocs",
+];
+
+/// Environment variable with the folder of the configuration, the logs and the data, overriding the default folders
+/// for the managed deployments (e.g. Citrix or the roaming profiles without the writable home folder).
+const CONFIG_DIR_ENV: &str = "TBA_CONFIG_DIR";
+/// Command line flag with the same folder, `--config-dir <folder>` or `--config-dir=<folder>`.
+/// It takes precedence over the environment variable.
+const CONFIG_DIR_FLAG: &str = "--config-dir";
+
+lazy_static! {
+    /// Folder set with `CONFIG_DIR_FLAG` or `CONFIG_DIR_ENV`, `None` if the default folders are used.
+    static ref CUSTOM_DIR: Option<PathBuf> = {
+        let args: Vec<String> = env::args().skip(1).collect();
+        let flag_value = args.iter().enumerate().find_map(|(index, arg)| {
+            if arg == CONFIG_DIR_FLAG {
+                // The folder is the next argument
+                return args.get(index + 1).cloned();
+            }
+            arg.strip_prefix(CONFIG_DIR_FLAG).and_then(|rest| rest.strip_prefix('=')).map(str::to_string)
+        });
+        flag_value
+            .or_else(|| env::var(CONFIG_DIR_ENV).ok())
+            .map(|dir| dir.trim().to_string())
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| {
+                let dir = PathBuf::from(dir);
+                // The relative folder is resolved once, so it doesn't change with the working folder
+                if dir.is_absolute() {
+                    dir
+                } else {
+                    env::current_dir().map(|current| current.join(&dir)).unwrap_or(dir)
+                }
+            })
+    };
+}
+
+/// Retrieves the legacy data folder: `Documents/tba` in the user's home directory.
+fn get_documents_data_dir() -> io::Result<PathBuf> {
+    let mut data_dir = PathBuf::new();
 
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     let home_dir = env::var("HOME");
@@ -63,275 +1019,1696 @@ pub fn get_config_path() -> io::Result<PathBuf> {
     let home_dir = env::var("USERPROFILE");
 
     match home_dir {
-        Ok(home) => config_path.push(home),
+        Ok(home) => data_dir.push(home),
         Err(e) => {
             error!("Failed to get home directory environment variable: {}", e);
             return Err(io::Error::new(io::ErrorKind::Other, "Failed to get home directory environment variable"));
         }
     }
 
-    config_path.push("Documents");
-    config_path.push("tba");
+    data_dir.push("Documents");
+    data_dir.push(APP_DIR_NAME);
 
-    if let Err(e) = fs::create_dir_all(&config_path) {
-        error!("Failed to create directories: {}", e);
-        return Err(e);
-    }
+    Ok(data_dir)
+}
 
-    config_path.push("config.yaml");
+/// Retrieves the local (not synced) application data folder of the platform.
+fn get_app_data_dir() -> Option<PathBuf> {
+    tauri::api::path::local_data_dir().map(|dir| dir.join(APP_DIR_NAME))
+}
 
-    Ok(config_path)
+/// Retrieves the application folder inside the XDG base directory: the directory from the environment variable,
+/// or the default one inside the home directory. The relative paths are ignored, as the specification requires.
+fn get_xdg_dir(variable: &str, default: &str) -> Option<PathBuf> {
+    env::var_os(variable)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(default)))
+        .map(|dir| dir.join(APP_DIR_NAME))
+}
 
+/// Retrieves the application folder of the configuration: `$XDG_CONFIG_HOME/tba` on Linux,
+/// the application data folder on the other platforms.
+fn get_app_config_dir() -> Option<PathBuf> {
+    if cfg!(target_os = "linux") {
+        get_xdg_dir("XDG_CONFIG_HOME", ".config")
+    } else {
+        get_app_data_dir()
+    }
 }
-/// Load the configuration from the file.
-/// This function reads the configuration file and parses it.
-///
-/// # Arguments
-///
-/// * `config_path` - The path to the configuration file.
+
+/// Retrieves the application folder of the logs: `$XDG_STATE_HOME/tba` on Linux,
+/// the application data folder on the other platforms.
+fn get_app_log_dir() -> Option<PathBuf> {
+    if cfg!(target_os = "linux") {
+        get_xdg_dir("XDG_STATE_HOME", ".local/state")
+    } else {
+        get_app_data_dir()
+    }
+}
+
+/// Checks if the legacy `Documents/tba` folder is still used: its configuration has not been migrated yet.
+fn is_legacy_data_dir_used() -> bool {
+    let legacy_config_exists = get_documents_data_dir()
+        .map(|dir| dir.join(CONFIG_FILE_NAME).exists())
+        .unwrap_or(false);
+    let config_exists = |dir: Option<PathBuf>| dir.map_or(false, |dir| dir.join(CONFIG_FILE_NAME).exists());
+    legacy_config_exists && !config_exists(get_app_config_dir()) && !config_exists(get_app_data_dir())
+}
+
+/// Creates the folder if it does not exist.
+fn ensure_dir(dir: PathBuf) -> io::Result<PathBuf> {
+    if let Err(e) = fs::create_dir_all(&dir) {
+        error!("Failed to create directories: {}", e);
+        return Err(e);
+    }
+    Ok(dir)
+}
+
+/// Retrieves the folder with the application data (the stores, the traces, etc.), creating it if it does not exist.
+///
+/// The data is kept in the local application data folder of the platform, or in the folder set with `--config-dir`
+/// or `TBA_CONFIG_DIR`, which also has the configuration and the logs then.
+/// The legacy `Documents/tba` folder is used only until its configuration is migrated (see `migrate_legacy_data_dir`).
+///
+/// # Returns
+///
+/// * `Result<PathBuf>` - The path to the data folder or an error if the folder could not be created.
+pub fn get_data_dir() -> io::Result<PathBuf> {
+    if let Some(custom_dir) = CUSTOM_DIR.as_ref() {
+        return ensure_dir(custom_dir.clone());
+    }
+    match get_app_data_dir() {
+        Some(app_data_dir) if !is_legacy_data_dir_used() => ensure_dir(app_data_dir),
+        _ => ensure_dir(get_documents_data_dir()?),
+    }
+}
+
+/// Retrieves the folder with the configuration file and its backups, creating it if it does not exist.
+/// On Linux it is `$XDG_CONFIG_HOME/tba`, on the other platforms it is the data folder (see `get_data_dir`).
+fn get_config_dir() -> io::Result<PathBuf> {
+    if CUSTOM_DIR.is_some() {
+        return get_data_dir();
+    }
+    match get_app_config_dir() {
+        Some(app_config_dir) if !is_legacy_data_dir_used() => ensure_dir(app_config_dir),
+        _ => get_data_dir(),
+    }
+}
+
+/// Retrieves the folder with the log files, creating it if it does not exist.
+/// On Linux it is `$XDG_STATE_HOME/tba`, on the other platforms it is the data folder (see `get_data_dir`).
+pub fn get_log_dir() -> io::Result<PathBuf> {
+    if CUSTOM_DIR.is_some() {
+        return get_data_dir();
+    }
+    match get_app_log_dir() {
+        Some(app_log_dir) if !is_legacy_data_dir_used() => ensure_dir(app_log_dir),
+        _ => get_data_dir(),
+    }
+}
+
+/// Retrieves the configuration file path.
+/// This function constructs the path to the configuration file, creating the necessary directories if they do not exist.
+///
+/// # Returns
+///
+/// * `Result<PathBuf>` - The path to the configuration file or an error if the path could not be created.
+pub fn get_config_path() -> io::Result<PathBuf> {
+    let mut config_path = get_config_dir()?;
+    config_path.push(CONFIG_FILE_NAME);
+    Ok(config_path)
+}
+
+/// Checks if the error is caused by another process holding the file (e.g. a cloud sync client).
+fn is_sharing_violation(e: &io::Error) -> bool {
+    if cfg!(target_os = "windows") {
+        // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION. ERROR_ACCESS_DENIED is not retried, it is mostly permanent
+        matches!(e.raw_os_error(), Some(32) | Some(33))
+    } else if cfg!(target_os = "macos") {
+        // EDEADLK is returned for the files that are being downloaded by iCloud
+        e.raw_os_error() == Some(11)
+    } else {
+        false
+    }
+}
+
+/// Runs the file operation, retrying it with a backoff if the file is locked by another process.
+///
+/// The operation is synchronous, so it is called from the async tasks too. The backoff doesn't hold the worker
+/// thread of the async runtime then: its tasks are moved to the other workers while the thread sleeps.
+///
+/// # Arguments
+///
+/// * `op` - The file operation.
+///
+/// # Returns
+///
+/// * `io::Result<T>` - The result of the last attempt.
+pub fn retry_io<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = Duration::from_millis(IO_RETRY_INITIAL_DELAY_MS);
+    for attempt in 1..IO_RETRY_ATTEMPTS {
+        match op() {
+            Err(e) if is_sharing_violation(&e) => {
+                log::warn!("The file is locked by another process (attempt {}): {}. Retrying in {:?}", attempt, e, delay);
+                backoff(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+/// Sleeps between the attempts of `retry_io`. On the worker of the multi-thread runtime the sleep is run
+/// with `block_in_place`, so the other tasks of the worker are not blocked.
+fn backoff(delay: Duration) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| std::thread::sleep(delay))
+        }
+        _ => std::thread::sleep(delay),
+    }
+}
+
+/// Reads the configuration file, retrying if it is locked by another process.
+fn read_config_file(config_path: &Path) -> io::Result<String> {
+    retry_io(|| fs::read_to_string(config_path))
+}
+
+/// Writes the configuration file, retrying if it is locked by another process.
+fn write_config_file(config_path: &Path, contents: &str) -> io::Result<()> {
+    retry_io(|| fs::write(config_path, contents))
+}
+
+/// Checks if the path is inside a folder synced by a cloud service (OneDrive, Dropbox, iCloud, etc.).
+pub fn is_cloud_synced_path(path: &Path) -> bool {
+    // Redirected folders are resolved to check where the data really is
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.components().any(|component| {
+        let name = component.as_os_str().to_string_lossy().to_lowercase();
+        CLOUD_SYNC_MARKERS.iter().any(|marker| name.starts_with(marker))
+    })
+}
+
+/// Migrates the configuration, logs and stores from the legacy `Documents/tba` folder
+/// to the local application data folder of the platform.
+///
+/// The migration is idempotent: every file is copied under a temporary name and renamed when it is complete,
+/// the files which are already in the new folder are not overwritten, and the configuration file is moved last,
+/// as its presence in the new folder switches the application to it (see `get_data_dir`).
+/// If the migration fails, the legacy folder is used and the migration continues on the next start.
+/// When everything is moved, the pointer file with the new location is left in the legacy folder.
+///
+/// This function is called before the logging is initialized, so the result is returned to be logged later.
+///
+/// # Returns
+///
+/// * `Result<Option<PathBuf>, String>` - The new data folder if the data has been migrated now,
+///   `None` if there is nothing to migrate, or the error message.
+pub fn migrate_legacy_data_dir() -> Result<Option<PathBuf>, String> {
+    // The custom folder is prepared by the administrator of the deployment
+    if CUSTOM_DIR.is_some() {
+        return Ok(None);
+    }
+    let (legacy_dir, app_data_dir) = match (get_documents_data_dir(), get_app_data_dir()) {
+        (Ok(legacy_dir), Some(app_data_dir)) => (legacy_dir, app_data_dir),
+        _ => return Ok(None),
+    };
+    if !legacy_dir.is_dir() || legacy_dir == app_data_dir || legacy_dir.join(MOVED_POINTER_FILE_NAME).exists() {
+        return Ok(None);
+    }
+
+    let is_empty = fs::read_dir(&legacy_dir)
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or(true);
+    if is_empty {
+        return Ok(None);
+    }
+
+    // The backup is moved with the other files, so the configuration can be rolled back if the migration breaks it
+    backup_config_file(&legacy_dir.join(CONFIG_FILE_NAME))
+        .map_err(|e| format!("Failed to back up the configuration before the migration: {}", e))?;
+    move_dir_contents(&legacy_dir, &app_data_dir)
+        .map_err(|e| format!("Failed to migrate the data from {}: {}", legacy_dir.display(), e))?;
+
+    let pointer = format!(
+        "The data of the Tacho Bridge Application has been moved to:\n{}\n",
+        app_data_dir.display()
+    );
+    retry_io(|| fs::write(legacy_dir.join(MOVED_POINTER_FILE_NAME), &pointer))
+        .map_err(|e| format!("Failed to write the pointer file: {}", e))?;
+
+    Ok(Some(app_data_dir))
+}
+
+/// Moves the files and subfolders from one folder to another, the configuration file last.
+fn move_dir_contents(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(from)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    paths.sort_by_key(|path| path.file_name().map(|name| name == CONFIG_FILE_NAME).unwrap_or(false));
+
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_os_string();
+        let target = to.join(&name);
+        if path.is_dir() {
+            move_dir_contents(&path, &target)?;
+            // The folder may still contain the files of other applications
+            let _ = fs::remove_dir(&path);
+            continue;
+        }
+        if name.to_string_lossy().ends_with(MIGRATION_TMP_SUFFIX) {
+            // Leftover of the interrupted migration, the file is copied again
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+
+        move_file(&path, &target)?;
+    }
+
+    Ok(())
+}
+
+/// Moves the file to another folder, which may be on another volume. The file which is already in the new folder
+/// has been migrated by the interrupted migration, so it is not overwritten.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if !to.exists() {
+        let mut tmp_name = to.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(MIGRATION_TMP_SUFFIX);
+        let tmp_target = to.with_file_name(tmp_name);
+        // rename doesn't work between the volumes, so the file is copied
+        retry_io(|| fs::copy(from, &tmp_target))?;
+        retry_io(|| fs::rename(&tmp_target, to))?;
+    }
+    retry_io(|| fs::remove_file(from))
+}
+
+/// Moves the configuration file with its backups and the log files from the data folder to the XDG base directories
+/// on Linux: the configuration to `$XDG_CONFIG_HOME/tba` and the logs to `$XDG_STATE_HOME/tba`. The other data stays
+/// in the data folder (`$XDG_DATA_HOME/tba`). The configuration is moved last, as its presence in the new folder
+/// switches the application to it (see `get_config_dir`), so the interrupted migration continues on the next start.
+///
+/// This function is called before the logging is initialized, so the result is returned to be logged later.
+///
+/// # Returns
+///
+/// * `Result<bool, String>` - `true` if the files have been moved now, `false` if there is nothing to move,
+///   or the error message.
+pub fn migrate_to_xdg_dirs() -> Result<bool, String> {
+    if CUSTOM_DIR.is_some() || is_legacy_data_dir_used() {
+        return Ok(false);
+    }
+    let (data_dir, config_dir, log_dir) = match (get_app_data_dir(), get_app_config_dir(), get_app_log_dir()) {
+        (Some(data_dir), Some(config_dir), Some(log_dir)) => (data_dir, config_dir, log_dir),
+        _ => return Ok(false),
+    };
+    let mut moved = false;
+
+    if log_dir != data_dir {
+        for name in [LOG_FILE_NAME, OLD_LOG_FILE_NAME] {
+            let path = data_dir.join(name);
+            if path.is_file() {
+                fs::create_dir_all(&log_dir)
+                    .and_then(|_| move_file(&path, &log_dir.join(name)))
+                    .map_err(|e| format!("Failed to move the log file {}: {}", path.display(), e))?;
+                moved = true;
+            }
+        }
+    }
+
+    if config_dir != data_dir {
+        let backups_dir = data_dir.join(CONFIG_BACKUPS_DIR_NAME);
+        if backups_dir.is_dir() {
+            move_dir_contents(&backups_dir, &config_dir.join(CONFIG_BACKUPS_DIR_NAME))
+                .map_err(|e| format!("Failed to move the configuration backups: {}", e))?;
+            let _ = fs::remove_dir(&backups_dir);
+            moved = true;
+        }
+        let config_path = data_dir.join(CONFIG_FILE_NAME);
+        if config_path.is_file() {
+            fs::create_dir_all(&config_dir)
+                .and_then(|_| move_file(&config_path, &config_dir.join(CONFIG_FILE_NAME)))
+                .map_err(|e| format!("Failed to move the configuration file: {}", e))?;
+            moved = true;
+        }
+    }
+
+    Ok(moved)
+}
+
+/// Logs a warning if the data folder is synced by a cloud service.
+pub fn warn_if_cloud_synced() {
+    if let Ok(data_dir) = get_data_dir() {
+        if is_cloud_synced_path(&data_dir) {
+            log::warn!(
+                "The data folder {} is synced by a cloud service, which can lock the config and log files. \
+                It is recommended to keep the data in the local application data folder.",
+                data_dir.display()
+            );
+        }
+    }
+}
+
+/// Load the configuration from the file.
+/// This function reads the configuration file and parses it.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+///
+/// # Returns
+///
+/// * `Result<ConfigurationFile, Box<dyn std::error::Error + Send + Sync>>` - The loaded configuration or an error.
+fn load_config(
+    config_path: &Path,
+) -> Result<ConfigurationFile, Box<dyn std::error::Error + Send + Sync>> {
+    let config_contents = read_config_file(config_path)?;
+    let config: ConfigurationFile = serde_yaml::from_str(&config_contents)?;
+    Ok(config)
+}
+
+/// Saves the configuration to the file.
+/// This function serializes the configuration and writes it to the file.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `config` - The configuration to save.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully saved, otherwise returns an error.
+fn save_config(
+    config_path: &Path,
+    config: &ConfigurationFile,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let yaml = serde_yaml::to_string(config)?;
+    // The change is not saved without the backup, so it can always be rolled back
+    backup_config_file(config_path)?;
+    write_config_file(config_path, &yaml)?;
+    Ok(())
+}
+
+/// Copies the configuration file to the `backups` folder next to it, under the name with the current time.
+/// The oldest backups over `backups.keep` are removed.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - The error if the backup can't be written, nothing is done if there is no file yet.
+fn backup_config_file(config_path: &Path) -> io::Result<()> {
+    let keep = get_backups_config().keep;
+    if keep == 0 || !config_path.exists() {
+        return Ok(());
+    }
+    let backups_dir = config_backups_dir(config_path);
+    fs::create_dir_all(&backups_dir)?;
+    let name = format!(
+        "{}{}{}",
+        CONFIG_BACKUP_PREFIX,
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S%.3f"),
+        CONFIG_BACKUP_SUFFIX
+    );
+    retry_io(|| fs::copy(config_path, backups_dir.join(&name)))?;
+
+    let backups = list_backup_names(&backups_dir);
+    for old in backups.iter().take(backups.len().saturating_sub(keep)) {
+        if let Err(e) = fs::remove_file(backups_dir.join(old)) {
+            log::warn!("Failed to remove the old backup {} of the configuration: {}", old, e);
+        }
+    }
+    Ok(())
+}
+
+/// Folder with the backups of the configuration file.
+fn config_backups_dir(config_path: &Path) -> PathBuf {
+    config_path.parent().unwrap_or_else(|| Path::new(".")).join(CONFIG_BACKUPS_DIR_NAME)
+}
+
+/// Names of the backups of the configuration file in the folder, the oldest first.
+fn list_backup_names(backups_dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(backups_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with(CONFIG_BACKUP_PREFIX) && name.ends_with(CONFIG_BACKUP_SUFFIX))
+                .collect()
+        })
+        .unwrap_or_default();
+    // The names have the time of the backup, so they are sorted by the time
+    names.sort();
+    names
+}
+
+/// Backup of the configuration file, for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConfigBackup {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Public function to list the backups of the configuration file, the newest first.
+/// This function is a Tauri command that is called from the settings of the frontend.
+///
+/// # Returns
+///
+/// * `Result<Vec<ConfigBackup>, String>` - The backups, or the error message if the data folder is not available.
+#[tauri::command]
+pub fn list_config_backups() -> Result<Vec<ConfigBackup>, String> {
+    let backups_dir = config_backups_dir(&get_config_path().map_err(|e| e.to_string())?);
+    Ok(list_backup_names(&backups_dir)
+        .into_iter()
+        .rev()
+        .map(|name| ConfigBackup {
+            size: fs::metadata(backups_dir.join(&name)).map(|metadata| metadata.len()).unwrap_or(0),
+            name,
+        })
+        .collect())
+}
+
+/// Replaces the configuration with the backup. The current configuration is backed up as well,
+/// so the restore can be rolled back too.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `name` - The name of the backup (see `list_config_backups`).
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully restored, otherwise returns an error.
+pub fn restore_config_backup_file(config_path: &Path, name: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let backups_dir = config_backups_dir(config_path);
+    // Only the backups from the folder are restored, the name is not a path
+    if !list_backup_names(&backups_dir).iter().any(|backup| backup == name) {
+        return Err(format!("There is no backup {} of the configuration", name).into());
+    }
+    let yaml = read_config_file(&backups_dir.join(name))?;
+    replace_config(config_path, &yaml)
+}
+
+/// Public function to roll the configuration back to the backup, e.g. after a bad edit or a failed migration.
+/// This function is a Tauri command that is called from the settings of the frontend.
+///
+/// # Arguments
+///
+/// * `name` - The name of the backup (see `list_config_backups`).
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error message if the backup can't be restored.
+#[tauri::command]
+pub async fn restore_config_backup(name: String) -> Result<(), String> {
+    config_writer::apply(ConfigMutation::RestoreBackup { name: name.clone() }).await?;
+    log::info!("The configuration is restored from the backup {}", name);
+    apply_changed_config().await;
+    Ok(())
+}
+
+/// Updates the configuration with a new card.
+/// This function updates the configuration file with a new card's ATR and card number.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `atr` - The ATR of the card.
+/// * `cardnumber` - The card number.
+/// * `label` - The name of the card for the UI, it is kept if `None` and removed if empty.
+/// * `notes` - The notes about the card, they are kept if `None` and removed if empty.
+/// * `iccid` - The ICCID of the card if it has been read, the card paired with another number is rejected.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn update_card_config(
+    config_path: &Path,
+    atr: &str,
+    cardnumber: &str,
+    label: Option<&str>,
+    notes: Option<&str>,
+    iccid: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    let cards = config.cards.get_or_insert_with(HashMap::new);
+    // One ATR can be paired only with one card number, so the previous pairing is dropped
+    cards.retain(|number, card| number == cardnumber || card.atr != atr);
+    // The same card with another ATR (e.g. after the warm reset) would start the connection of the wrong number
+    if let Some(iccid) = iccid {
+        if let Some(other) = find_iccid_owner(cards, iccid).filter(|other| other != cardnumber) {
+            return Err(format!(
+                "The card with the ICCID {} is already paired with the number {}, remove it to pair the card again",
+                iccid, other
+            )
+            .into());
+        }
+    }
+    let card = cards.entry(cardnumber.to_string()).or_default();
+    card.atr = atr.to_string();
+    if let Some(iccid) = iccid {
+        card.iccid = Some(iccid.to_string());
+    }
+    let non_empty = |text: &str| Some(text.trim().to_string()).filter(|text| !text.is_empty());
+    if let Some(label) = label {
+        card.label = non_empty(label);
+    }
+    if let Some(notes) = notes {
+        card.notes = non_empty(notes);
+    }
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Sets the expiry date of the card read from the chip.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `cardnumber` - The card number.
+/// * `expire` - The expiry date, "YYYY-MM-DD".
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn set_card_expire_config(
+    config_path: &Path,
+    cardnumber: &str,
+    expire: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    let card = config
+        .cards
+        .as_mut()
+        .and_then(|cards| cards.get_mut(cardnumber))
+        .ok_or_else(|| format!("The card {} is not in the configuration", cardnumber))?;
+    if card.expire.as_deref() == Some(expire) {
+        return Ok(());
+    }
+    card.expire = Some(expire.to_string());
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Saves the ICCID read from the chip to the settings of the paired card.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `cardnumber` - The card number.
+/// * `iccid` - The ICCID of the card.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn set_card_iccid_config(
+    config_path: &Path,
+    cardnumber: &str,
+    iccid: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    let card = config
+        .cards
+        .as_mut()
+        .and_then(|cards| cards.get_mut(cardnumber))
+        .ok_or_else(|| format!("The card {} is not in the configuration", cardnumber))?;
+    if card.iccid.as_deref() == Some(iccid) {
+        return Ok(());
+    }
+    card.iccid = Some(iccid.to_string());
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Returns the number of the card paired with the ICCID among the cards.
+fn find_iccid_owner(cards: &HashMap<String, CardConfig>, iccid: &str) -> Option<String> {
+    cards
+        .iter()
+        .find(|(_, card)| card.iccid.as_deref().map_or(false, |card_iccid| card_iccid.eq_ignore_ascii_case(iccid)))
+        .map(|(cardnumber, _)| cardnumber.clone())
+}
+
+/// Returns the contents of the configuration file, e.g. for the migration to another machine.
+pub fn export_config_yaml() -> io::Result<String> {
+    read_config_file(&get_config_path()?)
+}
+
+/// Replaces the whole configuration, e.g. with the one brought from another machine.
+/// The configuration is checked before it is saved.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `yaml` - The new configuration.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully replaced, otherwise returns an error.
+pub fn replace_config(config_path: &Path, yaml: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config: ConfigurationFile = serde_yaml::from_str(yaml)?;
+    config.version = env!("CARGO_PKG_VERSION").to_string();
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Merges the imported configuration into the current one, e.g. the setup of the office cloned to another PC.
+/// The imported cards, accounts and jobs are added to the current ones, replacing the ones with the same key,
+/// the other imported sections replace the current ones and the sections missing in the import are kept.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `yaml` - The imported configuration.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully merged, otherwise returns an error.
+pub fn merge_config(config_path: &Path, yaml: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let imported: ConfigurationFile = serde_yaml::from_str(yaml)?;
+    let mut config = load_config(config_path)?;
+
+    if let Some(imported_cards) = imported.cards {
+        let cards = config.cards.get_or_insert_with(HashMap::new);
+        for (cardnumber, card) in imported_cards {
+            // One ATR can be paired only with one card number, so the current pairing is dropped
+            cards.retain(|number, current| *number == cardnumber || current.atr != card.atr);
+            cards.insert(cardnumber, card);
+        }
+    }
+    if let Some(accounts) = imported.accounts {
+        config.accounts.get_or_insert_with(HashMap::new).extend(accounts);
+    }
+    if let Some(scheduler) = imported.scheduler {
+        config.scheduler.get_or_insert_with(HashMap::new).extend(scheduler);
+    }
+    if imported.server.is_some() {
+        config.server = imported.server;
+    }
+    if imported.ident.is_some() {
+        config.ident = imported.ident;
+    }
+    if imported.appearance.is_some() {
+        config.appearance = imported.appearance;
+    }
+    if imported.broadcast.is_some() {
+        config.broadcast = imported.broadcast;
+    }
+    if imported.security_log.is_some() {
+        config.security_log = imported.security_log;
+    }
+    if imported.readers.is_some() {
+        config.readers = imported.readers;
+    }
+    if imported.retention.is_some() {
+        config.retention = imported.retention;
+    }
+    if imported.protocol.is_some() {
+        config.protocol = imported.protocol;
+    }
+    if imported.reader_debounce.is_some() {
+        config.reader_debounce = imported.reader_debounce;
+    }
+    if imported.auto_resync.is_some() {
+        config.auto_resync = imported.auto_resync;
+    }
+    if imported.known_atrs.is_some() {
+        config.known_atrs = imported.known_atrs;
+    }
+    if imported.power_saving.is_some() {
+        config.power_saving = imported.power_saving;
+    }
+    if imported.proxy.is_some() {
+        config.proxy = imported.proxy;
+    }
+    if imported.connection_stagger.is_some() {
+        config.connection_stagger = imported.connection_stagger;
+    }
+    if imported.session.is_some() {
+        config.session = imported.session;
+    }
+    if imported.topics.is_some() {
+        config.topics = imported.topics;
+    }
+    if imported.integrity.is_some() {
+        config.integrity = imported.integrity;
+    }
+    if imported.apdu_trace.is_some() {
+        config.apdu_trace = imported.apdu_trace;
+    }
+    if imported.certificates.is_some() {
+        config.certificates = imported.certificates;
+    }
+    if imported.backups.is_some() {
+        config.backups = imported.backups;
+    }
+    if imported.notifications.is_some() {
+        config.notifications = imported.notifications;
+    }
+    // The imported cards may clash with the current ones, e.g. with the same ICCID
+    validate_config(&config)?;
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// How the imported configuration is applied (see `import_config`).
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigImportMode {
+    /// The imported configuration is merged into the current one (see `merge_config`).
+    Merge,
+    /// The current configuration is replaced with the imported one.
+    Replace,
+}
+
+/// Checks the imported configuration before it is applied: the server addresses must have the port,
+/// the ATRs of the cards must be hex and paired with one card only, and so must the ICCIDs.
+pub fn validate_config(config: &ConfigurationFile) -> Result<(), String> {
+    if let Some(server) = config.server.as_ref().filter(|server| !server.host.is_empty()) {
+        split_host_to_parts(&server.host).map_err(|e| format!("Invalid server address '{}': {}", server.host, e))?;
+    }
+    for (name, account) in config.accounts.iter().flatten() {
+        split_host_to_parts(&account.host)
+            .map_err(|e| format!("Invalid server address '{}' of the account '{}': {}", account.host, name, e))?;
+    }
+    let mut paired_atrs: HashMap<String, &str> = HashMap::new();
+    let mut paired_iccids: HashMap<String, &str> = HashMap::new();
+    for (cardnumber, card) in config.cards.iter().flatten() {
+        if let Some(iccid) = &card.iccid {
+            if let Some(other) = paired_iccids.insert(iccid.to_lowercase(), cardnumber) {
+                return Err(format!("The ICCID '{}' is paired with both cards {} and {}", iccid, other, cardnumber));
+            }
+        }
+        if card.atr.is_empty() {
+            continue;
+        }
+        if hex::decode(&card.atr).is_err() {
+            return Err(format!("Invalid ATR '{}' of the card {}", card.atr, cardnumber));
+        }
+        if let Some(other) = paired_atrs.insert(card.atr.to_lowercase(), cardnumber) {
+            return Err(format!("The ATR '{}' is paired with both cards {} and {}", card.atr, other, cardnumber));
+        }
+    }
+    Ok(())
+}
+
+/// Public function to export the whole configuration to the file, e.g. to set up another office PC the same way.
+/// This function is a Tauri command that is called from the settings of the frontend.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file chosen by the user.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error message if the configuration can't be exported.
+#[tauri::command]
+pub fn export_config(path: String) -> Result<(), String> {
+    let yaml = export_config_yaml().map_err(|e| format!("Failed to read the configuration: {}", e))?;
+    retry_io(|| fs::write(&path, &yaml)).map_err(|e| format!("Failed to write the configuration to {}: {}", path, e))?;
+    log::info!("The configuration is exported to {}", path);
+    Ok(())
+}
+
+/// Public function to import the configuration exported on another PC (see `export_config`).
+/// This function is a Tauri command that is called from the settings of the frontend.
+/// The configuration is checked before it is applied, the connections are re-established with the new settings.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file chosen by the user.
+/// * `mode` - Whether the imported configuration is merged into the current one or replaces it.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error message if the configuration is invalid or can't be applied.
+#[tauri::command]
+pub async fn import_config(path: String, mode: ConfigImportMode) -> Result<(), String> {
+    let yaml = read_config_file(Path::new(&path)).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let config: ConfigurationFile =
+        serde_yaml::from_str(&yaml).map_err(|e| format!("The configuration can't be read: {}", e))?;
+    validate_config(&config)?;
+
+    let mutation = match mode {
+        ConfigImportMode::Merge => ConfigMutation::MergeConfig { yaml: ConfigYaml(yaml) },
+        ConfigImportMode::Replace => ConfigMutation::ReplaceConfig { yaml: ConfigYaml(yaml) },
+    };
+    config_writer::apply(mutation).await?;
+    log::info!("The configuration is imported from {} ({:?})", path, mode);
+    apply_changed_config().await;
+    Ok(())
+}
+
+/// Applies the configuration replaced as a whole or changed remotely: the connections are re-established
+/// with the new settings and the frontend gets them.
+pub async fn apply_changed_config() {
+    crate::app_connect::apply_account_changes().await;
+    // The errors of the PC/SC service are logged by the sync
+    let _ = crate::smart_card::manual_sync_cards().await;
+    emit_config_to_frontend();
+}
+
+/// Sets the availability of the card.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `cardnumber` - The card number.
+/// * `availability` - Whether the card serves the requests.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn set_card_availability_config(
+    config_path: &Path,
+    cardnumber: &str,
+    availability: CardAvailability,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    let card = config
+        .cards
+        .as_mut()
+        .and_then(|cards| cards.get_mut(cardnumber))
+        .ok_or_else(|| format!("The card {} is not in the configuration", cardnumber))?;
+    card.availability = availability;
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Public function to pause, disable or activate the card.
+/// This function is a Tauri command that is called from the frontend when the office takes the card offline.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The card number.
+/// * `availability` - Whether the card serves the requests.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error if the configuration can't be updated.
+#[tauri::command]
+pub async fn set_card_availability(cardnumber: String, availability: CardAvailability) -> Result<(), String> {
+    let mutation = ConfigMutation::SetCardAvailability {
+        cardnumber: cardnumber.clone(),
+        availability,
+    };
+    config_writer::apply(mutation).await.map_err(|e| {
+        log::error!("Failed to update config: {}", e);
+        e
+    })?;
+    log::info!("The card {} is {:?}", cardnumber, availability);
+    Ok(())
+}
+
+/// Public function to update the configuration with a new card.
+/// This function is a Tauri command that updates the configuration file with a new card's ATR and card number.
+///
+/// # Arguments
+///
+/// * `atr` - The ATR of the card.
+/// * `cardnumber` - The card number.
+/// * `label` - The name of the card for the UI, optional.
+/// * `notes` - The notes about the card, optional.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error if the card can't be paired.
+#[tauri::command]
+pub async fn update_card(atr: String, cardnumber: String, label: Option<String>, notes: Option<String>) -> Result<(), String> {
+    // The ICCID of the inserted card is checked, so the card is not paired with two numbers
+    let iccid = crate::smart_card::unpaired_iccid(&atr);
+    let mutation = ConfigMutation::UpdateCard {
+        atr,
+        cardnumber: cardnumber.clone(),
+        label,
+        notes,
+        iccid,
+    };
+    match config_writer::apply(mutation).await {
+        Ok(_) => {
+            // The list of the cards in the UI shows the label and the notes
+            emit_config_to_frontend();
+            log::info!("The card, {} is added to the configuration! It is needed to restart the application to connect the card to the server. Automation will be implemented later.", cardnumber);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to update config: {}", e);
+            crate::global_app_handle::emit_notification("error", &format!("The card {} is not paired: {}", cardnumber, e));
+            Err(e)
+        }
+    }
+}
+
+/// Removes the card from the configuration.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `cardnumber` - The card number.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn remove_card_config(
+    config_path: &Path,
+    cardnumber: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    let removed = config
+        .cards
+        .as_mut()
+        .and_then(|cards| cards.remove(cardnumber))
+        .is_some();
+    if !removed {
+        return Err(format!("The card {} is not in the configuration", cardnumber).into());
+    }
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Tauri command to remove the card from the configuration.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The card number.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error if the card can't be removed, e.g. it is not in the configuration.
+#[tauri::command]
+pub async fn remove_card(cardnumber: String) -> Result<(), String> {
+    let mutation = ConfigMutation::RemoveCard {
+        cardnumber: cardnumber.clone(),
+    };
+    config_writer::apply(mutation).await.map_err(|e| {
+        log::error!("Failed to remove the card: {}", e);
+        e
+    })?;
+    log::info!("The card {} is removed from the configuration.", cardnumber);
+    Ok(())
+}
+
+/// Updates the server address in the configuration.
+/// This function updates the configuration file with a new server address.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `server_address` - The new server address.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn update_server_config(
+    config_path: &Path,
+    host: &str,
+    ident: &str,
+    theme: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    // The credentials and the certificates are not edited in the UI, so they are kept
+    let server = config.server.take();
+    config.server = Some(ServerConfig {
+        host: host.to_string(),
+        ..server.unwrap_or_default()
+    });
+    config.ident = Some(ident.to_string());
+    config.appearance = Some(AppearanceConfig {
+        dark_theme: match theme {
+            "Auto" => DarkTheme::Auto,
+            "Dark" => DarkTheme::Dark,
+            "Light" => DarkTheme::Light,
+            _ => DarkTheme::Auto,
+        },
+    });
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Updates the server address and the flespi token of the default account in the configuration file.
+///
+/// # Arguments
+///
+/// * `config_path` - The path to the configuration file.
+/// * `host` - The new server address, `None` to keep the current one.
+/// * `token` - The new flespi token, `None` to keep the current credentials.
+///
+/// # Returns
+///
+/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
+pub fn configure_server_config(
+    config_path: &Path,
+    host: Option<&str>,
+    token: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config(config_path)?;
+
+    let mut server = config.server.take().unwrap_or_default();
+    if let Some(host) = host {
+        server.host = host.to_string();
+    }
+    if let Some(token) = token {
+        // The broker with the token authentication gets the token as the username and the empty password
+        server.username = Some(token.to_string());
+        server.password = None;
+    }
+    config.server = Some(server);
+
+    save_config(config_path, &config)?;
+
+    load_config_to_cache(config_path)?;
+
+    Ok(())
+}
+
+/// Public function to update the server address in the configuration.
+/// This function is a Tauri command that updates the configuration file with a new server address.
+///
+/// # Arguments
+///
+/// * `server_address` - The new server address.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error if the configuration can't be updated.
+#[tauri::command]
+pub async fn update_server(host: String, ident: String, theme: String) -> Result<(), String> {
+    let mutation = ConfigMutation::UpdateServer {
+        host: host.clone(),
+        ident,
+        theme,
+    };
+    config_writer::apply(mutation).await.map_err(|e| {
+        log::error!("Failed to update server address: {}", e);
+        e
+    })?;
+    log::info!("The server address is updated to '{}'", host);
+    // The connections of the changed account are re-established with the new server and ident
+    crate::app_connect::apply_account_changes().await;
+    Ok(())
+}
+
+/*
+  HashMap. Card number = Card settings (ATR, etc.)
+
+  initializing a global cache (HashMap<String, CardConfig>) using Mutex.
+  Mapping card keys and matching them with the real company card number,
+  which can only be entered manually
+*/
+#[derive(Default)]
+pub struct CacheConfigData {
+    pub cards: HashMap<String, CardConfig>,
+    pub server: Option<ServerConfig>,
+    pub ident: Option<String>,
+    pub appearance: Option<AppearanceConfig>,
+    pub broadcast: Option<BroadcastConfig>,
+    pub security_log: Option<SecurityLogConfig>,
+    pub readers: ReadersConfig,
+    pub retention: Option<RetentionConfig>,
+    pub protocol: Option<ProtocolConfig>,
+    pub accounts: HashMap<String, AccountConfig>,
+    pub reader_debounce: Option<ReaderDebounceConfig>,
+    pub scheduler: HashMap<String, ScheduledJobConfig>,
+    pub auto_resync: Option<AutoResyncConfig>,
+    pub known_atrs: Vec<KnownAtrConfig>,
+    pub power_saving: Option<PowerSavingConfig>,
+    pub proxy: Option<ProxyConfig>,
+    pub connection_stagger: Option<ConnectionStaggerConfig>,
+    pub session: Option<SessionConfig>,
+    pub topics: Option<TopicsConfig>,
+    pub integrity: Option<IntegrityConfig>,
+    pub apdu_trace: Option<ApduTraceConfig>,
+    pub certificates: Option<CertificatesConfig>,
+    pub backups: Option<BackupsConfig>,
+    pub notifications: Option<NotificationsConfig>,
+}
+
+lazy_static! {
+    /// Global cache for card ATRs and numbers.
+    /// Initializing a global cache (HashMap<String, String>) using Mutex.
+    /// Mapping card keys and matching them with the real company card number,
+    /// which can only be entered manually.
+    static ref CACHE: Mutex<CacheConfigData> = Mutex::new(CacheConfigData::default());
+
+    /// Senders of the card settings to the running card tasks, by the card number.
+    /// The value is `None` if the card is not in the configuration.
+    static ref CARD_CONFIG_WATCHERS: Mutex<HashMap<String, watch::Sender<Option<CardConfig>>>> = Mutex::new(HashMap::new());
+
+    /// Sender of the broadcast settings to the broadcasting task.
+    static ref BROADCAST_CONFIG_WATCHER: watch::Sender<Option<BroadcastConfig>> = watch::channel(None).0;
+
+    /// Digest of the configuration file loaded to the cache, so the watcher doesn't reload the file saved by the application.
+    static ref LOADED_CONFIG_DIGEST: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+
+/// Interval in seconds of the checks of the configuration file for the external changes.
+const CONFIG_WATCH_INTERVAL_SECS: u64 = 2;
+
+/// Retrieves the server settings from the cache.
+///
+/// # Returns
+///
+/// * `Option<ServerConfig>` - The server settings, or `None` if the server is not configured.
+pub fn get_server_config() -> Option<ServerConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.server.clone()
+}
+
+/// Retrieves the ident of the application from the cache.
+///
+/// # Returns
+///
+/// * `Option<String>` - The ident, or `None` if the ident is not configured.
+pub fn get_ident() -> Option<String> {
+    let cache = CACHE.lock().unwrap();
+    cache.ident.clone()
+}
+
+/// Retrieves the appearance settings from the cache.
+///
+/// # Returns
+///
+/// * `Option<AppearanceConfig>` - The appearance settings, or `None` if they are not configured.
+pub fn get_appearance_config() -> Option<AppearanceConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.appearance.clone()
+}
+
+/// Retrieves the paired card from the cache by its ATR.
+///
+/// # Arguments
+///
+/// * `atr` - The ATR of the card as a hex string.
+///
+/// # Returns
+///
+/// * `Option<(String, CardConfig)>` - The number and the settings of the card, or `None` if the card is not paired.
+pub fn get_card_by_atr(atr: &str) -> Option<(String, CardConfig)> {
+    let cache = CACHE.lock().unwrap();
+    cache
+        .cards
+        .iter()
+        .find(|(_, card)| card.atr == atr)
+        .map(|(cardnumber, card)| (cardnumber.clone(), card.clone()))
+}
+
+/// Retrieves the settings of the card from the cache by the card number.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+///
+/// # Returns
+///
+/// * `Option<CardConfig>` - The card settings, or `None` if the card is not in the configuration.
+pub fn get_card_config(cardnumber: &str) -> Option<CardConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.cards.get(cardnumber).cloned()
+}
+
+/// Retrieves the protocol override of the card from the cache.
+///
+/// # Returns
+///
+/// * `Option<ForceProtocol>` - The protocol, or `None` if the protocol is negotiated as usual.
+pub fn get_card_force_protocol(cardnumber: &str) -> Option<ForceProtocol> {
+    let cache = CACHE.lock().unwrap();
+    cache.cards.get(cardnumber).and_then(|card| card.force_protocol)
+}
+
+/// Retrieves the ATR of the card for the status and telemetry messages (see `AtrDisclosure`).
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+/// * `atr` - The ATR of the card.
+///
+/// # Returns
+///
+/// * `Option<String>` - The ATR, its digest, or `None` if the ATR is hidden for the card.
+pub fn get_disclosed_atr(cardnumber: &str, atr: &str) -> Option<String> {
+    let cache = CACHE.lock().unwrap();
+    let disclosure = cache
+        .cards
+        .get(cardnumber)
+        .map(|card| card.atr_disclosure)
+        .unwrap_or_default();
+    disclosure.disclose(atr)
+}
+
+/// Retrieves the paired card from the cache by the ICCID saved in its settings.
+///
+/// # Arguments
+///
+/// * `iccid` - The ICCID of the card.
+///
+/// # Returns
+///
+/// * `Option<(String, CardConfig)>` - The number and the settings of the card, `None` if no card is paired with the ICCID.
+pub fn get_card_by_iccid(iccid: &str) -> Option<(String, CardConfig)> {
+    let cache = CACHE.lock().unwrap();
+    find_iccid_owner(&cache.cards, iccid)
+        .and_then(|cardnumber| cache.cards.get(&cardnumber).map(|card| (cardnumber.clone(), card.clone())))
+}
+
+/// Finds the card by its label, case-insensitively.
+///
+/// # Arguments
+///
+/// * `label` - The label of the card.
+///
+/// # Returns
+///
+/// * `Option<String>` - The number of the card, `None` if no card has the label.
+pub fn find_card_by_label(label: &str) -> Option<String> {
+    let cache = CACHE.lock().unwrap();
+    cache
+        .cards
+        .iter()
+        .find(|(_, card)| card.label.as_deref().map_or(false, |card_label| card_label.eq_ignore_ascii_case(label)))
+        .map(|(cardnumber, _)| cardnumber.clone())
+}
+
+/// Subscribes to the settings of the card.
+/// The receiver gets the new settings every time the configuration of the card changes,
+/// so the running task of the card applies them without reconnection.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+///
+/// # Returns
+///
+/// * `watch::Receiver<Option<CardConfig>>` - The receiver with the current settings of the card.
+pub fn watch_card_config(cardnumber: &str) -> watch::Receiver<Option<CardConfig>> {
+    let current = get_card_config(cardnumber);
+    let mut watchers = CARD_CONFIG_WATCHERS.lock().unwrap();
+    // The entry of the card whose task has ended is created again with the current settings
+    watchers.retain(|_, sender| sender.receiver_count() > 0);
+    watchers
+        .entry(cardnumber.to_string())
+        .or_insert_with(|| watch::channel(current).0)
+        .subscribe()
+}
+
+/// Subscribes to the broadcast settings, the receiver gets the new settings every time they change.
+pub fn watch_broadcast_config() -> watch::Receiver<Option<BroadcastConfig>> {
+    BROADCAST_CONFIG_WATCHER.subscribe()
+}
+
+/// Forgets the watchers of the cards whose tasks have ended, so the removed cards don't stay in the registry.
+pub fn prune_card_config_watchers() {
+    CARD_CONFIG_WATCHERS.lock().unwrap().retain(|_, sender| sender.receiver_count() > 0);
+}
+
+/// Sends the changed card settings to the subscribed card tasks.
+fn notify_card_config_watchers(cards: &HashMap<String, CardConfig>) {
+    let mut watchers = CARD_CONFIG_WATCHERS.lock().unwrap();
+    watchers.retain(|_, sender| sender.receiver_count() > 0);
+    for (cardnumber, sender) in watchers.iter() {
+        let new_config = cards.get(cardnumber).cloned();
+        sender.send_if_modified(|config| {
+            if *config == new_config {
+                return false;
+            }
+            log::debug!("Settings of the card {} are changed: {:?}", cardnumber, new_config);
+            *config = new_config;
+            true
+        });
+    }
+}
+
+/// Retrieves the share mode of the reader.
+/// The setting of the card (`CardConfig::share_mode`) has priority over the setting of the reader.
+///
+/// # Arguments
+///
+/// * `reader_name` - The full PC/SC name of the reader.
+///
+/// # Returns
+///
+/// * `CardShareMode` - The share mode, `Shared` if it is not configured.
+pub fn get_reader_share_mode(reader_name: &str) -> CardShareMode {
+    let cache = CACHE.lock().unwrap();
+    let label = crate::smart_card::ReaderId::from_name(reader_name).slot_label();
+    cache
+        .readers
+        .settings
+        .iter()
+        .find(|(name, _)| *name == reader_name || **name == label)
+        .map(|(_, reader)| reader.share_mode)
+        .unwrap_or_default()
+}
+
+/// Retrieves the alias of the reader from the cache.
+///
+/// # Arguments
+///
+/// * `reader_name` - The full PC/SC name of the reader.
+///
+/// # Returns
+///
+/// * `Option<String>` - The alias, `None` if it is not configured.
+pub fn get_reader_alias(reader_name: &str) -> Option<String> {
+    let cache = CACHE.lock().unwrap();
+    let label = crate::smart_card::ReaderId::from_name(reader_name).slot_label();
+    cache
+        .readers
+        .settings
+        .iter()
+        .filter(|(name, _)| *name == reader_name || **name == label)
+        .find_map(|(_, reader)| reader.alias.clone())
+        .filter(|alias| !alias.trim().is_empty())
+}
+
+/// Retrieves the filter and the settings of the readers from the cache.
+///
+/// # Returns
+///
+/// * `ReadersConfig` - The settings, all the readers are allowed if they are not configured.
+pub fn get_readers_config() -> ReadersConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.readers.clone()
+}
+
+/// Retrieves the debouncing settings of the reader state changes from the cache.
+///
+/// # Returns
+///
+/// * `ReaderDebounceConfig` - The settings, or the default settings if they are not configured.
+pub fn get_reader_debounce_config() -> ReaderDebounceConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.reader_debounce.clone().unwrap_or_default()
+}
+
+/// Retrieves the settings of the periodic job from the cache.
+///
+/// # Arguments
+///
+/// * `name` - The name of the job.
 ///
 /// # Returns
 ///
-/// * `Result<ConfigurationFile, Box<dyn std::error::Error + Send + Sync>>` - The loaded configuration or an error.
-fn load_config(
-    config_path: &Path,
-) -> Result<ConfigurationFile, Box<dyn std::error::Error + Send + Sync>> {
-    let mut config_contents = String::new();
-    File::open(config_path)?.read_to_string(&mut config_contents)?;
-    let config: ConfigurationFile = serde_yaml::from_str(&config_contents)?;
-    Ok(config)
+/// * `Option<ScheduledJobConfig>` - The settings of the job, or `None` if the job is not configured.
+pub fn get_scheduled_job_config(name: &str) -> Option<ScheduledJobConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.scheduler.get(name).cloned()
 }
 
-/// Saves the configuration to the file.
-/// This function serializes the configuration and writes it to the file.
+/// Retrieves the settings of the automatic resync of the readers from the cache.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `config_path` - The path to the configuration file.
-/// * `config` - The configuration to save.
+/// * `AutoResyncConfig` - The settings, or the default settings if they are not configured.
+pub fn get_auto_resync_config() -> AutoResyncConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.auto_resync.clone().unwrap_or_default()
+}
+
+/// Retrieves the settings of powering off the idle cards from the cache.
 ///
 /// # Returns
 ///
-/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully saved, otherwise returns an error.
-fn save_config(
-    config_path: &Path,
-    config: &ConfigurationFile,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let yaml = serde_yaml::to_string(config)?;
-    File::create(config_path)?.write_all(yaml.as_bytes())?;
-    Ok(())
+/// * `PowerSavingConfig` - The settings, or the default settings (disabled) if they are not configured.
+pub fn get_power_saving_config() -> PowerSavingConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.power_saving.clone().unwrap_or_default()
 }
 
-/// Updates the configuration with a new card.
-/// This function updates the configuration file with a new card's ATR and card number.
+/// Retrieves the number of the kept backups of the configuration file from the cache.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `config_path` - The path to the configuration file.
-/// * `atr` - The ATR of the card.
-/// * `cardnumber` - The card number.
+/// * `BackupsConfig` - The settings, or the default settings if they are not configured.
+pub fn get_backups_config() -> BackupsConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.backups.clone().unwrap_or_default()
+}
+
+/// Retrieves the settings of the card expiry warnings from the cache.
 ///
 /// # Returns
 ///
-/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
-fn update_card_config(
-    config_path: &Path,
-    atr: &str,
-    cardnumber: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut config = load_config(config_path)?;
-
-    config
-        .cards
-        .get_or_insert_with(HashMap::new)
-        .insert(atr.to_string(), cardnumber.to_string());
+/// * `NotificationsConfig` - The settings, or the default settings if they are not configured.
+pub fn get_notifications_config() -> NotificationsConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.notifications.clone().unwrap_or_default()
+}
 
-    save_config(config_path, &config)?;
+/// Retrieves the limits of the APDU traces of the cards from the cache.
+///
+/// # Returns
+///
+/// * `ApduTraceConfig` - The limits, or the default limits (memory only) if they are not configured.
+pub fn get_apdu_trace_config() -> ApduTraceConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.apdu_trace.clone().unwrap_or_default()
+}
 
-    load_config_to_cache(config_path)?;
+/// Retrieves the settings of the check of the card certificates from the cache.
+///
+/// # Returns
+///
+/// * `CertificatesConfig` - The settings, or the default settings (enabled, without the root keys) if they are not configured.
+pub fn get_certificates_config() -> CertificatesConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.certificates.clone().unwrap_or_default()
+}
 
-    Ok(())
+/// Retrieves the proxy of the MQTT connections from the cache.
+///
+/// # Returns
+///
+/// * `Option<ProxyConfig>` - The proxy, or `None` if the connections are direct.
+pub fn get_proxy_config() -> Option<ProxyConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.proxy.clone()
 }
 
-/// Public function to update the configuration with a new card.
-/// This function is a Tauri command that updates the configuration file with a new card's ATR and card number.
+/// Retrieves the settings of the integrity check from the cache.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `atr` - The ATR of the card.
-/// * `cardnumber` - The card number.
+/// * `IntegrityConfig` - The settings, or the default settings (no check) if they are not configured.
+pub fn get_integrity_config() -> IntegrityConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.integrity.clone().unwrap_or_default()
+}
+
+/// Retrieves the staggering settings of the card connections from the cache.
 ///
 /// # Returns
 ///
-/// * `bool` - Returns `true` if the configuration was successfully updated, otherwise `false`.
-#[tauri::command]
-pub fn update_card(atr: &str, cardnumber: &str) -> bool {
-    let config_path = match get_config_path() {
-        Ok(path) => path,
-        Err(e) => {
-            log::error!("Failed to get config path: {}", e);
-            return false;
-        }
-    };
+/// * `ConnectionStaggerConfig` - The settings, or the default settings (no delays) if they are not configured.
+pub fn get_connection_stagger_config() -> ConnectionStaggerConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.connection_stagger.clone().unwrap_or_default()
+}
 
-    match update_card_config(&config_path, atr, cardnumber) {
-        Ok(_) => {
-            log::info!("The card, {} is added to the configuration! It is needed to restart the application to connect the card to the server. Automation will be implemented later.", cardnumber);
-            true
-        }
-        Err(e) => {
-            log::error!("Failed to update config: {}", e);
-            false
-        }
-    }
+/// Retrieves the settings of the MQTT sessions of the card clients from the cache.
+///
+/// # Returns
+///
+/// * `SessionConfig` - The settings, or the default settings (clean sessions) if they are not configured.
+pub fn get_session_config() -> SessionConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.session.clone().unwrap_or_default()
 }
 
-/// Updates the server address in the configuration.
-/// This function updates the configuration file with a new server address.
+/// Retrieves the topic templates of the card connections from the cache.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `config_path` - The path to the configuration file.
-/// * `server_address` - The new server address.
+/// * `Option<TopicsConfig>` - The templates, or `None` if the requests are routed by the broker (flespi).
+pub fn get_topics_config() -> Option<TopicsConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.topics.clone()
+}
+
+/// Retrieves the additional ATR patterns of the tachograph cards from the cache.
 ///
 /// # Returns
 ///
-/// * `Result<(), Box<dyn std::error::Error + Send + Sync>>` - Returns `Ok` if the configuration was successfully updated, otherwise returns an error.
-pub fn update_server_config(
-    config_path: &Path,
-    host: &str,
-    ident: &str,
-    theme: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut config = load_config(config_path)?;
+/// * `Vec<KnownAtrConfig>` - The patterns, empty if they are not configured.
+pub fn get_known_atr_patterns() -> Vec<KnownAtrConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.known_atrs.clone()
+}
 
-    config.server = Some(ServerConfig {
-        host: host.to_string(),
-    });
-    config.ident = Some(ident.to_string());
-    config.appearance = Some(AppearanceConfig {
-        dark_theme: match theme {
-            "Auto" => DarkTheme::Auto,
-            "Dark" => DarkTheme::Dark,
-            "Light" => DarkTheme::Light,
-            _ => DarkTheme::Auto,
-        },
-    });
+/// Retrieves the limits of the event stores from the cache.
+///
+/// # Returns
+///
+/// * `RetentionConfig` - The limits, or the default limits if they are not configured.
+pub fn get_retention_config() -> RetentionConfig {
+    let cache = CACHE.lock().unwrap();
+    cache.retention.clone().unwrap_or_default()
+}
 
-    save_config(config_path, &config)?;
+/// Retrieves the protocol mode from the cache.
+///
+/// # Returns
+///
+/// * `ProtocolMode` - The protocol mode, `Lenient` if it is not configured.
+pub fn get_protocol_mode() -> ProtocolMode {
+    let cache = CACHE.lock().unwrap();
+    cache.protocol.as_ref().map(|protocol| protocol.mode).unwrap_or_default()
+}
 
-    load_config_to_cache(config_path)?;
+/// Retrieves the inactivity timeout of the authentication sessions from the cache.
+///
+/// # Returns
+///
+/// * `Option<Duration>` - The timeout, or `None` if it is disabled.
+pub fn get_session_timeout() -> Option<Duration> {
+    let cache = CACHE.lock().unwrap();
+    let timeout_secs = cache
+        .protocol
+        .as_ref()
+        .map(|protocol| protocol.session_timeout_secs)
+        .unwrap_or_else(default_session_timeout_secs);
+    (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs))
+}
 
-    Ok(())
+/// Retrieves the connections of all accounts from the cache: the default one (`server` and `ident`) and the additional ones.
+///
+/// # Returns
+///
+/// * `Vec<(String, AccountConfig)>` - The account names with their connections, the default account goes first.
+pub fn get_accounts() -> Vec<(String, AccountConfig)> {
+    let cache = CACHE.lock().unwrap();
+    let mut accounts = vec![(DEFAULT_ACCOUNT.to_string(), default_account(&cache))];
+    let mut additional: Vec<(String, AccountConfig)> = cache
+        .accounts
+        .iter()
+        .filter(|(name, _)| name.as_str() != DEFAULT_ACCOUNT)
+        .map(|(name, account)| (name.clone(), account.clone()))
+        .collect();
+    additional.sort_by(|a, b| a.0.cmp(&b.0));
+    accounts.extend(additional);
+    accounts
 }
 
-/// Public function to update the server address in the configuration.
-/// This function is a Tauri command that updates the configuration file with a new server address.
+/// Retrieves the connection of the account the card belongs to.
 ///
 /// # Arguments
 ///
-/// * `server_address` - The new server address.
+/// * `cardnumber` - The company card number.
 ///
 /// # Returns
 ///
-/// * `bool` - Returns `true` if the configuration was successfully updated, otherwise `false`.
-#[tauri::command]
-pub fn update_server(host: &str, ident: &str, theme: &str) -> bool {
-    let config_path = match get_config_path() {
-        Ok(path) => path,
-        Err(e) => {
-            log::error!("Failed to get config path: {}", e);
-            return false;
-        }
-    };
-
-    match update_server_config(&config_path, host, ident, theme) {
-        Ok(_) => {
-            log::info!("The server address is updated to '{}'. It is needed to restart the application for the changes to take effect.", host);
-            true
-        }
-        Err(e) => {
-            log::error!("Failed to update server address: {}", e);
-            false
-        }
-    }
+/// * `AccountConfig` - The connection of the card account, or of the default account if the card is not assigned
+///   to an account or the account is not in the configuration.
+pub fn get_card_account(cardnumber: &str) -> AccountConfig {
+    let cache = CACHE.lock().unwrap();
+    resolve_card_account(&cache, cardnumber).1
 }
 
-/*
-  HashMap. ATR = Card number
-
-  initializing a global cache (HashMap<String, String>) using Mutex.
-  Mapping card keys and matching them with the real company card number,
-  which can only be entered manually
-*/
-#[derive(Default)]
-pub struct CacheConfigData {
-    pub cards: HashMap<String, String>,
-    pub server: Option<ServerConfig>,
-    pub ident: Option<String>,
-    pub appearance: Option<AppearanceConfig>,
+/// Retrieves the name of the account the card belongs to (see `get_card_account`).
+pub fn get_card_account_name(cardnumber: &str) -> String {
+    let cache = CACHE.lock().unwrap();
+    resolve_card_account(&cache, cardnumber).0
 }
 
-lazy_static! {
-    /// Global cache for card ATRs and numbers.
-    /// Initializing a global cache (HashMap<String, String>) using Mutex.
-    /// Mapping card keys and matching them with the real company card number,
-    /// which can only be entered manually.
-    static ref CACHE: Mutex<CacheConfigData> = Mutex::new(CacheConfigData::default());
+fn resolve_card_account(cache: &CacheConfigData, cardnumber: &str) -> (String, AccountConfig) {
+    let account_name = cache.cards.get(cardnumber).and_then(|card| card.account.clone());
+    match account_name {
+        Some(name) if name != DEFAULT_ACCOUNT => match cache.accounts.get(&name) {
+            Some(account) => (name, account.clone()),
+            None => {
+                log::warn!("The account '{}' of the card {} is not configured, the default server is used", name, cardnumber);
+                (DEFAULT_ACCOUNT.to_string(), default_account(cache))
+            }
+        },
+        _ => (DEFAULT_ACCOUNT.to_string(), default_account(cache)),
+    }
 }
 
-pub enum CacheSection {
-    Cards,
-    Server,
-    Ident,
-    Appearance
+fn default_account(cache: &CacheConfigData) -> AccountConfig {
+    AccountConfig {
+        host: cache.server.as_ref().map(|server| server.host.clone()).unwrap_or_default(),
+        ident: cache.ident.clone().unwrap_or_default(),
+        username: cache.server.as_ref().and_then(|server| server.username.clone()),
+        password: cache.server.as_ref().and_then(|server| server.password.clone()),
+        client_cert: cache.server.as_ref().and_then(|server| server.client_cert.clone()),
+        client_key: cache.server.as_ref().and_then(|server| server.client_key.clone()),
+        client_token: cache.server.as_ref().and_then(|server| server.client_token.clone()),
+        ca_cert: cache.server.as_ref().and_then(|server| server.ca_cert.clone()),
+        insecure_skip_hostname_verification: cache
+            .server
+            .as_ref()
+            .map(|server| server.insecure_skip_hostname_verification)
+            .unwrap_or_default(),
+        mqtt_version: cache.server.as_ref().map(|server| server.mqtt_version).unwrap_or_default(),
+        allowed_redirect_hosts: cache
+            .server
+            .as_ref()
+            .map(|server| server.allowed_redirect_hosts.clone())
+            .unwrap_or_default(),
+        tuning: cache.server.as_ref().map(|server| server.tuning.clone()).unwrap_or_default(),
+    }
 }
 
-/// Retrieves a value from the cache by key.
-/// This function locks the cache, retrieves the value for the given key, and returns it.
+/// Retrieves the LAN broadcast settings from the cache.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `key` - The key to search in the cache.
+/// * `Option<BroadcastConfig>` - The broadcast settings, or `None` if the broadcast is not configured.
+pub fn get_broadcast_config() -> Option<BroadcastConfig> {
+    let cache = CACHE.lock().unwrap();
+    cache.broadcast.clone()
+}
+
+/// Retrieves the security log settings from the cache.
 ///
 /// # Returns
 ///
-/// * `String` - The value associated with the key, or an empty string if the key is not found.
-pub fn get_from_cache(section: CacheSection, key: &str) -> String {
+/// * `SecurityLogConfig` - The security log settings, or the default settings if they are not configured.
+pub fn get_security_log_config() -> SecurityLogConfig {
     let cache = CACHE.lock().unwrap();
-    match section {
-        CacheSection::Cards => match cache.cards.get(key) {
-            Some(value) => value.clone(),
-            None => "".to_string(),
-        },
-        CacheSection::Server => {
-            if let Some(server) = &cache.server {
-                match key {
-                    "host" => server.host.clone(),
-                    _ => "".to_string(),
-                }
-            } else {
-                "".to_string()
-            }
-        }
-        CacheSection::Ident => {
-            if let Some(ident) = &cache.ident {
-                ident.clone()
-            } else {
-                "".to_string()
-            }
-        }
-        CacheSection::Appearance => {
-            if let Some(appearance) = &cache.appearance {
-                match key {
-                    "dark_theme" => format!("{:?}", appearance.dark_theme),
-                    _ => "".to_string(),
-                }
-            } else {
-                "".to_string()
-            }
-        }
-    }
+    cache.security_log.clone().unwrap_or_default()
 }
 
 /// Splits a host string into host and port components.
@@ -374,10 +2751,8 @@ pub fn split_host_to_parts(host: &str) -> Result<(String, u16), String> {
 pub fn load_config_to_cache(
     config_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut file: File = File::open(config_path)?;
     log::debug!("load_config_to_cache");
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+    let contents = read_config_file(config_path)?;
 
     let config: ConfigurationFile = serde_yaml::from_str(&contents)?;
 
@@ -387,9 +2762,45 @@ pub fn load_config_to_cache(
         server: config.server,
         ident: config.ident,
         appearance: config.appearance,
+        broadcast: config.broadcast,
+        security_log: config.security_log,
+        readers: config.readers.unwrap_or_default(),
+        retention: config.retention,
+        protocol: config.protocol,
+        accounts: config.accounts.unwrap_or_default(),
+        reader_debounce: config.reader_debounce,
+        scheduler: config.scheduler.unwrap_or_default(),
+        auto_resync: config.auto_resync,
+        power_saving: config.power_saving,
+        proxy: config.proxy,
+        connection_stagger: config.connection_stagger,
+        session: config.session,
+        topics: config.topics,
+        integrity: config.integrity,
+        apdu_trace: config.apdu_trace,
+        certificates: config.certificates,
+        backups: config.backups,
+        notifications: config.notifications,
+        known_atrs: config.known_atrs.unwrap_or_default(),
     };
 
-    trace_cache(&*cache);
+    cache.readers.compile_virtual_patterns();
+    trace_cache(&cache);
+    let cards = cache.cards.clone();
+    let broadcast = cache.broadcast.clone();
+    drop(cache);
+    *LOADED_CONFIG_DIGEST.lock().unwrap() = Some(Sha256::digest(contents.as_bytes()).to_vec());
+
+    // The running card tasks get the new settings
+    notify_card_config_watchers(&cards);
+    // The broadcast is restarted with the new settings
+    BROADCAST_CONFIG_WATCHER.send_if_modified(|config| {
+        if *config == broadcast {
+            return false;
+        }
+        *config = broadcast;
+        true
+    });
 
     Ok(())
 }
@@ -398,8 +2809,8 @@ pub fn load_config_to_cache(
 /// This function prints cache in a table format for debugging and inspection.
 pub fn trace_cache(cache: &CacheConfigData) {
     log::debug!("HashMap value correspondence table ATR: Company card number ----------");
-    for (key, value) in cache.cards.iter() {
-        log::debug!("{:<16}: {:<20}", value, key);
+    for (number, card) in cache.cards.iter() {
+        log::debug!("{:<16}: {:<20} absent card: {:?}", number, card.atr, card.absent_card);
     }
     log::debug!("{}", "-".repeat(70));
     if let Some(ident) = &cache.ident {
@@ -407,14 +2818,23 @@ pub fn trace_cache(cache: &CacheConfigData) {
     }
     if let Some(server) = &cache.server {
         log::info!("Server Host: {}", server.host);
+        if let Some(username) = &server.username {
+            log::info!("Server Username: {}", username);
+        }
     } else {
         log::info!("No server configuration found.");
     }
+    for (name, account) in cache.accounts.iter() {
+        log::info!("Account '{}': host: {}, ident: {}", name, account.host, account.ident);
+    }
     if let Some(appearance) = &cache.appearance {
         log::info!("Appearance: {:?}", appearance);
     } else {
         log::info!("No appearance configuration found.");
     }
+    if let Some(broadcast) = &cache.broadcast {
+        log::info!("Broadcast: enabled: {}, address: {}", broadcast.enabled, broadcast.address);
+    }
 }
 
 /// Initializes the configuration file.
@@ -430,8 +2850,7 @@ pub fn init_config() -> io::Result<()> {
     if Path::new(&config_path).exists() {
         log::debug!("config: path exists");
         // Load existing configuration
-        let mut config_contents = String::new();
-        File::open(&config_path)?.read_to_string(&mut config_contents)?;
+        let config_contents = read_config_file(&config_path)?;
 
         let mut config: ConfigurationFile = serde_yaml::from_str(&config_contents)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
@@ -442,7 +2861,7 @@ pub fn init_config() -> io::Result<()> {
         // Save the updated configuration
         let yaml =
             serde_yaml::to_string(&config).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        File::create(&config_path)?.write_all(yaml.as_bytes())?;
+        write_config_file(&config_path, &yaml)?;
 
         // Load updated config to cache
         load_config_to_cache(&config_path).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
@@ -463,28 +2882,216 @@ pub fn init_config() -> io::Result<()> {
         ident: Some("".to_string()),
         server: None,
         cards: None,
+        broadcast: None,
+        security_log: None,
+        readers: None,
+        retention: None,
+        protocol: None,
+        accounts: None,
+        reader_debounce: None,
+        scheduler: None,
+        auto_resync: None,
+        power_saving: None,
+        proxy: None,
+        connection_stagger: None,
+        session: None,
+        topics: None,
+        integrity: None,
+        known_atrs: None,
+        apdu_trace: None,
+        certificates: None,
+        backups: None,
+        notifications: None,
     };
 
     log::debug!("config: default config created");
 
     let yaml = serde_yaml::to_string(&config).unwrap();
 
-    let mut file = File::create(config_path)?;
-    file.write_all(yaml.as_bytes())?;
+    write_config_file(&config_path, &yaml)?;
 
     log::debug!("config: default config saved");
 
     Ok(())
 }
 
+/// Watches the configuration file for the changes made outside of the application (e.g. by the administrator
+/// in the text editor) and applies them without the restart. The file is checked every `CONFIG_WATCH_INTERVAL_SECS`,
+/// the file saved by the application itself is not reloaded. The invalid file is reported and the previous
+/// settings are kept until the file is fixed.
+pub async fn watch_config_file() {
+    let config_path = match get_config_path() {
+        Ok(config_path) => config_path,
+        Err(e) => {
+            log::error!("The configuration file is not watched: {}", e);
+            return;
+        }
+    };
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let mut last_modified = modified(&config_path);
+    let mut interval = tokio::time::interval(Duration::from_secs(CONFIG_WATCH_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let current_modified = modified(&config_path);
+        if current_modified == last_modified {
+            continue;
+        }
+        last_modified = current_modified;
+
+        let contents = match read_config_file(&config_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::warn!("Failed to read the changed configuration file: {}", e);
+                continue;
+            }
+        };
+        let digest = Sha256::digest(contents.as_bytes()).to_vec();
+        if LOADED_CONFIG_DIGEST.lock().unwrap().as_ref() == Some(&digest) {
+            continue;
+        }
+
+        log::info!("The configuration file is changed outside of the application, reloading it");
+        let old_cards = CACHE.lock().unwrap().cards.clone();
+        // The reload is serialized with the changes made by the application (see `config_writer`)
+        match config_writer::apply(ConfigMutation::ReloadConfig).await {
+            Ok(_) => apply_reloaded_config(old_cards).await,
+            Err(e) => {
+                log::error!("Failed to reload the configuration file, the previous settings are kept: {}", e);
+                crate::global_app_handle::emit_notification(
+                    "error",
+                    &format!("The changed configuration file can't be loaded, the previous settings are kept: {}", e),
+                );
+            }
+        }
+    }
+}
+
+/// Applies the reloaded configuration to the running connections and the frontend.
+///
+/// The connections of the removed cards and of the cards paired with another ATR are torn down, the new cards are
+/// connected by the resync of the readers. The changed server settings re-establish the connections of the account
+/// (see `app_connect::apply_account_changes`), the changed settings of the card are applied by its running task.
+async fn apply_reloaded_config(old_cards: HashMap<String, CardConfig>) {
+    let cards = CACHE.lock().unwrap().cards.clone();
+    let removed: Vec<String> = old_cards
+        .iter()
+        .filter(|(cardnumber, old_card)| cards.get(*cardnumber).map_or(true, |card| card.atr != old_card.atr))
+        .map(|(cardnumber, _)| cardnumber.clone())
+        .collect();
+    let added = cards
+        .iter()
+        .any(|(cardnumber, card)| old_cards.get(cardnumber).map_or(true, |old_card| old_card.atr != card.atr));
+
+    let any_removed = !removed.is_empty();
+    if any_removed {
+        log::info!("The cards are removed or paired with another ATR in the configuration file: {:?}", removed);
+        crate::mqtt::remove_connections(removed).await;
+    }
+    crate::app_connect::apply_account_changes().await;
+    if added || any_removed {
+        // The errors of the PC/SC service are logged by the sync
+        let _ = crate::smart_card::manual_sync_cards().await;
+    }
+
+    emit_config_to_frontend();
+}
+
+/// Sends the changed server settings and the settings of the cards to the frontend.
+fn emit_config_to_frontend() {
+    if let Some(app) = crate::global_app_handle::get_app_handle() {
+        if let Err(e) = emit_global_config_server(&app) {
+            log::warn!("Failed to emit the server settings: {:?}", e);
+        }
+        if let Err(e) = emit_card_config_snapshot(&app) {
+            log::warn!("Failed to emit the card config snapshot: {:?}", e);
+        }
+    }
+}
+
+/// Value shown in place of the credentials returned to the frontend.
+const MASKED_SECRET: &str = "********";
+
+/// Public function to get the whole configuration at once, so the settings are rendered without waiting
+/// for the events. This function is a Tauri command that is called from the frontend.
+/// The credentials (the passwords and the usernames, which may be the flespi tokens) are masked.
+///
+/// # Returns
+///
+/// * `Result<ConfigurationFile, String>` - The configuration, or the error message if it can't be read.
+#[tauri::command]
+pub fn get_config() -> Result<ConfigurationFile, String> {
+    let config_path = get_config_path().map_err(|e| format!("Failed to get config path: {}", e))?;
+    let mut config = load_config(&config_path).map_err(|e| format!("Failed to read the configuration: {}", e))?;
+
+    let mask = |secret: &mut Option<String>| {
+        if secret.is_some() {
+            *secret = Some(MASKED_SECRET.to_string());
+        }
+    };
+    if let Some(server) = config.server.as_mut() {
+        mask(&mut server.username);
+        mask(&mut server.password);
+    }
+    for account in config.accounts.iter_mut().flat_map(|accounts| accounts.values_mut()) {
+        mask(&mut account.username);
+        mask(&mut account.password);
+    }
+    if let Some(proxy) = config.proxy.as_mut() {
+        mask(&mut proxy.password);
+    }
+    Ok(config)
+}
+
+/// Name of the event with the settings of all the cards.
+pub const CARD_CONFIG_SNAPSHOT_EVENT: &str = "global-card-config-snapshot";
+
+/// Settings of the card with its number, for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct CardConfigEntry {
+    pub card_number: String,
+    #[serde(flatten)]
+    pub config: CardConfig,
+}
+
+/// Public function to get the settings of all the cards, sorted by the card number.
+/// This function is a Tauri command that is called from the frontend to fill the list of the cards.
+///
+/// # Returns
+///
+/// * `Vec<CardConfigEntry>` - The cards with their settings.
+#[tauri::command]
+pub fn get_card_config_snapshot() -> Vec<CardConfigEntry> {
+    let mut cards: Vec<CardConfigEntry> = CACHE
+        .lock()
+        .unwrap()
+        .cards
+        .iter()
+        .map(|(card_number, config)| CardConfigEntry {
+            card_number: card_number.clone(),
+            config: config.clone(),
+        })
+        .collect();
+    cards.sort_by(|a, b| a.card_number.cmp(&b.card_number));
+    cards
+}
+
+/// Sends the settings of all the cards to the frontend in one event, after the frontend is loaded.
+/// With many cards one event per card would flood the frontend while it is starting.
+pub fn emit_card_config_snapshot(app: &tauri::AppHandle) -> Result<(), Box<dyn Error>> {
+    app.emit_all(CARD_CONFIG_SNAPSHOT_EVENT, get_card_config_snapshot())?;
+    Ok(())
+}
+
 pub fn emit_global_config_server(app: &tauri::AppHandle) -> Result<(), Box<dyn Error>> {
     // small note: the structure requires the clone trait because the configuration is passed by reference,
     // so the value cannot be fully transferred to ownership.
 
     // Gettting Host value from the "operation cahce" with the ServerConfig structure
-    let host = get_from_cache(CacheSection::Server, "host");
-    let ident = get_from_cache(CacheSection::Ident, "ident");
-    let appearance = get_from_cache(CacheSection::Appearance, "dark_theme");
+    let host = get_server_config().map(|server| server.host).unwrap_or_default();
+    let ident = get_ident().unwrap_or_default();
+    let appearance = get_appearance_config()
+        .map(|appearance| format!("{:?}", appearance.dark_theme))
+        .unwrap_or_default();
 
     let mut config_app_payload = HashMap::new();
     config_app_payload.insert("host", host);
@@ -0,0 +1,152 @@
+//! Module for the serialized changes of the configuration file.
+//!
+//! Every change of the configuration is a load-modify-save of the whole file, so two changes made at the same time
+//! (the card added from the UI while the server address is changed by a deep link) could lose one of them.
+//! All the changes are sent to the single writer task through a channel and applied one by one,
+//! and the result of every change is returned to the sender.
+
+use tokio::sync::{mpsc, oneshot};
+
+use lazy_static::lazy_static;
+
+use crate::config::{get_config_path, remove_card_config, set_card_expire_config, update_card_config, update_server_config};
+use crate::config::{load_config_to_cache, merge_config, replace_config, set_card_availability_config, CardAvailability};
+use crate::config::{configure_server_config, restore_config_backup_file, set_card_iccid_config};
+
+/// Change of the configuration file.
+#[derive(Debug, Clone)]
+pub enum ConfigMutation {
+    /// Pairs the card number with the ATR, sets the label and the notes of the card if they are given.
+    UpdateCard {
+        atr: String,
+        cardnumber: String,
+        label: Option<String>,
+        notes: Option<String>,
+        iccid: Option<String>,
+    },
+    /// Saves the ICCID read from the chip of the paired card.
+    SetCardIccid { cardnumber: String, iccid: String },
+    /// Removes the card from the configuration.
+    RemoveCard { cardnumber: String },
+    /// Sets the expiry date of the card read from the chip.
+    SetCardExpire { cardnumber: String, expire: String },
+    /// Pauses, disables or activates the card.
+    SetCardAvailability { cardnumber: String, availability: CardAvailability },
+    /// Replaces the whole configuration with the imported one.
+    ReplaceConfig { yaml: ConfigYaml },
+    /// Merges the imported configuration into the current one.
+    MergeConfig { yaml: ConfigYaml },
+    /// Changes the server address, the ident and the theme.
+    UpdateServer {
+        host: String,
+        ident: String,
+        theme: String,
+    },
+    /// Changes the server address and the flespi token from the deep link, the missing values are kept.
+    ConfigureServer { host: Option<String>, token: Option<ConfigSecret> },
+    /// Loads the configuration file changed outside of the application to the cache.
+    ReloadConfig,
+    /// Replaces the configuration with the backup.
+    RestoreBackup { name: String },
+}
+
+/// Contents of the configuration file. It has the credentials, so it is not written to the log with the mutation.
+#[derive(Clone)]
+pub struct ConfigYaml(pub String);
+
+impl std::fmt::Debug for ConfigYaml {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+/// Credential of the change, it is not written to the log with the mutation.
+#[derive(Clone)]
+pub struct ConfigSecret(pub String);
+
+impl std::fmt::Debug for ConfigSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<hidden>")
+    }
+}
+
+/// Change with the channel for its result.
+type WriteRequest = (ConfigMutation, oneshot::Sender<Result<(), String>>);
+
+lazy_static! {
+    /// Sender of the changes to the writer task. The task is started with the first change.
+    static ref WRITER: mpsc::UnboundedSender<WriteRequest> = {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tauri::async_runtime::spawn(run_writer(receiver));
+        sender
+    };
+}
+
+/// Applies the changes one by one, in the order they are sent.
+async fn run_writer(mut receiver: mpsc::UnboundedReceiver<WriteRequest>) {
+    while let Some((mutation, result_sender)) = receiver.recv().await {
+        // The file IO (with its retries of the locked file) is run off the async workers
+        let (mutation, result) = match tokio::task::spawn_blocking(move || {
+            let result = apply_mutation(&mutation);
+            (mutation, result)
+        })
+        .await
+        {
+            Ok(applied) => applied,
+            Err(e) => {
+                log::error!("The configuration change has failed: {}", e);
+                let _ = result_sender.send(Err(e.to_string()));
+                continue;
+            }
+        };
+        if let Err(e) = &result {
+            log::error!("Failed to apply the configuration change {:?}: {}", mutation, e);
+        }
+        // The sender may not wait for the result anymore, the change is applied anyway
+        let _ = result_sender.send(result);
+    }
+}
+
+fn apply_mutation(mutation: &ConfigMutation) -> Result<(), String> {
+    let config_path = get_config_path().map_err(|e| format!("Failed to get config path: {}", e))?;
+
+    let result = match mutation {
+        ConfigMutation::UpdateCard {
+            atr,
+            cardnumber,
+            label,
+            notes,
+            iccid,
+        } => update_card_config(&config_path, atr, cardnumber, label.as_deref(), notes.as_deref(), iccid.as_deref()),
+        ConfigMutation::SetCardIccid { cardnumber, iccid } => set_card_iccid_config(&config_path, cardnumber, iccid),
+        ConfigMutation::RemoveCard { cardnumber } => remove_card_config(&config_path, cardnumber),
+        ConfigMutation::SetCardExpire { cardnumber, expire } => set_card_expire_config(&config_path, cardnumber, expire),
+        ConfigMutation::SetCardAvailability { cardnumber, availability } => {
+            set_card_availability_config(&config_path, cardnumber, *availability)
+        }
+        ConfigMutation::ReplaceConfig { yaml } => replace_config(&config_path, &yaml.0),
+        ConfigMutation::MergeConfig { yaml } => merge_config(&config_path, &yaml.0),
+        ConfigMutation::UpdateServer { host, ident, theme } => update_server_config(&config_path, host, ident, theme),
+        ConfigMutation::ConfigureServer { host, token } => {
+            configure_server_config(&config_path, host.as_deref(), token.as_ref().map(|token| token.0.as_str()))
+        }
+        ConfigMutation::ReloadConfig => load_config_to_cache(&config_path),
+        ConfigMutation::RestoreBackup { name } => restore_config_backup_file(&config_path, name),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+/// Sends the change to the writer task and waits until it is applied.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The result of this change.
+pub async fn apply(mutation: ConfigMutation) -> Result<(), String> {
+    let (result_sender, result_receiver) = oneshot::channel();
+    WRITER
+        .send((mutation, result_sender))
+        .map_err(|_| "The configuration writer is stopped".to_string())?;
+    result_receiver
+        .await
+        .map_err(|_| "The configuration writer is stopped".to_string())?
+}
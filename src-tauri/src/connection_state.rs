@@ -0,0 +1,168 @@
+//! Module for the live states of the connections to the broker.
+//!
+//! The card states of the `global-cards-sync` event only tell if the card is online, and the application
+//! connection is not reported at all. Every connection reports its transitions here (connecting, connected,
+//! degraded, reconnecting, offline with the reason), they are kept as the latest state per connection and streamed
+//! through the broadcast channel: to the windows which have called `watch_connection_state` with the
+//! `connection-state` event, and to the local APIs with `subscribe`, so the indicators don't poll.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::global_app_handle::StateReason;
+use crate::timestamp::Timestamp;
+
+/// Name of the event with the transition of the connection state.
+pub const CONNECTION_STATE_EVENT: &str = "connection-state";
+/// Number of the transitions kept for the slow watchers, the older ones are skipped.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Connection the state belongs to.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionKind {
+    /// Connection of the card, by the card number.
+    Card,
+    /// Application connection of the account, by the ident.
+    App,
+}
+
+/// State of the connection.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionPhase {
+    /// The first connection is being established.
+    Connecting,
+    Connected,
+    /// Connected, but the link is constrained (see `link_quality`).
+    Degraded,
+    /// The connection is lost and is being established again.
+    Reconnecting,
+    /// The connection can't be established, see the reason, or it is closed.
+    Offline,
+}
+
+/// Transition of the connection state.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionStateChange {
+    pub kind: ConnectionKind,
+    /// The card number or the ident of the account.
+    pub id: String,
+    pub state: ConnectionPhase,
+    pub reason: Option<StateReason>,
+    pub detail: Option<String>,
+    pub updated_at: Timestamp,
+}
+
+lazy_static! {
+    /// The latest state of every connection.
+    static ref STATES: Mutex<BTreeMap<(ConnectionKind, String), ConnectionStateChange>> = Mutex::new(BTreeMap::new());
+    static ref CHANNEL: broadcast::Sender<ConnectionStateChange> = broadcast::channel(CHANNEL_CAPACITY).0;
+    /// Windows the transitions are streamed to, by the window label.
+    static ref WATCHERS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Reports the state of the connection. The same state with the same reason is not reported again.
+///
+/// # Arguments
+///
+/// * `kind` - The card or the application connection.
+/// * `id` - The card number or the ident of the account.
+/// * `state` - The new state.
+/// * `reason` - Why the connection is not established, `None` if it is established.
+/// * `detail` - Description of the reason, e.g. the error of the connection.
+pub fn report(kind: ConnectionKind, id: &str, state: ConnectionPhase, reason: Option<StateReason>, detail: Option<String>) {
+    let mut states = STATES.lock().unwrap();
+    let key = (kind, id.to_string());
+    if states.get(&key).map_or(false, |last| last.state == state && last.reason == reason) {
+        return;
+    }
+    let change = ConnectionStateChange {
+        kind,
+        id: id.to_string(),
+        state,
+        reason,
+        detail,
+        updated_at: Timestamp::now(),
+    };
+    log::debug!("{} | The connection is {:?}", id, state);
+    states.insert(key, change.clone());
+    // There may be no watchers
+    let _ = CHANNEL.send(change);
+}
+
+/// State of the established connection by the quality of the link.
+pub fn link_phase() -> ConnectionPhase {
+    if crate::link_quality::is_constrained() {
+        ConnectionPhase::Degraded
+    } else {
+        ConnectionPhase::Connected
+    }
+}
+
+/// State of the lost connection: it is reconnected if it has been established and the broker is just unreachable,
+/// otherwise it is offline until the reason is resolved.
+pub fn lost_phase(has_connected: bool, reason: StateReason) -> ConnectionPhase {
+    if has_connected && reason == StateReason::BrokerUnreachable {
+        ConnectionPhase::Reconnecting
+    } else {
+        ConnectionPhase::Offline
+    }
+}
+
+/// Reports the closed connection and forgets it.
+pub fn remove(kind: ConnectionKind, id: &str) {
+    report(kind, id, ConnectionPhase::Offline, None, None);
+    STATES.lock().unwrap().remove(&(kind, id.to_string()));
+}
+
+/// Returns the current states of the connections.
+pub fn snapshot() -> Vec<ConnectionStateChange> {
+    STATES.lock().unwrap().values().cloned().collect()
+}
+
+/// Subscribes to the transitions of the connection states, for the local APIs.
+pub fn subscribe() -> broadcast::Receiver<ConnectionStateChange> {
+    CHANNEL.subscribe()
+}
+
+/// Public function to watch the states of the connections.
+/// This function is a Tauri command that is called from the frontend to render the live indicators: the transitions
+/// are sent to the calling window with the `connection-state` event until the window is closed.
+///
+/// # Returns
+///
+/// * `Vec<ConnectionStateChange>` - The current states of the connections.
+#[tauri::command]
+pub fn watch_connection_state(window: tauri::Window) -> Vec<ConnectionStateChange> {
+    // The receiver is created before the snapshot, so no transition is lost in between
+    let receiver = subscribe();
+    let snapshot = snapshot();
+    if WATCHERS.lock().unwrap().insert(window.label().to_string()) {
+        tauri::async_runtime::spawn(forward(window, receiver));
+    }
+    snapshot
+}
+
+/// Sends the transitions to the window until it is closed.
+async fn forward(window: tauri::Window, mut receiver: broadcast::Receiver<ConnectionStateChange>) {
+    loop {
+        match receiver.recv().await {
+            Ok(change) => {
+                if let Err(e) = window.emit(CONNECTION_STATE_EVENT, change) {
+                    log::debug!("The connection states are not sent to the window {}: {}", window.label(), e);
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::debug!("{} connection state transition(s) are skipped for the window {}", skipped, window.label());
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    WATCHERS.lock().unwrap().remove(window.label());
+}
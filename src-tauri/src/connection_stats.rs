@@ -0,0 +1,114 @@
+//! Module for the statistics of the card connections.
+//!
+//! Every card connection counts its connections to the broker, the reconnects, the processed APDU commands
+//! with their average round-trip to the card, and keeps the last error, so the frontend can show the health
+//! of the cards in one table. The statistics are kept since the start of the application, also for the cards
+//! which are removed, and they are sent to the frontend periodically with the `global-connection-stats` event.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::global_app_handle::{emit_event_of_kind, EventKind};
+use crate::timestamp::Timestamp;
+
+/// Name of the event with the statistics of the connections.
+pub const CONNECTION_STATS_EVENT: &str = "global-connection-stats";
+/// Default interval of the job which sends the statistics to the frontend.
+pub const CONNECTION_STATS_INTERVAL_SECS: u64 = 30;
+
+/// Statistics of the card connection.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConnectionStats {
+    /// Number of the established connections to the broker, including the reconnects.
+    pub connects: u32,
+    pub reconnects: u32,
+    pub last_connected_at: Option<Timestamp>,
+    /// Number of the APDU commands sent to the card.
+    pub apdus: u64,
+    /// Average round-trip of the APDU commands to the card, in milliseconds.
+    pub average_apdu_ms: Option<f64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<Timestamp>,
+    /// Total round-trip of the APDU commands, for the average.
+    #[serde(skip)]
+    apdu_total: Duration,
+}
+
+lazy_static! {
+    /// Statistics by the card number.
+    static ref STATS: Mutex<BTreeMap<String, ConnectionStats>> = Mutex::new(BTreeMap::new());
+}
+
+fn update(cardnumber: &str, update: impl FnOnce(&mut ConnectionStats)) {
+    update(STATS.lock().unwrap().entry(cardnumber.to_string()).or_default());
+}
+
+/// Counts the established connection of the card.
+///
+/// # Arguments
+///
+/// * `reconnect` - Whether the card has been connected before.
+pub fn record_connect(cardnumber: &str, reconnect: bool) {
+    update(cardnumber, |stats| {
+        stats.connects += 1;
+        if reconnect {
+            stats.reconnects += 1;
+        }
+        stats.last_connected_at = Some(Timestamp::now());
+    });
+}
+
+/// Counts the APDU command sent to the card with its round-trip.
+pub fn record_apdu(cardnumber: &str, roundtrip: Duration) {
+    update(cardnumber, |stats| {
+        stats.apdus += 1;
+        stats.apdu_total += roundtrip;
+        stats.average_apdu_ms = Some(stats.apdu_total.as_secs_f64() * 1000.0 / stats.apdus as f64);
+    });
+}
+
+/// Keeps the last error of the card connection.
+pub fn record_error(cardnumber: &str, error: &str) {
+    update(cardnumber, |stats| {
+        stats.last_error = Some(error.to_string());
+        stats.last_error_at = Some(Timestamp::now());
+    });
+}
+
+/// Restores the statistics brought from another machine (see `migration`), the cards which already have
+/// the statistics on this machine are kept.
+pub fn restore(imported: BTreeMap<String, ConnectionStats>) {
+    let mut stats = STATS.lock().unwrap();
+    for (cardnumber, mut imported) in imported {
+        // The total is not exported, the average is kept by restoring it
+        let average_ms = imported.average_apdu_ms.unwrap_or_default();
+        imported.apdu_total = Duration::from_secs_f64(average_ms * imported.apdus as f64 / 1000.0);
+        stats.entry(cardnumber).or_insert(imported);
+    }
+}
+
+/// Sends the statistics to the frontend. Run by the scheduler.
+pub fn emit_stats() {
+    let stats = STATS.lock().unwrap().clone();
+    if stats.is_empty() {
+        return;
+    }
+    if let Err(e) = emit_event_of_kind(EventKind::Stats, CONNECTION_STATS_EVENT, stats) {
+        log::debug!("Failed to emit the connection statistics: {}", e);
+    }
+}
+
+/// Public function to get the statistics of the card connections.
+/// This function is a Tauri command that is called from the frontend to show the health of the cards.
+///
+/// # Returns
+///
+/// * `BTreeMap<String, ConnectionStats>` - The statistics by the card number.
+#[tauri::command]
+pub fn get_connection_stats() -> BTreeMap<String, ConnectionStats> {
+    STATS.lock().unwrap().clone()
+}
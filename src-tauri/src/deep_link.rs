@@ -0,0 +1,354 @@
+//! Module for handling the `tba://` deep links.
+//!
+//! The fleet platform's web UI can open links like `tba://configure?host=…&token=…` or `tba://resync?card=…`.
+//! The operating system starts the application with the link as a command line argument. If the application
+//! is already running, the started instance forwards the link to the running one and exits
+//! (see `handle_forwarded_args`). The action from the link is never performed silently: it is sent to the frontend
+//! and executed only after the user confirms it with the `confirm_deep_link` command.
+
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tauri::Manager;
+use url::Url;
+
+use crate::config_writer::{self, ConfigMutation, ConfigSecret};
+use crate::security_log::SecurityEvent;
+
+/// URL scheme of the application deep links.
+pub const URL_SCHEME: &str = "tba";
+
+/// Action requested by the deep link.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum DeepLinkAction {
+    /// Update the server configuration. Missing values are kept as they are.
+    Configure {
+        host: Option<String>,
+        /// The flespi token of the connection, it is not sent to the frontend.
+        #[serde(skip_serializing)]
+        token: Option<String>,
+    },
+    /// Restart the connection of the card (or of all cards if the card is not specified).
+    Resync { card: Option<String> },
+}
+
+impl DeepLinkAction {
+    /// Human readable description of the action for the confirmation dialog.
+    pub fn description(&self) -> String {
+        match self {
+            DeepLinkAction::Configure { host, token } => {
+                format!("Change the server configuration: {}", configure_details(host, token))
+            }
+            DeepLinkAction::Resync { card: Some(card) } => format!("Resync the card {}", card),
+            DeepLinkAction::Resync { card: None } => "Resync all cards".to_string(),
+        }
+    }
+}
+
+lazy_static! {
+    /// The deep link action waiting for the user confirmation.
+    static ref PENDING_ACTION: Mutex<Option<DeepLinkAction>> = Mutex::new(None);
+}
+
+/// Parses the deep link into the action.
+///
+/// # Arguments
+///
+/// * `link` - The link in the format `tba://<action>?<parameters>`.
+///
+/// # Returns
+///
+/// * `Result<DeepLinkAction, String>` - The parsed action or the error message.
+pub fn parse_deep_link(link: &str) -> Result<DeepLinkAction, String> {
+    let url = Url::parse(link).map_err(|e| format!("Invalid deep link: {}", e))?;
+    if url.scheme() != URL_SCHEME {
+        return Err(format!("Unsupported deep link scheme: {}", url.scheme()));
+    }
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .filter(|value| !value.is_empty())
+    };
+
+    match url.host_str() {
+        Some("configure") => Ok(DeepLinkAction::Configure {
+            host: param("host"),
+            token: param("token"),
+        }),
+        Some("resync") => Ok(DeepLinkAction::Resync { card: param("card") }),
+        other => Err(format!("Unknown deep link action: {:?}", other)),
+    }
+}
+
+/// Looks for the deep link in the command line arguments and keeps it until the user confirms it.
+pub fn handle_args() {
+    keep_link(std::env::args().skip(1));
+}
+
+/// Handles the command line of the instance started while the application is running: the started instance exits,
+/// and its deep link is confirmed in the running one.
+///
+/// # Arguments
+///
+/// * `app` - The handle of the running application.
+/// * `args` - The command line of the started instance, with the executable.
+pub fn handle_forwarded_args(app: &tauri::AppHandle, args: Vec<String>) {
+    // The running instance is brought to the front, so the user sees the confirmation
+    if let Some(window) = app.get_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+    if keep_link(args.into_iter().skip(1)) {
+        request_confirmation(app);
+    }
+}
+
+/// Keeps the deep link from the arguments until the user confirms it.
+///
+/// # Returns
+///
+/// * `bool` - Whether the arguments have the valid deep link.
+fn keep_link(mut args: impl Iterator<Item = String>) -> bool {
+    let prefix = format!("{}://", URL_SCHEME);
+    let link = match args.find(|arg| arg.starts_with(&prefix)) {
+        Some(link) => link,
+        None => return false,
+    };
+
+    match parse_deep_link(&link) {
+        Ok(action) => {
+            // The link may have the token, so only the description is logged
+            log::info!("Deep link is received: {}", action.description());
+            *PENDING_ACTION.lock().unwrap() = Some(action);
+            true
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            false
+        }
+    }
+}
+
+/// Sends the pending deep link action to the frontend to ask the user for confirmation.
+pub fn request_confirmation(app: &tauri::AppHandle) {
+    let action = match PENDING_ACTION.lock().unwrap().clone() {
+        Some(action) => action,
+        None => return,
+    };
+
+    let payload = serde_json::json!({
+        "description": action.description(),
+        "request": action,
+    });
+    if let Err(e) = app.emit_all("deep-link-request", payload) {
+        log::error!("Failed to send the deep link request to the frontend: {}", e);
+    }
+}
+
+/// Public function to confirm or reject the pending deep link action.
+/// This function is a Tauri command that is called from the confirmation dialog.
+///
+/// # Arguments
+///
+/// * `accepted` - `true` if the user has confirmed the action.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error if there is no action to confirm or the confirmed action has failed.
+#[tauri::command]
+pub async fn confirm_deep_link(accepted: bool) -> Result<(), String> {
+    let action = PENDING_ACTION
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| "There is no deep link action to confirm".to_string())?;
+
+    if !accepted {
+        log::info!("Deep link action is rejected by the user: {}", action.description());
+        return Ok(());
+    }
+
+    log::info!("Deep link action is confirmed by the user: {}", action.description());
+    match action {
+        DeepLinkAction::Configure { host, token } => {
+            if let Some(host) = &host {
+                crate::diagnostics::check_host_format(host)
+                    .map_err(|e| format!("Invalid server address '{}' in the deep link: {}", host, e))?;
+            }
+            let details = configure_details(&host, &token);
+            let mutation = ConfigMutation::ConfigureServer {
+                host,
+                token: token.map(ConfigSecret),
+            };
+            config_writer::apply(mutation).await.map_err(|e| {
+                log::error!("Failed to update server config from the deep link: {}", e);
+                e
+            })?;
+            crate::security_log::record(SecurityEvent::ConfigChange, None, "deep link", &details);
+            // The connections are re-established with the new server and token
+            crate::app_connect::apply_account_changes().await;
+            Ok(())
+        }
+        DeepLinkAction::Resync { card } => {
+            let cards = match card {
+                Some(card) => vec![card],
+                None => crate::smart_card::TASK_POOL
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|task| task.client_id.clone())
+                    .collect(),
+            };
+            crate::security_log::record(
+                SecurityEvent::RemoteCommand,
+                None,
+                "deep link",
+                &format!("resync cards: {:?}", cards),
+            );
+            crate::mqtt::remove_connections(cards).await;
+            crate::smart_card::manual_sync_cards().await
+        }
+    }
+}
+
+/// Details of the server configuration change, without the token itself.
+fn configure_details(host: &Option<String>, token: &Option<String>) -> String {
+    format!(
+        "host: {}, token: {}",
+        host.as_deref().unwrap_or("not changed"),
+        if token.is_some() { "replaced" } else { "not changed" }
+    )
+}
+
+/// Registers the `tba://` URL scheme for the current user, so the links open the application.
+///
+/// On macOS the links are delivered through Apple Events instead of the command line, which is not supported yet,
+/// so nothing is registered there.
+pub fn register_url_scheme() {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            log::warn!("Failed to get the executable path to register the URL scheme: {}", e);
+            return;
+        }
+    };
+
+    #[cfg(target_os = "windows")]
+    {
+        let key = format!("HKCU\\Software\\Classes\\{}", URL_SCHEME);
+        let command = format!("\"{}\" \"%1\"", exe.display());
+        // The registry is written only if the handler is missing or starts another executable
+        let registered = std::process::Command::new("reg")
+            .args(["query", &format!("{}\\shell\\open\\command", key), "/ve"])
+            .output()
+            .map(|output| output.status.success() && String::from_utf8_lossy(&output.stdout).contains(&command))
+            .unwrap_or(false);
+        if registered {
+            return;
+        }
+        let entries: [(String, Option<&str>, String); 3] = [
+            (key.clone(), None, "URL:Tacho Bridge Application".to_string()),
+            (key.clone(), Some("URL Protocol"), String::new()),
+            (format!("{}\\shell\\open\\command", key), None, command),
+        ];
+        for (path, name, value) in entries.iter() {
+            let mut reg = std::process::Command::new("reg");
+            reg.args(["add", path.as_str()]);
+            match name {
+                Some(name) => reg.args(["/v", *name]),
+                None => reg.arg("/ve"),
+            };
+            reg.args(["/d", value.as_str(), "/f"]);
+            if let Err(e) = reg.output() {
+                log::warn!("Failed to register the URL scheme: {}", e);
+                return;
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let home = match std::env::var("HOME") {
+            Ok(home) => home,
+            Err(_) => return,
+        };
+        let mut desktop_path = std::path::PathBuf::from(home);
+        desktop_path.push(".local/share/applications");
+        if let Err(e) = std::fs::create_dir_all(&desktop_path) {
+            log::warn!("Failed to create the applications directory: {}", e);
+            return;
+        }
+        desktop_path.push("tba-url-handler.desktop");
+
+        let desktop_entry = format!(
+            "[Desktop Entry]\nType=Application\nName=Tacho Bridge Application\nExec=\"{}\" %u\nNoDisplay=true\nMimeType=x-scheme-handler/{};\n",
+            exe.display(),
+            URL_SCHEME
+        );
+        // The handler is written only if it is missing or starts another executable
+        if std::fs::read_to_string(&desktop_path).map(|current| current == desktop_entry).unwrap_or(false) {
+            return;
+        }
+        if let Err(e) = std::fs::write(&desktop_path, desktop_entry) {
+            log::warn!("Failed to write the URL scheme handler: {}", e);
+            return;
+        }
+        let _ = std::process::Command::new("xdg-mime")
+            .args(["default", "tba-url-handler.desktop", &format!("x-scheme-handler/{}", URL_SCHEME)])
+            .output();
+    }
+
+    #[cfg(target_os = "macos")]
+    let _ = exe;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configure_link_is_parsed() {
+        assert_eq!(
+            parse_deep_link("tba://configure?host=mqtt.flespi.io%3A8883&token=abc%2B1"),
+            Ok(DeepLinkAction::Configure {
+                host: Some("mqtt.flespi.io:8883".to_string()),
+                token: Some("abc+1".to_string()),
+            })
+        );
+        // The missing and the empty values are kept as they are
+        assert_eq!(
+            parse_deep_link("tba://configure?token=&ident=old"),
+            Ok(DeepLinkAction::Configure { host: None, token: None })
+        );
+    }
+
+    #[test]
+    fn resync_link_is_parsed() {
+        assert_eq!(
+            parse_deep_link("tba://resync?card=RUD0000000000000"),
+            Ok(DeepLinkAction::Resync {
+                card: Some("RUD0000000000000".to_string())
+            })
+        );
+        assert_eq!(parse_deep_link("tba://resync"), Ok(DeepLinkAction::Resync { card: None }));
+    }
+
+    #[test]
+    fn invalid_links_are_rejected() {
+        assert!(parse_deep_link("https://configure?host=evil.com").is_err());
+        assert!(parse_deep_link("tba://format?disk=c").is_err());
+        assert!(parse_deep_link("not a link").is_err());
+    }
+
+    #[test]
+    fn token_is_not_described() {
+        let action = parse_deep_link("tba://configure?token=secret").unwrap();
+        assert!(!action.description().contains("secret"));
+        assert!(!serde_json::to_string(&action).unwrap().contains("secret"));
+    }
+}
@@ -0,0 +1,424 @@
+//! Module for the network diagnostics of the broker connection.
+//!
+//! The most common support case is "is it our network or your server?". The diagnostics run the steps of the
+//! connection one by one: DNS resolution of the broker, TCP connect, TLS handshake (when the client certificate
+//! is configured), MQTT CONNECT and the round-trip PING. Every step is timed and reported to the frontend
+//! as soon as it is finished, the steps after the failed one are skipped.
+//!
+//! The same steps validate the server address entered by the user before it is saved (see `validate_server`):
+//! the format, DNS, TCP and optionally MQTT CONNECT are checked with a shorter timeout, so a typo is reported
+//! right away instead of the silent reconnects of the saved server.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::config::{get_accounts, split_host_to_parts, AccountConfig, DEFAULT_ACCOUNT};
+use crate::global_app_handle::emit_global_event;
+use crate::mqtt_client::{create_client, MqttEvent, MqttOptions};
+
+/// Timeout of every step of the diagnostics.
+const STEP_TIMEOUT_SECS: u64 = 10;
+/// Timeout of every step of the validation of the server address, the user waits for it in the settings.
+const VALIDATION_STEP_TIMEOUT_SECS: u64 = 5;
+
+/// Keep-alive of the diagnostics connection, the PING is sent after it. The minimum allowed by the MQTT client.
+const PING_KEEP_ALIVE_SECS: u64 = 5;
+
+/// Event with the result of every step, so the frontend shows the progress.
+const DIAGNOSTICS_STEP_EVENT: &str = "network-diagnostics-step";
+
+/// Step of the diagnostics.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticsStep {
+    /// The format of the server address, checked only by the validation.
+    Format,
+    Dns,
+    Tcp,
+    Tls,
+    MqttConnect,
+    Ping,
+}
+
+/// Steps in the order they are run.
+const STEPS: [DiagnosticsStep; 6] = [
+    DiagnosticsStep::Format,
+    DiagnosticsStep::Dns,
+    DiagnosticsStep::Tcp,
+    DiagnosticsStep::Tls,
+    DiagnosticsStep::MqttConnect,
+    DiagnosticsStep::Ping,
+];
+
+/// Result of the step.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Ok,
+    Failed,
+    /// The step is not applicable (e.g. TLS without the client certificate) or the previous step has failed.
+    Skipped,
+}
+
+/// Report of the step of the diagnostics.
+#[derive(Serialize, Clone, Debug)]
+pub struct StepReport {
+    pub step: DiagnosticsStep,
+    pub status: StepStatus,
+    /// Duration of the step, `None` if it is skipped.
+    pub duration_ms: Option<u64>,
+    /// Result of the step for the user, e.g. the resolved addresses or the error.
+    pub detail: String,
+}
+
+/// Result of the validation of the server address.
+#[derive(Serialize, Clone, Debug)]
+pub struct ServerValidation {
+    /// All the checked steps have passed.
+    pub valid: bool,
+    /// The failed step, `None` if the server is valid.
+    pub failed_step: Option<DiagnosticsStep>,
+    /// Explanation of the result for the user, with the hint on what to check.
+    pub diagnosis: String,
+    /// Reports of the checked steps.
+    pub steps: Vec<StepReport>,
+}
+
+/// Reports of the steps.
+struct Diagnostics {
+    reports: Vec<StepReport>,
+    /// Timeout of every step.
+    step_timeout: Duration,
+    /// The last step run, the validation of the server address doesn't wait for the PING.
+    last_step: DiagnosticsStep,
+    /// Every report is sent to the frontend as soon as the step is finished.
+    emit_steps: bool,
+}
+
+impl Diagnostics {
+    fn report(&mut self, step: DiagnosticsStep, status: StepStatus, duration: Option<Duration>, detail: String) {
+        let report = StepReport {
+            step,
+            status,
+            duration_ms: duration.map(|duration| duration.as_millis() as u64),
+            detail,
+        };
+        log::info!("Network diagnostics: {:?}", report);
+        if self.emit_steps {
+            if let Err(e) = emit_global_event(DIAGNOSTICS_STEP_EVENT, report.clone()) {
+                log::warn!("Failed to emit the network diagnostics step: {}", e);
+            }
+        }
+        self.reports.push(report);
+    }
+
+    /// Whether the step is run, i.e. it is not after the last step.
+    fn includes(&self, step: DiagnosticsStep) -> bool {
+        let position = |step| STEPS.iter().position(|s| *s == step);
+        position(step) <= position(self.last_step)
+    }
+
+    fn ok(&mut self, step: DiagnosticsStep, started: Instant, detail: String) {
+        self.report(step, StepStatus::Ok, Some(started.elapsed()), detail);
+    }
+
+    fn failed(&mut self, step: DiagnosticsStep, started: Instant, detail: String) {
+        self.report(step, StepStatus::Failed, Some(started.elapsed()), detail);
+    }
+
+    /// Skips the remaining steps after the failed one.
+    fn skip_from(&mut self, step: DiagnosticsStep) {
+        for step in STEPS.iter().skip_while(|s| **s != step) {
+            if self.includes(*step) {
+                self.report(*step, StepStatus::Skipped, None, "The previous step has failed".to_string());
+            }
+        }
+    }
+}
+
+/// Public function to run the network diagnostics of the broker connection.
+/// This function is a Tauri command that is called from the frontend, the steps are also sent
+/// with the `network-diagnostics-step` event as soon as they are finished.
+///
+/// # Arguments
+///
+/// * `account` - The name of the account, the default account if it is not set.
+///
+/// # Returns
+///
+/// * `Result<Vec<StepReport>, String>` - The reports of all the steps, or the error if the account can't be diagnosed.
+#[tauri::command]
+pub async fn run_network_diagnostics(account: Option<String>) -> Result<Vec<StepReport>, String> {
+    let account_name = account.unwrap_or_else(|| DEFAULT_ACCOUNT.to_string());
+    let account = get_accounts()
+        .into_iter()
+        .find(|(name, _)| *name == account_name)
+        .map(|(_, account)| account)
+        .ok_or_else(|| format!("The account '{}' is not configured", account_name))?;
+    let (host, port) = split_host_to_parts(&account.host)?;
+    log::info!("Network diagnostics of {}:{} (account '{}')", host, port, account_name);
+
+    let mut diagnostics = Diagnostics {
+        reports: Vec::new(),
+        step_timeout: Duration::from_secs(STEP_TIMEOUT_SECS),
+        last_step: DiagnosticsStep::Ping,
+        emit_steps: true,
+    };
+    run_steps(&mut diagnostics, &account, &host, port).await;
+    Ok(diagnostics.reports)
+}
+
+/// Public function to validate the server address before it is saved.
+/// This function is a Tauri command that is called from the server settings of the frontend.
+///
+/// The address is checked for the 'host:port' format, resolved and connected to. The MQTT CONNECT uses
+/// the credentials and the TLS settings of the default account with the new address.
+///
+/// # Arguments
+///
+/// * `host` - The server address, 'host:port'.
+/// * `mqtt_connect` - The MQTT CONNECT is also checked, only the TCP connection if it is not set.
+///
+/// # Returns
+///
+/// * `ServerValidation` - The result of the validation with the reports of the checked steps.
+#[tauri::command]
+pub async fn validate_server(host: String, mqtt_connect: Option<bool>) -> ServerValidation {
+    let last_step = if mqtt_connect.unwrap_or(false) {
+        DiagnosticsStep::MqttConnect
+    } else {
+        DiagnosticsStep::Tcp
+    };
+    let mut diagnostics = Diagnostics {
+        reports: Vec::new(),
+        step_timeout: Duration::from_secs(VALIDATION_STEP_TIMEOUT_SECS),
+        last_step,
+        emit_steps: false,
+    };
+    log::info!("Validation of the server address '{}'", host);
+
+    let started = Instant::now();
+    match check_host_format(&host) {
+        Ok((name, port)) => {
+            diagnostics.ok(DiagnosticsStep::Format, started, format!("Host {}, port {}", name, port));
+            // The default account goes first
+            let (_, mut account) = get_accounts().swap_remove(0);
+            account.host = format!("{}:{}", name, port);
+            run_steps(&mut diagnostics, &account, &name, port).await;
+        }
+        Err(e) => {
+            diagnostics.failed(DiagnosticsStep::Format, started, e);
+            diagnostics.skip_from(DiagnosticsStep::Dns);
+        }
+    }
+
+    let failed = diagnostics.reports.iter().find(|report| report.status == StepStatus::Failed);
+    let failed_step = failed.map(|report| report.step);
+    let diagnosis = match failed {
+        None if last_step == DiagnosticsStep::MqttConnect => "The server accepts the MQTT connection".to_string(),
+        None => "The server accepts the connections".to_string(),
+        Some(report) => {
+            let hint = match report.step {
+                DiagnosticsStep::Format => "Enter the address as 'host:port', e.g. 'mqtt.flespi.io:8883'",
+                DiagnosticsStep::Dns => "Check the host name for typos and the DNS settings of the network",
+                DiagnosticsStep::Tcp => "Check the port and that the firewall allows the connections to it",
+                DiagnosticsStep::Tls => "Check the certificates of the account and that the port is the TLS one",
+                DiagnosticsStep::MqttConnect | DiagnosticsStep::Ping => "Check the credentials and the MQTT version of the account",
+            };
+            format!("{}. {}", report.detail, hint)
+        }
+    };
+    ServerValidation {
+        valid: failed_step.is_none(),
+        failed_step,
+        diagnosis,
+        steps: diagnostics.reports,
+    }
+}
+
+/// Checks the format of the server address.
+///
+/// # Returns
+///
+/// * `Result<(String, u16), String>` - The host name and the port, or the problem of the address.
+pub fn check_host_format(host: &str) -> Result<(String, u16), String> {
+    if host.contains("://") {
+        return Err("The address must not have the scheme (e.g. 'mqtt://')".to_string());
+    }
+    let (name, port) = split_host_to_parts(host.trim())?;
+    if name.is_empty() {
+        return Err("The host name is empty".to_string());
+    }
+    if name.chars().any(char::is_whitespace) {
+        return Err(format!("The host name '{}' contains spaces", name));
+    }
+    if port == 0 {
+        return Err("The port must not be 0".to_string());
+    }
+    Ok((name, port))
+}
+
+async fn run_steps(diagnostics: &mut Diagnostics, account: &AccountConfig, host: &str, port: u16) {
+    let step_timeout = diagnostics.step_timeout;
+    // The first steps check the direct route to the broker, the MQTT connection uses the proxy if it is configured
+    let proxy_note = if crate::config::get_proxy_config().is_some() {
+        " (direct, without the proxy)"
+    } else {
+        ""
+    };
+
+    // DNS resolution
+    let started = Instant::now();
+    let addresses: Vec<SocketAddr> = match timeout(step_timeout, tokio::net::lookup_host((host, port))).await {
+        Ok(Ok(addresses)) => addresses.collect(),
+        Ok(Err(e)) => {
+            diagnostics.failed(DiagnosticsStep::Dns, started, format!("Failed to resolve {}: {}", host, e));
+            return diagnostics.skip_from(DiagnosticsStep::Tcp);
+        }
+        Err(_) => {
+            diagnostics.failed(DiagnosticsStep::Dns, started, format!("Resolving {} has timed out", host));
+            return diagnostics.skip_from(DiagnosticsStep::Tcp);
+        }
+    };
+    if addresses.is_empty() {
+        diagnostics.failed(DiagnosticsStep::Dns, started, format!("{} has no addresses", host));
+        return diagnostics.skip_from(DiagnosticsStep::Tcp);
+    }
+    let resolved: Vec<String> = addresses.iter().map(|address| address.ip().to_string()).collect();
+    diagnostics.ok(DiagnosticsStep::Dns, started, format!("{} is resolved to {}", host, resolved.join(", ")));
+
+    // TCP connect
+    let started = Instant::now();
+    let stream = match timeout(step_timeout, tokio::net::TcpStream::connect(addresses.as_slice())).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            diagnostics.failed(DiagnosticsStep::Tcp, started, format!("Failed to connect to port {}{}: {}", port, proxy_note, e));
+            return diagnostics.skip_from(DiagnosticsStep::Tls);
+        }
+        Err(_) => {
+            diagnostics.failed(DiagnosticsStep::Tcp, started, format!("Connecting to port {}{} has timed out", port, proxy_note));
+            return diagnostics.skip_from(DiagnosticsStep::Tls);
+        }
+    };
+    let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+    diagnostics.ok(DiagnosticsStep::Tcp, started, format!("Connected to {}{}", peer, proxy_note));
+
+    // TLS handshake, only the connections with the client certificate or the CA bundle use TLS
+    let started = Instant::now();
+    match crate::client_tls::load_tls(account) {
+        Ok(Some(config)) => {
+            let host = host.to_string();
+            let stream = stream.into_std().map_err(|e| e.to_string());
+            let handshake = tokio::task::spawn_blocking(move || tls_handshake(stream?, &host, config, step_timeout))
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            match handshake {
+                Ok(()) => diagnostics.ok(DiagnosticsStep::Tls, started, "TLS handshake".to_string()),
+                Err(e) => {
+                    diagnostics.failed(DiagnosticsStep::Tls, started, format!("TLS handshake has failed: {}", e));
+                    return diagnostics.skip_from(DiagnosticsStep::MqttConnect);
+                }
+            }
+        }
+        Err(e) => {
+            diagnostics.failed(DiagnosticsStep::Tls, started, format!("TLS handshake has failed: {}", e));
+            return diagnostics.skip_from(DiagnosticsStep::MqttConnect);
+        }
+        Ok(None) => diagnostics.report(
+            DiagnosticsStep::Tls,
+            StepStatus::Skipped,
+            None,
+            "TLS is not configured for the account".to_string(),
+        ),
+    }
+
+    // MQTT CONNECT with the settings of the account
+    if !diagnostics.includes(DiagnosticsStep::MqttConnect) {
+        return;
+    }
+    let started = Instant::now();
+    let client_id = format!("tba-diagnostics-{}", crate::installation::installation_id());
+    let mut mqtt_options = MqttOptions::new(account.mqtt_version, &client_id, host, port);
+    mqtt_options.set_keep_alive(Duration::from_secs(PING_KEEP_ALIVE_SECS));
+    account.tuning.apply_packet_size(&mut mqtt_options);
+    account.apply_credentials(&mut mqtt_options);
+    let applied = account
+        .apply_tls(&mut mqtt_options)
+        .and_then(|_| crate::proxy::apply_proxy(&mut mqtt_options));
+    if let Err(e) = applied {
+        diagnostics.failed(DiagnosticsStep::MqttConnect, started, e);
+        return diagnostics.skip_from(DiagnosticsStep::Ping);
+    }
+    // The client must live while the event loop is polled, otherwise the event loop stops
+    let (_mqtt_client, mut eventloop) = create_client(mqtt_options, 10);
+    account.tuning.apply_connection_timeout(&mut eventloop);
+
+    let connected = timeout(step_timeout, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(MqttEvent::ConnAck { .. }) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err("The broker has not responded to CONNECT".to_string()));
+    match connected {
+        Ok(()) => diagnostics.ok(DiagnosticsStep::MqttConnect, started, format!("Connected as {}", client_id)),
+        Err(e) => {
+            diagnostics.failed(DiagnosticsStep::MqttConnect, started, e);
+            return diagnostics.skip_from(DiagnosticsStep::Ping);
+        }
+    }
+
+    // Round-trip PING, sent by the client after the keep-alive interval
+    if !diagnostics.includes(DiagnosticsStep::Ping) {
+        return;
+    }
+    let started = Instant::now();
+    let ping_timeout = Duration::from_secs(PING_KEEP_ALIVE_SECS) + step_timeout;
+    let mut ping_sent: Option<Instant> = None;
+    let pinged = timeout(ping_timeout, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(MqttEvent::PingRequest) => ping_sent = Some(Instant::now()),
+                Ok(MqttEvent::PingResponse) => {
+                    if let Some(sent) = ping_sent {
+                        return Ok(sent);
+                    }
+                }
+                Ok(_) => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err("The broker has not responded to PING".to_string()));
+    match pinged {
+        Ok(sent) => diagnostics.ok(DiagnosticsStep::Ping, sent, "The broker has responded to PING".to_string()),
+        Err(e) => diagnostics.failed(DiagnosticsStep::Ping, started, e),
+    }
+}
+
+/// Performs the TLS handshake over the connected TCP stream.
+fn tls_handshake(
+    mut stream: std::net::TcpStream,
+    host: &str,
+    config: rustls::ClientConfig,
+    step_timeout: Duration,
+) -> Result<(), String> {
+    stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(step_timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(step_timeout)).map_err(|e| e.to_string())?;
+    let server_name = rustls::ServerName::try_from(host).map_err(|e| e.to_string())?;
+    let mut connection = rustls::ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
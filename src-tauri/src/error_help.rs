@@ -0,0 +1,462 @@
+//! Module for the catalogue of the error explanations.
+//!
+//! The frontend gets the structured error codes (the reasons of the card states, the kinds of the connection errors,
+//! the PC/SC errors, the protocol anomalies and the configuration errors) and shows the user the cause and what
+//! to do instead of the raw code. The codes are namespaced:
+//!
+//! * `card_state.<reason>` - The reasons of the card states (see `global_app_handle::StateReason`).
+//! * `mqtt.<kind>` - The connection errors (see `mqtt_client::ConnectionErrorKind`).
+//! * `pcsc.<error>` - The errors of the smart card service.
+//! * `protocol.<anomaly>` - The deviations of the server requests (see `protocol::ProtocolAnomaly::kind`).
+//! * `config.<error>` - The errors of the configuration.
+
+use serde::Serialize;
+
+/// Language of the explanations if the requested one is not in the catalogue.
+const DEFAULT_LOCALE: &str = "en";
+
+/// Explanation of the error in one language.
+struct Text {
+    title: &'static str,
+    cause: &'static str,
+    remediation: &'static str,
+}
+
+/// Entry of the catalogue with the explanations in all languages.
+struct Entry {
+    code: &'static str,
+    texts: &'static [(&'static str, Text)],
+}
+
+/// Explanation of the error for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct ErrorHelp {
+    pub code: String,
+    /// Language of the explanation, the default one if the requested language is not available.
+    pub locale: String,
+    pub title: String,
+    pub cause: String,
+    pub remediation: String,
+}
+
+const CATALOGUE: &[Entry] = &[
+    Entry {
+        code: "card_state.broker_unreachable",
+        texts: &[
+            ("en", Text {
+                title: "Server is unreachable",
+                cause: "The bridge can't connect to the MQTT broker, or the broker has closed the connection.",
+                remediation: "Check the internet connection and the server address in the settings. Run the network diagnostics to find the failing step.",
+            }),
+            ("ru", Text {
+                title: "Сервер недоступен",
+                cause: "Приложение не может подключиться к MQTT-брокеру, или брокер закрыл соединение.",
+                remediation: "Проверьте подключение к интернету и адрес сервера в настройках. Запустите диагностику сети, чтобы найти шаг с ошибкой.",
+            }),
+        ],
+    },
+    Entry {
+        code: "card_state.credentials_rejected",
+        texts: &[
+            ("en", Text {
+                title: "Credentials are rejected",
+                cause: "The broker has rejected the username, the password or the token, or the client is not authorized.",
+                remediation: "Check the username and the password (or the flespi token) of the server or of the account in the configuration.",
+            }),
+            ("ru", Text {
+                title: "Учётные данные отклонены",
+                cause: "Брокер отклонил имя пользователя, пароль или токен, или клиент не авторизован.",
+                remediation: "Проверьте имя пользователя и пароль (или токен flespi) сервера или аккаунта в конфигурации.",
+            }),
+        ],
+    },
+    Entry {
+        code: "card_state.card_mute",
+        texts: &[
+            ("en", Text {
+                title: "Card doesn't respond",
+                cause: "The card doesn't respond to the commands: it is damaged, dirty, inserted badly, or the reader is faulty.",
+                remediation: "Reinsert the card, clean its contacts or try another reader. If the card keeps failing, contact the card issuer.",
+            }),
+            ("ru", Text {
+                title: "Карта не отвечает",
+                cause: "Карта не отвечает на команды: она повреждена, загрязнена, плохо вставлена, или неисправен считыватель.",
+                remediation: "Переставьте карту, очистите её контакты или попробуйте другой считыватель. Если ошибка повторяется, обратитесь к эмитенту карты.",
+            }),
+        ],
+    },
+    Entry {
+        code: "card_state.reader_removed",
+        texts: &[
+            ("en", Text {
+                title: "Reader is disconnected",
+                cause: "The card reader is disconnected from the computer or the system has lost it.",
+                remediation: "Check the USB cable of the reader, reconnect it and wait until the card appears again.",
+            }),
+            ("ru", Text {
+                title: "Считыватель отключён",
+                cause: "Считыватель карт отключён от компьютера, или система его потеряла.",
+                remediation: "Проверьте USB-кабель считывателя, переподключите его и дождитесь появления карты.",
+            }),
+        ],
+    },
+    Entry {
+        code: "card_state.scheduled_offline",
+        texts: &[
+            ("en", Text {
+                title: "Scheduled maintenance",
+                cause: "The server is offline during the announced maintenance window.",
+                remediation: "No action is needed, the cards are connected again when the maintenance is over.",
+            }),
+            ("ru", Text {
+                title: "Плановое обслуживание",
+                cause: "Сервер недоступен во время объявленного окна обслуживания.",
+                remediation: "Ничего делать не нужно, карты подключатся снова после окончания обслуживания.",
+            }),
+        ],
+    },
+    Entry {
+        code: "card_state.wrong_card_type",
+        texts: &[
+            ("en", Text {
+                title: "Wrong card type",
+                cause: "The inserted card is a driver card, the remote download is authenticated with the company card.",
+                remediation: "Replace the card with the company card of the vehicle owner.",
+            }),
+            ("ru", Text {
+                title: "Неверный тип карты",
+                cause: "Вставлена карта водителя, а удалённая выгрузка авторизуется картой предприятия.",
+                remediation: "Замените карту на карту предприятия владельца транспортного средства.",
+            }),
+        ],
+    },
+    Entry {
+        code: "card_state.reader_ignored",
+        texts: &[
+            ("en", Text {
+                title: "Reader is ignored",
+                cause: "The reader is excluded by the allow or deny patterns of the readers in the configuration.",
+                remediation: "Insert the card into another reader, or change the readers.allow and readers.deny patterns in config.yaml.",
+            }),
+            ("ru", Text {
+                title: "Считыватель игнорируется",
+                cause: "Считыватель исключён шаблонами allow или deny считывателей в конфигурации.",
+                remediation: "Вставьте карту в другой считыватель или измените шаблоны readers.allow и readers.deny в config.yaml.",
+            }),
+        ],
+    },
+    Entry {
+        code: "mqtt.server_moved",
+        texts: &[
+            ("en", Text {
+                title: "Server has moved",
+                cause: "The server has asked to connect to another server.",
+                remediation: "Update the server address in the settings to the new one announced by the service provider.",
+            }),
+            ("ru", Text {
+                title: "Сервер перемещён",
+                cause: "Сервер попросил подключиться к другому серверу.",
+                remediation: "Обновите адрес сервера в настройках на новый, указанный поставщиком услуги.",
+            }),
+        ],
+    },
+    Entry {
+        code: "mqtt.server_disconnect",
+        texts: &[
+            ("en", Text {
+                title: "Disconnected by the server",
+                cause: "The server has closed the connection, most likely the channel or the device is turned off.",
+                remediation: "Check that the channel and the device of the bridge are enabled on the server.",
+            }),
+            ("ru", Text {
+                title: "Отключено сервером",
+                cause: "Сервер закрыл соединение, скорее всего канал или устройство выключены.",
+                remediation: "Проверьте, что канал и устройство приложения включены на сервере.",
+            }),
+        ],
+    },
+    Entry {
+        code: "mqtt.await_ping_resp",
+        texts: &[
+            ("en", Text {
+                title: "Server doesn't respond",
+                cause: "The server doesn't respond to the keep-alive messages, the network is slow or unstable.",
+                remediation: "Check the network. On the slow links increase the keep-alive and the connection timeout in the server settings.",
+            }),
+            ("ru", Text {
+                title: "Сервер не отвечает",
+                cause: "Сервер не отвечает на сообщения keep-alive, сеть медленная или нестабильная.",
+                remediation: "Проверьте сеть. На медленных каналах увеличьте keep-alive и таймаут подключения в настройках сервера.",
+            }),
+        ],
+    },
+    Entry {
+        code: "mqtt.io",
+        texts: &[
+            ("en", Text {
+                title: "Network error",
+                cause: "The connection to the server has failed or has been interrupted.",
+                remediation: "Check the internet connection, the firewall and the server address and port.",
+            }),
+            ("ru", Text {
+                title: "Ошибка сети",
+                cause: "Подключение к серверу не удалось или было прервано.",
+                remediation: "Проверьте подключение к интернету, файрвол, адрес и порт сервера.",
+            }),
+        ],
+    },
+    Entry {
+        code: "mqtt.proxy",
+        texts: &[
+            ("en", Text {
+                title: "Proxy error",
+                cause: "The connection through the proxy has failed.",
+                remediation: "Check the address and the credentials of the proxy in the configuration.",
+            }),
+            ("ru", Text {
+                title: "Ошибка прокси",
+                cause: "Подключение через прокси не удалось.",
+                remediation: "Проверьте адрес и учётные данные прокси в конфигурации.",
+            }),
+        ],
+    },
+    Entry {
+        code: "pcsc.no_service",
+        texts: &[
+            ("en", Text {
+                title: "Smart card service is not running",
+                cause: "The smart card service of the system (PC/SC) is stopped.",
+                remediation: "Start the Smart Card service on Windows or pcscd on Linux and macOS.",
+            }),
+            ("ru", Text {
+                title: "Служба смарт-карт не запущена",
+                cause: "Системная служба смарт-карт (PC/SC) остановлена.",
+                remediation: "Запустите службу «Смарт-карта» в Windows или pcscd в Linux и macOS.",
+            }),
+        ],
+    },
+    Entry {
+        code: "pcsc.no_readers_available",
+        texts: &[
+            ("en", Text {
+                title: "No readers",
+                cause: "No card readers are connected to the computer.",
+                remediation: "Connect the card reader and check that its driver is installed.",
+            }),
+            ("ru", Text {
+                title: "Нет считывателей",
+                cause: "К компьютеру не подключено ни одного считывателя карт.",
+                remediation: "Подключите считыватель и проверьте, что установлен его драйвер.",
+            }),
+        ],
+    },
+    Entry {
+        code: "pcsc.sharing_violation",
+        texts: &[
+            ("en", Text {
+                title: "Card is used by another application",
+                cause: "Another application has opened the card exclusively.",
+                remediation: "Close the other applications which use the card (e.g. the tachograph download software).",
+            }),
+            ("ru", Text {
+                title: "Карта занята другим приложением",
+                cause: "Другое приложение открыло карту в монопольном режиме.",
+                remediation: "Закройте другие приложения, использующие карту (например, программу выгрузки тахографа).",
+            }),
+        ],
+    },
+    Entry {
+        code: "pcsc.removed_card",
+        texts: &[
+            ("en", Text {
+                title: "Card is removed",
+                cause: "The card has been removed from the reader during the operation.",
+                remediation: "Insert the card back and keep it in the reader during the authentication.",
+            }),
+            ("ru", Text {
+                title: "Карта извлечена",
+                cause: "Карта была извлечена из считывателя во время операции.",
+                remediation: "Вставьте карту обратно и не извлекайте её во время аутентификации.",
+            }),
+        ],
+    },
+    Entry {
+        code: "pcsc.unresponsive_card",
+        texts: &[
+            ("en", Text {
+                title: "Card is not readable",
+                cause: "The reader can't communicate with the card, the card is inserted the wrong way or damaged.",
+                remediation: "Check that the chip of the card faces the contacts of the reader and reinsert it.",
+            }),
+            ("ru", Text {
+                title: "Карта не читается",
+                cause: "Считыватель не может связаться с картой: карта вставлена неправильно или повреждена.",
+                remediation: "Проверьте, что чип карты обращён к контактам считывателя, и вставьте её заново.",
+            }),
+        ],
+    },
+    Entry {
+        code: "protocol.invalid_json",
+        texts: &[
+            ("en", Text {
+                title: "Invalid request",
+                cause: "The server has sent the request which is not valid JSON.",
+                remediation: "Contact the support of the server, the request doesn't follow the protocol.",
+            }),
+            ("ru", Text {
+                title: "Некорректный запрос",
+                cause: "Сервер прислал запрос, который не является корректным JSON.",
+                remediation: "Обратитесь в поддержку сервера, запрос не соответствует протоколу.",
+            }),
+        ],
+    },
+    Entry {
+        code: "protocol.unknown_field",
+        texts: &[
+            ("en", Text {
+                title: "Unknown field in the request",
+                cause: "The request of the server has a field this version of the application doesn't know.",
+                remediation: "Update the application. In the strict protocol mode such requests are rejected.",
+            }),
+            ("ru", Text {
+                title: "Неизвестное поле в запросе",
+                cause: "В запросе сервера есть поле, неизвестное этой версии приложения.",
+                remediation: "Обновите приложение. В строгом режиме протокола такие запросы отклоняются.",
+            }),
+        ],
+    },
+    Entry {
+        code: "protocol.missing_field",
+        texts: &[
+            ("en", Text {
+                title: "Missing field in the request",
+                cause: "The request of the server doesn't have a required field.",
+                remediation: "Contact the support of the server, the request doesn't follow the protocol.",
+            }),
+            ("ru", Text {
+                title: "В запросе нет поля",
+                cause: "В запросе сервера нет обязательного поля.",
+                remediation: "Обратитесь в поддержку сервера, запрос не соответствует протоколу.",
+            }),
+        ],
+    },
+    Entry {
+        code: "protocol.invalid_type",
+        texts: &[
+            ("en", Text {
+                title: "Invalid field in the request",
+                cause: "A field of the server request has the wrong type.",
+                remediation: "Contact the support of the server, the request doesn't follow the protocol.",
+            }),
+            ("ru", Text {
+                title: "Некорректное поле в запросе",
+                cause: "Поле запроса сервера имеет неверный тип.",
+                remediation: "Обратитесь в поддержку сервера, запрос не соответствует протоколу.",
+            }),
+        ],
+    },
+    Entry {
+        code: "config.invalid_host",
+        texts: &[
+            ("en", Text {
+                title: "Invalid server address",
+                cause: "The server address in the configuration is not in the host:port form.",
+                remediation: "Enter the server address as host:port in the settings, e.g. mqtt.flespi.io:8883.",
+            }),
+            ("ru", Text {
+                title: "Некорректный адрес сервера",
+                cause: "Адрес сервера в конфигурации указан не в формате host:port.",
+                remediation: "Укажите адрес сервера в настройках в формате host:port, например mqtt.flespi.io:8883.",
+            }),
+        ],
+    },
+    Entry {
+        code: "config.incomplete_client_tls",
+        texts: &[
+            ("en", Text {
+                title: "Incomplete client certificate",
+                cause: "Only one of client_cert and client_key is set for the mutual TLS.",
+                remediation: "Set both the client certificate and its private key, or remove both.",
+            }),
+            ("ru", Text {
+                title: "Неполный клиентский сертификат",
+                cause: "Для взаимного TLS задан только один из параметров client_cert и client_key.",
+                remediation: "Укажите и клиентский сертификат, и его закрытый ключ, или удалите оба.",
+            }),
+        ],
+    },
+    Entry {
+        code: "config.unknown_account",
+        texts: &[
+            ("en", Text {
+                title: "Unknown account",
+                cause: "The card is assigned to the account which is not in the configuration, the default server is used.",
+                remediation: "Add the account to the configuration or change the account of the card.",
+            }),
+            ("ru", Text {
+                title: "Неизвестный аккаунт",
+                cause: "Карта назначена аккаунту, которого нет в конфигурации, используется сервер по умолчанию.",
+                remediation: "Добавьте аккаунт в конфигурацию или измените аккаунт карты.",
+            }),
+        ],
+    },
+];
+
+/// Finds the explanation of the error in the language, or in the default language.
+fn find_help(code: &str, locale: &str) -> Option<ErrorHelp> {
+    let entry = CATALOGUE.iter().find(|entry| entry.code == code)?;
+    // "ru-RU" is looked up as "ru"
+    let language = locale.split(['-', '_']).next().unwrap_or(DEFAULT_LOCALE).to_lowercase();
+    let (locale, text) = entry
+        .texts
+        .iter()
+        .find(|(text_locale, _)| *text_locale == language)
+        .or_else(|| entry.texts.iter().find(|(text_locale, _)| *text_locale == DEFAULT_LOCALE))?;
+    Some(ErrorHelp {
+        code: entry.code.to_string(),
+        locale: locale.to_string(),
+        title: text.title.to_string(),
+        cause: text.cause.to_string(),
+        remediation: text.remediation.to_string(),
+    })
+}
+
+/// Public function to get the explanation of the error code.
+/// This function is a Tauri command that is called from the frontend to show the help of the error.
+///
+/// # Arguments
+///
+/// * `code` - The namespaced code of the error, e.g. `card_state.card_mute`.
+/// * `locale` - The language of the explanation, e.g. `ru` or `ru-RU`, English if it is not set or not available.
+///
+/// # Returns
+///
+/// * `Result<ErrorHelp, String>` - The explanation, or the error if the code is unknown.
+#[tauri::command]
+pub fn get_error_help(code: String, locale: Option<String>) -> Result<ErrorHelp, String> {
+    find_help(&code, locale.as_deref().unwrap_or(DEFAULT_LOCALE)).ok_or_else(|| format!("No help for the error code '{}'", code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_entry_has_the_default_locale() {
+        for entry in CATALOGUE {
+            assert!(
+                entry.texts.iter().any(|(locale, _)| *locale == DEFAULT_LOCALE),
+                "{} has no default text",
+                entry.code
+            );
+            assert_eq!(CATALOGUE.iter().filter(|other| other.code == entry.code).count(), 1, "{} is duplicated", entry.code);
+        }
+    }
+
+    #[test]
+    fn unknown_locale_falls_back_to_default() {
+        assert_eq!(find_help("card_state.card_mute", "ru-RU").unwrap().locale, "ru");
+        assert_eq!(find_help("card_state.card_mute", "de").unwrap().locale, "en");
+        assert!(find_help("card_state.unknown", "en").is_none());
+    }
+}
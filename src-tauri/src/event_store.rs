@@ -0,0 +1,302 @@
+//! Module for the in-memory stores of the events shown in the UI.
+//!
+//! The application keeps the history of the card states, the notification center and the daily statistics
+//! of the authentications. The installations may run unattended for years, so every store is bounded:
+//! the entries are evicted by age and by count (see `RetentionConfig`), and the scheduled compaction job
+//! removes the aged entries even if nothing new is recorded.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::config::get_retention_config;
+use crate::hooks::ConnectionHooks;
+use crate::global_app_handle::{CardStatePayload, StateReason};
+use crate::timestamp::Timestamp;
+
+/// Default interval of the compaction of the stores.
+pub const COMPACTION_INTERVAL_SECS: u64 = 600;
+
+/// Entry of the store with the time it was recorded.
+#[derive(Serialize, Clone, Debug)]
+pub struct StoredEntry<T> {
+    pub recorded_at: Timestamp,
+    pub data: T,
+}
+
+/// Store of the last entries, bounded by the number of entries and by their age.
+pub struct BoundedStore<T> {
+    entries: VecDeque<StoredEntry<T>>,
+}
+
+impl<T: Clone> BoundedStore<T> {
+    fn new() -> Self {
+        BoundedStore {
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Adds the entry and evicts the oldest ones above the limit.
+    fn push(&mut self, data: T, max_entries: usize) {
+        self.entries.push_back(StoredEntry {
+            recorded_at: Timestamp::now(),
+            data,
+        });
+        while self.entries.len() > max_entries {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Removes the entries recorded before the time.
+    fn evict_older_than(&mut self, epoch: i64) -> usize {
+        let before = self.entries.len();
+        while self
+            .entries
+            .front()
+            .map(|entry| entry.recorded_at.epoch < epoch)
+            .unwrap_or(false)
+        {
+            self.entries.pop_front();
+        }
+        before - self.entries.len()
+    }
+
+    fn to_vec(&self) -> Vec<StoredEntry<T>> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+/// Notification shown in the notification center.
+#[derive(Serialize, Clone, Debug)]
+pub struct Notification {
+    pub level: String,
+    pub message: String,
+}
+
+/// Error of the card shown in the UI, e.g. "last failure: card mute at 14:32".
+#[derive(Serialize, Clone, Debug)]
+pub struct CardError {
+    pub reason: StateReason,
+    pub detail: Option<String>,
+    /// State of the card at the error.
+    pub card_state: String,
+}
+
+/// Number of authentications of the card per day (the day is "YYYY-MM-DD" in UTC).
+type DailyStatistics = BTreeMap<String, HashMap<String, u32>>;
+
+lazy_static! {
+    static ref CARD_EVENTS: Mutex<BoundedStore<CardStatePayload>> = Mutex::new(BoundedStore::new());
+    static ref NOTIFICATIONS: Mutex<BoundedStore<Notification>> = Mutex::new(BoundedStore::new());
+    /// The last errors of every card.
+    static ref CARD_ERRORS: Mutex<HashMap<String, BoundedStore<CardError>>> = Mutex::new(HashMap::new());
+    static ref STATISTICS: Mutex<DailyStatistics> = Mutex::new(BTreeMap::new());
+    /// Number of the protocol anomalies in the server requests since the start, by the kind.
+    static ref PROTOCOL_ANOMALIES: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Records the card state to the history, and the error of the card to its last errors.
+pub fn record_card_event(payload: &CardStatePayload) {
+    let retention = get_retention_config();
+    CARD_EVENTS.lock().unwrap().push(payload.clone(), retention.max_events);
+    if let (Some(reason), false) = (payload.reason, payload.card_number.is_empty()) {
+        CARD_ERRORS
+            .lock()
+            .unwrap()
+            .entry(payload.card_number.clone())
+            .or_insert_with(BoundedStore::new)
+            .push(
+                CardError {
+                    reason,
+                    detail: payload.detail.clone(),
+                    card_state: payload.card_state.clone(),
+                },
+                retention.max_card_errors,
+            );
+    }
+}
+
+/// Records the notification to the notification center.
+pub fn record_notification(level: &str, message: &str) {
+    let max_entries = get_retention_config().max_notifications;
+    NOTIFICATIONS.lock().unwrap().push(
+        Notification {
+            level: level.to_string(),
+            message: message.to_string(),
+        },
+        max_entries,
+    );
+}
+
+/// Counts the finished authentication of the card in the daily statistics.
+fn record_authentication(cardnumber: &str) {
+    let day = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let mut statistics = STATISTICS.lock().unwrap();
+    *statistics
+        .entry(day)
+        .or_default()
+        .entry(cardnumber.to_string())
+        .or_insert(0) += 1;
+}
+
+/// Connection hooks of the daily statistics of the authentications.
+pub struct StatisticsHooks;
+
+impl ConnectionHooks for StatisticsHooks {
+    fn on_session_finished(&self, cardnumber: &str, _apdu_count: u32) {
+        record_authentication(cardnumber);
+    }
+}
+
+/// Counts the protocol anomaly in the statistics (see `protocol::ProtocolAnomaly::kind`).
+pub fn record_protocol_anomaly(kind: &'static str) {
+    *PROTOCOL_ANOMALIES.lock().unwrap().entry(kind).or_insert(0) += 1;
+}
+
+/// Authentications of the card from the statistics.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct CardStatistics {
+    pub authentications_today: u32,
+    /// Number of the authentications within the retention period.
+    pub authentications_total: u32,
+}
+
+/// Returns the authentications of the card from the statistics.
+pub fn card_statistics(cardnumber: &str) -> CardStatistics {
+    let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let statistics = STATISTICS.lock().unwrap();
+    let mut card_statistics = CardStatistics::default();
+    for (day, cards) in statistics.iter() {
+        let count = cards.get(cardnumber).copied().unwrap_or(0);
+        card_statistics.authentications_total += count;
+        if *day == today {
+            card_statistics.authentications_today = count;
+        }
+    }
+    card_statistics
+}
+
+/// Returns the last recorded state of the card, `None` if there is no state of the card in the history.
+pub fn last_card_event(cardnumber: &str) -> Option<StoredEntry<CardStatePayload>> {
+    CARD_EVENTS
+        .lock()
+        .unwrap()
+        .entries
+        .iter()
+        .rev()
+        .find(|entry| entry.data.card_number == cardnumber)
+        .cloned()
+}
+
+/// Returns the last errors of the card, the oldest first.
+pub fn card_errors(cardnumber: &str) -> Vec<StoredEntry<CardError>> {
+    CARD_ERRORS
+        .lock()
+        .unwrap()
+        .get(cardnumber)
+        .map(|errors| errors.to_vec())
+        .unwrap_or_default()
+}
+
+/// Removes the entries which are older than the retention period from all stores.
+pub fn compact() {
+    let max_age_days = get_retention_config().max_age_days as i64;
+    let oldest = chrono::Utc::now() - chrono::Duration::days(max_age_days);
+
+    let events = CARD_EVENTS.lock().unwrap().evict_older_than(oldest.timestamp());
+    let notifications = NOTIFICATIONS.lock().unwrap().evict_older_than(oldest.timestamp());
+    let mut card_errors = CARD_ERRORS.lock().unwrap();
+    let errors: usize = card_errors
+        .values_mut()
+        .map(|errors| errors.evict_older_than(oldest.timestamp()))
+        .sum();
+    card_errors.retain(|_, errors| !errors.entries.is_empty());
+    drop(card_errors);
+
+    let oldest_day = oldest.format("%Y-%m-%d").to_string();
+    let mut statistics = STATISTICS.lock().unwrap();
+    let days_before = statistics.len();
+    // The days are sorted, so everything before the oldest kept day is split off and dropped
+    *statistics = statistics.split_off(&oldest_day);
+    let days = days_before - statistics.len();
+
+    if events + notifications + errors + days > 0 {
+        log::debug!(
+            "Stores are compacted: {} event(s), {} notification(s), {} card error(s), {} day(s) of statistics are removed",
+            events,
+            notifications,
+            errors,
+            days
+        );
+    }
+}
+
+/// Size of the store for the diagnostics.
+#[derive(Serialize, Clone, Debug)]
+pub struct StoreSize {
+    pub entries: usize,
+    /// Approximate size in bytes (the size of the serialized entries).
+    pub bytes: usize,
+}
+
+impl StoreSize {
+    fn of<T: Serialize>(entries: usize, data: &T) -> Self {
+        StoreSize {
+            entries,
+            bytes: serde_json::to_vec(data).map(|json| json.len()).unwrap_or(0),
+        }
+    }
+}
+
+/// Sizes of the stores for the diagnostics.
+#[derive(Serialize, Clone, Debug)]
+pub struct StoreSizes {
+    pub card_events: StoreSize,
+    pub notifications: StoreSize,
+    pub statistics: StoreSize,
+}
+
+/// Public function to get the current sizes of the stores.
+/// This function is a Tauri command that is called from the diagnostics view of the frontend.
+#[tauri::command]
+pub fn get_store_sizes() -> StoreSizes {
+    let card_events = CARD_EVENTS.lock().unwrap();
+    let notifications = NOTIFICATIONS.lock().unwrap();
+    let statistics = STATISTICS.lock().unwrap();
+    StoreSizes {
+        card_events: StoreSize::of(card_events.entries.len(), &card_events.entries),
+        notifications: StoreSize::of(notifications.entries.len(), &notifications.entries),
+        statistics: StoreSize::of(statistics.len(), &*statistics),
+    }
+}
+
+/// History of the events for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct EventHistory {
+    pub card_events: Vec<StoredEntry<CardStatePayload>>,
+    pub notifications: Vec<StoredEntry<Notification>>,
+    /// The last errors of every card.
+    pub card_errors: HashMap<String, Vec<StoredEntry<CardError>>>,
+    pub statistics: DailyStatistics,
+    pub protocol_anomalies: BTreeMap<&'static str, u64>,
+}
+
+/// Public function to get the history of the card states, the notifications and the statistics.
+/// This function is a Tauri command that is called from the frontend.
+#[tauri::command]
+pub fn get_event_history() -> EventHistory {
+    EventHistory {
+        card_events: CARD_EVENTS.lock().unwrap().to_vec(),
+        notifications: NOTIFICATIONS.lock().unwrap().to_vec(),
+        card_errors: CARD_ERRORS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(cardnumber, errors)| (cardnumber.clone(), errors.to_vec()))
+            .collect(),
+        statistics: STATISTICS.lock().unwrap().clone(),
+        protocol_anomalies: PROTOCOL_ANOMALIES.lock().unwrap().clone(),
+    }
+}
@@ -0,0 +1,117 @@
+//! Module for the fault injection in the QA builds.
+//!
+//! The QA verifies the recovery logic of the bridge and the states of the UI without unplugging the hardware:
+//! the MQTT publishes are dropped, the APDU commands are delayed and the card is reset at random, as configured
+//! at runtime with the `set_fault_injection` command. The faults are injected only in the builds with
+//! the `fault-injection` feature, in the other builds the command fails and the hooks do nothing.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use pcsc::{Card, Context, Disposition, Protocols, Scope, ShareMode};
+use serde::{Deserialize, Serialize};
+
+/// Faults injected into the bridge.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FaultInjectionSettings {
+    /// Share of the MQTT publishes which are dropped, in percent.
+    #[serde(default)]
+    pub publish_drop_percent: u8,
+    /// Delay of every APDU command.
+    #[serde(default)]
+    pub apdu_latency_ms: u64,
+    /// Chance of the card reset before the APDU command, in percent.
+    #[serde(default)]
+    pub card_reset_percent: u8,
+}
+
+lazy_static! {
+    static ref SETTINGS: Mutex<FaultInjectionSettings> = Mutex::new(FaultInjectionSettings::default());
+}
+
+fn settings() -> Option<FaultInjectionSettings> {
+    cfg!(feature = "fault-injection").then(|| SETTINGS.lock().unwrap().clone())
+}
+
+/// Returns `true` with the chance in percent.
+fn chance(percent: u8) -> bool {
+    if percent == 0 {
+        return false;
+    }
+    let mut hasher = RandomState::new().build_hasher();
+    Instant::now().hash(&mut hasher);
+    hasher.finish() % 100 < percent as u64
+}
+
+/// Checks if the MQTT publish has to be dropped.
+pub fn should_drop_publish(topic: &str) -> bool {
+    let dropped = settings().map_or(false, |settings| chance(settings.publish_drop_percent));
+    if dropped {
+        log::warn!("Fault injection: the publish to {} is dropped", topic);
+    }
+    dropped
+}
+
+/// Injects the faults before the APDU command: the latency and the reset of the card.
+pub fn before_apdu(card: &Card) {
+    let settings = match settings() {
+        Some(settings) => settings,
+        None => return,
+    };
+    if settings.apdu_latency_ms > 0 {
+        std::thread::sleep(Duration::from_millis(settings.apdu_latency_ms));
+    }
+    if chance(settings.card_reset_percent) {
+        log::warn!("Fault injection: the card is reset");
+        if let Err(e) = reset_card(card) {
+            log::error!("Fault injection: failed to reset the card: {}", e);
+        }
+    }
+}
+
+/// Resets the card through another connection, so the connection of the bridge sees the reset
+/// the same way as the reset by another application.
+fn reset_card(card: &Card) -> Result<(), pcsc::Error> {
+    let status = card.status2_owned()?;
+    let reader_name = status.reader_names().first().ok_or(pcsc::Error::UnknownReader)?.to_owned();
+    let ctx = Context::establish(Scope::User)?;
+    let other = ctx.connect(&reader_name, ShareMode::Shared, Protocols::ANY)?;
+    other.disconnect(Disposition::ResetCard).map_err(|(_, e)| e)
+}
+
+/// Public function to configure the injected faults at runtime.
+/// This function is a Tauri command that is called from the QA tools.
+///
+/// # Arguments
+///
+/// * `settings` - The faults to inject, the default settings turn the injection off.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - The error if the build doesn't support the fault injection.
+#[tauri::command]
+pub fn set_fault_injection(settings: FaultInjectionSettings) -> Result<(), String> {
+    if !cfg!(feature = "fault-injection") {
+        return Err("The fault injection is not available in this build".to_string());
+    }
+    if settings.publish_drop_percent > 100 || settings.card_reset_percent > 100 {
+        return Err("The chances must be within 0..100 percent".to_string());
+    }
+    log::warn!("Fault injection settings: {:?}", settings);
+    *SETTINGS.lock().unwrap() = settings;
+    Ok(())
+}
+
+/// Public function to get the injected faults.
+/// This function is a Tauri command that is called from the QA tools.
+///
+/// # Returns
+///
+/// * `Result<FaultInjectionSettings, String>` - The current settings, or the error if the build doesn't support the fault injection.
+#[tauri::command]
+pub fn get_fault_injection() -> Result<FaultInjectionSettings, String> {
+    settings().ok_or_else(|| "The fault injection is not available in this build".to_string())
+}
@@ -1,13 +1,136 @@
 use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
 use tauri::{AppHandle, Manager};
 
-use crate::smart_card::TachoState;
+use crate::card_identification::TachographCardType;
+use crate::known_cards::CardGeneration;
+use crate::timestamp::Timestamp;
+
+/// Name of the event that carries card state updates to the frontend.
+pub const CARD_STATE_EVENT: &str = "global-cards-sync";
+/// Name of the event that shows a notification to the user.
+pub const NOTIFICATION_EVENT: &str = "global-notification";
 
 lazy_static! {
     static ref APP_HANDLE: Mutex<Option<AppHandle>> = Mutex::new(None);
+    /// The latest card state payload of every reader that failed to be delivered (or was emitted before
+    /// the frontend was ready), by the reader name. They are sent after the frontend-ready handshake
+    /// and with the next card state which is delivered.
+    static ref PENDING_EVENTS: Mutex<HashMap<String, CardStatePayload>> = Mutex::new(HashMap::new());
+    /// Notifications shown before the frontend was ready, they are sent after the frontend-ready handshake.
+    static ref PENDING_NOTIFICATIONS: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+    /// Categories of the events every window has subscribed to, by the window label.
+    /// The window which has never subscribed receives all the events.
+    static ref SUBSCRIPTIONS: Mutex<HashMap<String, HashSet<EventKind>>> = Mutex::new(HashMap::new());
+}
+
+/// Category of the events the frontend can subscribe to (see `subscribe_events`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Card state updates (`global-cards-sync`).
+    CardState,
+    /// Notifications for the user (`global-notification`).
+    Notifications,
+    /// Statistics of the cards.
+    Stats,
+    /// Log records.
+    Logs,
+}
+
+impl EventKind {
+    const ALL: [EventKind; 4] = [EventKind::CardState, EventKind::Notifications, EventKind::Stats, EventKind::Logs];
+}
+
+/// Flag that is set when the frontend has sent the "frontend-loaded" event.
+static FRONTEND_READY: AtomicBool = AtomicBool::new(false);
+/// Maximum number of the notifications kept until the frontend is ready, the later ones are only in the event store.
+const MAX_PENDING_NOTIFICATIONS: usize = 50;
+/// Time to wait for the "frontend-loaded" event before the frontend is reported as failed to load.
+pub const FRONTEND_LOAD_TIMEOUT_SECS: u64 = 30;
+/// Total number of card state emissions that could not be delivered to the frontend.
+static FAILED_EMISSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Represents the state of a tachograph card sent to the frontend.
+///
+/// # Fields
+///
+/// * `atr` - A string representing the Answer To Reset (ATR) of the card. The ATR is a sequence
+///   of bytes returned by the card upon reset, identifying the card's communication parameters.
+/// * `reader_name` - The name of the smart card reader through which the card is being accessed.
+/// * `reader_label` - The name of the reader for the UI. Distinguishes the slots of dual-slot readers.
+/// * `card_state` - A string describing the current state of the card (e.g., "PRESENT", "EMPTY").
+/// * `card_number` - The identification number of the tachograph card.
+/// * `online` - Whether the card is connected to the server. `None` if it is unknown at the moment.
+/// * `authentication` - Whether the authentication process is in progress. `None` if it is unknown.
+/// * `updated_at` - When the state was observed.
+/// * `reason` - Why the card is offline or can't be used, `None` if there is no problem or it is unknown.
+/// * `detail` - Description of the reason for the UI, e.g. the error of the connection.
+/// * `card_type` - Type of the tachograph card, `None` until it is detected (filled from the reader pool).
+/// * `card_model` - Model of the card identified by its ATR (see `known_cards`), `None` if the ATR is unknown.
+/// * `card_generation` - Generation of the card, `None` until it is detected (filled from the reader pool).
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct CardStatePayload {
+    pub atr: String,
+    pub reader_name: String,
+    pub reader_label: String,
+    pub card_state: String,
+    pub card_number: String,
+    pub online: Option<bool>,
+    pub authentication: Option<bool>,
+    pub updated_at: Timestamp,
+    pub reason: Option<StateReason>,
+    pub detail: Option<String>,
+    pub card_type: Option<TachographCardType>,
+    pub card_model: Option<String>,
+    pub card_generation: Option<CardGeneration>,
+}
+
+/// Why the card is offline or can't be used, so the UI can explain the state of the card.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateReason {
+    /// The broker can't be reached or has closed the connection.
+    BrokerUnreachable,
+    /// The broker has rejected the username/password or the client is not authorized.
+    CredentialsRejected,
+    /// The card doesn't respond to the APDU commands.
+    CardMute,
+    /// The reader is disconnected from the computer.
+    ReaderRemoved,
+    /// The server is offline during the announced maintenance window.
+    ScheduledOffline,
+    /// The card is not the one for the remote authentication, e.g. the driver card.
+    WrongCardType,
+    /// The reader is filtered out by the `readers.allow`/`readers.deny` settings.
+    ReaderIgnored,
+    /// The card is paused or disabled by the user (see `config::set_card_availability`).
+    PausedByUser,
+}
+
+/// Errors that can occur while sending an event to the frontend.
+#[derive(Debug)]
+pub enum EmitError {
+    /// The global application handle has not been initialized yet.
+    AppHandleNotSet,
+    /// Tauri failed to deliver the event.
+    Tauri(tauri::Error),
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitError::AppHandleNotSet => write!(f, "App handle is not set"),
+            EmitError::Tauri(e) => write!(f, "Tauri emit error: {}", e),
+        }
+    }
 }
 
+impl std::error::Error for EmitError {}
+
 // initialize the global app handle
 pub fn set_app_handle(handle: AppHandle) {
     let mut app_handle = APP_HANDLE.lock().unwrap();
@@ -20,22 +143,208 @@ pub fn get_app_handle() -> Option<AppHandle> {
     app_handle.clone()
 }
 
-pub fn emit_event(event_name: &str, atr: String, reader_name: String, card_state: String, card_number: String, online: Option<bool>, authentication: Option<bool>) {
-    let payload = TachoState {
-        atr,
-        reader_name,
-        card_state,
-        card_number,
-        online,
-        authentication
-    };
+/// Returns the number of card state emissions that failed since the application start.
+pub fn failed_emissions_count() -> usize {
+    FAILED_EMISSIONS.load(Ordering::Relaxed)
+}
+
+/// Sends the card state to the frontend.
+///
+/// If the frontend has not finished loading yet, the payload is kept instead and sent after the frontend-ready
+/// handshake (see `set_frontend_ready`). The payload which fails is kept too and sent after the next card state
+/// which is delivered. Only the latest payload of every reader is kept, it replaces the older ones.
+pub fn emit_card_state(mut payload: CardStatePayload) -> Result<(), EmitError> {
+    if payload.card_type.is_none() {
+        payload.card_type = crate::reader_pool::find_card_type(&payload.reader_name);
+    }
+    if payload.card_generation.is_none() {
+        payload.card_generation = crate::reader_pool::find_card_generation(&payload.reader_name);
+    }
+    // The external displays in the LAN receive the same card states as the frontend
+    crate::broadcast::broadcast_card_state(&payload);
+    crate::event_store::record_card_event(&payload);
+
+    if !FRONTEND_READY.load(Ordering::Acquire) {
+        // The frontend gets the payload with the handshake, so it is not delivered twice
+        keep_pending(payload);
+        return Ok(());
+    }
+
+    // The frontend is ready, so the retry is done immediately
+    let result = send_card_state(&payload).or_else(|_| {
+        FAILED_EMISSIONS.fetch_add(1, Ordering::Relaxed);
+        send_card_state(&payload)
+    });
+    match result {
+        Ok(()) => {
+            // The older state of the reader which has failed is outdated now
+            PENDING_EVENTS.lock().unwrap().remove(&payload.reader_name);
+            // The frontend receives the events again, so the failed states of the other readers are sent too
+            resend_pending();
+            Ok(())
+        }
+        Err(e) => {
+            FAILED_EMISSIONS.fetch_add(1, Ordering::Relaxed);
+            log::error!("Failed to emit card state after retry: {}", e);
+            keep_pending(payload);
+            Err(e)
+        }
+    }
+}
+
+/// Keeps the payload to send it later (see `emit_card_state`), replacing the older one of the reader.
+fn keep_pending(payload: CardStatePayload) {
+    PENDING_EVENTS.lock().unwrap().insert(payload.reader_name.clone(), payload);
+}
+
+/// Marks the frontend as ready to receive events and sends the payloads
+/// which were emitted before it or have failed.
+pub fn set_frontend_ready() {
+    FRONTEND_READY.store(true, Ordering::Release);
+
+    let notifications: Vec<serde_json::Value> = std::mem::take(&mut *PENDING_NOTIFICATIONS.lock().unwrap());
+    for payload in notifications {
+        if let Err(e) = emit_event_of_kind(EventKind::Notifications, NOTIFICATION_EVENT, payload) {
+            log::warn!("Failed to send the pending notification: {}", e);
+        }
+    }
+
+    resend_pending();
+
+    let failed = failed_emissions_count();
+    if failed > 0 {
+        log::warn!("{} card state event(s) failed to be delivered to the frontend", failed);
+    }
+}
+
+/// Sends the pending card states. The ones which fail again are kept for the next delivered card state,
+/// unless a newer state of the reader has been kept meanwhile.
+fn resend_pending() {
+    let pending: Vec<CardStatePayload> = std::mem::take(&mut *PENDING_EVENTS.lock().unwrap()).into_values().collect();
+    if !pending.is_empty() {
+        log::debug!("Sending {} pending card state event(s)", pending.len());
+    }
+    for payload in pending {
+        if let Err(e) = send_card_state(&payload) {
+            FAILED_EMISSIONS.fetch_add(1, Ordering::Relaxed);
+            log::error!(
+                "Failed to re-send card state for the reader {}: {}",
+                payload.reader_name,
+                e
+            );
+            PENDING_EVENTS
+                .lock()
+                .unwrap()
+                .entry(payload.reader_name.clone())
+                .or_insert(payload);
+        }
+    }
+}
+
+/// Waits for the frontend-ready handshake. The cards are bridged without the frontend, but if the webview
+/// never loads, the user doesn't see them, so it is logged and the notification waits for the frontend.
+pub async fn watch_frontend_load() {
+    tokio::time::sleep(std::time::Duration::from_secs(FRONTEND_LOAD_TIMEOUT_SECS)).await;
+    if !FRONTEND_READY.load(Ordering::Acquire) {
+        log::error!(
+            "The frontend has not loaded in {} seconds, the cards are bridged without the user interface",
+            FRONTEND_LOAD_TIMEOUT_SECS
+        );
+        emit_notification(
+            "warning",
+            "The user interface has loaded late, the cards were bridged in the background. Restart the application if the window stays empty.",
+        );
+    }
+}
+
+/// Checks if the window receives the events of the category.
+fn is_subscribed(window_label: &str, kind: EventKind) -> bool {
+    SUBSCRIPTIONS
+        .lock()
+        .unwrap()
+        .get(window_label)
+        .map(|kinds| kinds.contains(&kind))
+        .unwrap_or(true)
+}
+
+/// Sends the event of the category to the windows which are subscribed to it.
+pub fn emit_event_of_kind<S: serde::Serialize + Clone>(kind: EventKind, event_name: &str, payload: S) -> Result<(), EmitError> {
+    let app_handle = get_app_handle().ok_or(EmitError::AppHandleNotSet)?;
+    app_handle
+        .emit_filter(event_name, payload, |window| is_subscribed(window.label(), kind))
+        .map_err(EmitError::Tauri)?;
+    log::debug!("{} has been sent", event_name);
+    Ok(())
+}
+
+/// Public function to subscribe the window to the categories of the events.
+/// This function is a Tauri command that is called from the frontend when it starts displaying the events.
+/// The window which has never subscribed receives all the events.
+///
+/// # Arguments
+///
+/// * `window` - The window which calls the command.
+/// * `kinds` - The categories of the events.
+#[tauri::command]
+pub fn subscribe_events(window: tauri::Window, kinds: Vec<EventKind>) {
+    let mut subscriptions = SUBSCRIPTIONS.lock().unwrap();
+    let subscribed = subscriptions.entry(window.label().to_string()).or_default();
+    subscribed.extend(kinds);
+    log::debug!("Window {} is subscribed to {:?}", window.label(), subscribed);
+}
 
-    if let Some(app_handle) = get_app_handle() {
-        if let Err(e) = app_handle.emit_all(event_name, payload) {
-            println!("Error: {:?}", e);
+/// Public function to unsubscribe the window from the categories of the events.
+/// This function is a Tauri command that is called from the frontend when it stops displaying the events.
+///
+/// # Arguments
+///
+/// * `window` - The window which calls the command.
+/// * `kinds` - The categories of the events.
+#[tauri::command]
+pub fn unsubscribe_events(window: tauri::Window, kinds: Vec<EventKind>) {
+    let mut subscriptions = SUBSCRIPTIONS.lock().unwrap();
+    // The window which has never subscribed receives all the events, so the rest of them stay subscribed
+    let subscribed = subscriptions
+        .entry(window.label().to_string())
+        .or_insert_with(|| EventKind::ALL.iter().copied().collect());
+    for kind in &kinds {
+        subscribed.remove(kind);
+    }
+    log::debug!("Window {} is subscribed to {:?}", window.label(), subscribed);
+}
+
+/// Sends an arbitrary event to the frontend.
+pub fn emit_global_event<S: serde::Serialize + Clone>(event_name: &str, payload: S) -> Result<(), EmitError> {
+    let app_handle = get_app_handle().ok_or(EmitError::AppHandleNotSet)?;
+    app_handle.emit_all(event_name, payload).map_err(EmitError::Tauri)?;
+    log::debug!("{} has been sent", event_name);
+    Ok(())
+}
+
+/// Shows a notification to the user.
+///
+/// # Arguments
+///
+/// * `level` - Notification level: "info", "warning" or "error".
+/// * `message` - Text of the notification.
+pub fn emit_notification(level: &str, message: &str) {
+    crate::event_store::record_notification(level, message);
+    let payload = serde_json::json!({
+        "level": level,
+        "message": message,
+    });
+    if !FRONTEND_READY.load(Ordering::Acquire) {
+        let mut pending = PENDING_NOTIFICATIONS.lock().unwrap();
+        if pending.len() < MAX_PENDING_NOTIFICATIONS {
+            pending.push(payload);
         }
-        println!("{} has been sent", event_name);
-    } else {
-        println!("App handle is not set");
+        return;
     }
+    if let Err(e) = emit_event_of_kind(EventKind::Notifications, NOTIFICATION_EVENT, payload) {
+        log::warn!("Failed to emit the notification '{}': {}", message, e);
+    }
+}
+
+fn send_card_state(payload: &CardStatePayload) -> Result<(), EmitError> {
+    emit_event_of_kind(EventKind::CardState, CARD_STATE_EVENT, payload.clone())
 }
@@ -0,0 +1,570 @@
+//! Module for the client certificates on the hardware tokens.
+//!
+//! The high-security customers don't keep the private key of the bridge in a file: the key is generated on
+//! a hardware token of the bridge computer, and the broker authenticates the bridge with the mutual TLS
+//! (see `HardwareTokenConfig`). The certificate is read from the token when the TLS configuration is built,
+//! and every handshake is signed by the token, so the key never leaves it. The token is used through:
+//! - the PKCS#11 module of the token vendor (the `pkcs11_module` setting). The TPM keys are used the same way,
+//!   through the PKCS#11 module of the TPM (`libtpm2_pkcs11.so` of tpm2-pkcs11);
+//! - the PIV commands (GENERAL AUTHENTICATE) sent directly to the smart card in the reader, for the PIV tokens
+//!   (a YubiKey or a PIV applet on another card) on the computers without the PKCS#11 module.
+//!
+//! The PIN is taken from the environment variable, never from the configuration.
+//! Only the ECC P-256 and P-384 keys are supported: TLS 1.3 signs with RSA-PSS, which the PIV tokens don't do.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::slot::Slot;
+use cryptoki::types::AuthPin;
+use lazy_static::lazy_static;
+use pcsc::{Card, Context, Protocols, Scope, ShareMode, MAX_BUFFER_SIZE};
+use rustls::client::ResolvesClientCert;
+use rustls::sign::{CertifiedKey, Signer, SigningKey};
+use rustls::{Certificate, SignatureAlgorithm, SignatureScheme};
+use sha2::{Digest, Sha256, Sha384};
+
+use crate::client_tls::{check_validity, read_der};
+use crate::config::HardwareTokenConfig;
+
+/// Select the PIV application by its AID.
+const SELECT_PIV_APDU: [u8; 10] = [0x00, 0xA4, 0x04, 0x00, 0x05, 0xA0, 0x00, 0x00, 0x03, 0x08];
+/// Object identifier of the ECC public key (1.2.840.10045.2.1) in the SubjectPublicKeyInfo of the certificate.
+const EC_PUBLIC_KEY_OID: [u8; 7] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+/// Object identifiers of the named curves: prime256v1 (1.2.840.10045.3.1.7) and secp384r1 (1.3.132.0.34).
+const P256_OID: [u8; 8] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const P384_OID: [u8; 5] = [0x2B, 0x81, 0x04, 0x00, 0x22];
+
+lazy_static! {
+    /// The loaded PKCS#11 modules by their path. The module is initialized once for the process:
+    /// finalizing it on every TLS configuration would break the connections which sign with it.
+    static ref PKCS11_MODULES: Mutex<HashMap<String, Pkcs11>> = Mutex::new(HashMap::new());
+}
+
+/// Curve of the key on the token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Curve {
+    P256,
+    P384,
+}
+
+impl Curve {
+    /// Detects the curve of the public key of the certificate, `None` if the key is not an ECC key on the supported curve.
+    ///
+    /// SubjectPublicKeyInfo ::= SEQUENCE { algorithm SEQUENCE { id-ecPublicKey, namedCurve }, subjectPublicKey }
+    fn of_certificate(der: &[u8]) -> Option<Self> {
+        let (_, algorithm, _) = read_der(subject_public_key_info(der)?)?;
+        let (tag, algorithm_id, parameters) = read_der(algorithm)?;
+        if tag != 0x06 || algorithm_id != EC_PUBLIC_KEY_OID {
+            return None;
+        }
+        let (tag, curve_id, _) = read_der(parameters)?;
+        if tag != 0x06 {
+            None
+        } else if curve_id == P256_OID {
+            Some(Curve::P256)
+        } else if curve_id == P384_OID {
+            Some(Curve::P384)
+        } else {
+            None
+        }
+    }
+
+    /// PIV algorithm identifier.
+    fn algorithm(self) -> u8 {
+        match self {
+            Curve::P256 => 0x11,
+            Curve::P384 => 0x14,
+        }
+    }
+
+    fn scheme(self) -> SignatureScheme {
+        match self {
+            Curve::P256 => SignatureScheme::ECDSA_NISTP256_SHA256,
+            Curve::P384 => SignatureScheme::ECDSA_NISTP384_SHA384,
+        }
+    }
+
+    fn digest(self, message: &[u8]) -> Vec<u8> {
+        match self {
+            Curve::P256 => Sha256::digest(message).to_vec(),
+            Curve::P384 => Sha384::digest(message).to_vec(),
+        }
+    }
+}
+
+/// Returns the SubjectPublicKeyInfo of the DER encoded x509 certificate.
+///
+/// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer, validity, subject,
+/// subjectPublicKeyInfo, ... }
+fn subject_public_key_info(der: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = read_der(der)?;
+    let (_, tbs, _) = read_der(certificate)?;
+
+    let (tag, _, mut rest) = read_der(tbs)?;
+    if tag != 0xA0 {
+        // There is no version, the first field is the serial number
+        rest = tbs;
+    }
+    for _ in 0..5 {
+        // serialNumber, signature, issuer, validity, subject
+        rest = read_der(rest)?.2;
+    }
+    let (tag, public_key_info, _) = read_der(rest)?;
+    (tag == 0x30).then_some(public_key_info)
+}
+
+/// Key of the bridge on the hardware token.
+struct TokenKey {
+    curve: Curve,
+    backend: Backend,
+}
+
+enum Backend {
+    Piv(PivToken),
+    Pkcs11(Pkcs11Token),
+}
+
+/// PIV token with the key of the bridge.
+#[derive(Debug)]
+struct PivToken {
+    reader: String,
+    /// Reference of the key, e.g. 0x9A.
+    key_reference: u8,
+    pin_env: Option<String>,
+}
+
+/// Token of the PKCS#11 module with the key of the bridge.
+struct Pkcs11Token {
+    context: Pkcs11,
+    slot: Slot,
+    /// CKA_ID of the certificate and of its private key.
+    id: Vec<u8>,
+    pin_env: Option<String>,
+    /// The login state is shared by all the sessions of the token, so the handshakes are signed one by one.
+    signing: Mutex<()>,
+}
+
+/// Returns the key reference and the certificate object of the PIV slot.
+fn slot_objects(slot: &str) -> Option<(u8, [u8; 3])> {
+    match slot.to_ascii_lowercase().as_str() {
+        "9a" => Some((0x9A, [0x5F, 0xC1, 0x05])),
+        "9c" => Some((0x9C, [0x5F, 0xC1, 0x0A])),
+        "9d" => Some((0x9D, [0x5F, 0xC1, 0x0B])),
+        "9e" => Some((0x9E, [0x5F, 0xC1, 0x01])),
+        _ => None,
+    }
+}
+
+/// Builds the resolver of the client certificate on the token for the TLS configuration.
+/// The certificate is read from the token, so the token must be present.
+///
+/// # Returns
+///
+/// * `Result<Arc<dyn ResolvesClientCert>, String>` - The resolver, or the error which is reported to the user.
+pub fn client_cert_resolver(config: &HardwareTokenConfig) -> Result<Arc<dyn ResolvesClientCert>, String> {
+    let (certificate, backend) = match &config.pkcs11_module {
+        Some(module) => {
+            let (certificate, token) = open_pkcs11_token(module, config)?;
+            log::info!("The client certificate of the mutual TLS is on the token of the PKCS#11 module {}", module);
+            (certificate, Backend::Pkcs11(token))
+        }
+        None => {
+            let (certificate, token) = open_piv_token(config)?;
+            log::info!("The client certificate of the mutual TLS is on the PIV token in the reader {}", config.reader);
+            (certificate, Backend::Piv(token))
+        }
+    };
+    check_validity(&certificate, chrono::Utc::now()).map_err(|e| format!("Certificate of the hardware token: {}", e))?;
+    let curve = Curve::of_certificate(&certificate)
+        .ok_or("The key of the hardware token is not supported, only the ECC P-256 and P-384 keys can be used")?;
+
+    Ok(Arc::new(TokenCertResolver(Arc::new(CertifiedKey::new(
+        vec![Certificate(certificate)],
+        Arc::new(TokenSigningKey(Arc::new(TokenKey { curve, backend }))),
+    )))))
+}
+
+fn open_piv_token(config: &HardwareTokenConfig) -> Result<(Vec<u8>, PivToken), String> {
+    if config.reader.is_empty() {
+        return Err("The reader of the hardware token is not set".to_string());
+    }
+    let (key_reference, object) =
+        slot_objects(&config.slot).ok_or_else(|| format!("Unknown PIV slot {} of the hardware token", config.slot))?;
+    let card = connect(&config.reader)?;
+    transmit(&card, &SELECT_PIV_APDU).map_err(|e| format!("The token in the reader {} is not a PIV token: {}", config.reader, e))?;
+    let certificate = read_certificate(&card, object)?;
+    let token = PivToken {
+        reader: config.reader.clone(),
+        key_reference,
+        pin_env: config.pin_env.clone(),
+    };
+    Ok((certificate, token))
+}
+
+/// Connects to the token in the first reader whose name contains the given part.
+fn connect(reader: &str) -> Result<Card, String> {
+    let context = Context::establish(Scope::User).map_err(|e| format!("Failed to establish the PC/SC context: {}", e))?;
+    let readers = context.list_readers_owned().map_err(|e| format!("Failed to list the readers: {}", e))?;
+    let name: CString = readers
+        .into_iter()
+        .find(|name| name.to_string_lossy().contains(reader))
+        .ok_or_else(|| format!("The reader {} of the hardware token is not found", reader))?;
+    context
+        .connect(&name, ShareMode::Shared, Protocols::ANY)
+        .map_err(|e| format!("Failed to connect to the hardware token in the reader {}: {}", reader, e))
+}
+
+/// Sends the command and returns the data of the response, the chained data (61xx) is collected with GET RESPONSE.
+fn transmit(card: &Card, apdu: &[u8]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    let mut command = apdu.to_vec();
+    loop {
+        let mut buffer = [0u8; MAX_BUFFER_SIZE];
+        let response = card.transmit(&command, &mut buffer).map_err(|e| e.to_string())?;
+        if response.len() < 2 {
+            return Err("the response is too short".to_string());
+        }
+        let (body, status) = response.split_at(response.len() - 2);
+        data.extend_from_slice(body);
+        match (status[0], status[1]) {
+            (0x90, 0x00) => return Ok(data),
+            (0x61, remaining) => command = vec![0x00, 0xC0, 0x00, 0x00, remaining],
+            (sw1, sw2) => return Err(format!("the token has responded with the status {:02X}{:02X}", sw1, sw2)),
+        }
+    }
+}
+
+/// Reads the certificate from the PIV data object.
+fn read_certificate(card: &Card, object: [u8; 3]) -> Result<Vec<u8>, String> {
+    let get_data = [0x00, 0xCB, 0x3F, 0xFF, 0x05, 0x5C, 0x03, object[0], object[1], object[2], 0x00];
+    let response = transmit(card, &get_data).map_err(|e| format!("Failed to read the certificate of the hardware token: {}", e))?;
+    parse_certificate_object(&response)
+}
+
+/// Returns the certificate of the PIV data object: 53 { 70 certificate, 71 certinfo, FE LRC }.
+fn parse_certificate_object(object: &[u8]) -> Result<Vec<u8>, String> {
+    let (_, mut fields, _) = read_der(object).ok_or("The certificate object of the hardware token can't be parsed")?;
+    let mut certificate = None;
+    while let Some((tag, value, rest)) = read_der(fields) {
+        match tag {
+            0x70 => certificate = Some(value.to_vec()),
+            0x71 if value.first().map_or(false, |info| *info != 0) => {
+                return Err("The compressed certificate of the hardware token is not supported".to_string())
+            }
+            _ => {}
+        }
+        fields = rest;
+    }
+    certificate.ok_or_else(|| "There is no certificate in the slot of the hardware token".to_string())
+}
+
+/// Builds GENERAL AUTHENTICATE of the digest: the dynamic authentication template requests the response (82)
+/// for the challenge (81).
+fn general_authenticate(curve: Curve, key_reference: u8, digest: &[u8]) -> Vec<u8> {
+    let mut template = vec![0x82, 0x00, 0x81, digest.len() as u8];
+    template.extend_from_slice(digest);
+    let mut command = vec![0x00, 0x87, curve.algorithm(), key_reference, template.len() as u8 + 2, 0x7C, template.len() as u8];
+    command.extend_from_slice(&template);
+    command.push(0x00);
+    command
+}
+
+/// Returns the signature of the response to GENERAL AUTHENTICATE: 7C { 82 signature }.
+fn parse_signature(response: &[u8]) -> Result<Vec<u8>, String> {
+    let (_, template, _) = read_der(response).ok_or("The signature of the hardware token can't be parsed")?;
+    let (tag, signature, _) = read_der(template).ok_or("The signature of the hardware token can't be parsed")?;
+    if tag != 0x82 {
+        return Err("There is no signature in the response of the hardware token".to_string());
+    }
+    Ok(signature.to_vec())
+}
+
+impl PivToken {
+    /// Signs the digest with the key of the token.
+    fn sign(&self, curve: Curve, digest: &[u8]) -> Result<Vec<u8>, String> {
+        let mut card = connect(&self.reader)?;
+        // The other applications don't select another application on the token in the middle of the signing
+        let transaction = card.transaction().map_err(|e| format!("Failed to lock the hardware token: {}", e))?;
+        transmit(&transaction, &SELECT_PIV_APDU)?;
+        if let Some(pin_env) = &self.pin_env {
+            let pin = read_pin(pin_env)?;
+            if pin.len() > 8 {
+                return Err("The PIN of the hardware token is longer than 8 digits".to_string());
+            }
+            let mut verify = vec![0x00, 0x20, 0x00, 0x80, 0x08];
+            verify.extend(pin.bytes().chain(std::iter::repeat(0xFF)).take(8));
+            transmit(&transaction, &verify).map_err(|e| format!("The PIN of the hardware token is rejected: {}", e))?;
+        }
+        let response = transmit(&transaction, &general_authenticate(curve, self.key_reference, digest))?;
+        parse_signature(&response)
+    }
+}
+
+fn read_pin(pin_env: &str) -> Result<String, String> {
+    std::env::var(pin_env).map_err(|_| format!("The PIN of the hardware token is not set in {}", pin_env))
+}
+
+/// Returns the PKCS#11 module, it is loaded and initialized on the first use.
+fn pkcs11_module(module: &str) -> Result<Pkcs11, String> {
+    let mut modules = PKCS11_MODULES.lock().unwrap();
+    if let Some(context) = modules.get(module) {
+        return Ok(context.clone());
+    }
+    let context = Pkcs11::new(module).map_err(|e| format!("Failed to load the PKCS#11 module {}: {}", module, e))?;
+    context
+        .initialize(CInitializeArgs::OsThreads)
+        .map_err(|e| format!("Failed to initialize the PKCS#11 module {}: {}", module, e))?;
+    modules.insert(module.to_string(), context.clone());
+    Ok(context)
+}
+
+/// Finds the certificate with its private key on the tokens of the PKCS#11 module: on the token with the
+/// `token_label` and with the `key_label` if they are set, otherwise the first certificate which has the key.
+fn open_pkcs11_token(module: &str, config: &HardwareTokenConfig) -> Result<(Vec<u8>, Pkcs11Token), String> {
+    let context = pkcs11_module(module)?;
+    let slots = context
+        .get_slots_with_token()
+        .map_err(|e| format!("Failed to list the tokens of the PKCS#11 module {}: {}", module, e))?;
+    for slot in slots {
+        if let Some(token_label) = &config.token_label {
+            let info = context.get_token_info(slot).map_err(|e| format!("Failed to read the PKCS#11 token: {}", e))?;
+            if info.label().trim() != token_label {
+                continue;
+            }
+        }
+        let session = open_session(&context, slot, config.pin_env.as_deref())?;
+        let mut template = vec![Attribute::Class(ObjectClass::CERTIFICATE)];
+        if let Some(key_label) = &config.key_label {
+            template.push(Attribute::Label(key_label.as_bytes().to_vec()));
+        }
+        let certificates = session
+            .find_objects(&template)
+            .map_err(|e| format!("Failed to find the certificates on the PKCS#11 token: {}", e))?;
+        for certificate in certificates {
+            let attributes = session
+                .get_attributes(certificate, &[AttributeType::Value, AttributeType::Id])
+                .map_err(|e| format!("Failed to read the certificate on the PKCS#11 token: {}", e))?;
+            let (mut value, mut id) = (None, None);
+            for attribute in attributes {
+                match attribute {
+                    Attribute::Value(bytes) => value = Some(bytes),
+                    Attribute::Id(bytes) => id = Some(bytes),
+                    _ => {}
+                }
+            }
+            let (value, id) = match (value, id) {
+                (Some(value), Some(id)) => (value, id),
+                _ => continue,
+            };
+            if find_private_key(&session, &id)?.is_some() {
+                let token = Pkcs11Token {
+                    context: context.clone(),
+                    slot,
+                    id,
+                    pin_env: config.pin_env.clone(),
+                    signing: Mutex::new(()),
+                };
+                return Ok((value, token));
+            }
+        }
+    }
+    Err(format!("There is no client certificate with its private key on the tokens of the PKCS#11 module {}", module))
+}
+
+/// Opens the session on the token and logs in with the PIN, the private keys are visible only after the login.
+fn open_session(context: &Pkcs11, slot: Slot, pin_env: Option<&str>) -> Result<Session, String> {
+    let session = context
+        .open_ro_session(slot)
+        .map_err(|e| format!("Failed to open the session on the PKCS#11 token: {}", e))?;
+    if let Some(pin_env) = pin_env {
+        session
+            .login(UserType::User, Some(&AuthPin::new(read_pin(pin_env)?)))
+            .map_err(|e| format!("The PIN of the hardware token is rejected: {}", e))?;
+    }
+    Ok(session)
+}
+
+fn find_private_key(session: &Session, id: &[u8]) -> Result<Option<ObjectHandle>, String> {
+    session
+        .find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY), Attribute::Id(id.to_vec())])
+        .map(|keys| keys.into_iter().next())
+        .map_err(|e| format!("Failed to find the private key on the PKCS#11 token: {}", e))
+}
+
+impl Pkcs11Token {
+    /// Signs the digest with the key of the token.
+    fn sign(&self, curve: Curve, digest: &[u8]) -> Result<Vec<u8>, String> {
+        let _signing = self.signing.lock().unwrap();
+        // The session is closed when it is dropped, the last closed session logs out
+        let session = open_session(&self.context, self.slot, self.pin_env.as_deref())?;
+        let key = find_private_key(&session, &self.id)?.ok_or("The private key has disappeared from the PKCS#11 token")?;
+        let signature = session
+            .sign(&Mechanism::Ecdsa, key, digest)
+            .map_err(|e| format!("Failed to sign with the PKCS#11 token: {}", e))?;
+        ecdsa_signature_der(curve, &signature)
+    }
+}
+
+/// Converts the PKCS#11 ECDSA signature (r || s) to the DER ECDSA-Sig-Value ::= SEQUENCE { r INTEGER, s INTEGER }
+/// of TLS.
+fn ecdsa_signature_der(curve: Curve, signature: &[u8]) -> Result<Vec<u8>, String> {
+    let size = match curve {
+        Curve::P256 => 32,
+        Curve::P384 => 48,
+    };
+    if signature.len() != size * 2 {
+        return Err(format!("The signature of the PKCS#11 token has the unexpected length {}", signature.len()));
+    }
+    let mut sequence = Vec::with_capacity(size * 2 + 6);
+    for integer in signature.chunks(size) {
+        let start = integer.iter().position(|byte| *byte != 0).unwrap_or(integer.len() - 1);
+        let integer = &integer[start..];
+        // The integer is positive, the leading zero keeps the high bit from being read as the sign
+        let padding = usize::from(integer[0] & 0x80 != 0);
+        sequence.push(0x02);
+        sequence.push((integer.len() + padding) as u8);
+        sequence.extend(std::iter::repeat(0x00).take(padding));
+        sequence.extend_from_slice(integer);
+    }
+    let mut der = vec![0x30, sequence.len() as u8];
+    der.extend(sequence);
+    Ok(der)
+}
+
+impl TokenKey {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let digest = self.curve.digest(message);
+        match &self.backend {
+            Backend::Piv(token) => token.sign(self.curve, &digest),
+            Backend::Pkcs11(token) => token.sign(self.curve, &digest),
+        }
+    }
+}
+
+/// The certificate on the token is always offered to the broker.
+struct TokenCertResolver(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for TokenCertResolver {
+    fn resolve(&self, _acceptable_issuers: &[&[u8]], sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        self.0.key.choose_scheme(sigschemes).map(|_| self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+struct TokenSigningKey(Arc<TokenKey>);
+
+impl SigningKey for TokenSigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        offered
+            .contains(&self.0.curve.scheme())
+            .then(|| Box::new(TokenSigner(self.0.clone())) as Box<dyn Signer>)
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ECDSA
+    }
+}
+
+struct TokenSigner(Arc<TokenKey>);
+
+impl Signer for TokenSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        self.0.sign(message).map_err(|e| {
+            log::error!("Failed to sign the TLS handshake with the hardware token: {}", e);
+            rustls::Error::General(e)
+        })
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.0.curve.scheme()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P256_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/p256.der");
+    const P384_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/p384.der");
+    /// RSA certificate with the prime256v1 OID in a private extension.
+    const RSA_CERTIFICATE: &[u8] = include_bytes!("../tests/certificates/rsa.der");
+
+    #[test]
+    fn detects_curve_of_public_key() {
+        assert_eq!(Curve::of_certificate(P256_CERTIFICATE), Some(Curve::P256));
+        assert_eq!(Curve::of_certificate(P384_CERTIFICATE), Some(Curve::P384));
+    }
+
+    #[test]
+    fn ignores_curve_oid_outside_public_key() {
+        assert_eq!(Curve::of_certificate(RSA_CERTIFICATE), None);
+    }
+
+    #[test]
+    fn rejects_truncated_certificate() {
+        assert_eq!(Curve::of_certificate(&P256_CERTIFICATE[..100]), None);
+        assert_eq!(Curve::of_certificate(&[]), None);
+    }
+
+    #[test]
+    fn maps_piv_slots() {
+        assert_eq!(slot_objects("9A"), Some((0x9A, [0x5F, 0xC1, 0x05])));
+        assert_eq!(slot_objects("9e"), Some((0x9E, [0x5F, 0xC1, 0x01])));
+        assert_eq!(slot_objects("82"), None);
+    }
+
+    #[test]
+    fn parses_certificate_object() {
+        let mut object = vec![0x53, 0x82, 0x00, 0x00, 0x70, 0x82];
+        object.extend((P256_CERTIFICATE.len() as u16).to_be_bytes());
+        object.extend_from_slice(P256_CERTIFICATE);
+        object.extend([0x71, 0x01, 0x00, 0xFE, 0x00]);
+        let length = (object.len() - 4) as u16;
+        object[2..4].copy_from_slice(&length.to_be_bytes());
+        assert_eq!(parse_certificate_object(&object), Ok(P256_CERTIFICATE.to_vec()));
+
+        // The compressed certificate
+        let last = object.len() - 3;
+        object[last] = 0x01;
+        assert!(parse_certificate_object(&object).is_err());
+    }
+
+    #[test]
+    fn builds_general_authenticate() {
+        let digest = [0xAB; 32];
+        let command = general_authenticate(Curve::P256, 0x9A, &digest);
+        assert_eq!(command[..9], [0x00, 0x87, 0x11, 0x9A, 0x26, 0x7C, 0x24, 0x82, 0x00]);
+        assert_eq!(command[9..11], [0x81, 0x20]);
+        assert_eq!(command[11..43], digest);
+        assert_eq!(command.len(), 44);
+    }
+
+    #[test]
+    fn parses_signature_response() {
+        assert_eq!(parse_signature(&[0x7C, 0x04, 0x82, 0x02, 0x30, 0x00]), Ok(vec![0x30, 0x00]));
+        assert!(parse_signature(&[0x7C, 0x04, 0x81, 0x02, 0x30, 0x00]).is_err());
+    }
+
+    #[test]
+    fn converts_pkcs11_signature_to_der() {
+        let mut signature = vec![0u8; 64];
+        signature[0] = 0x80;
+        signature[31] = 0x01;
+        signature[63] = 0x05;
+        let der = ecdsa_signature_der(Curve::P256, &signature).unwrap();
+        let mut expected = vec![0x30, 0x26, 0x02, 0x21, 0x00, 0x80];
+        expected.extend([0u8; 30]);
+        expected.extend([0x01, 0x02, 0x01, 0x05]);
+        assert_eq!(der, expected);
+        assert!(ecdsa_signature_der(Curve::P384, &signature).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! Module for the heartbeat of the bridge.
+//!
+//! The fleet operator monitors hundreds of bridges and can't ask the office staff what the application shows.
+//! Every application connection publishes the heartbeat to `<ident>/heartbeat`: the version of the application,
+//! the operating system, the uptime, the readers (without the cards of the other accounts) and the paired cards
+//! of the account with the states of their connections. The heartbeat is the `heartbeat` job of the scheduler,
+//! every `HEARTBEAT_INTERVAL_SECS` by default, the interval is changed or the heartbeat is turned off
+//! in the `scheduler` section of the configuration.
+//! The same data is returned by the `report_status` remote command.
+
+use serde::Serialize;
+
+use crate::config::{get_card_account, get_card_config_snapshot};
+use crate::connection_state::{ConnectionKind, ConnectionPhase};
+use crate::global_app_handle::StateReason;
+use crate::reader_pool::ReaderInfo;
+use crate::timestamp::Timestamp;
+
+/// Default interval in seconds of the heartbeat.
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 5 * 60;
+
+/// Heartbeat of the bridge for the account.
+#[derive(Serialize, Clone, Debug)]
+pub struct Heartbeat {
+    pub app_version: String,
+    pub os: String,
+    pub installation_id: String,
+    pub uptime_secs: u64,
+    pub timestamp: Timestamp,
+    pub readers: Vec<ReaderInfo>,
+    pub cards: Vec<CardHeartbeat>,
+}
+
+/// Paired card of the account in the heartbeat.
+#[derive(Serialize, Clone, Debug)]
+pub struct CardHeartbeat {
+    pub card_number: String,
+    /// The connection of the card is established.
+    pub online: bool,
+    /// State of the connection, `None` if the card is not connected (e.g. it is not in the reader).
+    pub state: Option<ConnectionPhase>,
+    pub reason: Option<StateReason>,
+}
+
+/// Collects the heartbeat of the account with the ident.
+pub fn heartbeat(ident: &str) -> Heartbeat {
+    let connections = crate::connection_state::snapshot();
+    let cards = get_card_config_snapshot()
+        .into_iter()
+        .filter(|entry| get_card_account(&entry.card_number).ident == ident)
+        .map(|entry| {
+            let connection = connections
+                .iter()
+                .find(|state| state.kind == ConnectionKind::Card && state.id == entry.card_number);
+            let state = connection.map(|connection| connection.state);
+            CardHeartbeat {
+                online: matches!(state, Some(ConnectionPhase::Connected | ConnectionPhase::Degraded)),
+                state,
+                reason: connection.and_then(|connection| connection.reason),
+                card_number: entry.card_number,
+            }
+        })
+        .collect();
+    Heartbeat {
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        os: std::env::consts::OS.to_string(),
+        installation_id: crate::installation::installation_id().to_string(),
+        uptime_secs: crate::stagger::uptime().as_secs(),
+        timestamp: Timestamp::now(),
+        // The bridge serving several accounts doesn't report the cards of one account to another
+        readers: crate::reader_pool::get_readers()
+            .into_iter()
+            .filter(|reader| reader.entry.card_number.is_empty() || get_card_account(&reader.entry.card_number).ident == ident)
+            .collect(),
+        cards,
+    }
+}
+
+/// Topic of the heartbeat of the account with the ident.
+pub fn heartbeat_topic(ident: &str) -> String {
+    format!("{}/heartbeat", ident)
+}
+
+/// Publishes the heartbeat with the established application connections.
+/// This function is a periodic job of the scheduler.
+pub fn publish_heartbeats() {
+    tauri::async_runtime::spawn(crate::app_connect::publish_heartbeats());
+}
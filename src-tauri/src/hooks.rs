@@ -0,0 +1,70 @@
+//! Module for the hooks of the MQTT connection lifecycle.
+//!
+//! The hooks are trait objects registered at the start of the application, before the connections are created.
+//! They are called for every card connection, so custom accounting or filtering of the requests
+//! can be added without changes in the `mqtt` module.
+
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+
+use crate::protocol::ApduRequest;
+
+/// Callbacks of the card connection lifecycle. All the methods do nothing by default.
+///
+/// The hooks are called from the connection tasks, so they must be fast and must not block.
+pub trait ConnectionHooks: Send + Sync {
+    /// The connection of the card to the server is established.
+    fn on_connection_established(&self, _cardnumber: &str) {}
+
+    /// The request is received from the server.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `false` to drop the request. The card is not accessed and no response is sent.
+    fn on_request_received(&self, _cardnumber: &str, _request: &ApduRequest) -> bool {
+        true
+    }
+
+    /// The response is sent to the server.
+    fn on_response_sent(&self, _cardnumber: &str, _topic: &str, _payload: &str) {}
+
+    /// The authentication session of the card is finished.
+    fn on_session_finished(&self, _cardnumber: &str, _apdu_count: u32) {}
+}
+
+lazy_static! {
+    static ref HOOKS: RwLock<Vec<Box<dyn ConnectionHooks>>> = RwLock::new(Vec::new());
+}
+
+/// Registers the hooks. The hooks are called in the order of the registration.
+pub fn register_hooks(hooks: Box<dyn ConnectionHooks>) {
+    HOOKS.write().unwrap().push(hooks);
+}
+
+pub fn connection_established(cardnumber: &str) {
+    for hooks in HOOKS.read().unwrap().iter() {
+        hooks.on_connection_established(cardnumber);
+    }
+}
+
+/// Returns `false` if any of the hooks drops the request.
+pub fn request_received(cardnumber: &str, request: &ApduRequest) -> bool {
+    HOOKS
+        .read()
+        .unwrap()
+        .iter()
+        .all(|hooks| hooks.on_request_received(cardnumber, request))
+}
+
+pub fn response_sent(cardnumber: &str, topic: &str, payload: &str) {
+    for hooks in HOOKS.read().unwrap().iter() {
+        hooks.on_response_sent(cardnumber, topic, payload);
+    }
+}
+
+pub fn session_finished(cardnumber: &str, apdu_count: u32) {
+    for hooks in HOOKS.read().unwrap().iter() {
+        hooks.on_session_finished(cardnumber, apdu_count);
+    }
+}
@@ -0,0 +1,67 @@
+//! Module for the installation ID.
+//!
+//! The ident is editable by the user and may be regenerated, so the support can't use it to correlate the reports
+//! from the same machine. The installation ID is a random UUID generated at the first run and kept in the data folder
+//! (not in the configuration, so it is not copied with the configuration to another machine). It is included
+//! in the card status messages, in the crash reports in the log and is shown in the UI for the support requests.
+
+use std::fs;
+
+use lazy_static::lazy_static;
+
+use crate::config::{get_data_dir, retry_io};
+
+/// Name of the file with the installation ID in the data folder.
+const INSTALLATION_ID_FILE_NAME: &str = "installation_id";
+
+lazy_static! {
+    static ref INSTALLATION_ID: String = load_or_create_installation_id();
+}
+
+/// Reads the installation ID from the data folder, or generates and saves a new one at the first run.
+/// If the ID can't be saved, the generated one is used until the application is closed.
+fn load_or_create_installation_id() -> String {
+    let path = match get_data_dir() {
+        Ok(dir) => dir.join(INSTALLATION_ID_FILE_NAME),
+        Err(e) => {
+            log::error!("Failed to get the data folder for the installation ID: {}", e);
+            return uuid::Uuid::new_v4().to_string();
+        }
+    };
+
+    if let Ok(contents) = retry_io(|| fs::read_to_string(&path)) {
+        match uuid::Uuid::parse_str(contents.trim()) {
+            Ok(id) => return id.to_string(),
+            Err(e) => log::warn!("Invalid installation ID in {}, a new one is generated: {}", path.display(), e),
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    match retry_io(|| fs::write(&path, &id)) {
+        Ok(_) => log::info!("New installation ID is generated: {}", id),
+        Err(e) => log::error!("Failed to save the installation ID to {}: {}", path.display(), e),
+    }
+    id
+}
+
+/// Returns the installation ID.
+pub fn installation_id() -> &'static str {
+    &INSTALLATION_ID
+}
+
+/// Returns the user properties of the CONNECT packets which identify the bridge instance:
+/// the version of the application, the operating system and the installation ID.
+pub fn bridge_properties() -> Vec<(String, String)> {
+    vec![
+        ("app_version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("os".to_string(), std::env::consts::OS.to_string()),
+        ("installation_id".to_string(), installation_id().to_string()),
+    ]
+}
+
+/// Public function to get the installation ID for the support requests.
+/// This function is a Tauri command that is called from the frontend.
+#[tauri::command]
+pub fn get_installation_id() -> String {
+    installation_id().to_string()
+}
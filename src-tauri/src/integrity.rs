@@ -0,0 +1,192 @@
+//! Module for the integrity check of the application.
+//!
+//! Some customers have the security policies for the software which handles the company cards: the installed
+//! application must be the one that was released. With the `integrity.enabled` setting the executable and
+//! the bundled resources are hashed at the start and compared with the manifest of the release, which is signed
+//! with the release key (Ed25519). The public key is compiled in from the `TBA_INTEGRITY_PUBLIC_KEY` environment
+//! variable (hex) of the release build, the builds without the key can't verify the manifest.
+//!
+//! The manifest is JSON with the SHA-256 (hex) of every file, by the path relative to the directory of
+//! the executable, and the signature (hex) of the `files` object serialized with the sorted keys:
+//!
+//! ```json
+//! { "files": { "tacho-bridge-application": "9f86d0...", "resources/known_atrs.yaml": "2c26b4..." }, "signature": "..." }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::get_integrity_config;
+use crate::global_app_handle::emit_notification;
+use crate::timestamp::Timestamp;
+
+/// Name of the manifest next to the executable.
+const MANIFEST_FILE_NAME: &str = "integrity.json";
+
+/// Public key of the release manifests, hex.
+const PUBLIC_KEY_HEX: Option<&str> = option_env!("TBA_INTEGRITY_PUBLIC_KEY");
+
+/// Manifest of the release.
+#[derive(Deserialize)]
+struct Manifest {
+    files: BTreeMap<String, String>,
+    signature: String,
+}
+
+/// Result of the integrity check.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegrityStatus {
+    /// The check is not enabled in the configuration.
+    Disabled,
+    /// The check is enabled but it has not finished yet.
+    Pending,
+    /// All files match the signed manifest.
+    Verified,
+    /// The manifest can't be verified or some files don't match it.
+    Failed,
+}
+
+/// Report of the integrity check for the diagnostics.
+#[derive(Serialize, Clone, Debug)]
+pub struct IntegrityReport {
+    pub status: IntegrityStatus,
+    pub checked_at: Option<Timestamp>,
+    /// Number of the verified files.
+    pub files: usize,
+    /// The files which are missing or don't match the manifest.
+    pub mismatched: Vec<String>,
+    /// Why the check has failed, if it is not about the files.
+    pub detail: Option<String>,
+}
+
+impl IntegrityReport {
+    fn new(status: IntegrityStatus) -> Self {
+        IntegrityReport {
+            status,
+            checked_at: None,
+            files: 0,
+            mismatched: Vec::new(),
+            detail: None,
+        }
+    }
+
+    fn failed(detail: String) -> Self {
+        IntegrityReport {
+            checked_at: Some(Timestamp::now()),
+            detail: Some(detail),
+            ..IntegrityReport::new(IntegrityStatus::Failed)
+        }
+    }
+}
+
+lazy_static! {
+    static ref REPORT: Mutex<IntegrityReport> = Mutex::new(IntegrityReport::new(IntegrityStatus::Disabled));
+}
+
+/// Starts the integrity check if it is enabled in the configuration. The files are hashed in the background,
+/// so the start of the application is not delayed.
+pub fn start_check() {
+    let config = get_integrity_config();
+    if !config.enabled {
+        return;
+    }
+    *REPORT.lock().unwrap() = IntegrityReport::new(IntegrityStatus::Pending);
+    std::thread::spawn(move || {
+        let report = check(config.manifest.map(PathBuf::from));
+        match report.status {
+            IntegrityStatus::Verified => log::info!("Integrity check: {} file(s) are verified", report.files),
+            _ => {
+                log::error!("Integrity check has failed: {:?}", report);
+                emit_notification(
+                    "error",
+                    "The integrity check of the application has failed. Reinstall the application from the official release.",
+                );
+            }
+        }
+        *REPORT.lock().unwrap() = report;
+    });
+}
+
+fn check(manifest_path: Option<PathBuf>) -> IntegrityReport {
+    let base_dir = match std::env::current_exe().map(|exe| exe.parent().map(Path::to_path_buf)) {
+        Ok(Some(base_dir)) => base_dir,
+        Ok(None) => return IntegrityReport::failed("The directory of the executable is unknown".to_string()),
+        Err(e) => return IntegrityReport::failed(format!("The executable is unknown: {}", e)),
+    };
+    let manifest_path = manifest_path.unwrap_or_else(|| base_dir.join(MANIFEST_FILE_NAME));
+    let manifest: Manifest = match std::fs::read(&manifest_path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+    {
+        Ok(manifest) => manifest,
+        Err(e) => return IntegrityReport::failed(format!("Failed to read the manifest {}: {}", manifest_path.display(), e)),
+    };
+    if let Err(e) = verify_signature(&manifest) {
+        return IntegrityReport::failed(e);
+    }
+
+    let mismatched: Vec<String> = manifest
+        .files
+        .iter()
+        .filter(|(path, expected)| match file_hash(&base_dir.join(path)) {
+            Ok(hash) => !hash.eq_ignore_ascii_case(expected),
+            Err(e) => {
+                log::warn!("Integrity check: failed to read {}: {}", path, e);
+                true
+            }
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    IntegrityReport {
+        status: if mismatched.is_empty() {
+            IntegrityStatus::Verified
+        } else {
+            IntegrityStatus::Failed
+        },
+        checked_at: Some(Timestamp::now()),
+        files: manifest.files.len() - mismatched.len(),
+        mismatched,
+        detail: None,
+    }
+}
+
+/// Verifies the signature of the files of the manifest with the compiled-in public key.
+fn verify_signature(manifest: &Manifest) -> Result<(), String> {
+    let public_key = PUBLIC_KEY_HEX.ok_or("The build has no public key to verify the manifest")?;
+    let public_key = hex::decode(public_key).map_err(|e| format!("Invalid public key: {}", e))?;
+    let signature = hex::decode(&manifest.signature).map_err(|e| format!("Invalid signature of the manifest: {}", e))?;
+    // The BTreeMap is serialized with the sorted keys, the same as it is signed
+    let message = serde_json::to_vec(&manifest.files).map_err(|e| e.to_string())?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(&message, &signature)
+        .map_err(|_| "The signature of the manifest is invalid".to_string())
+}
+
+fn file_hash(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Public function to get the result of the integrity check.
+/// This function is a Tauri command that is called from the diagnostics view of the frontend.
+#[tauri::command]
+pub fn get_integrity_report() -> IntegrityReport {
+    REPORT.lock().unwrap().clone()
+}
@@ -0,0 +1,167 @@
+//! Module for the table of the known tachograph card ATRs.
+//!
+//! People insert bank cards or SIM cards into the readers by mistake. Such a card doesn't match any ATR pattern
+//! of the tachograph cards, so the user gets the "probably not a tachograph card" warning instead of the confusing
+//! errors of the card identification. The built-in table is extended with the `known_atrs` patterns from
+//! the configuration, e.g. for the cards of a new issuer. The configured pattern may name the manufacturer and
+//! the model of the card, they are shown in the UI and the logs instead of the generic description.
+//!
+//! Pattern syntax: the ATR in hex (spaces are ignored), `.` matches any hex digit, `*` at the end matches the rest
+//! of the ATR.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::config::{get_known_atr_patterns, KnownAtrConfig};
+use crate::global_app_handle::emit_notification;
+
+/// Generation of the tachograph card.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CardGeneration {
+    /// First generation (Annex 1B).
+    Gen1,
+    /// Second generation, smart tachograph (Annex 1C).
+    Gen2,
+}
+
+impl std::fmt::Display for CardGeneration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CardGeneration::Gen1 => write!(f, "gen1"),
+            CardGeneration::Gen2 => write!(f, "gen2"),
+        }
+    }
+}
+
+struct KnownAtr {
+    pattern: &'static str,
+    generation: CardGeneration,
+    description: &'static str,
+}
+
+/// ATR patterns of the tachograph cards met in the field.
+const KNOWN_ATRS: &[KnownAtr] = &[
+    KnownAtr {
+        pattern: "3b9f96801fc.8031e073fe211b6*",
+        generation: CardGeneration::Gen1,
+        description: "Tachograph card, first generation",
+    },
+    KnownAtr {
+        pattern: "3b9f96c00a1f..8031e073fe211b6*",
+        generation: CardGeneration::Gen1,
+        description: "Tachograph card, first generation",
+    },
+    KnownAtr {
+        pattern: "3bff9600008131fe4380*",
+        generation: CardGeneration::Gen2,
+        description: "Tachograph card, second generation",
+    },
+    KnownAtr {
+        pattern: "3bff9600008131804380*",
+        generation: CardGeneration::Gen2,
+        description: "Tachograph card, second generation",
+    },
+];
+
+/// The known card the ATR belongs to.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct KnownCard {
+    /// The generation of the card, `None` if the ATR only matches the patterns from the configuration.
+    pub generation: Option<CardGeneration>,
+    /// The manufacturer of the card, if it is named in the configuration.
+    pub manufacturer: Option<String>,
+    pub description: String,
+}
+
+impl KnownCard {
+    /// The model of the card for the UI and the logs: the manufacturer and the description.
+    pub fn model(&self) -> String {
+        match &self.manufacturer {
+            Some(manufacturer) => format!("{} {}", manufacturer, self.description),
+            None => self.description.clone(),
+        }
+    }
+}
+
+lazy_static! {
+    /// ATRs of the unknown cards which have already been reported, so the warning is shown once per card.
+    static ref REPORTED_ATRS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Checks if the ATR matches the pattern (see the module description for the syntax).
+fn matches_pattern(pattern: &str, atr: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().filter(|c| !c.is_whitespace()).map(|c| c.to_ascii_lowercase()).collect();
+    let atr: Vec<char> = atr.chars().map(|c| c.to_ascii_lowercase()).collect();
+
+    let (pattern, prefix_only) = match pattern.split_last() {
+        Some(('*', rest)) => (rest, true),
+        _ => (&pattern[..], false),
+    };
+    if atr.len() < pattern.len() || (!prefix_only && atr.len() != pattern.len()) {
+        return false;
+    }
+    pattern.iter().zip(atr.iter()).all(|(p, a)| *p == '.' || p == a)
+}
+
+/// Finds the known card by the ATR.
+///
+/// The generation is taken from the built-in table, the manufacturer and the model from the configured pattern,
+/// so the configuration names the models of the cards which the built-in table only tells the generation of.
+///
+/// # Arguments
+///
+/// * `atr` - The ATR of the card in hex.
+///
+/// # Returns
+///
+/// * `Option<KnownCard>` - The card the ATR matches, or `None` if the ATR is unknown.
+pub fn find_known_card(atr: &str) -> Option<KnownCard> {
+    let built_in = KNOWN_ATRS.iter().find(|known| matches_pattern(known.pattern, atr));
+    let configured = get_known_atr_patterns()
+        .into_iter()
+        .find(|known| matches_pattern(known.pattern(), atr));
+
+    let (manufacturer, model) = match &configured {
+        Some(KnownAtrConfig::Card { manufacturer, model, .. }) => (manufacturer.clone(), model.clone()),
+        _ => (None, None),
+    };
+    let description = match (model, built_in, &configured) {
+        (Some(model), _, _) => model,
+        (None, Some(known), _) => known.description.to_string(),
+        (None, None, Some(known)) => format!("Card from the configuration ({})", known.pattern()),
+        (None, None, None) => return None,
+    };
+    Some(KnownCard {
+        generation: built_in.map(|known| known.generation),
+        manufacturer,
+        description,
+    })
+}
+
+/// Warns the user (once per card) that the inserted card is probably not a tachograph card.
+///
+/// # Arguments
+///
+/// * `atr` - The ATR of the card in hex.
+/// * `reader_label` - The reader the card is inserted to.
+pub fn warn_if_unknown_card(atr: &str, reader_label: &str) {
+    if atr.is_empty() || find_known_card(atr).is_some() {
+        return;
+    }
+    if !REPORTED_ATRS.lock().unwrap().insert(atr.to_string()) {
+        return;
+    }
+    log::warn!("The card with the unknown ATR {} is inserted into the reader {}", atr, reader_label);
+    emit_notification(
+        "warning",
+        &format!(
+            "The card in the reader {} is probably not a tachograph card (e.g. a bank or SIM card). \
+             If it is a company card, add its ATR to the known_atrs section of the configuration.",
+            reader_label
+        ),
+    );
+}
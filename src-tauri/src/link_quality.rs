@@ -0,0 +1,206 @@
+//! Module for the adaptive status traffic on the constrained links.
+//!
+//! The bridges on the mobile or satellite links lose the connection often, and the status messages of the cards
+//! compete with the APDU responses for the narrow link. The quality of the link is estimated from the reconnects
+//! and the round-trip time of the keep-alive PINGs of the card connections. While the link is constrained,
+//! the status messages are published with QoS 0 and no more often than `CONSTRAINED_STATUS_INTERVAL_SECS`
+//! per card (except the start and the finish of the authentication and the status after the connection),
+//! the APDU responses are not affected.
+//! The defaults are restored when the link is stable for `STABLE_WINDOW_SECS`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::mqtt_client::QoS;
+
+/// Period of the reconnects and the PINGs the quality of the link is estimated for.
+const STABLE_WINDOW_SECS: u64 = 600;
+
+/// Number of the reconnects within the window which makes the link constrained.
+const MAX_RECONNECTS: usize = 3;
+
+/// Round-trip time of the PING which makes the link constrained.
+const MAX_PING_RTT_MS: u64 = 2000;
+
+/// Reconnects of the cards within this interval are counted as one reconnect of the link.
+const RECONNECT_DEDUP_SECS: u64 = 5;
+
+/// Minimum interval between the status messages of the card while the link is constrained.
+const CONSTRAINED_STATUS_INTERVAL_SECS: u64 = 60;
+
+/// Mode of the status traffic.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkMode {
+    /// The status messages are published with QoS 1 on every change.
+    Normal,
+    /// The status messages are published with QoS 0 and their interval is lengthened.
+    Constrained,
+}
+
+/// The last status message of the card.
+struct StatusSent {
+    at: Instant,
+    session_active: bool,
+}
+
+struct LinkQuality {
+    mode: LinkMode,
+    /// The last time the link has been constrained.
+    since: Instant,
+    reconnects: VecDeque<Instant>,
+    /// The last PINGs with their round-trip times.
+    pings: VecDeque<(Instant, Duration)>,
+    statuses: HashMap<String, StatusSent>,
+}
+
+impl LinkQuality {
+    fn evict(&mut self, now: Instant) {
+        let window = Duration::from_secs(STABLE_WINDOW_SECS);
+        while self.reconnects.front().map_or(false, |at| now.duration_since(*at) > window) {
+            self.reconnects.pop_front();
+        }
+        while self.pings.front().map_or(false, |(at, _)| now.duration_since(*at) > window) {
+            self.pings.pop_front();
+        }
+    }
+
+    fn average_rtt(&self) -> Option<Duration> {
+        if self.pings.is_empty() {
+            return None;
+        }
+        Some(self.pings.iter().map(|(_, rtt)| *rtt).sum::<Duration>() / self.pings.len() as u32)
+    }
+
+    /// Updates the mode by the reconnects and the PINGs within the window.
+    fn update(&mut self, now: Instant) {
+        self.evict(now);
+        let constrained = self.reconnects.len() >= MAX_RECONNECTS
+            || self.average_rtt().map_or(false, |rtt| rtt >= Duration::from_millis(MAX_PING_RTT_MS));
+        let mode = match (self.mode, constrained) {
+            (_, true) => LinkMode::Constrained,
+            // The link is stable for the whole window since it became constrained
+            (LinkMode::Constrained, false) if now.duration_since(self.since) < Duration::from_secs(STABLE_WINDOW_SECS) => {
+                LinkMode::Constrained
+            }
+            (_, false) => LinkMode::Normal,
+        };
+        if mode != self.mode {
+            log::warn!("The link to the broker is {:?}, the status messages are adapted", mode);
+            self.mode = mode;
+        }
+        if mode == LinkMode::Constrained && constrained {
+            self.since = now;
+        }
+    }
+}
+
+lazy_static! {
+    static ref LINK_QUALITY: Mutex<LinkQuality> = Mutex::new(LinkQuality {
+        mode: LinkMode::Normal,
+        since: Instant::now(),
+        reconnects: VecDeque::new(),
+        pings: VecDeque::new(),
+        statuses: HashMap::new(),
+    });
+}
+
+/// Records the reconnect of the card, the frequent reconnects make the link constrained.
+pub fn record_reconnect() {
+    let now = Instant::now();
+    let mut quality = LINK_QUALITY.lock().unwrap();
+    let duplicate = quality
+        .reconnects
+        .back()
+        .map_or(false, |at| now.duration_since(*at) < Duration::from_secs(RECONNECT_DEDUP_SECS));
+    if !duplicate {
+        quality.reconnects.push_back(now);
+    }
+    quality.update(now);
+}
+
+/// Records the round-trip time of the keep-alive PING.
+pub fn record_ping(rtt: Duration) {
+    let now = Instant::now();
+    let mut quality = LINK_QUALITY.lock().unwrap();
+    quality.pings.push_back((now, rtt));
+    quality.update(now);
+}
+
+/// Checks if the link is constrained at the moment.
+pub fn is_constrained() -> bool {
+    let mut quality = LINK_QUALITY.lock().unwrap();
+    quality.update(Instant::now());
+    quality.mode == LinkMode::Constrained
+}
+
+/// Returns the QoS of the status messages in the current mode.
+pub fn status_qos() -> QoS {
+    let mut quality = LINK_QUALITY.lock().unwrap();
+    quality.update(Instant::now());
+    match quality.mode {
+        LinkMode::Normal => QoS::AtLeastOnce,
+        LinkMode::Constrained => QoS::AtMostOnce,
+    }
+}
+
+/// Checks if the status message of the card has to be published in the current mode.
+/// The start and the finish of the authentication are always published.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The number of the card.
+/// * `session_active` - The authentication of the card is in progress.
+pub fn should_publish_status(cardnumber: &str, session_active: bool) -> bool {
+    let now = Instant::now();
+    let mut quality = LINK_QUALITY.lock().unwrap();
+    quality.update(now);
+    let mode = quality.mode;
+    let publish = match quality.statuses.get(cardnumber) {
+        Some(sent) if mode == LinkMode::Constrained && sent.session_active == session_active => {
+            now.duration_since(sent.at) >= Duration::from_secs(CONSTRAINED_STATUS_INTERVAL_SECS)
+        }
+        _ => true,
+    };
+    if publish {
+        quality
+            .statuses
+            .insert(cardnumber.to_string(), StatusSent { at: now, session_active });
+    }
+    publish
+}
+
+/// Forgets the last status message of the card, so the next one is published in any mode.
+/// Called on the connection, as the last will of the card has replaced its retained status.
+pub fn reset_status(cardnumber: &str) {
+    LINK_QUALITY.lock().unwrap().statuses.remove(cardnumber);
+}
+
+/// Quality of the link for the diagnostics.
+#[derive(Serialize, Clone, Debug)]
+pub struct LinkQualityReport {
+    pub mode: LinkMode,
+    /// Reconnects of the cards within the window.
+    pub reconnects: usize,
+    /// Average round-trip time of the PINGs within the window, `None` if there were no PINGs.
+    pub average_ping_ms: Option<u64>,
+    pub window_secs: u64,
+}
+
+/// Public function to get the quality of the link and the mode of the status traffic.
+/// This function is a Tauri command that is called from the diagnostics view of the frontend.
+#[tauri::command]
+pub fn get_link_quality() -> LinkQualityReport {
+    let mut quality = LINK_QUALITY.lock().unwrap();
+    quality.update(Instant::now());
+    LinkQualityReport {
+        mode: quality.mode,
+        reconnects: quality.reconnects.len(),
+        average_ping_ms: quality.average_rtt().map(|rtt| rtt.as_millis() as u64),
+        window_secs: STABLE_WINDOW_SECS,
+    }
+}
@@ -1,47 +1,38 @@
-use std::env;
 // use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use fern;
-use log;
+/// Maximum size of the log file. The bigger file is renamed to `log.old.txt` at the start,
+/// so at most two log files are kept on unattended installations.
+const MAX_LOG_FILE_SIZE: u64 = 20 * 1024 * 1024;
 
 /// Sets up logging for the application.
 ///
-/// This function configures the logging system using the `fern` crate. It sets the log file path
-/// based on the operating system and initializes the logging format and level.
+/// This function configures the logging system using the `fern` crate. The log file is created
+/// in the log folder (see `config::get_log_dir`) and the logging format and level are initialized.
 ///
 /// # Platform-specific behavior
 ///
-/// * On macOS, the log file is created in the `~/Documents/tba` directory.
-/// * On Windows, the log file is created in the `%USERPROFILE%\Documents\tba` directory.
+/// * On Linux, the log file is created in the `$XDG_STATE_HOME/tba` (`~/.local/state/tba`) directory by default.
+/// * On macOS, the log file is created in the `~/Library/Application Support/tba` directory by default.
+/// * On Windows, the log file is created in the `%LOCALAPPDATA%\tba` directory by default.
 pub fn setup_logging() {
-    let mut log_path = PathBuf::new();
+    let mut log_path: PathBuf = match crate::config::get_log_dir() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Failed to create log directory: {}", e);
+            return;
+        }
+    };
 
-    #[cfg(target_os = "macos")]
-    {
-        log_path.push(env::var("HOME").unwrap());
-        log_path.push("Documents");
-        log_path.push("tba");
-    }
-    #[cfg(target_os = "linux")]
-    {
-        log_path.push(env::var("HOME").unwrap());
-        log_path.push("Documents");
-        log_path.push("tba");
-    }
-    #[cfg(target_os = "windows")]
-    {
-        log_path.push(env::var("USERPROFILE").unwrap());
-        log_path.push("Documents");
-        log_path.push("tba");
-    }
-
-    if let Err(e) = std::fs::create_dir_all(&log_path) {
-        eprintln!("Failed to create log directory: {}", e);
-        return;
-    }
-
-    log_path.push("log.txt");
+    log_path.push(crate::config::LOG_FILE_NAME);
+    rotate_log_file(&log_path);
+    let log_file = match crate::config::retry_io(|| fern::log_file(&log_path)) {
+        Ok(log_file) => log_file,
+        Err(e) => {
+            eprintln!("Failed to open the log file {}: {}", log_path.display(), e);
+            return;
+        }
+    };
 
     if let Err(e) = fern::Dispatch::new()
         .format(|out, message, record| {
@@ -54,9 +45,24 @@ pub fn setup_logging() {
             ))
         })
         .level(log::LevelFilter::Debug)  // For debugging it is needed to set up 'Debug' filter level
-        .chain(fern::log_file(log_path).unwrap())
+        .chain(log_file)
         .apply()
     {
         eprintln!("Failed to initialize logging: {}", e);
     }
 }
+
+/// Renames the log file to `log.old.txt` (replacing the previous one) if it is bigger than `MAX_LOG_FILE_SIZE`.
+fn rotate_log_file(log_path: &Path) {
+    let size = match std::fs::metadata(log_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return,
+    };
+    if size <= MAX_LOG_FILE_SIZE {
+        return;
+    }
+    let old_path = log_path.with_file_name(crate::config::OLD_LOG_FILE_NAME);
+    if let Err(e) = crate::config::retry_io(|| std::fs::rename(log_path, &old_path)) {
+        eprintln!("Failed to rotate the log file: {}", e);
+    }
+}
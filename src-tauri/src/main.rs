@@ -1,11 +1,54 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 // Module imports
+mod apdu_trace; // APDU traces of the cards.
 mod app_connect;
+mod atr; // Parsing of the card ATRs.
+mod auto_resync; // Automatic resync of the readers with the failing cards.
+mod broadcast; // LAN broadcast of the card states.
+mod card_certificates; // Local check of the card certificates.
+mod card_identification; // Identification data of the tachograph cards.
+mod card_init; // Initialization of the inserted cards out of the monitor loop.
+mod card_lookup; // Lookup of the cards by the number or the ICCID.
+mod client_tls; // TLS connections to the broker.
 mod config; // Configuration handling.
+mod connection_state; // Live states of the connections to the broker.
+mod connection_stats; // Statistics of the card connections.
+mod config_writer; // Serialized changes of the configuration file.
+mod deep_link; // Handling of the tba:// links.
+mod diagnostics; // Network diagnostics of the broker connection.
+mod error_help; // Help on the error codes.
+mod event_store; // Bounded stores of the events, notifications and statistics.
+mod fault_injection; // Fault injection for the QA builds.
+mod hardware_token; // Client certificates on the hardware tokens.
+mod heartbeat; // Heartbeat of the bridge for the fleet monitoring.
+mod hooks; // Hooks of the MQTT connection lifecycle.
+mod installation; // Machine-unique installation ID.
+mod integrity; // Integrity check of the executable and the resources.
+mod known_cards; // Known tachograph card ATRs.
+mod link_quality; // Adaptive status traffic on the constrained links.
 mod logger; // Logging functionality.
+mod maintenance; // Maintenance windows announced by the server.
+mod migration; // Migration of the bridge to another machine.
 mod mqtt; // MQTT communication.
+mod mqtt_client; // MQTT client of both protocol versions.
+mod multiplex; // Connection shared by the cards.
+mod outbox; // Offline buffering of the APDU responses.
+mod preview; // Dry-run of the destructive actions.
+mod protocol; // Parsing of the server requests.
+mod proxy; // Connections to the broker through the proxy.
+mod reader_debounce; // Debouncing of the reader state changes.
+mod reader_pool; // Readers with the inserted cards.
+mod remote_commands; // Remote commands of the application connection.
+mod scheduler; // Periodic jobs.
+mod server_redirect; // Redirects of the broker to another server.
+mod security_log; // Tamper-evident log of the remote interactions.
+mod simulated_card; // Simulated reader for the development without the hardware.
+mod single_instance; // Forwarding of the command line to the running instance.
 mod smart_card; // PCSC module for smart card operations. // Application connection to the MQTT broker.
+mod stagger; // Staggering of the card connections.
+mod timestamp; // Time values in the emitted payloads.
+mod topics; // Topics of the card connections.
 
 // External crate imports
 use tauri::{async_runtime, Manager, WindowEvent}; // Tauri application framework and async runtime.
@@ -16,9 +59,35 @@ fn main() {
     // Initialize logging. This function configures the logging system using the `fern` crate.
     // need to debug later. Add checking for the init result
     //
+    // Move the data from the legacy Documents folder before the log file is opened
+    let migration = config::migrate_legacy_data_dir();
+    // On Linux the configuration and the logs are kept in the XDG base directories
+    let xdg_migration = config::migrate_to_xdg_dirs();
     logger::setup_logging();
     // Log the application launch
     log::info!("-== Application is launched ==-");
+    // The instance started with a tba:// link while the application is running forwards the link to it and exits
+    if single_instance::forward_to_running_instance() {
+        log::info!("-== The command line is forwarded to the running instance ==-\n");
+        return;
+    }
+    log::info!("Installation ID: {}", installation::installation_id());
+    // Crash reports in the log are correlated by the installation ID
+    std::panic::set_hook(Box::new(|info| {
+        log::error!("Application panicked (installation {}): {}", installation::installation_id(), info);
+        eprintln!("{}", info);
+    }));
+    match migration {
+        Ok(Some(data_dir)) => log::info!("The data is migrated to {}", data_dir.display()),
+        Ok(None) => {}
+        Err(e) => log::error!("{}", e),
+    }
+    match xdg_migration {
+        Ok(true) => log::info!("The configuration and the logs are moved to the XDG base directories"),
+        Ok(false) => {}
+        Err(e) => log::error!("{}", e),
+    }
+    config::warn_if_cloud_synced();
 
     // Initialize configuration. This function reads the configuration file and initializes the configuration structure.
     // The configuration file is located in the `assets` directory and is named `config.yaml`.
@@ -29,6 +98,25 @@ fn main() {
         }
     }
 
+    // The startup window of the connection staggering is counted from here
+    stagger::mark_startup();
+    integrity::start_check();
+
+    // Hooks of the card connections, registered before the connections are created
+    hooks::register_hooks(Box::new(event_store::StatisticsHooks));
+    hooks::register_hooks(Box::new(apdu_trace::ApduTraceHooks));
+
+    // Periodic jobs, run by the scheduler task
+    scheduler::register_job("store_compaction", event_store::COMPACTION_INTERVAL_SECS, event_store::compact);
+    scheduler::register_job("security_log_retention", security_log::RETENTION_INTERVAL_SECS, security_log::apply_retention);
+    scheduler::register_job("connection_stats", connection_stats::CONNECTION_STATS_INTERVAL_SECS, connection_stats::emit_stats);
+    scheduler::register_job("heartbeat", heartbeat::HEARTBEAT_INTERVAL_SECS, heartbeat::publish_heartbeats);
+    scheduler::register_job("card_expiry", card_identification::EXPIRY_CHECK_INTERVAL_SECS, card_identification::check_expiry);
+
+    // Register the tba:// links and check if the application is opened with one of them
+    deep_link::register_url_scheme();
+    deep_link::handle_args();
+
     // start builder to run tauri applicationrustup target add aarch64-pc-windows-msvc
     tauri::Builder::default()
         .setup(|app| {
@@ -37,6 +125,8 @@ fn main() {
 
             // Initialize the global application handle
             global_app_handle::set_app_handle(app.handle());
+            // The instances started while the application is running forward their command line here
+            single_instance::listen(app.handle());
 
             if let Some(window) = app.get_window("main") {
                 // getting Application version foriom the Cargo.toml file
@@ -61,6 +151,9 @@ fn main() {
                     }
 
                     println!("Received event with payload: {:?}", event.payload());
+                    // The frontend is ready to receive events, re-send the card states which could have been lost
+                    global_app_handle::set_frontend_ready();
+
                     // Load server configuration from cache to frontend using event
                     match config::emit_global_config_server(&front_app_handle) {
                         Ok(_) => {
@@ -71,38 +164,102 @@ fn main() {
                         }
                     }
 
-                    // Run async function in the background with the Tauri runtime
-                    // let app_handle_for_sc_monitor = app_handle.clone();
-                    async_runtime::spawn(async {
-                        /*
-                            This slip is needed as a temporary solution. Fix it later!
-                            The fact is that the back-end starts faster than the front, and the sent event with card data arrives at the front-end before it has time to load.
-                            *** In the near future, I will add a flag for the state of readiness to receive events from the backend. ***
-                        */
-                        // Start monitoring smart cards. This function will run forever with the loop
-                        smart_card::sc_monitor().await;
-                    });
+                    // Load the settings of all the cards to the frontend in one event
+                    if let Err(e) = config::emit_card_config_snapshot(&front_app_handle) {
+                        log::error!("Failed to emit the card config snapshot: {:?}", e);
+                    }
+
+                    // Ask the user to confirm the action from the deep link the application is opened with
+                    deep_link::request_confirmation(&front_app_handle);
                 });
 
                 // Handle the application close event to log this.
                 window.on_window_event(move |event| {
                     if let WindowEvent::CloseRequested { .. } = event {
                         log::info!("-== Application is closed by user ==-\n");
+                        // The PC/SC context of the monitor is released before the exit
+                        smart_card::stop_monitor();
                     }
                 });
             }
 
+            // The cards are bridged without waiting for the frontend, as the webview may never load (seen on Linux).
+            // The card states and the notifications are kept until the frontend-ready handshake.
+            async_runtime::spawn(async {
+                // Start monitoring smart cards. This function runs until the monitor is stopped
+                smart_card::sc_monitor().await;
+            });
+
+            async_runtime::spawn(async {
+                // Report the frontend which has not loaded in time
+                global_app_handle::watch_frontend_load().await;
+            });
+
             async_runtime::spawn(async {
                 // Start Main MQTT App client connection
                 app_connect::app_connection().await;
             });
 
+            async_runtime::spawn(async {
+                // Start running the periodic jobs
+                scheduler::start_scheduler().await;
+            });
+
+            async_runtime::spawn(async {
+                // Apply the changes of the configuration file made outside of the application
+                config::watch_config_file().await;
+            });
+
+            async_runtime::spawn(async {
+                // Start broadcasting the card states to the local network (if enabled in the config)
+                broadcast::start_broadcast().await;
+            });
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             config::update_card,           // update list of cards from the frontend
             config::update_server,         // update server config from the frontend
+            config::remove_card,           // remove the card from the configuration
+            config::get_card_config_snapshot, // settings of all the cards in one snapshot
+            config::get_config,            // the whole configuration without the credentials
+            config::set_card_availability, // pause, disable or activate the card
+            config::export_config,         // save the configuration for another PC
+            config::import_config,         // merge or replace the configuration with the exported one
+            config::list_config_backups,   // backups of the configuration for the rollback
+            config::restore_config_backup, // roll the configuration back to the backup
             smart_card::manual_sync_cards, // manual sync cards from the frontend
+            deep_link::confirm_deep_link,  // confirm or reject the action from the tba:// link
+            security_log::verify_security_log, // check the integrity of the security log
+            smart_card::refresh_iccid,     // re-read the ICCID of the card
+            smart_card::get_reader_atr,    // ATR of the card in the reader for the diagnostics
+            apdu_trace::export_apdu_trace, // commands of the card for the investigation of the failed authentication
+            event_store::get_store_sizes,  // sizes of the event stores for the diagnostics
+            event_store::get_event_history, // history of the card states, notifications and statistics
+            reader_pool::get_readers,      // readers with the cards for the diagnostics
+            preview::preview_remove_card,  // what the removal of the card would affect
+            preview::preview_server_change, // what the change of the server would affect
+            installation::get_installation_id, // installation ID for the support requests
+            card_lookup::lookup_card,      // search of the card by the number or the ICCID
+            card_lookup::find_card_by_iccid, // number of the card with the ICCID
+            scheduler::list_scheduled_jobs, // periodic jobs for the diagnostics
+            diagnostics::run_network_diagnostics, // step-by-step check of the broker connection
+            diagnostics::validate_server, // check of the server address before it is saved
+            global_app_handle::subscribe_events,   // receive only the displayed event categories
+            global_app_handle::unsubscribe_events, // stop receiving the event categories
+            integrity::get_integrity_report, // result of the integrity check for the diagnostics
+            link_quality::get_link_quality, // mode of the status traffic for the diagnostics
+            fault_injection::set_fault_injection, // faults injected in the QA builds
+            fault_injection::get_fault_injection, // current faults of the QA builds
+            error_help::get_error_help, // cause and remediation of the error code
+            connection_stats::get_connection_stats, // health of the card connections
+            connection_state::watch_connection_state, // live states of the connections for the indicators
+            card_identification::get_card_details, // identification data of the cards read from the chips
+            card_certificates::get_card_certificates, // local check of the card certificates
+            migration::export_migration_archive, // encrypted archive for the new machine
+            migration::import_migration_archive, // take over the bridge from the old machine
+            migration::get_tombstone,      // why the cards are not bridged after the migration
+            migration::reactivate_bridge,  // bridge the cards again on the old machine
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,108 @@
+//! Module for the maintenance windows announced by the server.
+//!
+//! The server may send a "maintenance" message on the app connection to announce planned backend downtime.
+//! During the window the UI shows a banner and the reconnect errors are not reported as warnings,
+//! because the connection losses are expected. Normal alerting resumes when the window ends.
+
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::global_app_handle::emit_global_event;
+use crate::timestamp::Timestamp;
+
+/// Name of the event that shows/hides the maintenance banner in the UI.
+const MAINTENANCE_EVENT: &str = "global-maintenance-banner";
+
+/// Planned backend downtime. The time is in Unix seconds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceWindow {
+    pub start: i64,
+    pub end: i64,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl MaintenanceWindow {
+    fn contains(&self, timestamp: i64) -> bool {
+        self.start <= timestamp && timestamp < self.end
+    }
+}
+
+lazy_static! {
+    static ref MAINTENANCE: Mutex<Option<MaintenanceWindow>> = Mutex::new(None);
+}
+
+/// Checks if the backend is in the announced maintenance window right now.
+pub fn is_maintenance_active() -> bool {
+    let now = chrono::Utc::now().timestamp();
+    MAINTENANCE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|window| window.contains(now))
+        .unwrap_or(false)
+}
+
+/// Handles the "maintenance" message from the server.
+///
+/// The message is `{"maintenance": {"start": <unix time>, "end": <unix time>, "message": "..."}}`.
+/// `{"maintenance": null}` cancels the announced window.
+pub fn handle_maintenance_message(value: &Value) {
+    if value.is_null() {
+        log::info!("Maintenance window is cancelled by the server");
+        *MAINTENANCE.lock().unwrap() = None;
+        emit_banner(None);
+        return;
+    }
+
+    let window: MaintenanceWindow = match serde_json::from_value(value.clone()) {
+        Ok(window) => window,
+        Err(e) => {
+            log::error!("Invalid maintenance message: {}", e);
+            return;
+        }
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if window.end <= now {
+        log::debug!("Maintenance window {:?} is already over", window);
+        return;
+    }
+
+    log::info!("Maintenance window is announced: {:?}", window);
+    *MAINTENANCE.lock().unwrap() = Some(window.clone());
+    emit_banner(Some(&window));
+
+    // Hide the banner when the window is over, unless it has been replaced by another one
+    tauri::async_runtime::spawn(async move {
+        let remaining = (window.end - chrono::Utc::now().timestamp()).max(0) as u64;
+        tokio::time::sleep(std::time::Duration::from_secs(remaining)).await;
+
+        let mut maintenance = MAINTENANCE.lock().unwrap();
+        if maintenance.as_ref() == Some(&window) {
+            log::info!("Maintenance window is over, normal alerting is resumed");
+            *maintenance = None;
+            emit_banner(None);
+        }
+    });
+}
+
+/// Shows the maintenance banner in the UI, or hides it if there is no window.
+fn emit_banner(window: Option<&MaintenanceWindow>) {
+    let payload = match window {
+        Some(window) => serde_json::json!({
+            "active": true,
+            "start": Timestamp::from_epoch(window.start),
+            "end": Timestamp::from_epoch(window.end),
+            "message": window.message,
+        }),
+        None => serde_json::json!({ "active": false }),
+    };
+
+    if let Err(e) = emit_global_event(MAINTENANCE_EVENT, payload) {
+        log::warn!("Failed to emit the maintenance banner: {}", e);
+    }
+}
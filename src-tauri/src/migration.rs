@@ -0,0 +1,386 @@
+//! Module for the migration of the bridge to another machine.
+//!
+//! When the office replaces the computer with the readers, the configuration is moved to the new one with an
+//! encrypted archive: the configuration (with the ident and the cards), the ICCIDs read from the cards and the
+//! statistics of the card connections. The archive is encrypted with AES-256-GCM, the key is derived from the password
+//! entered by the user with PBKDF2, as the archive has the credentials of the broker.
+//!
+//! Both machines connect the same cards with the same client IDs, so they would disconnect each other. After the
+//! import the new machine publishes the retained tombstone of the old installation
+//! (`tba/installations/<installation ID>/tombstone`) with its application connection. The old machine is subscribed
+//! to its tombstone: it stops bridging the cards, keeps the tombstone in its data folder, so the cards are not
+//! connected after the restart either, and tells the user. The bridging is reactivated with `reactivate_bridge`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{get_data_dir, retry_io, validate_config, ConfigurationFile};
+use crate::config_writer::{ConfigMutation, ConfigYaml};
+use crate::connection_stats::ConnectionStats;
+use crate::global_app_handle::emit_notification;
+use crate::installation::installation_id;
+use crate::mqtt_client::{MqttClient, QoS};
+use crate::timestamp::Timestamp;
+
+/// Beginning of the archive file, also authenticated with the encrypted contents.
+const ARCHIVE_MAGIC: &[u8] = b"TBA-MIGRATION-1";
+const SALT_LENGTH: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 200_000;
+/// Version of the archive contents.
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Name of the file with the tombstone of this installation in the data folder.
+const TOMBSTONE_FILE_NAME: &str = "tombstone.json";
+/// Name of the file with the installations whose tombstones have to be published by this one.
+const PENDING_TOMBSTONES_FILE_NAME: &str = "pending_tombstones.json";
+
+/// Contents of the migration archive.
+#[derive(Serialize, Deserialize)]
+struct MigrationArchive {
+    version: u32,
+    created_at: Timestamp,
+    app_version: String,
+    /// Installation the archive is exported from, it is deactivated by the import.
+    installation_id: String,
+    config: String,
+    known_iccids: HashMap<String, String>,
+    connection_stats: BTreeMap<String, ConnectionStats>,
+}
+
+/// The installation has been moved to another machine.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tombstone {
+    /// Installation ID of the new machine.
+    pub moved_to: String,
+    pub moved_at: Timestamp,
+}
+
+/// Result of the import for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportSummary {
+    pub exported_at: Timestamp,
+    pub source_installation_id: String,
+    pub cards: usize,
+}
+
+lazy_static! {
+    static ref TOMBSTONE: Mutex<Option<Tombstone>> = Mutex::new(load_tombstone());
+}
+
+/// The tombstone of this installation has to be cleared on the broker (see `reactivate_bridge`).
+static CLEAR_TOMBSTONE: AtomicBool = AtomicBool::new(false);
+
+/// Topic of the tombstone of the installation.
+fn tombstone_topic(installation_id: &str) -> String {
+    format!("tba/installations/{}/tombstone", installation_id)
+}
+
+/// Topic of the tombstone of this installation, the application connections subscribe to it.
+pub fn own_tombstone_topic() -> String {
+    tombstone_topic(installation_id())
+}
+
+fn load_tombstone() -> Option<Tombstone> {
+    let path = get_data_dir().ok()?.join(TOMBSTONE_FILE_NAME);
+    let contents = retry_io(|| fs::read_to_string(&path)).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(tombstone) => Some(tombstone),
+        Err(e) => {
+            log::warn!("Invalid tombstone in {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Checks if the bridging is deactivated, because the installation has been moved to another machine.
+pub fn is_tombstoned() -> bool {
+    TOMBSTONE.lock().unwrap().is_some()
+}
+
+/// Derives the key of the archive from the password.
+fn archive_key(password: &str, salt: &[u8]) -> Result<LessSafeKey, String> {
+    let mut key = [0u8; 32];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).expect("The number of the iterations is not zero");
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut key);
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| "Failed to create the key of the archive".to_string())?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Encrypts the contents: the magic, the salt, the nonce and the encrypted contents with the tag.
+fn encrypt(contents: &[u8], password: &str) -> Result<Vec<u8>, String> {
+    let random = SystemRandom::new();
+    let mut salt = [0u8; SALT_LENGTH];
+    let mut nonce = [0u8; NONCE_LEN];
+    random.fill(&mut salt).map_err(|_| "Failed to generate the salt".to_string())?;
+    random.fill(&mut nonce).map_err(|_| "Failed to generate the nonce".to_string())?;
+
+    let mut encrypted = contents.to_vec();
+    archive_key(password, &salt)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(ARCHIVE_MAGIC), &mut encrypted)
+        .map_err(|_| "Failed to encrypt the archive".to_string())?;
+
+    let mut archive = ARCHIVE_MAGIC.to_vec();
+    archive.extend_from_slice(&salt);
+    archive.extend_from_slice(&nonce);
+    archive.extend(encrypted);
+    Ok(archive)
+}
+
+/// Decrypts the archive, the wrong password and the damaged archive can't be told apart.
+fn decrypt(archive: &[u8], password: &str) -> Result<Vec<u8>, String> {
+    let rest = archive
+        .strip_prefix(ARCHIVE_MAGIC)
+        .ok_or("The file is not a migration archive of the bridge")?;
+    if rest.len() < SALT_LENGTH + NONCE_LEN {
+        return Err("The migration archive is damaged".to_string());
+    }
+    let (salt, rest) = rest.split_at(SALT_LENGTH);
+    let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "The migration archive is damaged".to_string())?;
+
+    let mut contents = encrypted.to_vec();
+    let length = archive_key(password, salt)?
+        .open_in_place(nonce, Aad::from(ARCHIVE_MAGIC), &mut contents)
+        .map_err(|_| "The password is wrong or the migration archive is damaged".to_string())?
+        .len();
+    contents.truncate(length);
+    Ok(contents)
+}
+
+/// Public function to export the migration archive to move the bridge to another machine.
+/// This function is a Tauri command that is called from the frontend.
+///
+/// # Arguments
+///
+/// * `path` - The path of the archive file.
+/// * `password` - The password the archive is encrypted with.
+#[tauri::command]
+pub fn export_migration_archive(path: String, password: String) -> Result<(), String> {
+    if password.is_empty() {
+        return Err("The password of the migration archive is empty".to_string());
+    }
+    let archive = MigrationArchive {
+        version: ARCHIVE_VERSION,
+        created_at: Timestamp::now(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        installation_id: installation_id().to_string(),
+        config: crate::config::export_config_yaml().map_err(|e| format!("Failed to read the configuration: {}", e))?,
+        known_iccids: crate::smart_card::known_iccids(),
+        connection_stats: crate::connection_stats::get_connection_stats(),
+    };
+    let contents = serde_json::to_vec(&archive).map_err(|e| e.to_string())?;
+    let encrypted = encrypt(&contents, &password)?;
+    retry_io(|| fs::write(&path, &encrypted)).map_err(|e| format!("Failed to write the migration archive: {}", e))?;
+    log::info!("The migration archive is exported to {}", path);
+    Ok(())
+}
+
+/// Public function to import the migration archive exported on another machine.
+/// This function is a Tauri command that is called from the frontend. The configuration is replaced, the cards
+/// are connected with it, and the old machine is deactivated with the tombstone.
+///
+/// # Arguments
+///
+/// * `path` - The path of the archive file.
+/// * `password` - The password the archive is encrypted with.
+///
+/// # Returns
+///
+/// * `Result<ImportSummary, String>` - What is imported, or the error for the user.
+#[tauri::command]
+pub async fn import_migration_archive(path: String, password: String) -> Result<ImportSummary, String> {
+    let encrypted = retry_io(|| fs::read(&path)).map_err(|e| format!("Failed to read the migration archive: {}", e))?;
+    let contents = decrypt(&encrypted, &password)?;
+    let archive: MigrationArchive =
+        serde_json::from_slice(&contents).map_err(|e| format!("The migration archive can't be read: {}", e))?;
+    if archive.version > ARCHIVE_VERSION {
+        return Err(format!("The migration archive is exported by the newer version {}", archive.app_version));
+    }
+    if archive.installation_id == installation_id() {
+        return Err("The migration archive is exported on this machine".to_string());
+    }
+
+    let config: ConfigurationFile = serde_yaml::from_str(&archive.config)
+        .map_err(|e| format!("The configuration of the migration archive can't be read: {}", e))?;
+    validate_config(&config)?;
+    crate::config_writer::apply(ConfigMutation::ReplaceConfig {
+        yaml: ConfigYaml(archive.config),
+    })
+    .await?;
+    // The tombstone is published when the application connection is established with the imported configuration
+    add_pending_tombstone(&archive.installation_id)?;
+    for (cardnumber, iccid) in archive.known_iccids.iter() {
+        crate::smart_card::remember_iccid(cardnumber, iccid);
+    }
+    crate::connection_stats::restore(archive.connection_stats);
+    log::info!(
+        "The migration archive of the installation {} exported at {} is imported",
+        archive.installation_id,
+        archive.created_at.iso
+    );
+
+    crate::app_connect::apply_account_changes().await;
+    // The bridge is taken over anyway, the errors of the PC/SC service are logged by the sync
+    let _ = crate::smart_card::manual_sync_cards().await;
+    Ok(ImportSummary {
+        exported_at: archive.created_at,
+        source_installation_id: archive.installation_id,
+        cards: crate::config::get_card_config_snapshot().len(),
+    })
+}
+
+fn read_pending_tombstones() -> Vec<String> {
+    let path = match get_data_dir() {
+        Ok(dir) => dir.join(PENDING_TOMBSTONES_FILE_NAME),
+        Err(_) => return Vec::new(),
+    };
+    retry_io(|| fs::read_to_string(&path))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn write_pending_tombstones(installations: &[String]) -> Result<(), String> {
+    let path = get_data_dir().map_err(|e| e.to_string())?.join(PENDING_TOMBSTONES_FILE_NAME);
+    if installations.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        };
+    }
+    let contents = serde_json::to_string(installations).map_err(|e| e.to_string())?;
+    retry_io(|| fs::write(&path, &contents)).map_err(|e| format!("Failed to save the pending tombstones: {}", e))
+}
+
+fn add_pending_tombstone(installation: &str) -> Result<(), String> {
+    let mut pending = read_pending_tombstones();
+    if !pending.iter().any(|pending| pending == installation) {
+        pending.push(installation.to_string());
+    }
+    write_pending_tombstones(&pending)
+}
+
+/// Publishes the tombstones of the migrated installations and clears the tombstone of the reactivated one.
+/// Called by the application connection when it is established.
+pub async fn publish_tombstones(client: &MqttClient) {
+    let pending = read_pending_tombstones();
+    let mut failed = Vec::new();
+    for installation in pending {
+        let tombstone = Tombstone {
+            moved_to: installation_id().to_string(),
+            moved_at: Timestamp::now(),
+        };
+        let payload = serde_json::to_string(&tombstone).unwrap_or_default();
+        match client.publish(tombstone_topic(&installation), QoS::AtLeastOnce, true, payload).await {
+            Ok(_) => log::info!("The installation {} is deactivated, it has been moved to this machine", installation),
+            Err(e) => {
+                log::warn!("Failed to publish the tombstone of the installation {}: {:?}", installation, e);
+                failed.push(installation);
+            }
+        }
+    }
+    if let Err(e) = write_pending_tombstones(&failed) {
+        log::warn!("{}", e);
+    }
+
+    if CLEAR_TOMBSTONE.load(Ordering::SeqCst) {
+        match client.publish(own_tombstone_topic(), QoS::AtLeastOnce, true, String::new()).await {
+            Ok(_) => CLEAR_TOMBSTONE.store(false, Ordering::SeqCst),
+            Err(e) => log::warn!("Failed to clear the tombstone of this installation: {:?}", e),
+        }
+    }
+}
+
+/// Handles the message on the tombstone topic of this installation: the bridging is stopped.
+pub async fn handle_tombstone_message(payload: &[u8]) {
+    // The empty retained message clears the tombstone
+    if payload.is_empty() || CLEAR_TOMBSTONE.load(Ordering::SeqCst) {
+        return;
+    }
+    let tombstone: Tombstone = match serde_json::from_slice(payload) {
+        Ok(tombstone) => tombstone,
+        Err(e) => {
+            log::warn!("Invalid tombstone message: {}", e);
+            return;
+        }
+    };
+    if tombstone.moved_to == installation_id() || is_tombstoned() {
+        return;
+    }
+
+    log::warn!(
+        "The bridge has been moved to the installation {} at {}, the cards are not bridged on this machine anymore",
+        tombstone.moved_to,
+        tombstone.moved_at.iso
+    );
+    match get_data_dir() {
+        Ok(dir) => {
+            let contents = serde_json::to_string(&tombstone).unwrap_or_default();
+            if let Err(e) = retry_io(|| fs::write(dir.join(TOMBSTONE_FILE_NAME), &contents)) {
+                log::error!("Failed to save the tombstone: {}", e);
+            }
+        }
+        Err(e) => log::error!("Failed to get the data folder for the tombstone: {}", e),
+    }
+    *TOMBSTONE.lock().unwrap() = Some(tombstone);
+
+    let cards: Vec<String> = crate::smart_card::TASK_POOL
+        .lock()
+        .await
+        .iter()
+        .map(|task| task.client_id.clone())
+        .collect();
+    crate::mqtt::remove_connections(cards).await;
+    emit_notification(
+        "warning",
+        "The bridge has been moved to another machine, the cards are not bridged on this one anymore.",
+    );
+}
+
+/// Public function to get the tombstone of this installation, `None` if the bridging is active.
+/// This function is a Tauri command that is called from the frontend to show why the cards are not bridged.
+#[tauri::command]
+pub fn get_tombstone() -> Option<Tombstone> {
+    TOMBSTONE.lock().unwrap().clone()
+}
+
+/// Public function to reactivate the bridging on the machine the bridge has been moved from.
+/// This function is a Tauri command that is called from the frontend, e.g. when the migration is rolled back.
+#[tauri::command]
+pub async fn reactivate_bridge() -> Result<(), String> {
+    let path = get_data_dir().map_err(|e| e.to_string())?.join(TOMBSTONE_FILE_NAME);
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(format!("Failed to remove the tombstone: {}", e)),
+        _ => {}
+    }
+    CLEAR_TOMBSTONE.store(true, Ordering::SeqCst);
+    *TOMBSTONE.lock().unwrap() = None;
+    log::info!("The bridging is reactivated on this machine");
+    // The retained tombstone is cleared now, or when the application connection is established again
+    crate::app_connect::publish_tombstones().await;
+    // The bridging is reactivated anyway, the errors of the PC/SC service are logged by the sync
+    let _ = crate::smart_card::manual_sync_cards().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_is_decrypted_with_the_same_password_only() {
+        let archive = encrypt(b"host: mqtt.flespi.io", "secret").unwrap();
+        assert_eq!(decrypt(&archive, "secret").unwrap(), b"host: mqtt.flespi.io");
+        assert!(decrypt(&archive, "wrong").is_err());
+        assert!(decrypt(b"config.yaml", "secret").is_err());
+    }
+}
@@ -3,28 +3,29 @@
 //! This module provides functionality for creating and managing MQTT connections.
 
 // Standard library imports
+use std::collections::HashMap; // For the registry of the active sessions.
 use std::ffi::CStr; // For handling C-style strings in Rust.
+use std::sync::Arc; // For the requests waiting for the card, shared with the task pool.
+use std::time::Instant; // For measuring the time of waiting for the card.
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}; // Messages to the publisher task of the card.
+use tokio::sync::watch; // Settings of the card for the publisher task.
 use std::io::ErrorKind;
 use std::time::Duration; // For specifying time durations. // For categorizing I/O errors.
 
 // MQTT client library imports
-use rumqttc::v5::mqttbytes::QoS; // Quality of Service levels for MQTT.
-use rumqttc::v5::ConnectionError; // For handling MQTT connection errors.
-use rumqttc::v5::StateError::{self, AwaitPingResp, ServerDisconnect};
-use rumqttc::v5::{AsyncClient, Event, Incoming, MqttOptions}; // Core MQTT async client and options. // Specific error for server disconnection.
+use crate::mqtt_client::{create_client, ConnectionErrorKind, MqttClient, MqttEvent, MqttOptions, QoS}; // MQTT client of both protocol versions.
 // use rumqttc::{Transport, TlsConfiguration};
 
 // use native_tls::TlsConnector;
 
+use lazy_static::lazy_static;
 use pcsc::Disposition;
-use pcsc::Protocols;
 use pcsc::ShareMode;
 
 // Tauri application framework imports
 use tauri::async_runtime::{self, JoinHandle}; // Async runtime and task join handles for Tauri apps.
 
 // Serialization/Deserialization library imports
-use serde_json::Value; // For working with JSON data structures.
 
 /// Timeout in seconds to wait before reconnecting to the server.
 ///
@@ -32,22 +33,400 @@ use serde_json::Value; // For working with JSON data structures.
 /// to the MQTT server in case of connection loss.
 const SLEEP_DURATION_SECS: u64 = 10;
 
+/// Time in seconds after which the server may retry the request if the card is not present.
+const ABSENT_CARD_RETRY_AFTER_SECS: u64 = 5;
+
+/// Time in seconds the request waits for the card in the `Hold` mode, if it is not set for the card.
+const ABSENT_CARD_HOLD_SECS: u64 = 5;
+
+/// Interval in milliseconds of the checks if the card is back for the requests waiting for it.
+const CARD_CHECK_INTERVAL_MS: u64 = 500;
+
+/// Time in seconds after which the server may retry the request to the paused card, if it is not set for the card.
+const PAUSED_CARD_RETRY_AFTER_SECS: u64 = 3600;
+
+/// Maximum number of requests kept while the card is not present (see `AbsentCardBehavior`).
+const MAX_QUEUED_REQUESTS: usize = 16;
+
+/// Prefix of the status topic of the card: `<prefix>/<card number>/status`.
+const CARD_STATUS_TOPIC_PREFIX: &str = "tba/cards";
+
+/// Interval of the checks if the card is idle long enough to be powered off (see `PowerSavingConfig`).
+const IDLE_CHECK_INTERVAL_SECS: u64 = 30;
+
+/// Interval of the checks if the authentication session is abandoned by the tracker (see `get_session_timeout`).
+const SESSION_CHECK_INTERVAL_SECS: u64 = 5;
+
+/// Time in seconds the connection task is given to send the DISCONNECT before it is aborted.
+const DISCONNECT_TIMEOUT_SECS: u64 = 3;
+
+/// Duration of the authentication session used for the wait estimation until the first session is measured.
+const DEFAULT_SESSION_DURATION_SECS: u64 = 30;
+
+lazy_static! {
+    /// Cards with the authentication session in progress, with the topic of the request that started the session.
+    static ref ACTIVE_SESSIONS: std::sync::Mutex<HashMap<String, String>> = std::sync::Mutex::new(HashMap::new());
+}
+
+/// Returns the topic of the request that started the authentication session of the card,
+/// or `None` if there is no authentication in progress.
+pub fn get_active_session(cardnumber: &str) -> Option<String> {
+    ACTIVE_SESSIONS.lock().unwrap().get(cardnumber).cloned()
+}
+
+/// Authentication session of the card, published on the status topic so the server can route
+/// the authentication requests to the least busy company card.
+struct SessionInfo {
+    cardnumber: String,
+    /// Topic of the request that started the session (identifies the tracker).
+    tracker: Option<String>,
+    started: Option<Instant>,
+    /// Number of APDU commands in the session.
+    apdu_count: u32,
+    /// Average duration of the finished sessions.
+    average_duration: Duration,
+}
+
+impl SessionInfo {
+    fn new(cardnumber: &str) -> Self {
+        SessionInfo {
+            cardnumber: cardnumber.to_string(),
+            tracker: None,
+            started: None,
+            apdu_count: 0,
+            average_duration: Duration::from_secs(DEFAULT_SESSION_DURATION_SECS),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.started.is_some()
+    }
+
+    fn start(&mut self, tracker: &str) {
+        self.tracker = Some(tracker.to_string());
+        self.started = Some(Instant::now());
+        self.apdu_count = 0;
+        ACTIVE_SESSIONS.lock().unwrap().insert(self.cardnumber.clone(), tracker.to_string());
+    }
+
+    fn finish(&mut self) {
+        if let Some(started) = self.started.take() {
+            // Moving average, so the estimation follows the current network conditions
+            self.average_duration = (self.average_duration * 3 + started.elapsed()) / 4;
+        }
+        self.tracker = None;
+        self.apdu_count = 0;
+        ACTIVE_SESSIONS.lock().unwrap().remove(&self.cardnumber);
+    }
+
+    /// Estimated time until the card is free for a new session.
+    fn estimated_wait(&self, queue_length: usize) -> Duration {
+        let remaining = self
+            .started
+            .map(|started| self.average_duration.saturating_sub(started.elapsed()))
+            .unwrap_or_default();
+        remaining + self.average_duration * queue_length as u32
+    }
+}
+
+/// Request received while the card was not in the reader, it is processed when the card is inserted back.
+pub struct WaitingRequest {
+    /// Topic of the request (identifies the tracker).
+    topic: String,
+    topic_ack: String,
+    hex_value: String,
+    /// The held request is rejected at this time, the queued one waits until the card is back
+    /// (see `AbsentCardBehavior`).
+    deadline: Option<Instant>,
+}
+
+/// Requests waiting for the card. They are shared with the task pool, so the requests are answered
+/// when the connection is removed before the card is back (see `disconnect_task`).
+pub type WaitingRequests = Arc<std::sync::Mutex<Vec<WaitingRequest>>>;
+
+fn waiting_count(waiting_requests: &WaitingRequests) -> usize {
+    waiting_requests.lock().unwrap().len()
+}
+
+/// Takes the oldest request waiting for the card.
+fn next_waiting_request(waiting_requests: &WaitingRequests) -> Option<WaitingRequest> {
+    let mut waiting_requests = waiting_requests.lock().unwrap();
+    (!waiting_requests.is_empty()).then(|| waiting_requests.remove(0))
+}
+
+/// Takes the held requests whose card is not back in time.
+fn take_expired_requests(waiting_requests: &WaitingRequests, now: Instant) -> Vec<WaitingRequest> {
+    let mut waiting_requests = waiting_requests.lock().unwrap();
+    let (expired, waiting) = std::mem::take(&mut *waiting_requests)
+        .into_iter()
+        .partition(|request| request.deadline.map_or(false, |deadline| deadline <= now));
+    *waiting_requests = waiting;
+    expired
+}
+
+/// Keeps the request to the card which is not in the reader according to the absent card behavior of the card.
+///
+/// # Returns
+///
+/// * `bool` - Whether the request waits for the card, otherwise it is answered with "card not present".
+fn keep_waiting_request(
+    waiting_requests: &WaitingRequests,
+    card_config: Option<&CardConfig>,
+    topic: &str,
+    topic_ack: &str,
+    hex_value: &str,
+    log_header: &str,
+) -> bool {
+    let behavior = card_config.map(|card_config| card_config.absent_card).unwrap_or_default();
+    log::warn!(
+        "{} Request is received while the card is not present. Behavior: {:?}",
+        log_header,
+        behavior
+    );
+    let deadline = match behavior {
+        AbsentCardBehavior::Reject => return false,
+        AbsentCardBehavior::Hold => {
+            let hold_secs = card_config.and_then(|card_config| card_config.hold_secs).unwrap_or(ABSENT_CARD_HOLD_SECS);
+            Some(Instant::now() + Duration::from_secs(hold_secs))
+        }
+        AbsentCardBehavior::Queue => None,
+    };
+    let mut waiting_requests = waiting_requests.lock().unwrap();
+    if waiting_requests.len() >= MAX_QUEUED_REQUESTS {
+        log::warn!("{} The queue of requests is full, rejecting the request", log_header);
+        return false;
+    }
+    waiting_requests.push(WaitingRequest {
+        topic: topic.to_string(),
+        topic_ack: topic_ack.to_string(),
+        hex_value: hex_value.to_string(),
+        deadline,
+    });
+    true
+}
+
+/// Topic of the status messages of the card.
+fn card_status_topic(cardnumber: &str) -> String {
+    format!("{}/{}/status", CARD_STATUS_TOPIC_PREFIX, cardnumber)
+}
+
+/// Status message the broker publishes on behalf of the card when the application crashes or loses the network,
+/// so the server learns the card went offline. It replaces the retained status of the card.
+fn card_last_will(cardnumber: &str) -> String {
+    serde_json::json!({
+        "card": cardnumber,
+        "installation_id": crate::installation::installation_id(),
+        "online": false,
+    })
+    .to_string()
+}
+
+/// Publishes the session and queue state of the card on its status topic.
+/// The message is retained, so the server gets the current state right after subscribing.
+fn publish_card_status(publisher: &CardPublisher, cardnumber: &str, session: &SessionInfo, queue_length: usize) {
+    // The status is not published on every change while the link is constrained
+    if !crate::link_quality::should_publish_status(cardnumber, session.is_active()) {
+        log::debug!("{} | The status is not published, the link is constrained", cardnumber);
+        return;
+    }
+    let mut payload = serde_json::json!({
+        "card": cardnumber,
+        "installation_id": crate::installation::installation_id(),
+        "online": true,
+        "session_active": session.is_active(),
+        "tracker": session.tracker,
+        "queue_length": queue_length,
+        "estimated_wait": session.estimated_wait(queue_length).as_secs(),
+        "updated_at": Timestamp::now(),
+    });
+    let card_config = get_card_config(cardnumber);
+    let atr = card_config.as_ref().and_then(|card_config| get_disclosed_atr(cardnumber, &card_config.atr));
+    if let Some(atr) = atr {
+        payload["atr"] = serde_json::Value::String(atr);
+    }
+    // The server learns that the card is taken offline before it sends the requests
+    if let Some(availability) = card_config.map(|card_config| card_config.availability) {
+        payload["availability"] = serde_json::json!(availability);
+    }
+    if let Some(pin_policy) = crate::card_identification::pin_policy(cardnumber) {
+        payload["pin_policy"] = serde_json::json!(pin_policy);
+    }
+    // The server of the customers with the security policies learns if the application is the released one
+    let integrity = crate::integrity::get_integrity_report().status;
+    if integrity != crate::integrity::IntegrityStatus::Disabled {
+        payload["integrity"] = serde_json::json!(integrity);
+    }
+    publisher.send(Outgoing::Retained {
+        topic: card_status_topic(cardnumber),
+        payload: payload.to_string(),
+        description: "card status",
+    });
+}
+
+/// Request of the card connection, sent by the publisher task of the card (see `CardPublisher`).
+enum Outgoing {
+    /// Response to the request, it is kept in the outbox if the publish fails.
+    Response { topic: String, payload: String },
+    /// Retained message of the card (e.g. the status), with its description for the log.
+    Retained {
+        topic: String,
+        payload: String,
+        description: &'static str,
+    },
+    /// Subscription to the requests of the card.
+    Subscribe(String),
+}
+
+/// Publisher of the messages of the card connection.
+///
+/// The requests of the MQTT client wait for the room in the channel of the event loop, and the room is made
+/// only while the event loop is polled. The card task polls the event loop itself, so it would wait forever
+/// if it published more messages at once than the channel holds (e.g. the waiting requests processed when
+/// the card is back, or the outbox flushed at the CONNACK). The card task only queues the messages here,
+/// and the publisher task awaits the client.
+#[derive(Clone)]
+struct CardPublisher {
+    sender: UnboundedSender<Outgoing>,
+}
+
+impl CardPublisher {
+    /// Spawns the publisher task of the card. The task ends when the card task drops its publisher.
+    /// The messages are published with the QoS of the current settings of the card.
+    fn spawn(mqtt_client: MqttClient, cardnumber: String, outbox: SharedOutbox, card_config: watch::Receiver<Option<CardConfig>>) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        async_runtime::spawn(run_publisher(mqtt_client, cardnumber, outbox, card_config, receiver));
+        CardPublisher { sender }
+    }
+
+    fn send(&self, outgoing: Outgoing) {
+        // The publisher task lives as long as the card task, so the message is not lost here
+        let _ = self.sender.send(outgoing);
+    }
+}
+
+/// Publishes the messages of the card in the order they are queued.
+async fn run_publisher(
+    mqtt_client: MqttClient,
+    cardnumber: String,
+    outbox: SharedOutbox,
+    card_config: watch::Receiver<Option<CardConfig>>,
+    mut receiver: UnboundedReceiver<Outgoing>,
+) {
+    while let Some(outgoing) = receiver.recv().await {
+        let qos = card_qos(card_config.borrow().as_ref());
+        match outgoing {
+            Outgoing::Response { topic, payload } => {
+                match mqtt_client.publish(topic.clone(), qos, false, payload.clone()).await {
+                    Ok(_) => crate::hooks::response_sent(&cardnumber, &topic, &payload),
+                    Err(e) => {
+                        log::error!("{} | Error sending the response, it is kept in the outbox: {:?}", cardnumber, e);
+                        if !outbox.lock().unwrap().push(topic, payload) {
+                            log::warn!("{} | The outbox is full, the oldest response is dropped", cardnumber);
+                        }
+                    }
+                }
+            }
+            Outgoing::Retained { topic, payload, description } => {
+                let qos = crate::link_quality::status_qos();
+                if let Err(e) = mqtt_client.publish(topic, qos, true, payload).await {
+                    log::error!("{} | Failed to publish the {}: {:?}", cardnumber, description, e);
+                }
+            }
+            Outgoing::Subscribe(subscription) => {
+                if let Err(e) = mqtt_client.subscribe(subscription.clone(), qos).await {
+                    log::error!("{} | Failed to subscribe to {}: {:?}", cardnumber, subscription, e);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the QoS of the requests and the responses of the card (see `CardConfig::qos`).
+fn card_qos(card_config: Option<&CardConfig>) -> QoS {
+    match card_config.and_then(|card_config| card_config.qos) {
+        Some(0) => QoS::AtMostOnce,
+        Some(2) => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+/// Returns the user properties of the CONNECT packet of the card: the bridge properties, the reader,
+/// the ATR (as it is disclosed for the card), the card number and the generation of the card.
+fn card_properties(cardnumber: &str, reader_name: &CStr, atr: &str) -> Vec<(String, String)> {
+    let mut properties = crate::installation::bridge_properties();
+    properties.push(("reader".to_string(), reader_name.to_string_lossy().into()));
+    if let Some(atr) = get_disclosed_atr(cardnumber, atr) {
+        properties.push(("atr".to_string(), atr));
+    }
+    properties.push(("card".to_string(), cardnumber.to_string()));
+    if let Some(card_generation) = card_generation(cardnumber, atr) {
+        properties.push(("card_generation".to_string(), card_generation.to_string()));
+    }
+    properties
+}
+
+/// Returns the generation of the card: the one detected by the card initialization, otherwise the one of its ATR.
+/// The server runs the authentication of the generation, the commands of Gen1 and Gen2 cards differ.
+fn card_generation(cardnumber: &str, atr: &str) -> Option<CardGeneration> {
+    crate::reader_pool::find_card(cardnumber)
+        .and_then(|info| info.entry.card_generation)
+        .or_else(|| find_known_card(atr).and_then(|known| known.generation))
+}
+
+/// Publishes the capabilities of the bridge and the card on its capabilities topic (see `protocol::Capabilities`).
+/// The message is retained, so the server gets the capabilities before the first request.
+fn publish_capabilities(publisher: &CardPublisher, cardnumber: &str, atr: &str) {
+    let card_generation = card_generation(cardnumber, atr);
+    let pin_policy = crate::card_identification::pin_policy(cardnumber);
+    let payload = match serde_json::to_string(&capabilities(card_generation, pin_policy)) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("{} | Failed to serialize the capabilities: {}", cardnumber, e);
+            return;
+        }
+    };
+    publisher.send(Outgoing::Retained {
+        topic: format!("{}/{}/capabilities", CARD_STATUS_TOPIC_PREFIX, cardnumber),
+        payload,
+        description: "capabilities",
+    });
+}
+
 // Import TASK_POOL from the smart_card module
-use crate::smart_card::TASK_POOL;
+use crate::smart_card::{ConnectionTask, ManagedCard, TASK_POOL};
 
 // Importing specific functionality from local modules
-use crate::config::get_from_cache; // Function to get data from cache for syncing server data.
-use crate::config::split_host_to_parts;
-use crate::config::CacheSection; // Enum for cache sections for getting data from cache. // Function to split the host into parts for MQTT connection.
+use crate::config::{get_reader_share_mode, watch_card_config, AbsentCardBehavior, CardAvailability, CardConfig, CardShareMode}; // Per-card settings.
+use crate::config::{get_card_account, split_host_to_parts, AccountConfig}; // Server of the card account for the MQTT connection.
+use crate::config::{get_protocol_mode, get_session_timeout}; // Parsing of the server requests.
+use crate::config::get_power_saving_config; // Powering off the idle cards.
+use crate::config::get_session_config; // Persistent sessions of the card clients.
+use crate::topics::CardTopics; // Topics of the requests and the responses.
+use crate::multiplex::CardEvents; // Connection shared by the cards.
+use crate::outbox::{Outbox, SharedOutbox}; // Responses waiting for the connection.
+use crate::config::{get_card_config, get_disclosed_atr}; // ATR in the status messages.
 
 // Import the global_app_handle module to send events to the frontend
-use crate::global_app_handle::emit_event;
+use crate::global_app_handle::{emit_card_state, emit_notification, CardStatePayload, StateReason};
+use crate::timestamp::Timestamp;
+use crate::protocol::{apdu_response, capabilities, handle_request, ApduRequest, CardReply, ProtocolAnomaly, RequestCard}; // Server protocol.
+use crate::known_cards::{find_known_card, CardGeneration}; // Generation of the card in the capabilities.
+use crate::security_log::SecurityEvent; // Audit of the authentication sessions.
+use crate::connection_state::{self, link_phase, lost_phase, ConnectionKind, ConnectionPhase}; // Live states of the connections.
 
-/// Ensures an MQTT connection for the specified client ID.
-pub async fn ensure_connection(reader_name: &CStr, client_id: String, atr: String) {
+/// Ensures an MQTT connection for the specified client ID with the card initialized by `card_init`.
+pub async fn ensure_connection(reader_name: &CStr, client_id: String, atr: String, mut card: ManagedCard) {
     // Return early if the client_id is empty, as we cannot ensure a connection without a valid ID
     if client_id.is_empty() {
-        log::warn!("Reader: {:?}. ClientID is empty. Cannot ensure connection.", reader_name);
+        log::warn!(
+            "Reader: {}. ClientID is empty. Cannot ensure connection.",
+            crate::smart_card::ReaderId::from_name(&reader_name.to_string_lossy())
+        );
+        return;
+    }
+    // The bridge has been moved to another machine, which connects the cards now
+    if crate::migration::is_tombstoned() {
+        log::debug!("{} | The card is not connected, the bridge has been moved to another machine", client_id);
         return;
     }
 
@@ -57,100 +436,313 @@ pub async fn ensure_connection(reader_name: &CStr, client_id: String, atr: Strin
     // This part of function checks if a connection already exists for the given client ID
     // in the task pool. If not, it initiates a new connection. This is useful for maintaining
     // a list of active MQTT connections and ensuring that each client ID is only connected once.
-    let exists = task_pool.iter().any(|(id, _, _)| *id == client_id);
-    // If existing connection is found, then return, no add a new connection for this client_id
-    if exists {
-        return;
-    }
-
-    // Getting server data from the cache
-    let full_host = get_from_cache(CacheSection::Server, "host");
-    let (host, port) = match split_host_to_parts(&full_host) {
-        Ok((host, port)) => {
-            // log::debug!("Server data from cache: {:?}:{}", host, port);
-            (host, port)
+    let iccid = card.cached_iccid().map(|iccid| iccid.to_string());
+    if let Some(index) = task_pool.iter().position(|task| task.client_id == client_id) {
+        // The same physical card in the renamed reader: the connection is moved to the new reader,
+        // so the card is not bridged twice and the connection doesn't use the card handle of the old reader
+        let existing = &task_pool[index];
+        if iccid.is_none() || existing.iccid != iccid || existing.reader_name.as_c_str() == reader_name {
+            // If existing connection is found, then return, no add a new connection for this client_id
+            return;
         }
-        Err(e) => {
-            log::error!("Error: {}", e);
+        log::info!(
+            "{} | The card is moved from the reader {:?} to {:?}",
+            client_id,
+            existing.reader_name,
+            reader_name
+        );
+        // The old connection is closed gracefully, so the broker doesn't keep its session and the requests
+        // waiting for the card are answered. The pool is not locked meanwhile, as `remove_connections` does.
+        let existing = task_pool.remove(index);
+        drop(task_pool);
+        disconnect_task(existing).await;
+        task_pool = TASK_POOL.lock().await;
+        // Another connection of the card may have been started while the old one was closed
+        if task_pool.iter().any(|task| task.client_id == client_id) {
             return;
         }
-    };
+    }
 
-    // Getting the flespi token from the cache
-    // let flespi_token = get_from_cache(CacheSection::Server, "token");
+    // Getting server data of the card account from the cache
+    let account = get_card_account(&client_id);
+    // The server the broker has redirected the connections to (see the server_redirect module)
+    let full_host = crate::server_redirect::current_host(&account.host);
 
-    //////////////////////////////////////////////////
-    //  Create a new client ID for the MQTT connection
-    //////////////////////////////////////////////////
-    let mut mqtt_options = MqttOptions::new(&client_id, &host, port);
-    // mqtt_options.set_credentials(flespi_token, "");
-    mqtt_options.set_keep_alive(Duration::from_secs(300));
-    // log::debug!("mqtt_options: {:?}", mqtt_options);
-    println!("mqtt_options: {:?}", mqtt_options);
+    // Topics of the requests and the responses of the card
+    let topics = CardTopics::new(&account.ident, &client_id);
 
-    ////////////// TLS ////////////////
-    // let connector = TlsConnector::new().unwrap();
-    // let transport = Transport::tls_with_default_config();
-    // mqtt_options.set_transport(transport);
+    // The card of the multiplexed mode uses the shared connection of the account, it needs the subscription
+    // to get its requests from the shared connection
+    let session_config = get_session_config();
+    let shared_subscription = match (session_config.multiplexed, topics.subscription()) {
+        (true, Some(subscription)) => Some(subscription),
+        (true, None) => {
+            log::warn!("{} | The multiplexed connection requires the topic templates, the card uses its own connection", client_id);
+            None
+        }
+        (false, _) => None,
+    };
+    let is_shared = shared_subscription.is_some();
+    let subscription = topics.subscription();
+
+    let (mqtt_client, mut eventloop): (MqttClient, CardEvents) = match shared_subscription {
+        Some(subscription) => match crate::multiplex::attach(&account, &client_id, subscription) {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("{} | The card can't be connected: {}", client_id, e);
+                return;
+            }
+        },
+        None => {
+            let mqtt_options = match card_mqtt_options(&account, &client_id, reader_name, &atr, &full_host) {
+                Ok(mqtt_options) => mqtt_options,
+                Err(e) => {
+                    log::error!("{} | The card can't be connected: {}", client_id, e);
+                    return;
+                }
+            };
 
-    // Create a new asynchronous MQTT client and its associated event loop
-    // `mqtt_options` specifies the configuration for the MQTT connection
-    // `10` is the capacity of the internal channel used by the event loop for buffering operations
-    let (mqtt_client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+            // Create a new asynchronous MQTT client and its associated event loop
+            // `mqtt_options` specifies the configuration for the MQTT connection
+            // `10` is the capacity of the internal channel used by the event loop for buffering operations
+            let (mqtt_client, mut eventloop) = create_client(mqtt_options, 10);
+            account.tuning.apply_connection_timeout(&mut eventloop);
+            (mqtt_client, CardEvents::Own(eventloop))
+        }
+    };
 
-    let mqtt_clinet_cloned = mqtt_client.clone();
     let client_id_cloned = client_id.clone();
     let reader_name = reader_name.to_owned(); // clonning the reader name for the async task
+    let reader_name_owned = reader_name.clone();
 
     // format of the logging header
     let log_header: String = format!("{} |", client_id);
 
-    // init card fot the following using in the loop
-    let mut card = match crate::smart_card::create_card_object(&reader_name) {
-        Ok(card) => {
-            log::debug!(
-                "Card object created successfully for the reader: {}",
-                reader_name.to_string_lossy()
-            );
-            card
-        }
-        Err(err) => {
-            // Log the error and return from the current function to reconnect to the card
-            log::error!(
-                "Failed to create card object: {} for the reader: {}",
-                err,
-                reader_name.to_string_lossy()
-            );
-            return;
-        }
-    };
 
     // flag to control the card connection (to the server) status
     let mut is_online: bool = false;
+    // why the card is offline, the frontend is notified every time it changes
+    let mut offline_reason: Option<StateReason> = None;
+
+    // Base card state payload for the frontend. Every event of this connection only changes the connection flags
+    // and the reason of the card taken offline by the user.
+    let mut card_state = CardStatePayload {
+        atr: atr.clone(),
+        reader_name: reader_name.to_string_lossy().into(),
+        reader_label: crate::smart_card::ReaderId::from_name(&reader_name.to_string_lossy()).label(),
+        card_state: "PRESENT".into(),
+        card_number: client_id_cloned.clone(),
+        online: None,
+        authentication: None,
+        updated_at: Timestamp::default(),
+        reason: None,
+        detail: None,
+        card_type: None,
+        card_model: find_known_card(&atr).map(|known| known.model()),
+        card_generation: None,
+    };
 
     // create async task for the mqtt client
+    // Responses waiting for the connection (see the outbox module)
+    let outbox: SharedOutbox = Arc::new(std::sync::Mutex::new(Outbox::load(&client_id)));
+    // Requests received while the card was not in the reader (see AbsentCardBehavior)
+    let waiting_requests: WaitingRequests = Arc::default();
+    let task_waiting_requests = waiting_requests.clone();
+    let mut card_check = tokio::time::interval(Duration::from_millis(CARD_CHECK_INTERVAL_MS));
+    // The checks missed while no request was waiting are not made up at once
+    card_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // Current authentication session (for the security log and the status topic)
+    let mut session = SessionInfo::new(&client_id);
+    // Number of the APDU commands failed in a row, the reader is resynced when it reaches the threshold
+    let mut consecutive_failures: u32 = 0;
+    // Settings of the card, updated live when the configuration changes
+    let mut card_config_rx = watch_card_config(&client_id);
+    let mut card_config: Option<CardConfig> = card_config_rx.borrow().clone();
+    (card_state.reason, card_state.detail) = availability_reason(card_config.as_ref());
+    // Time of the last request, the idle card is powered off (see `PowerSavingConfig`)
+    let mut last_activity = Instant::now();
+    let mut idle_check = tokio::time::interval(Duration::from_secs(IDLE_CHECK_INTERVAL_SECS));
+    let mut session_check = tokio::time::interval(Duration::from_secs(SESSION_CHECK_INTERVAL_SECS));
+    // The connection has been established before, the next CONNACK is a reconnect (see `link_quality`)
+    let mut has_connected = false;
+    // Time of the keep-alive PING, for the round-trip time
+    let mut ping_sent: Option<Instant> = None;
+
+    // The slot of the connection, so many cards don't connect to the broker at once.
+    // The shared connection is established once, so its cards are not delayed
+    let connection_delay = if is_shared {
+        Duration::ZERO
+    } else {
+        crate::stagger::connection_delay(&client_id)
+    };
+
+    let task_client = mqtt_client.clone();
+    let publisher = CardPublisher::spawn(mqtt_client.clone(), client_id.clone(), outbox.clone(), card_config_rx.clone());
     let handle: JoinHandle<()> = async_runtime::spawn(async move {
+        if !connection_delay.is_zero() {
+            tokio::time::sleep(connection_delay).await;
+        }
+        connection_state::report(ConnectionKind::Card, &client_id_cloned, ConnectionPhase::Connecting, None, None);
         loop {
-            match eventloop.poll().await {
+            let polled = tokio::select! {
+                polled = eventloop.poll() => polled,
+                changed = card_config_rx.changed() => {
+                    if changed.is_ok() {
+                        let availability = card_config.as_ref().map(|card_config| card_config.availability);
+                        let qos = card_qos(card_config.as_ref());
+                        card_config = card_config_rx.borrow().clone();
+                        log::info!("{} Card settings are updated: {:?}", log_header, card_config);
+                        // The broker replaces the QoS of the existing subscription, the session is kept
+                        if qos != card_qos(card_config.as_ref()) && is_online {
+                            if let Some(subscription) = topics.subscription() {
+                                log::info!("{} Subscribing again with {:?}", log_header, card_qos(card_config.as_ref()));
+                                publisher.send(Outgoing::Subscribe(subscription));
+                            }
+                        }
+                        if availability != card_config.as_ref().map(|card_config| card_config.availability) {
+                            // The UI shows why the card doesn't serve the requests
+                            (card_state.reason, card_state.detail) = availability_reason(card_config.as_ref());
+                            if let Err(e) = emit_card_state(CardStatePayload {
+                                online: Some(is_online),
+                                updated_at: Timestamp::now(),
+                                ..card_state.clone()
+                            }) {
+                                log::warn!("{} Failed to emit card state: {}", log_header, e);
+                            }
+                            // The server learns about the paused or activated card without waiting for the next status
+                            if is_online {
+                                publish_card_status(&publisher, &client_id_cloned, &session, waiting_count(&waiting_requests));
+                            }
+                        }
+                    }
+                    continue;
+                }
+                _ = card_check.tick(), if waiting_count(&waiting_requests) > 0 => {
+                    // Check if the card is back in the reader to process the waiting requests
+                    let reconnected = ManagedCard::create_card(&reader_name, &client_id_cloned).ok();
+                    if let Some(new_card) = reconnected {
+                        card = new_card;
+                        log::info!("{} The card is back, processing {} waiting request(s)", log_header, waiting_count(&waiting_requests));
+                        // The requests are taken one by one, so the rest is answered if the connection is removed meanwhile
+                        while let Some(request) = next_waiting_request(&waiting_requests) {
+                            // The request to the card powered off while idle starts the session
+                            let session_started = if request.hex_value.is_empty() || session.is_active() {
+                                true
+                            } else if start_session(&mut card, &mut session, card_config.as_ref(), &card_state, &request.topic) {
+                                session.apdu_count += 1;
+                                true
+                            } else {
+                                false
+                            };
+                            let payload_ack = if !session_started {
+                                apdu_response("")
+                            } else {
+                                match card.exchange(&request.hex_value, &atr, &client_id_cloned) {
+                                    Ok(response) => apdu_response(&response),
+                                    Err(err) if crate::smart_card::is_card_absent_error(&*err) => {
+                                        card_not_present_response(ABSENT_CARD_RETRY_AFTER_SECS)
+                                    }
+                                    Err(err) => {
+                                        log::error!("{} Failed to send waiting APDU command to card: {}", log_header, err);
+                                        apdu_response("")
+                                    }
+                                }
+                            };
+                            publish_response(&publisher, &outbox, is_online, &client_id_cloned, request.topic_ack, payload_ack);
+                        }
+                        publish_card_status(&publisher, &client_id_cloned, &session, waiting_count(&waiting_requests));
+                    } else {
+                        // The held requests are rejected when the card is not back in time
+                        let expired = take_expired_requests(&waiting_requests, Instant::now());
+                        if !expired.is_empty() {
+                            log::warn!("{} The card is not back in time, rejecting {} held request(s)", log_header, expired.len());
+                            for request in expired {
+                                let payload_ack = card_not_present_response(ABSENT_CARD_RETRY_AFTER_SECS);
+                                publish_response(&publisher, &outbox, is_online, &client_id_cloned, request.topic_ack, payload_ack);
+                            }
+                            publish_card_status(&publisher, &client_id_cloned, &session, waiting_count(&waiting_requests));
+                        }
+                    }
+                    continue;
+                }
+                _ = idle_check.tick(), if card.is_powered() => {
+                    let power_saving = get_power_saving_config();
+                    if power_saving.enabled
+                        && !session.is_active()
+                        && waiting_count(&waiting_requests) == 0
+                        && last_activity.elapsed() >= Duration::from_secs(power_saving.idle_secs)
+                    {
+                        match card.power_off() {
+                            Ok(_) => log::info!("{} The card is idle, it is powered off until the next request", log_header),
+                            Err(e) => log::warn!("{} Failed to power off the idle card: {}", log_header, e),
+                        }
+                    }
+                    continue;
+                }
+                _ = session_check.tick(), if session.is_active() => {
+                    // The tracker has stopped sending the commands, so the session would never be finished
+                    let timeout = match get_session_timeout() {
+                        Some(timeout) if last_activity.elapsed() >= timeout => timeout,
+                        _ => continue,
+                    };
+                    log::warn!(
+                        "{} No requests in the authentication session for {} seconds, the session is reset",
+                        log_header,
+                        timeout.as_secs()
+                    );
+                    crate::security_log::record(
+                        SecurityEvent::AuthenticationFinished,
+                        Some(&client_id_cloned),
+                        session.tracker.as_deref().unwrap_or_default(),
+                        &format!("timed out after {} APDU commands", session.apdu_count),
+                    );
+                    session.finish();
+                    if let Err(e) = card.reconnect(ShareMode::Shared, Disposition::ResetCard) {
+                        log::error!("{} Failed to reconnect card: {:?}", log_header, e);
+                    }
+                    if let Err(e) = emit_card_state(CardStatePayload {
+                        online: Some(is_online),
+                        authentication: Some(false),
+                        updated_at: Timestamp::now(),
+                        ..card_state.clone()
+                    }) {
+                        log::warn!("{} Failed to emit card state: {}", log_header, e);
+                    }
+                    emit_notification(
+                        "warning",
+                        &format!(
+                            "The authentication with the card {} is abandoned by the tracker, the card is reset.",
+                            client_id_cloned
+                        ),
+                    );
+                    publish_card_status(&publisher, &client_id_cloned, &session, waiting_count(&waiting_requests));
+                    continue;
+                }
+            };
+
+            match polled {
                 Ok(notification) => {
                     if !is_online {
                         is_online = true;
+                        offline_reason = None;
+                        connection_state::report(ConnectionKind::Card, &client_id_cloned, link_phase(), None, None);
 
                         // Send the global-cards-sync event to the frontend that card is connected
-                        emit_event("global-cards-sync",
-                            atr.clone().into(),
-                            reader_name.to_string_lossy().into(),
-                            "PRESENT".into(),
-                            client_id_cloned.clone(),
-                            Some(true),
-                            None
-                        );
+                        if let Err(e) = emit_card_state(CardStatePayload {
+                            online: Some(true),
+                            authentication: None,
+                            updated_at: Timestamp::now(),
+                            ..card_state.clone()
+                        }) {
+                            log::warn!("{} Failed to emit card state: {}", log_header, e);
+                        }
                     }
 
                     log::debug!("{} Notification: {:?}", log_header, notification);
 
                     match notification {
-                        Event::Incoming(Incoming::Publish(publish)) => {
+                        MqttEvent::Publish(publish) => {
+                            last_activity = Instant::now();
                             // Extracting the topic from the incoming data
                             let topic_str = match std::str::from_utf8(&publish.topic) {
                                 Ok(str) => str,
@@ -165,203 +757,156 @@ pub async fn ensure_connection(reader_name: &CStr, client_id: String, atr: Strin
 
                             // Convert &str to String for further use
                             let topic = topic_str.to_string();
-                            // The contents of response and request are the same.
-                            // Card number and parcel ID. So we just change the initial topic
-                            let topic_ack = topic.replace("request", "response");
-                            // serializable data to interpret it as json
-                            match serde_json::from_slice::<Value>(&publish.payload) {
-                                Ok(json_payload) => {
-                                    println!("Parsed JSON payload: {:?}", json_payload);
-
-                                    let mut payload_ack = String::new();
-
-                                    // Check for the presence of the "finish" parameter
-                                    if let Some(finish_value) = json_payload.get("finish").and_then(|v| v.as_bool()) {
-                                        log::debug!(
-                                            "{} Finish parameter: {}",
-                                            log_header,
-                                            finish_value
-                                        );
-
-                                        // Processing the "finish" parameter depending on its value
-                                        if finish_value {
-                                            // Send the global-cards-sync event to the frontend that card is connected
-                                            emit_event("global-cards-sync",
-                                                atr.clone().into(),
-                                                reader_name.to_string_lossy().into(),
-                                                "PRESENT".into(),
-                                                client_id_cloned.clone(),
-                                                Some(true),
-                                                Some(false)
-                                            );
-
-                                            log::info!("Authentication process is finished");
-                                            // Reset the card to its original state
-                                            match card.reconnect(
-                                                ShareMode::Shared,
-                                                Protocols::ANY,
-                                                Disposition::ResetCard,
-                                            ) {
-                                                Ok(_) => {
-                                                    println!("Card reconnected successfully.");
-                                                }
-                                                Err(e) => {
-                                                    println!("Failed to reconnect card: {:?}", e);
-                                                    log::error!(
-                                                        "{} Failed to reconnect card: {:?}",
-                                                        log_header,
-                                                        e
-                                                    );
-                                                }
-                                            }
-
-                                            payload_ack = process_rapdu_mqtt_hex("".to_string());
-
-                                            // handle the case when finish == true
-                                        } else {
-                                            // finish flag is false here
-                                            // PROCESS AUTHORIZATION WITH APDU COMMUNICATION
-                                            // The "hex" parameter contains the apdu instruction that needs to be transferred to the card
-                                            if let Some(hex_value) = json_payload.get("payload").and_then(|v| v.as_str()) {
-                                                // 00A4020c020002 - select icc id file
-                                                // 00b0000019 - read selected file
-
-                                                log::info!(
-                                                    "{} TRACKER: Payload hex value: {}",
-                                                    log_header,
-                                                    hex_value
-                                                );
-
-                                                let mut rapdu_mqtt_hex = String::new(); // empty string for the response
-
-                                                if hex_value.is_empty() {
-                                                    // If the input value is empty, then pass the ATR to the server.
-                                                    rapdu_mqtt_hex = atr.clone();
-                                                    // finish_value = true;    // This is a crutch, temporary solution to not include the visual effect of authorization.
-                                                    //                         // Because the ATR request is not always the beginning of authorization.
-                                                    //                         // Sometimes it is a part of the command that can be rejected by the tracker, so this part should be ignored
-
-                                                    // Send the global-cards-sync event to the frontend that card is connected
-                                                    emit_event("global-cards-sync",
-                                                        atr.clone().into(),
-                                                        reader_name.to_string_lossy().into(),
-                                                        "PRESENT".into(),
-                                                        client_id_cloned.clone(),
-                                                        Some(true),
-                                                        Some(false)
-                                                    );
-
-                                                } else {
-                                                    // Otherwise, the logic for exchanging messages with the map.
-                                                    match crate::smart_card::send_apdu_to_card_command(&card, &hex_value) {
-                                                        Ok(response) => {
-                                                            rapdu_mqtt_hex = response;
-                                                            println!("{} APDU response: {:?}", client_id_cloned, rapdu_mqtt_hex);
-                                                        }
-                                                        Err(err) => {
-                                                            log::error!("Failed to send APDU command to card: {}", err);
-                                                        }
-                                                    }
-
-                                                    // Send the global-cards-sync event to the frontend that card is connected
-                                                    emit_event("global-cards-sync",
-                                                        atr.clone().into(),
-                                                        reader_name.to_string_lossy().into(),
-                                                        "PRESENT".into(),
-                                                        client_id_cloned.clone(),
-                                                        Some(true),
-                                                        Some(true)
-                                                    );
-
-                                                }
-
-                                                payload_ack = process_rapdu_mqtt_hex(rapdu_mqtt_hex);
-
-
-                                                // log::info!("finish_value: {}", finish_value);
-                                            } else {
-                                                log::error!(
-                                                    "{} Hex value not found or is not a string",
-                                                    log_header
-                                                );
-                                            }
-
-                                            log::info!(
-                                                "{} CARD: Payload hex value: {}",
-                                                log_header,
-                                                payload_ack
-                                            );
-                                        }
-
-                                        // publish a message to the channel
-                                        let publish_result = mqtt_client
-                                            .publish(
-                                                topic_ack,
-                                                QoS::AtLeastOnce,
-                                                false,
-                                                payload_ack,
-                                            )
-                                            .await;
-                                        match publish_result {
-                                            Ok(_) => println!("Message published successfully"),
-                                            Err(e) => println!("Error sending message: {:?}", e),
-                                        }
-                                    } else {
-                                        println!("Finish parameter not found or is not a boolean");
-                                        log::error!(
-                                            "{} Finish parameter not found or is not a boolean",
-                                            log_header
-                                        );
-                                    }
-                                }
-                                Err(e) => {
-                                    log::error!(
-                                        "{} parsing JSON payload issue: {:?}",
-                                        log_header,
-                                        e
-                                    );
-                                }
+                            let topic_ack = topics.response_topic(&topic);
+                            // The card taken offline by the office is not accessed, the server is told to stop retrying
+                            let availability = card_config.as_ref().map(|card_config| card_config.availability).unwrap_or_default();
+                            if availability != CardAvailability::Active {
+                                let retry_after = card_config.as_ref().and_then(|card_config| card_config.retry_after_secs);
+                                log::info!("{} The request is rejected, the card is {:?}", log_header, availability);
+                                let payload_ack = card_unavailable_response(availability, retry_after);
+                                publish_response(&publisher, &outbox, is_online, &client_id_cloned, topic_ack, payload_ack);
+                                continue;
+                            }
+                            // The request is handled according to the protocol mode from the config
+                            let mut request_card = CardRequest {
+                                card: &mut card,
+                                session: &mut session,
+                                consecutive_failures: &mut consecutive_failures,
+                                card_config: card_config.as_ref(),
+                                card_state: &card_state,
+                                waiting_requests: &waiting_requests,
+                                publisher: &publisher,
+                                cardnumber: &client_id_cloned,
+                                log_header: &log_header,
+                                reader_name: &reader_name,
+                                atr: &atr,
+                                topic: &topic,
+                                topic_ack: &topic_ack,
+                            };
+                            if let Some(payload_ack) = handle_request(&publish.payload, get_protocol_mode(), &mut request_card) {
+                                log::info!("{} CARD: Payload hex value: {}", log_header, payload_ack);
+                                // publish a message to the channel
+                                publish_response(&publisher, &outbox, is_online, &client_id_cloned, topic_ack, payload_ack);
                             }
                         }
-                        Event::Incoming(Incoming::ConnAck(..)) => {
+                        MqttEvent::ConnAck { session_present } => {
                             log::info!(
                                 "{} Сonnection to the server has been successfully established.",
                                 log_header
-                            )
+                            );
+                            if session_present {
+                                log::info!("{} The previous session is resumed with its subscriptions and pending messages", log_header);
+                            }
+                            crate::hooks::connection_established(&client_id_cloned);
+                            crate::connection_stats::record_connect(&client_id_cloned, has_connected);
+                            if has_connected {
+                                crate::link_quality::record_reconnect();
+                            }
+                            has_connected = true;
+                            crate::link_quality::reset_status(&client_id_cloned);
+                            // The requests are routed by the broker, or subscribed with the topic template
+                            if let Some(subscription) = topics.subscription() {
+                                publisher.send(Outgoing::Subscribe(subscription));
+                            }
+                            // The responses produced while the connection was down
+                            let (responses, expired) = outbox.lock().unwrap().take();
+                            if !responses.is_empty() || expired > 0 {
+                                if expired > 0 {
+                                    log::warn!("{} {} expired response(s) of the outbox are dropped", log_header, expired);
+                                }
+                                log::info!("{} Publishing {} response(s) from the outbox", log_header, responses.len());
+                                for (topic_ack, payload_ack) in responses {
+                                    publish_response(&publisher, &outbox, is_online, &client_id_cloned, topic_ack, payload_ack);
+                                }
+                            }
+                            publish_capabilities(&publisher, &client_id_cloned, &atr);
+                            publish_card_status(&publisher, &client_id_cloned, &session, waiting_count(&waiting_requests));
+                        }
+                        MqttEvent::Disconnect => {
+                            log::info!("{} The connection is closed", log_header);
+                            return;
+                        }
+                        MqttEvent::PingRequest => ping_sent = Some(Instant::now()),
+                        MqttEvent::PingResponse => {
+                            if let Some(sent) = ping_sent.take() {
+                                crate::link_quality::record_ping(sent.elapsed());
+                                connection_state::report(ConnectionKind::Card, &client_id_cloned, link_phase(), None, None);
+                            }
                         }
                         _ => {} // This handles any other events that you haven't explicitly matched above
                     }
                 }
                 Err(e) => {
-                    if is_online {
+                    crate::connection_stats::record_error(&client_id_cloned, &e.to_string());
+                    let reason = if crate::maintenance::is_maintenance_active() {
+                        StateReason::ScheduledOffline
+                    } else if e.kind() == ConnectionErrorKind::CredentialsRejected {
+                        StateReason::CredentialsRejected
+                    } else {
+                        StateReason::BrokerUnreachable
+                    };
+                    if is_online || offline_reason != Some(reason) {
                         is_online = false;
+                        offline_reason = Some(reason);
+                        connection_state::report(
+                            ConnectionKind::Card,
+                            &client_id_cloned,
+                            lost_phase(has_connected, reason),
+                            Some(reason),
+                            Some(e.to_string()),
+                        );
 
                         // Send the global-cards-sync event to the frontend that card is connected
-                        emit_event("global-cards-sync",
-                            atr.clone().into(),
-                            reader_name.to_string_lossy().into(),
-                            "PRESENT".into(),
-                            client_id_cloned.clone(),
-                            Some(false),
-                            None
-                        );
+                        if let Err(e) = emit_card_state(CardStatePayload {
+                            online: Some(false),
+                            authentication: None,
+                            updated_at: Timestamp::now(),
+                            reason: Some(reason),
+                            detail: Some(e.to_string()),
+                            ..card_state.clone()
+                        }) {
+                            log::warn!("{} Failed to emit card state: {}", log_header, e);
+                        }
                     }
 
-                    match e {
-                        ConnectionError::Io(ref io_err) => match io_err.kind() {
+                    // Connection losses are expected during the maintenance, so they are not reported as warnings
+                    if crate::maintenance::is_maintenance_active() {
+                        log::debug!("{} Connection error during the maintenance window: {:?}", log_header, e);
+                        tokio::time::sleep(Duration::from_secs(SLEEP_DURATION_SECS)).await;
+                        continue;
+                    }
+
+                    match e.kind() {
+                        // The shared connection follows the redirect itself (see the multiplex module)
+                        ConnectionErrorKind::ServerMoved if !is_shared => {
+                            // The connection is moved to the referenced server if it is allowed
+                            if let Some(target) = crate::server_redirect::follow_redirect(&account, &log_header).await {
+                                match (&mut eventloop, card_mqtt_options(&account, &client_id_cloned, &reader_name, &atr, &target)) {
+                                    (CardEvents::Own(own), Ok(mqtt_options)) => {
+                                        own.set_options(mqtt_options);
+                                        account.tuning.apply_connection_timeout(own);
+                                        continue;
+                                    }
+                                    (_, Err(e)) => log::error!("{} The connection can't be moved to {}: {}", log_header, target, e),
+                                    _ => {}
+                                }
+                            }
+                        }
+                        ConnectionErrorKind::ServerMoved => {}
+                        ConnectionErrorKind::Io(kind) => match kind {
                             ErrorKind::ConnectionAborted => log::warn!("{} Can't establish a connection to a remote server.", log_header),
                             ErrorKind::ConnectionReset => log::warn!("{} The connection could not be established. Check the server address in the configuration.", log_header),
                             ErrorKind::TimedOut => log::warn!("{} Connection timeout. The server may be down or the network is unstable.", log_header),
                             _ => log::error!("{} An IO error occurred.", log_header),
                         },
-                        ConnectionError::MqttState(ServerDisconnect { .. }) => log::warn!("{} The connection was terminated on the server side. Most likely the user has turned off the channel/device.", log_header),
-                        ConnectionError::MqttState(AwaitPingResp { .. }) => {
+                        ConnectionErrorKind::ServerDisconnect => log::warn!("{} The connection was terminated on the server side. Most likely the user has turned off the channel/device.", log_header),
+                        ConnectionErrorKind::AwaitPingResp => {
                             log::warn!("{} Awaiting PING response from the server. The connection might be unstable.", log_header);
                             // Implement your reconnection or handling strategy here
                         },
-                        ConnectionError::MqttState(StateError::Io(os_err)) => {
-                            println!("An IO error occurred in MQTT state: {:?}", os_err);
-                        },
+                        ConnectionErrorKind::StateIo => log::error!("{} An IO error occurred in MQTT state: {:?}", log_header, e),
+                        ConnectionErrorKind::Proxy => crate::proxy::report_proxy_error(&format!("Failed to connect through the proxy: {}", e)),
+                        ConnectionErrorKind::CredentialsRejected => log::error!("{} The broker has rejected the credentials. Check the username and password in the configuration.", log_header),
                         _ => {
                             log::error!("{} Unhandled error: {:?}", log_header, e);
                             // return; // exit the loop
@@ -374,45 +919,450 @@ pub async fn ensure_connection(reader_name: &CStr, client_id: String, atr: Strin
         }
     });
 
-    task_pool.push((client_id, mqtt_clinet_cloned, handle));
+    task_pool.push(ConnectionTask {
+        client_id,
+        iccid,
+        reader_name: reader_name_owned,
+        mqtt_client: task_client,
+        subscription,
+        shared: is_shared,
+        handle,
+        waiting_requests: task_waiting_requests,
+    });
+}
+
+/// Builds the options of the own connection of the card to the server.
+///
+/// # Arguments
+///
+/// * `account` - The account of the card.
+/// * `client_id` - The card number, the client ID of the connection.
+/// * `reader_name` - The reader of the card, for the CONNECT properties.
+/// * `atr` - The ATR of the card, for the CONNECT properties.
+/// * `full_host` - The server, 'host:port': the configured one or the one of the redirect.
+fn card_mqtt_options(account: &AccountConfig, client_id: &str, reader_name: &CStr, atr: &str, full_host: &str) -> Result<MqttOptions, String> {
+    let (host, port) = split_host_to_parts(full_host)?;
+    let session_config = get_session_config();
+
+    //////////////////////////////////////////////////
+    //  Create a new client ID for the MQTT connection
+    //////////////////////////////////////////////////
+    let mut mqtt_options = MqttOptions::new(account.mqtt_version, client_id, &host, port);
+    mqtt_options.set_keep_alive(account.tuning.card_keep_alive());
+    account.tuning.apply_packet_size(&mut mqtt_options);
+    mqtt_options.set_user_properties(card_properties(client_id, reader_name, atr));
+    log::debug!("mqtt_options: {:?}", mqtt_options);
+    account.apply_credentials(&mut mqtt_options);
+    mqtt_options.set_last_will(card_status_topic(client_id), card_last_will(client_id), QoS::AtLeastOnce, true);
+    if session_config.persistent {
+        mqtt_options.set_persistent_session(Duration::from_secs(session_config.expiry_secs));
+    }
+    account.apply_tls(&mut mqtt_options)?;
+    crate::proxy::apply_proxy(&mut mqtt_options)?;
+
+    ////////////// TLS ////////////////
+    // let connector = TlsConnector::new().unwrap();
+    // let transport = Transport::tls_with_default_config();
+    // mqtt_options.set_transport(transport);
+
+    Ok(mqtt_options)
 }
 
 /// Removes specified MQTT connections.
 ///
 /// This function iterates over a list of client IDs, finds the corresponding
-/// tasks in the task pool, and cancels them. It ensures that any active connection
+/// tasks in the task pool, and disconnects them. It ensures that any active connection
 /// associated with the given client IDs is terminated.
 pub async fn remove_connections(client_ids: Vec<String>) {
     log::debug!("removing conn {:?}", client_ids);
-    // Unlock task_pool mutex
-    let mut task_pool = TASK_POOL.lock().await;
+    // The tasks are taken out of the pool first, so the pool is not locked while they are disconnected
+    let tasks: Vec<ConnectionTask> = {
+        // Unlock task_pool mutex
+        let mut task_pool = TASK_POOL.lock().await;
+        client_ids
+            .iter()
+            .filter_map(|client_id| {
+                // Attempt to find a task associated with the current client ID
+                let index = task_pool.iter().position(|task| task.client_id == *client_id)?;
+                Some(task_pool.remove(index))
+            })
+            .collect()
+    };
+
+    // The connections are disconnected in parallel, so the removal takes at most one timeout
+    let disconnections: Vec<_> = tasks.into_iter().map(|task| tokio::spawn(disconnect_task(task))).collect();
+    for disconnection in disconnections {
+        if let Err(e) = disconnection.await {
+            log::error!("Disconnection task failed: {:?}", e);
+        }
+    }
+    // The ended tasks have dropped their receivers of the card settings
+    crate::config::prune_card_config_watchers();
+}
 
-    for client_id in client_ids {
-        // Attempt to find a task associated with the current client ID
-        if let Some(index) = task_pool.iter().position(|(id, _, _)| *id == client_id) {
-            // If found, remove the task from the pool and abort it
-            let (_, _, handle) = task_pool.remove(index);
+/// Disconnects the connection of the card gracefully: the offline status is published, the requests
+/// are unsubscribed and the DISCONNECT is sent, so the broker doesn't keep the half-open session until
+/// the keep-alive expires. The task is aborted if it doesn't finish within `DISCONNECT_TIMEOUT_SECS`.
+async fn disconnect_task(task: ConnectionTask) {
+    let ConnectionTask {
+        client_id,
+        mqtt_client,
+        subscription,
+        shared,
+        mut handle,
+        waiting_requests,
+        ..
+    } = task;
+
+    // The requests wait for the room in the channel of the event loop, so they are limited by the timeout too
+    let graceful = tokio::time::timeout(Duration::from_secs(DISCONNECT_TIMEOUT_SECS), async {
+        // The requests waiting for the card are answered, the card is not coming back to this connection
+        let waiting: Vec<WaitingRequest> = std::mem::take(&mut *waiting_requests.lock().unwrap());
+        for request in waiting {
+            let payload = card_not_present_response(ABSENT_CARD_RETRY_AFTER_SECS);
+            match mqtt_client.publish(request.topic_ack.clone(), QoS::AtLeastOnce, false, payload.clone()).await {
+                Ok(_) => crate::hooks::response_sent(&client_id, &request.topic_ack, &payload),
+                Err(e) => log::warn!("{} | Failed to answer the request waiting for the card: {:?}", client_id, e),
+            }
+        }
+        // The broker doesn't publish the last will after the DISCONNECT, so the offline status is published here
+        if let Err(e) = mqtt_client
+            .publish(card_status_topic(&client_id), QoS::AtLeastOnce, true, card_last_will(&client_id))
+            .await
+        {
+            log::warn!("{} | Failed to publish the offline status: {:?}", client_id, e);
+        }
+        // The shared connection stays for the other cards, it is closed with its last card
+        if shared {
+            crate::multiplex::detach(&client_id);
+            return false;
+        }
+        if let Some(subscription) = subscription {
+            if let Err(e) = mqtt_client.unsubscribe(subscription).await {
+                log::warn!("{} | Failed to unsubscribe from the requests: {:?}", client_id, e);
+            }
+        }
+        if let Err(e) = mqtt_client.disconnect().await {
+            log::warn!("{} | Failed to disconnect from the server: {:?}", client_id, e);
+        }
+        (&mut handle).await.is_ok()
+    })
+    .await;
+    match graceful {
+        Ok(true) => {}
+        Ok(false) => handle.abort(),
+        Err(_) => {
+            log::warn!("{} | The connection is not closed in time, it is aborted", client_id);
             handle.abort();
-            // Log the termination of the connection
-            log::info!(
-                "{} Connection to the server has been terminated.",
-                client_id
+        }
+    }
+    // The session of the stopped task is not finished by the task itself
+    ACTIVE_SESSIONS.lock().unwrap().remove(&client_id);
+    connection_state::remove(ConnectionKind::Card, &client_id);
+    // Log the termination of the connection
+    log::info!("{} Connection to the server has been terminated.", client_id);
+}
+
+/// Publishes the response to the request. The response is kept in the outbox if the connection is down
+/// or the publish fails, it is published when the connection returns.
+fn publish_response(publisher: &CardPublisher, outbox: &SharedOutbox, is_online: bool, cardnumber: &str, topic: String, payload: String) {
+    if is_online {
+        publisher.send(Outgoing::Response { topic, payload });
+        return;
+    }
+    log::info!("{} | The connection is down, the response is kept in the outbox", cardnumber);
+    if !outbox.lock().unwrap().push(topic, payload) {
+        log::warn!("{} | The outbox is full, the oldest response is dropped", cardnumber);
+    }
+}
+
+/// Creates the response for the request that can't be processed because the card is not in the reader.
+///
+/// The server may retry the request after `retry_after` seconds.
+fn card_not_present_response(retry_after: u64) -> String {
+    serde_json::json!({
+        "payload": "",
+        "error": "card_not_present",
+        "retry_after": retry_after,
+    })
+    .to_string()
+}
+
+/// Reason and its description for the UI of the card paused or disabled by the user, `None` if the card is active.
+pub fn availability_reason(card_config: Option<&CardConfig>) -> (Option<StateReason>, Option<String>) {
+    match card_config.map(|card_config| card_config.availability).unwrap_or_default() {
+        CardAvailability::Active => (None, None),
+        CardAvailability::Paused => (Some(StateReason::PausedByUser), Some("The card is paused by the user".to_string())),
+        CardAvailability::Disabled => (Some(StateReason::PausedByUser), Some("The card is disabled by the user".to_string())),
+    }
+}
+
+/// Creates the response for the request to the card which is paused or disabled by the office.
+///
+/// The server may retry the request to the paused card after `retry_after` seconds (`PAUSED_CARD_RETRY_AFTER_SECS`
+/// by default), the disabled card has no `retry_after`.
+fn card_unavailable_response(availability: CardAvailability, retry_after: Option<u64>) -> String {
+    let retry_after = match availability {
+        CardAvailability::Paused => Some(retry_after.unwrap_or(PAUSED_CARD_RETRY_AFTER_SECS)),
+        _ => None,
+    };
+    serde_json::json!({
+        "payload": "",
+        "error": "card_unavailable",
+        "reason": availability,
+        "retry_after": retry_after,
+    })
+    .to_string()
+}
+
+/// Opens the card exclusively for the authentication session if it is configured for the card or the reader.
+/// If the exclusive access can't be obtained, the session continues in the shared mode and the user is notified.
+fn apply_session_share_mode(card: &mut ManagedCard, cardnumber: &str, share_mode: CardShareMode) {
+    if share_mode != CardShareMode::Exclusive {
+        return;
+    }
+    match card.set_share_mode(ShareMode::Exclusive) {
+        Ok(()) => log::debug!("{} | The card is opened exclusively for the session", cardnumber),
+        Err(e) => {
+            log::warn!("{} | Failed to open the card exclusively, the shared mode is used: {}", cardnumber, e);
+            emit_notification(
+                "warning",
+                &format!(
+                    "The card {} is used by another application and can't be opened exclusively. The shared mode is used for the authentication.",
+                    cardnumber
+                ),
             );
         }
     }
 }
 
-fn process_rapdu_mqtt_hex(rapdu_mqtt_hex: String) -> String {
-    // Create a JSON object with the hex value
-    let json_value = serde_json::json!({
-        "payload": rapdu_mqtt_hex,
-    });
+/// Gets the ICCID of the card for the new authentication session.
+///
+/// The ICCID is re-read if it was requested with the `refresh_iccid` command, and once more if the read fails.
+/// The error is returned as a string, as the boxed error can't be held across the await.
+fn session_iccid(card: &mut ManagedCard, cardnumber: &str) -> Result<String, String> {
+    if crate::smart_card::take_iccid_refresh_request(cardnumber) {
+        return card.refresh_iccid().map(|iccid| iccid.to_string()).map_err(|e| e.to_string());
+    }
+    match card.iccid() {
+        Ok(iccid) => return Ok(iccid.to_string()),
+        Err(e) => log::warn!("{} | Failed to read the ICCID, retrying: {}", cardnumber, e),
+    }
+    card.refresh_iccid().map(|iccid| iccid.to_string()).map_err(|e| e.to_string())
+}
 
-    // Serialize the JSON object to a string and assign it to `payload_ack`
-    let payload_ack = json_value.to_string();
+/// Starts the authentication session: the card is opened with the share mode of the session
+/// and its ICCID is read for the security log.
+///
+/// Returns `false` if the card can't be identified, then the authentication is cancelled and the card is reset.
+fn start_session(
+    card: &mut ManagedCard,
+    session: &mut SessionInfo,
+    card_config: Option<&CardConfig>,
+    card_state: &CardStatePayload,
+    topic: &str,
+) -> bool {
+    let cardnumber = session.cardnumber.clone();
+    // The card is shared again when the session is finished (the card is reset)
+    let share_mode = card_config
+        .and_then(|card_config| card_config.share_mode)
+        .unwrap_or_else(|| get_reader_share_mode(&card_state.reader_name));
+    apply_session_share_mode(card, &cardnumber, share_mode);
+    let iccid = match session_iccid(card, &cardnumber) {
+        Ok(iccid) => {
+            crate::smart_card::remember_iccid(&cardnumber, &iccid);
+            iccid
+        }
+        Err(err) => {
+            log::error!("{} | Failed to read the ICCID, the authentication is cancelled: {}", cardnumber, err);
+            if let Err(e) = card.reconnect(ShareMode::Shared, Disposition::ResetCard) {
+                log::error!("{} | Failed to reconnect card: {:?}", cardnumber, e);
+            }
+            return false;
+        }
+    };
+    crate::security_log::record(
+        SecurityEvent::AuthenticationStarted,
+        Some(&cardnumber),
+        topic,
+        &format!("reader: {}, iccid: {}", card_state.reader_label, iccid),
+    );
+    session.start(topic);
+    true
+}
 
-    // Print the acknowledgment payload to the console
-    println!("Payload ack: {}", payload_ack);
+/// The card of the server request: the request flow of `protocol::handle_request` with the session,
+/// the requests waiting for the card and the card state of the connection.
+struct CardRequest<'a> {
+    card: &'a mut ManagedCard,
+    session: &'a mut SessionInfo,
+    consecutive_failures: &'a mut u32,
+    card_config: Option<&'a CardConfig>,
+    card_state: &'a CardStatePayload,
+    waiting_requests: &'a WaitingRequests,
+    publisher: &'a CardPublisher,
+    cardnumber: &'a str,
+    log_header: &'a str,
+    reader_name: &'a CStr,
+    atr: &'a str,
+    topic: &'a str,
+    topic_ack: &'a str,
+}
 
-    payload_ack
+impl CardRequest<'_> {
+    fn publish_status(&self) {
+        publish_card_status(self.publisher, self.cardnumber, self.session, waiting_count(self.waiting_requests));
+    }
+
+    /// Sends the card state to the frontend.
+    fn emit_state(&self, card_state: CardStatePayload) {
+        if let Err(e) = emit_card_state(card_state) {
+            log::warn!("{} Failed to emit card state: {}", self.log_header, e);
+        }
+    }
+
+    /// The card state with the connection online, `authentication` if the authentication is in progress.
+    fn online_state(&self, authentication: bool) -> CardStatePayload {
+        CardStatePayload {
+            online: Some(true),
+            authentication: Some(authentication),
+            updated_at: Timestamp::now(),
+            ..self.card_state.clone()
+        }
+    }
+}
+
+impl RequestCard for CardRequest<'_> {
+    fn anomaly(&mut self, anomaly: &ProtocolAnomaly) {
+        log::warn!("{} Protocol anomaly in the request: {}", self.log_header, anomaly);
+        crate::event_store::record_protocol_anomaly(anomaly.kind());
+    }
+
+    fn rejected(&mut self, anomalies: &[ProtocolAnomaly]) {
+        log::error!("{} The request is rejected: {:?}", self.log_header, anomalies);
+    }
+
+    fn accept(&mut self, request: &ApduRequest) -> Option<CardReply> {
+        log::debug!("{} Parsed APDU request: {:?}", self.log_header, request);
+        if !crate::hooks::request_received(self.cardnumber, request) {
+            log::info!("{} The request is dropped by the connection hooks", self.log_header);
+            return Some(CardReply::Deferred);
+        }
+        // The idle card is powered on again by the first request
+        if self.card.is_powered() {
+            return None;
+        }
+        match ManagedCard::create_card(self.reader_name, self.cardnumber) {
+            Ok(new_card) => {
+                log::info!("{} The card is powered on for the request", self.log_header);
+                *self.card = new_card;
+                None
+            }
+            Err(err) => {
+                log::error!("{} Failed to power on the card: {}", self.log_header, err);
+                // The request waits for the card like the one to the card removed during the session,
+                // there is nothing to finish without the card
+                let hex_value = request.payload.as_deref().unwrap_or_default();
+                if !request.finish
+                    && keep_waiting_request(self.waiting_requests, self.card_config, self.topic, self.topic_ack, hex_value, self.log_header)
+                {
+                    self.publish_status();
+                    return Some(CardReply::Deferred);
+                }
+                Some(CardReply::Response(card_not_present_response(ABSENT_CARD_RETRY_AFTER_SECS)))
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.emit_state(self.online_state(false));
+        log::info!("Authentication process is finished");
+        crate::security_log::record(
+            SecurityEvent::AuthenticationFinished,
+            Some(self.cardnumber),
+            self.topic,
+            &format!(
+                "iccid: {}, APDU commands: {}",
+                self.card.cached_iccid().unwrap_or("unknown"),
+                self.session.apdu_count
+            ),
+        );
+        let apdu_count = self.session.apdu_count;
+        self.session.finish();
+        self.publish_status();
+        crate::hooks::session_finished(self.cardnumber, apdu_count);
+        // Reset the card to its original state
+        match self.card.reconnect(ShareMode::Shared, Disposition::ResetCard) {
+            Ok(_) => log::debug!("{} Card reconnected successfully.", self.log_header),
+            Err(e) => log::error!("{} Failed to reconnect card: {:?}", self.log_header, e),
+        }
+    }
+
+    fn atr(&mut self) -> String {
+        // The ATR request is not always the beginning of the authorization, so it doesn't start the session
+        self.emit_state(self.online_state(false));
+        self.atr.to_string()
+    }
+
+    fn transmit(&mut self, hex_value: &str) -> CardReply {
+        log::info!("{} TRACKER: Payload hex value: {}", self.log_header, hex_value);
+        if !self.session.is_active() {
+            if !start_session(self.card, self.session, self.card_config, self.card_state, self.topic) {
+                // The server gets the empty response for the cancelled authentication
+                return CardReply::Rapdu(String::new());
+            }
+            self.publish_status();
+        }
+        self.session.apdu_count += 1;
+
+        let apdu_started = Instant::now();
+        let apdu_result = if waiting_count(self.waiting_requests) > 0 {
+            // The request waits after the ones received before it, so the card gets them in order
+            Err((true, "The previous requests are waiting for the card".to_string()))
+        } else {
+            self.card.exchange(hex_value, self.atr, self.cardnumber).map_err(|err| {
+                // The card handle is dead after the restart of the PC/SC service, the monitor connects the card again
+                if crate::smart_card::is_service_error(&*err) {
+                    crate::smart_card::report_service_lost();
+                }
+                (crate::smart_card::is_card_absent_error(&*err), err.to_string())
+            })
+        };
+        // The error of the card which doesn't respond, for the frontend
+        let mut card_error: Option<String> = None;
+        let reply = match apdu_result {
+            Ok(response) => {
+                crate::connection_stats::record_apdu(self.cardnumber, apdu_started.elapsed());
+                *self.consecutive_failures = 0;
+                log::debug!("{} APDU response: {:?}", self.log_header, response);
+                CardReply::Rapdu(response)
+            }
+            Err((true, _)) => {
+                if keep_waiting_request(self.waiting_requests, self.card_config, self.topic, self.topic_ack, hex_value, self.log_header) {
+                    self.publish_status();
+                    // The response is sent when the card is inserted back or the hold time is over
+                    return CardReply::Deferred;
+                }
+                CardReply::Response(card_not_present_response(ABSENT_CARD_RETRY_AFTER_SECS))
+            }
+            Err((false, err)) => {
+                log::error!("Failed to send APDU command to card: {}", err);
+                crate::connection_stats::record_error(self.cardnumber, &err);
+                *self.consecutive_failures += 1;
+                if crate::auto_resync::is_failure_threshold_reached(*self.consecutive_failures)
+                    && crate::auto_resync::request_resync(self.cardnumber, self.reader_name.to_owned(), *self.consecutive_failures)
+                {
+                    *self.consecutive_failures = 0;
+                }
+                card_error = Some(err);
+                CardReply::Rapdu(String::new())
+            }
+        };
+        self.emit_state(CardStatePayload {
+            reason: card_error.as_ref().map(|_| StateReason::CardMute),
+            detail: card_error,
+            ..self.online_state(true)
+        });
+        reply
+    }
 }
@@ -0,0 +1,414 @@
+//! Module for the MQTT client of both protocol versions.
+//!
+//! The connections use MQTT 5 by default, the legacy brokers which support only MQTT 3.1.1 are used with
+//! the `mqtt_version: v311` setting of the server (or of the account). The client, the options, the events
+//! and the errors of both versions are wrapped here, so the APDU request/response flow is the same for both.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use rumqttc::v5::mqttbytes::v5::{ConnectProperties, ConnectReturnCode, DisconnectReasonCode};
+use rumqttc::v5::StateError;
+use rumqttc::{Proxy, Transport};
+use serde::{Deserialize, Serialize};
+
+pub use rumqttc::v5::mqttbytes::QoS;
+
+/// Version of the MQTT protocol.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttVersion {
+    /// MQTT 5.
+    #[default]
+    V5,
+    /// MQTT 3.1.1, for the legacy brokers.
+    V311,
+}
+
+/// Options of the MQTT connection.
+#[derive(Debug)]
+pub enum MqttOptions {
+    V5(Box<rumqttc::v5::MqttOptions>),
+    V311(Box<rumqttc::MqttOptions>),
+}
+
+impl MqttOptions {
+    pub fn new(version: MqttVersion, client_id: &str, host: &str, port: u16) -> Self {
+        match version {
+            MqttVersion::V5 => MqttOptions::V5(Box::new(rumqttc::v5::MqttOptions::new(client_id, host, port))),
+            MqttVersion::V311 => MqttOptions::V311(Box::new(rumqttc::MqttOptions::new(client_id, host, port))),
+        }
+    }
+
+    pub fn set_keep_alive(&mut self, duration: Duration) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_keep_alive(duration);
+            }
+            MqttOptions::V311(options) => {
+                options.set_keep_alive(duration);
+            }
+        }
+    }
+
+    pub fn set_credentials(&mut self, username: String, password: String) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_credentials(username, password);
+            }
+            MqttOptions::V311(options) => {
+                options.set_credentials(username, password);
+            }
+        }
+    }
+
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_proxy(proxy);
+            }
+            MqttOptions::V311(options) => {
+                options.set_proxy(proxy);
+            }
+        }
+    }
+
+    /// Sets the message the broker publishes when the client disconnects without the DISCONNECT packet.
+    pub fn set_last_will(&mut self, topic: String, payload: String, qos: QoS, retain: bool) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_last_will(rumqttc::v5::mqttbytes::v5::LastWill::new(topic, payload, qos, retain, None));
+            }
+            MqttOptions::V311(options) => {
+                options.set_last_will(rumqttc::LastWill::new(topic, payload, v311_qos(qos), retain));
+            }
+        }
+    }
+
+    /// Keeps the session of the client on the broker after the disconnection, so the broker keeps the subscriptions
+    /// and the QoS 1/2 messages of the client until it reconnects.
+    ///
+    /// # Arguments
+    ///
+    /// * `expiry` - How long the broker keeps the session after the disconnection (MQTT 5 only, the MQTT 3.1.1
+    ///   broker keeps it as long as it is configured on the broker).
+    pub fn set_persistent_session(&mut self, expiry: Duration) {
+        match self {
+            MqttOptions::V5(options) => {
+                let mut properties = options.connect_properties().unwrap_or_else(ConnectProperties::new);
+                properties.session_expiry_interval = Some(expiry.as_secs().min(u32::MAX as u64) as u32);
+                options.set_clean_start(false);
+                options.set_connect_properties(properties);
+            }
+            MqttOptions::V311(options) => {
+                options.set_clean_session(false);
+            }
+        }
+    }
+
+    /// Sets the maximum size of the MQTT packets, the same for the incoming and the outgoing packets.
+    pub fn set_max_packet_size(&mut self, max_packet_size: u32) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_max_packet_size(Some(max_packet_size));
+            }
+            MqttOptions::V311(options) => {
+                options.set_max_packet_size(max_packet_size as usize, max_packet_size as usize);
+            }
+        }
+    }
+
+    /// Sets the user properties of the CONNECT packet, so the broker knows where the session comes from.
+    /// MQTT 3.1.1 has no user properties, the call is ignored for it.
+    pub fn set_user_properties(&mut self, user_properties: Vec<(String, String)>) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_user_properties(user_properties);
+            }
+            MqttOptions::V311(_) => {}
+        }
+    }
+
+    pub fn set_transport(&mut self, transport: Transport) {
+        match self {
+            MqttOptions::V5(options) => {
+                options.set_transport(transport);
+            }
+            MqttOptions::V311(options) => {
+                options.set_transport(transport);
+            }
+        }
+    }
+}
+
+/// Creates the MQTT client and its event loop.
+///
+/// # Arguments
+///
+/// * `options` - The options of the connection, they define the protocol version.
+/// * `cap` - The capacity of the channel of the requests to the event loop.
+pub fn create_client(options: MqttOptions, cap: usize) -> (MqttClient, EventLoop) {
+    match options {
+        MqttOptions::V5(options) => {
+            let (client, eventloop) = rumqttc::v5::AsyncClient::new(*options, cap);
+            (MqttClient::V5(client), EventLoop::V5(Box::new(eventloop)))
+        }
+        MqttOptions::V311(options) => {
+            let (client, eventloop) = rumqttc::AsyncClient::new(*options, cap);
+            (MqttClient::V311(client), EventLoop::V311(Box::new(eventloop)))
+        }
+    }
+}
+
+/// MQTT client of the connection.
+#[derive(Clone, Debug)]
+pub enum MqttClient {
+    V5(rumqttc::v5::AsyncClient),
+    V311(rumqttc::AsyncClient),
+}
+
+/// Error of the request to the event loop.
+pub enum ClientError {
+    V5(rumqttc::v5::ClientError),
+    V311(rumqttc::ClientError),
+}
+
+impl fmt::Debug for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::V5(e) => write!(f, "{:?}", e),
+            ClientError::V311(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl MqttClient {
+    pub async fn publish<S: Into<String>>(&self, topic: S, qos: QoS, retain: bool, payload: String) -> Result<(), ClientError> {
+        let topic = topic.into();
+        if crate::fault_injection::should_drop_publish(&topic) {
+            return Ok(());
+        }
+        match self {
+            MqttClient::V5(client) => client.publish(topic, qos, retain, payload).await.map_err(ClientError::V5),
+            MqttClient::V311(client) => client
+                .publish(topic, v311_qos(qos), retain, payload)
+                .await
+                .map_err(ClientError::V311),
+        }
+    }
+
+    pub async fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V5(client) => client.subscribe(topic, qos).await.map_err(ClientError::V5),
+            MqttClient::V311(client) => client.subscribe(topic, v311_qos(qos)).await.map_err(ClientError::V311),
+        }
+    }
+
+    pub async fn unsubscribe<S: Into<String>>(&self, topic: S) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V5(client) => client.unsubscribe(topic).await.map_err(ClientError::V5),
+            MqttClient::V311(client) => client.unsubscribe(topic).await.map_err(ClientError::V311),
+        }
+    }
+
+    /// Sends the DISCONNECT to the server, the event loop returns `MqttEvent::Disconnect` when it is sent.
+    pub async fn disconnect(&self) -> Result<(), ClientError> {
+        match self {
+            MqttClient::V5(client) => client.disconnect().await.map_err(ClientError::V5),
+            MqttClient::V311(client) => client.disconnect().await.map_err(ClientError::V311),
+        }
+    }
+}
+
+fn v311_qos(qos: QoS) -> rumqttc::QoS {
+    match qos {
+        QoS::AtMostOnce => rumqttc::QoS::AtMostOnce,
+        QoS::AtLeastOnce => rumqttc::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => rumqttc::QoS::ExactlyOnce,
+    }
+}
+
+/// Message received from the server.
+#[derive(Debug)]
+pub struct IncomingPublish {
+    pub topic: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Event of the connection, the same for both protocol versions.
+pub enum MqttEvent {
+    /// The connection is established, `session_present` if the broker has resumed the previous session.
+    ConnAck { session_present: bool },
+    /// The message is received from the server.
+    Publish(IncomingPublish),
+    /// The keep-alive PING is sent to the server.
+    PingRequest,
+    /// The server has responded to the PING.
+    PingResponse,
+    /// The DISCONNECT is sent to the server, the connection is closed by the client.
+    Disconnect,
+    /// Any other packet, with its description for the log.
+    Other(String),
+}
+
+impl fmt::Debug for MqttEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttEvent::ConnAck { session_present } => write!(f, "ConnAck(session_present: {})", session_present),
+            MqttEvent::Publish(publish) => write!(f, "{:?}", publish),
+            MqttEvent::PingRequest => write!(f, "PingRequest"),
+            MqttEvent::PingResponse => write!(f, "PingResponse"),
+            MqttEvent::Disconnect => write!(f, "Disconnect"),
+            MqttEvent::Other(description) => write!(f, "{}", description),
+        }
+    }
+}
+
+/// Event loop of the connection.
+pub enum EventLoop {
+    V5(Box<rumqttc::v5::EventLoop>),
+    V311(Box<rumqttc::EventLoop>),
+}
+
+impl EventLoop {
+    /// Sets the timeout of the network connection and of the CONNACK.
+    pub fn set_connection_timeout(&mut self, timeout: Duration) {
+        match self {
+            EventLoop::V5(eventloop) => {
+                eventloop.options.set_connection_timeout(timeout.as_secs());
+            }
+            EventLoop::V311(eventloop) => {
+                let mut network_options = eventloop.network_options();
+                network_options.set_connection_timeout(timeout.as_secs());
+                eventloop.set_network_options(network_options);
+            }
+        }
+    }
+
+    /// Replaces the options of the connection, the next reconnection uses them (e.g. the host of the redirect).
+    /// The options of the other protocol version are ignored.
+    pub fn set_options(&mut self, options: MqttOptions) {
+        match (self, options) {
+            (EventLoop::V5(eventloop), MqttOptions::V5(options)) => eventloop.options = *options,
+            (EventLoop::V311(eventloop), MqttOptions::V311(options)) => eventloop.mqtt_options = *options,
+            _ => log::warn!("The options of another MQTT version are not applied to the connection"),
+        }
+    }
+
+    /// Polls the next event of the connection, reconnecting if needed.
+    pub async fn poll(&mut self) -> Result<MqttEvent, ConnectionError> {
+        match self {
+            EventLoop::V5(eventloop) => {
+                use rumqttc::v5::{Event, Incoming};
+                use rumqttc::Outgoing;
+                match eventloop.poll().await.map_err(ConnectionError::V5)? {
+                    Event::Incoming(Incoming::ConnAck(connack)) => Ok(MqttEvent::ConnAck {
+                        session_present: connack.session_present,
+                    }),
+                    Event::Incoming(Incoming::Publish(publish)) => Ok(MqttEvent::Publish(IncomingPublish {
+                        topic: publish.topic.to_vec(),
+                        payload: publish.payload.to_vec(),
+                    })),
+                    Event::Incoming(Incoming::PingResp(..)) => Ok(MqttEvent::PingResponse),
+                    Event::Outgoing(Outgoing::PingReq) => Ok(MqttEvent::PingRequest),
+                    Event::Outgoing(Outgoing::Disconnect) => Ok(MqttEvent::Disconnect),
+                    event => Ok(MqttEvent::Other(format!("{:?}", event))),
+                }
+            }
+            EventLoop::V311(eventloop) => {
+                use rumqttc::{Event, Incoming, Outgoing};
+                match eventloop.poll().await.map_err(ConnectionError::V311)? {
+                    Event::Incoming(Incoming::ConnAck(connack)) => Ok(MqttEvent::ConnAck {
+                        session_present: connack.session_present,
+                    }),
+                    Event::Incoming(Incoming::Publish(publish)) => Ok(MqttEvent::Publish(IncomingPublish {
+                        topic: publish.topic.into_bytes(),
+                        payload: publish.payload.to_vec(),
+                    })),
+                    Event::Incoming(Incoming::PingResp) => Ok(MqttEvent::PingResponse),
+                    Event::Outgoing(Outgoing::PingReq) => Ok(MqttEvent::PingRequest),
+                    Event::Outgoing(Outgoing::Disconnect) => Ok(MqttEvent::Disconnect),
+                    event => Ok(MqttEvent::Other(format!("{:?}", event))),
+                }
+            }
+        }
+    }
+}
+
+/// Error of the connection.
+#[derive(Debug)]
+pub enum ConnectionError {
+    V5(rumqttc::v5::ConnectionError),
+    V311(rumqttc::ConnectionError),
+    /// The error of the shared connection, relayed to the cards which use it (see the multiplex module).
+    Shared { kind: ConnectionErrorKind, message: String },
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::V5(e) => write!(f, "{}", e),
+            ConnectionError::V311(e) => write!(f, "{}", e),
+            ConnectionError::Shared { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Kind of the connection error, to report it to the user in the same way for both protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionErrorKind {
+    /// The server has asked to connect to another server (MQTT 5 only).
+    ServerMoved,
+    /// The network error.
+    Io(ErrorKind),
+    /// The connection is terminated by the server (MQTT 5 only).
+    ServerDisconnect,
+    /// The server doesn't respond to the PING.
+    AwaitPingResp,
+    /// The network error while the state is sent.
+    StateIo,
+    /// The connection through the proxy has failed.
+    Proxy,
+    /// The broker has rejected the credentials or the client is not authorized.
+    CredentialsRejected,
+    Other,
+}
+
+impl ConnectionError {
+    pub fn kind(&self) -> ConnectionErrorKind {
+        use rumqttc::v5::ConnectionError as V5Error;
+        match self {
+            ConnectionError::V5(e) => match e {
+                // "Use another server" and "Server moved" reason codes in CONNACK or DISCONNECT
+                V5Error::MqttState(StateError::ServerDisconnect {
+                    reason_code: DisconnectReasonCode::UseAnotherServer | DisconnectReasonCode::ServerMoved,
+                    ..
+                })
+                | V5Error::ConnectionRefused(ConnectReturnCode::UseAnotherServer | ConnectReturnCode::ServerMoved) => {
+                    ConnectionErrorKind::ServerMoved
+                }
+                V5Error::ConnectionRefused(ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized) => {
+                    ConnectionErrorKind::CredentialsRejected
+                }
+                V5Error::Io(io_err) => ConnectionErrorKind::Io(io_err.kind()),
+                V5Error::MqttState(StateError::ServerDisconnect { .. }) => ConnectionErrorKind::ServerDisconnect,
+                V5Error::MqttState(StateError::AwaitPingResp) => ConnectionErrorKind::AwaitPingResp,
+                V5Error::MqttState(StateError::Io(_)) => ConnectionErrorKind::StateIo,
+                V5Error::Proxy(_) => ConnectionErrorKind::Proxy,
+                _ => ConnectionErrorKind::Other,
+            },
+            ConnectionError::V311(e) => match e {
+                rumqttc::ConnectionError::ConnectionRefused(
+                    rumqttc::ConnectReturnCode::BadUserNamePassword | rumqttc::ConnectReturnCode::NotAuthorized,
+                ) => ConnectionErrorKind::CredentialsRejected,
+                rumqttc::ConnectionError::Io(io_err) => ConnectionErrorKind::Io(io_err.kind()),
+                rumqttc::ConnectionError::MqttState(rumqttc::StateError::AwaitPingResp) => ConnectionErrorKind::AwaitPingResp,
+                rumqttc::ConnectionError::MqttState(rumqttc::StateError::Io(_)) => ConnectionErrorKind::StateIo,
+                rumqttc::ConnectionError::Proxy(_) => ConnectionErrorKind::Proxy,
+                _ => ConnectionErrorKind::Other,
+            },
+            ConnectionError::Shared { kind, .. } => *kind,
+        }
+    }
+}
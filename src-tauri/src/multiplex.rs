@@ -0,0 +1,363 @@
+//! Module for the multiplexed connection of the cards.
+//!
+//! By default every card has its own MQTT connection, so the PC with many readers opens many sockets to the broker
+//! and all of them reconnect at once after a network blip. With the `session.multiplexed` setting all cards
+//! of the account share one connection: every card subscribes to its requests with the `topics.request` template,
+//! and the shared connection routes the received requests to the cards by these subscriptions. The card task
+//! gets the events of the shared connection in the same form as the events of its own connection, so the request
+//! flow doesn't depend on the mode.
+//!
+//! The broker can't publish the last will of every card for the shared connection, so the connection has the last
+//! will of the bridge on `tba/bridges/<installation ID>/status` instead.
+//!
+//! The shared connection is closed when its last card is detached, or when its account is no longer configured
+//! (e.g. the ident is changed), see `close_unconfigured`.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::config::{get_accounts, get_session_config, split_host_to_parts, AccountConfig};
+use crate::mqtt_client::{create_client, ConnectionError, ConnectionErrorKind, EventLoop, MqttClient, MqttEvent, MqttOptions, QoS};
+use crate::topics::topic_matches;
+
+/// Timeout in seconds to wait before reconnecting the shared connection to the server.
+const SLEEP_DURATION_SECS: u64 = 10;
+
+/// Prefix of the status topic of the bridge: `<prefix>/<installation ID>/status`.
+const BRIDGE_STATUS_TOPIC_PREFIX: &str = "tba/bridges";
+
+type EventResult = Result<MqttEvent, ConnectionError>;
+
+/// Card using the shared connection.
+struct Route {
+    /// Topic filter of the requests of the card.
+    filter: String,
+    sender: UnboundedSender<EventResult>,
+}
+
+/// Connection shared by the cards of the account.
+struct SharedConnection {
+    client: MqttClient,
+    /// Cards of the connection by the card number.
+    routes: Arc<Mutex<HashMap<String, Route>>>,
+    connected: Arc<AtomicBool>,
+    /// The connection is closed, its task stops instead of reconnecting.
+    closed: Arc<AtomicBool>,
+}
+
+lazy_static! {
+    /// Shared connections by the account (see `account_key`).
+    static ref CONNECTIONS: Mutex<HashMap<String, SharedConnection>> = Mutex::new(HashMap::new());
+}
+
+/// Events of the card connection: from its own event loop or from the shared connection.
+pub enum CardEvents {
+    Own(EventLoop),
+    Shared(UnboundedReceiver<EventResult>),
+}
+
+impl CardEvents {
+    /// Polls the next event of the card connection.
+    pub async fn poll(&mut self) -> EventResult {
+        match self {
+            CardEvents::Own(eventloop) => eventloop.poll().await,
+            CardEvents::Shared(receiver) => match receiver.recv().await {
+                Some(event) => event,
+                None => Err(ConnectionError::Shared {
+                    kind: ConnectionErrorKind::Other,
+                    message: "The shared connection is closed".to_string(),
+                }),
+            },
+        }
+    }
+}
+
+/// The accounts with the same broker and credentials share the connection.
+fn account_key(account: &AccountConfig) -> String {
+    format!("{}|{}|{}", account.host, account.ident, account.username.as_deref().unwrap_or_default())
+}
+
+fn bridge_status_topic() -> String {
+    format!("{}/{}/status", BRIDGE_STATUS_TOPIC_PREFIX, crate::installation::installation_id())
+}
+
+fn bridge_status(online: bool) -> String {
+    serde_json::json!({
+        "installation_id": crate::installation::installation_id(),
+        "online": online,
+    })
+    .to_string()
+}
+
+/// Attaches the card to the shared connection of its account, the connection is established with the first card.
+///
+/// # Arguments
+///
+/// * `account` - The account of the card.
+/// * `cardnumber` - The number of the card.
+/// * `filter` - The topic filter of the requests of the card.
+///
+/// # Returns
+///
+/// * `Result<(MqttClient, CardEvents), String>` - The client to publish the responses and the events of the card,
+///   or the error if the connection can't be established with the settings of the account.
+pub fn attach(account: &AccountConfig, cardnumber: &str, filter: String) -> Result<(MqttClient, CardEvents), String> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let key = account_key(account);
+    if !connections.contains_key(&key) {
+        let connection = connect(account)?;
+        connections.insert(key.clone(), connection);
+    }
+    let connection = &connections[&key];
+
+    let (sender, receiver) = unbounded_channel();
+    // The card attached to the established connection subscribes right away, as the others did at the CONNACK
+    if connection.connected.load(Ordering::SeqCst) {
+        let _ = sender.send(Ok(MqttEvent::ConnAck { session_present: false }));
+    }
+    connection
+        .routes
+        .lock()
+        .unwrap()
+        .insert(cardnumber.to_string(), Route { filter, sender });
+    log::info!("{} | The card uses the shared connection to {}", cardnumber, account.host);
+    Ok((connection.client.clone(), CardEvents::Shared(receiver)))
+}
+
+/// Detaches the removed card from its shared connection, the connection is closed with its last card.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The number of the card.
+pub fn detach(cardnumber: &str) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let key = connections
+        .iter()
+        .find(|(_, connection)| connection.routes.lock().unwrap().contains_key(cardnumber))
+        .map(|(key, _)| key.clone());
+    let key = match key {
+        Some(key) => key,
+        None => return,
+    };
+    let connection = &connections[&key];
+    let route = connection.routes.lock().unwrap().remove(cardnumber);
+    if let Some(route) = route {
+        spawn_unsubscribe(cardnumber.to_string(), connection.client.clone(), route.filter);
+    }
+    close_if_unused(&mut connections, &key);
+}
+
+/// Closes the shared connections of the accounts which are no longer configured, e.g. after the change
+/// of the ident or of the server.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The cards of the closed connections, to be connected again with their current account.
+pub fn close_unconfigured() -> Vec<String> {
+    let configured: Vec<String> = get_accounts().iter().map(|(_, account)| account_key(account)).collect();
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let unconfigured: Vec<String> = connections
+        .keys()
+        .filter(|key| !configured.contains(key))
+        .cloned()
+        .collect();
+    let mut cards = Vec::new();
+    for key in unconfigured {
+        if let Some(connection) = connections.remove(&key) {
+            cards.extend(connection.routes.lock().unwrap().keys().cloned());
+            close(&key, connection);
+        }
+    }
+    cards
+}
+
+/// Closes the shared connection if it has no cards.
+fn close_if_unused(connections: &mut HashMap<String, SharedConnection>, key: &str) {
+    let unused = connections
+        .get(key)
+        .map(|connection| connection.routes.lock().unwrap().is_empty())
+        .unwrap_or_default();
+    if unused {
+        if let Some(connection) = connections.remove(key) {
+            close(key, connection);
+        }
+    }
+}
+
+/// Disconnects the shared connection removed from `CONNECTIONS`, its task stops after the DISCONNECT.
+fn close(key: &str, connection: SharedConnection) {
+    log::info!("The shared connection of {} is closed", key);
+    connection.closed.store(true, Ordering::SeqCst);
+    let client = connection.client;
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = client.disconnect().await {
+            log::warn!("Failed to disconnect the shared connection: {:?}", e);
+        }
+    });
+}
+
+/// Unsubscribes from the requests of the detached card. The request is sent from its own task, so the polling
+/// of the connection is not blocked when the channel of the client is full.
+fn spawn_unsubscribe(client_id: String, client: MqttClient, filter: String) {
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = client.unsubscribe(filter.clone()).await {
+            log::warn!("{} | Failed to unsubscribe from {}: {:?}", client_id, filter, e);
+        }
+    });
+}
+
+/// Establishes the shared connection of the account.
+fn connect(account: &AccountConfig) -> Result<SharedConnection, String> {
+    let client_id = shared_client_id(account);
+    // The server the broker has redirected the connections to (see the server_redirect module)
+    let mqtt_options = shared_mqtt_options(account, &crate::server_redirect::current_host(&account.host))?;
+
+    // The capacity is larger than of the card connections, as all cards publish through this client
+    let (client, mut eventloop) = create_client(mqtt_options, 100);
+    account.tuning.apply_connection_timeout(&mut eventloop);
+    let connection = SharedConnection {
+        client: client.clone(),
+        routes: Arc::new(Mutex::new(HashMap::new())),
+        connected: Arc::new(AtomicBool::new(false)),
+        closed: Arc::new(AtomicBool::new(false)),
+    };
+    tauri::async_runtime::spawn(run(
+        account.clone(),
+        client_id,
+        client,
+        eventloop,
+        connection.routes.clone(),
+        connection.connected.clone(),
+        connection.closed.clone(),
+    ));
+    Ok(connection)
+}
+
+fn shared_client_id(account: &AccountConfig) -> String {
+    format!("tba-{}-{}", crate::installation::installation_id(), account.ident)
+}
+
+/// Builds the options of the shared connection of the account to the server.
+///
+/// # Arguments
+///
+/// * `account` - The account of the connection.
+/// * `full_host` - The server, 'host:port': the configured one or the one of the redirect.
+fn shared_mqtt_options(account: &AccountConfig, full_host: &str) -> Result<MqttOptions, String> {
+    let (host, port) = split_host_to_parts(full_host)?;
+    let mut mqtt_options = MqttOptions::new(account.mqtt_version, &shared_client_id(account), &host, port);
+    // The shared connection carries the card traffic, so it has the keep-alive of the card connections
+    mqtt_options.set_keep_alive(account.tuning.card_keep_alive());
+    account.tuning.apply_packet_size(&mut mqtt_options);
+    // The connection is shared by the cards, so only the bridge is identified on CONNECT
+    mqtt_options.set_user_properties(crate::installation::bridge_properties());
+    account.apply_credentials(&mut mqtt_options);
+    mqtt_options.set_last_will(bridge_status_topic(), bridge_status(false), QoS::AtLeastOnce, true);
+    let session_config = get_session_config();
+    if session_config.persistent {
+        mqtt_options.set_persistent_session(Duration::from_secs(session_config.expiry_secs));
+    }
+    account.apply_tls(&mut mqtt_options)?;
+    crate::proxy::apply_proxy(&mut mqtt_options)?;
+    Ok(mqtt_options)
+}
+
+/// Polls the shared connection and routes its events to the cards.
+async fn run(
+    account: AccountConfig,
+    client_id: String,
+    client: MqttClient,
+    mut eventloop: EventLoop,
+    routes: Arc<Mutex<HashMap<String, Route>>>,
+    connected: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+) {
+    let key = account_key(&account);
+    loop {
+        match eventloop.poll().await {
+            Ok(MqttEvent::ConnAck { session_present }) => {
+                log::info!("{} | The shared connection is established", client_id);
+                connected.store(true, Ordering::SeqCst);
+                for route in routes.lock().unwrap().values() {
+                    let _ = route.sender.send(Ok(MqttEvent::ConnAck { session_present }));
+                }
+                // The status is published from its own task, the polling would wait for the room in the channel
+                let (client, client_id) = (client.clone(), client_id.clone());
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = client.publish(bridge_status_topic(), QoS::AtLeastOnce, true, bridge_status(true)).await {
+                        log::error!("{} | Failed to publish the bridge status: {:?}", client_id, e);
+                    }
+                });
+            }
+            Ok(MqttEvent::Publish(publish)) => {
+                let topic = String::from_utf8_lossy(&publish.topic).to_string();
+                let routes = routes.lock().unwrap();
+                match routes.values().find(|route| topic_matches(&route.filter, &topic)) {
+                    Some(route) => {
+                        let _ = route.sender.send(Ok(MqttEvent::Publish(publish)));
+                    }
+                    None => log::warn!("{} | No card for the message on {}", client_id, topic),
+                }
+            }
+            Ok(MqttEvent::Disconnect) if closed.load(Ordering::SeqCst) => {
+                log::info!("{} | The shared connection is disconnected", client_id);
+                return;
+            }
+            Ok(_) => {}
+            Err(_) if closed.load(Ordering::SeqCst) => return,
+            Err(e) => {
+                log::warn!("{} | The shared connection has failed: {}", client_id, e);
+                connected.store(false, Ordering::SeqCst);
+                let (kind, message) = (e.kind(), e.to_string());
+                for route in routes.lock().unwrap().values() {
+                    let _ = route.sender.send(Err(ConnectionError::Shared {
+                        kind,
+                        message: message.clone(),
+                    }));
+                }
+                // The connection is moved to the referenced server if it is allowed
+                let log_header = format!("{} |", client_id);
+                if kind == ConnectionErrorKind::ServerMoved {
+                    if let Some(target) = crate::server_redirect::follow_redirect(&account, &log_header).await {
+                        match shared_mqtt_options(&account, &target) {
+                            Ok(mqtt_options) => {
+                                eventloop.set_options(mqtt_options);
+                                account.tuning.apply_connection_timeout(&mut eventloop);
+                                continue;
+                            }
+                            Err(e) => log::error!("{} The connection can't be moved to {}: {}", log_header, target, e),
+                        }
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(SLEEP_DURATION_SECS)).await;
+            }
+        }
+
+        // The requests of the cards whose tasks have stopped are not received anymore
+        let detached: Vec<String> = {
+            let mut routes = routes.lock().unwrap();
+            let detached = routes
+                .values()
+                .filter(|route| route.sender.is_closed())
+                .map(|route| route.filter.clone())
+                .collect();
+            routes.retain(|_, route| !route.sender.is_closed());
+            detached
+        };
+        if !detached.is_empty() {
+            for filter in detached {
+                spawn_unsubscribe(client_id.clone(), client.clone(), filter);
+            }
+            let mut connections = CONNECTIONS.lock().unwrap();
+            // The entry of the account may already belong to a new connection
+            if connections.get(&key).map(|connection| Arc::ptr_eq(&connection.routes, &routes)) == Some(true) {
+                close_if_unused(&mut connections, &key);
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Module for the offline buffering of the APDU responses.
+//!
+//! The response is lost if it is produced while the connection of the card is down (e.g. the queued request
+//! processed when the card is back) or if its publish fails. The MQTT client replays only the responses it has
+//! accepted, so the card client keeps the other ones in its outbox and publishes them when the connection returns.
+//! The outbox is small and its responses expire, as the server gives up the authentication session after a while
+//! and the stale response would only confuse it. The outbox of every card is saved to `outbox/<card number>.json`
+//! in the data folder, so the responses survive the restart of the application within their lifetime.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{get_data_dir, retry_io};
+
+/// Maximum number of the responses kept in the outbox, the oldest ones are dropped.
+const MAX_OUTBOX_RESPONSES: usize = 16;
+
+/// Time after which the response is not published anymore.
+const OUTBOX_TTL_SECS: i64 = 60;
+
+/// Folder of the outbox files in the data folder.
+const OUTBOX_DIR_NAME: &str = "outbox";
+
+/// Response waiting for the connection.
+#[derive(Serialize, Deserialize)]
+struct PendingResponse {
+    topic: String,
+    payload: String,
+    /// Unix time in seconds, the wall clock is used as the response may be published after the restart.
+    queued_at: i64,
+}
+
+/// Outbox of the responses of the card client.
+#[derive(Default)]
+pub struct Outbox {
+    responses: VecDeque<PendingResponse>,
+    /// File the outbox is saved to, `None` if the outbox is kept only in memory.
+    path: Option<PathBuf>,
+}
+
+/// Outbox shared by the card task and its publisher task, which keeps the responses whose publish has failed.
+pub type SharedOutbox = Arc<Mutex<Outbox>>;
+
+/// Path of the outbox file of the card. The card number is reduced to the characters safe in the file names.
+fn outbox_file_path(cardnumber: &str) -> std::io::Result<PathBuf> {
+    let name: String = cardnumber
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let mut path = get_data_dir()?;
+    path.push(OUTBOX_DIR_NAME);
+    fs::create_dir_all(&path)?;
+    path.push(format!("{}.json", name));
+    Ok(path)
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+impl Outbox {
+    /// Loads the outbox of the card saved before the restart, the outbox is empty if there is none.
+    /// The outbox is saved to its file on every change.
+    pub fn load(cardnumber: &str) -> Self {
+        let path = match outbox_file_path(cardnumber) {
+            Ok(path) => path,
+            Err(e) => {
+                log::warn!("{} | The outbox is kept only in memory: {}", cardnumber, e);
+                return Outbox::default();
+            }
+        };
+        let responses = match retry_io(|| fs::read_to_string(&path)) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log::warn!("{} | Invalid outbox in {}, it is dropped: {}", cardnumber, path.display(), e);
+                VecDeque::new()
+            }),
+            Err(_) => VecDeque::new(),
+        };
+        Outbox {
+            responses,
+            path: Some(path),
+        }
+    }
+
+    /// Keeps the response until the connection returns.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `false` if the outbox is full and the oldest response is dropped.
+    pub fn push(&mut self, topic: String, payload: String) -> bool {
+        let pushed = self.push_at(topic, payload, now());
+        self.save();
+        pushed
+    }
+
+    fn push_at(&mut self, topic: String, payload: String, queued_at: i64) -> bool {
+        self.responses.push_back(PendingResponse { topic, payload, queued_at });
+        if self.responses.len() > MAX_OUTBOX_RESPONSES {
+            self.responses.pop_front();
+            return false;
+        }
+        true
+    }
+
+    /// Takes the responses to publish, in the order they are produced. The expired responses are dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `(Vec<(String, String)>, usize)` - The topics and the payloads of the responses, and the number
+    ///   of the expired ones.
+    pub fn take(&mut self) -> (Vec<(String, String)>, usize) {
+        let taken = self.take_at(now());
+        self.save();
+        taken
+    }
+
+    fn take_at(&mut self, now: i64) -> (Vec<(String, String)>, usize) {
+        let total = self.responses.len();
+        let fresh: Vec<(String, String)> = self
+            .responses
+            .drain(..)
+            .filter(|response| now - response.queued_at < OUTBOX_TTL_SECS)
+            .map(|response| (response.topic, response.payload))
+            .collect();
+        let expired = total - fresh.len();
+        (fresh, expired)
+    }
+
+    /// Saves the outbox to its file, the file is removed when the outbox is empty.
+    fn save(&self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        let result = if self.responses.is_empty() {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        } else {
+            serde_json::to_string(&self.responses)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                .and_then(|contents| retry_io(|| fs::write(path, &contents)))
+        };
+        if let Err(e) = result {
+            log::error!("Failed to save the outbox to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_taken_in_order() {
+        let mut outbox = Outbox::default();
+        outbox.push("a/response".to_string(), "1".to_string());
+        outbox.push("b/response".to_string(), "2".to_string());
+        let (responses, expired) = outbox.take();
+        assert_eq!(responses, vec![("a/response".to_string(), "1".to_string()), ("b/response".to_string(), "2".to_string())]);
+        assert_eq!(expired, 0);
+        assert_eq!(outbox.take(), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn expired_and_overflowing_responses_are_dropped() {
+        let mut outbox = Outbox::default();
+        let start = now();
+        for i in 0..MAX_OUTBOX_RESPONSES {
+            assert!(outbox.push_at("t".to_string(), i.to_string(), start));
+        }
+        assert!(!outbox.push_at("t".to_string(), "late".to_string(), start + OUTBOX_TTL_SECS));
+        let (responses, expired) = outbox.take_at(start + OUTBOX_TTL_SECS + 1);
+        assert_eq!(responses, vec![("t".to_string(), "late".to_string())]);
+        assert_eq!(expired, MAX_OUTBOX_RESPONSES - 1);
+    }
+
+    #[test]
+    fn saved_responses_are_loaded() {
+        let path = std::env::temp_dir().join(format!("tba-outbox-{}.json", uuid::Uuid::new_v4()));
+        let mut outbox = Outbox {
+            path: Some(path.clone()),
+            ..Outbox::default()
+        };
+        outbox.push("a/response".to_string(), "1".to_string());
+        let contents = fs::read_to_string(&path).unwrap();
+        let responses: VecDeque<PendingResponse> = serde_json::from_str(&contents).unwrap();
+        let mut restored = Outbox {
+            responses,
+            path: Some(path.clone()),
+        };
+        assert_eq!(restored.take(), (vec![("a/response".to_string(), "1".to_string())], 0));
+        // The empty outbox has no file
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,123 @@
+//! Module for the dry-run of the destructive actions.
+//!
+//! Removing a card from the configuration or changing the server terminates the MQTT connections of the cards,
+//! including the authentications in progress. The preview commands report what would happen without changing
+//! anything, so the UI can show an informed confirmation dialog before the action.
+
+use serde::Serialize;
+
+use crate::config::{get_accounts, get_card_account_name, get_card_config, split_host_to_parts, DEFAULT_ACCOUNT};
+use crate::mqtt::get_active_session;
+use crate::reader_pool::find_card_reader;
+use crate::smart_card::{ReaderId, TASK_POOL};
+
+/// Card affected by the action.
+#[derive(Serialize, Clone, Debug)]
+pub struct AffectedCard {
+    pub card_number: String,
+    /// The reader the card is inserted to, `None` if the card is not in any reader.
+    pub reader: Option<ReaderId>,
+    pub reader_label: Option<String>,
+    /// The MQTT connection of the card is running and would be terminated.
+    pub connected: bool,
+    /// Topic of the request of the authentication in progress, which would be interrupted.
+    pub authentication: Option<String>,
+}
+
+impl AffectedCard {
+    fn new(card_number: &str, connected: bool) -> Self {
+        let reader = find_card_reader(card_number);
+        AffectedCard {
+            card_number: card_number.to_string(),
+            reader_label: reader.as_ref().map(|reader| reader.label()),
+            reader,
+            connected,
+            authentication: get_active_session(card_number),
+        }
+    }
+}
+
+/// What would happen if the card is removed from the configuration.
+#[derive(Serialize, Clone, Debug)]
+pub struct RemoveCardPreview {
+    /// The card is in the configuration, otherwise there is nothing to remove.
+    pub configured: bool,
+    pub card: AffectedCard,
+}
+
+/// What would happen if the server of the default account is changed.
+#[derive(Serialize, Clone, Debug)]
+pub struct ServerChangePreview {
+    pub current_host: String,
+    pub new_host: String,
+    /// The new host differs from the current one, otherwise nothing would happen.
+    pub changed: bool,
+    /// The reason the new host can't be used, `None` if it is valid.
+    pub error: Option<String>,
+    /// The cards whose connections would be terminated and established to the new server.
+    pub cards: Vec<AffectedCard>,
+}
+
+/// Returns the numbers of the cards with the running MQTT connections.
+async fn connected_cards() -> Vec<String> {
+    TASK_POOL.lock().await.iter().map(|task| task.client_id.clone()).collect()
+}
+
+/// Public function to preview the removal of the card from the configuration.
+/// This function is a Tauri command that is called from the frontend before the confirmation dialog.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+///
+/// # Returns
+///
+/// * `RemoveCardPreview` - The connection, the reader and the authentication of the card that would be affected.
+#[tauri::command]
+pub async fn preview_remove_card(cardnumber: String) -> RemoveCardPreview {
+    let connected = connected_cards().await.contains(&cardnumber);
+    RemoveCardPreview {
+        configured: get_card_config(&cardnumber).is_some(),
+        card: AffectedCard::new(&cardnumber, connected),
+    }
+}
+
+/// Public function to preview the change of the server of the default account.
+/// This function is a Tauri command that is called from the frontend before the confirmation dialog.
+///
+/// # Arguments
+///
+/// * `host` - The new server address.
+///
+/// # Returns
+///
+/// * `ServerChangePreview` - The cards that would be reconnected to the new server.
+#[tauri::command]
+pub async fn preview_server_change(host: String) -> ServerChangePreview {
+    let current_host = get_accounts()
+        .into_iter()
+        .find(|(name, _)| name == DEFAULT_ACCOUNT)
+        .map(|(_, account)| account.host)
+        .unwrap_or_default();
+    let changed = current_host != host;
+
+    // The cards of the other accounts are connected to their own servers
+    let cards = if changed {
+        connected_cards()
+            .await
+            .iter()
+            .filter(|card| get_card_account_name(card) == DEFAULT_ACCOUNT)
+            .map(|card| AffectedCard::new(card, true))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    ServerChangePreview {
+        error: split_host_to_parts(&host).err(),
+        current_host,
+        new_host: host,
+        changed,
+        cards,
+    }
+}
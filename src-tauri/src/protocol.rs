@@ -0,0 +1,497 @@
+//! Module for parsing the APDU requests from the server.
+//!
+//! The request is `{"finish": <bool>, "payload": "<APDU hex>"}`. The parser works in two modes (see `ProtocolMode`):
+//! the lenient mode ignores the unknown fields, the strict mode rejects the request with any deviation from the protocol.
+//! In both modes every deviation is reported as a protocol anomaly, which is logged and counted in the statistics,
+//! so the server-side regressions are noticed early.
+//!
+//! Special cases of the protocol, covered by the conformance tests with the recorded server traffic
+//! (`tests/conformance/*.json`):
+//! * `finish: true` - the authentication is finished, the card is reset and the response has the empty payload;
+//! * empty `payload` - the server asks for the ATR of the card, the card is not accessed;
+//! * missing `payload` (lenient mode only) - there is nothing to send to the card, the response is the empty message;
+//! * rejected request - the protocol error response in the strict mode, no response in the lenient mode;
+//! * `61xx` and `6Cxx` status words of the T=0 cards - the bridge gets the rest of the response with GET RESPONSE
+//!   and repeats the command with the correct Le, so the server always gets the complete response data.
+//!
+//! On every connection of the card the bridge publishes its capabilities (see `Capabilities`),
+//! so the server tailors the authentication flow instead of probing the card.
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::card_identification::PinPolicy;
+use crate::config::ProtocolMode;
+use crate::known_cards::CardGeneration;
+
+/// Version of the protocol, reported in the capabilities message.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Capabilities of the bridge and the card, published on the connection of the card.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    /// Maximum length of the card response in bytes, including the status word.
+    pub max_response_length: usize,
+    /// Extended length APDUs (more than 256 bytes of data) are not supported by the bridge.
+    pub extended_apdu: bool,
+    /// Several APDUs in one request are not supported.
+    pub batching: bool,
+    /// The payloads are not compressed.
+    pub compression: bool,
+    /// Generation of the card recognized by the ATR, `None` if it is unknown.
+    pub card_generation: Option<CardGeneration>,
+    /// PIN policy of the card detected at the insertion, `None` if the card has not been read.
+    pub pin_policy: Option<PinPolicy>,
+}
+
+/// Capabilities of the card with the given generation and PIN policy.
+pub fn capabilities(card_generation: Option<CardGeneration>, pin_policy: Option<PinPolicy>) -> Capabilities {
+    Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        max_response_length: crate::smart_card::MAX_BUFFER_SIZE,
+        extended_apdu: false,
+        batching: false,
+        compression: false,
+        card_generation,
+        pin_policy,
+    }
+}
+
+/// Fields of the APDU request.
+const KNOWN_FIELDS: [&str; 2] = ["finish", "payload"];
+
+/// Parsed APDU request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApduRequest {
+    /// The authentication is finished, the card has to be reset.
+    pub finish: bool,
+    /// APDU command in hex. Empty string is the request of the ATR. `None` if it is missing (lenient mode only).
+    pub payload: Option<String>,
+}
+
+/// Deviation of the request from the protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolAnomaly {
+    /// The request is not a JSON object.
+    InvalidJson(String),
+    /// The request contains the field which is not in the protocol.
+    UnknownField(String),
+    /// The required field is missing.
+    MissingField(&'static str),
+    /// The field has the wrong type.
+    InvalidType(&'static str),
+}
+
+impl ProtocolAnomaly {
+    /// Kind of the anomaly for the statistics counters.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolAnomaly::InvalidJson(_) => "invalid_json",
+            ProtocolAnomaly::UnknownField(_) => "unknown_field",
+            ProtocolAnomaly::MissingField(_) => "missing_field",
+            ProtocolAnomaly::InvalidType(_) => "invalid_type",
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolAnomaly::InvalidJson(e) => write!(f, "invalid JSON: {}", e),
+            ProtocolAnomaly::UnknownField(field) => write!(f, "unknown field '{}'", field),
+            ProtocolAnomaly::MissingField(field) => write!(f, "missing field '{}'", field),
+            ProtocolAnomaly::InvalidType(field) => write!(f, "invalid type of the field '{}'", field),
+        }
+    }
+}
+
+/// Result of the parsing: the request (if it is accepted) and all found anomalies.
+pub struct ParsedRequest {
+    pub request: Option<ApduRequest>,
+    pub anomalies: Vec<ProtocolAnomaly>,
+}
+
+/// Parses the APDU request.
+///
+/// # Arguments
+///
+/// * `payload` - The MQTT message payload.
+/// * `mode` - The protocol mode from the configuration.
+///
+/// # Returns
+///
+/// * `ParsedRequest` - The request is `None` if it is rejected: it is not a JSON object, the `finish` flag is missing
+///   or invalid, or there is any anomaly in the strict mode.
+pub fn parse_apdu_request(payload: &[u8], mode: ProtocolMode) -> ParsedRequest {
+    let mut anomalies = Vec::new();
+
+    let object = match serde_json::from_slice::<Value>(payload) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => {
+            anomalies.push(ProtocolAnomaly::InvalidJson("not an object".to_string()));
+            return ParsedRequest { request: None, anomalies };
+        }
+        Err(e) => {
+            anomalies.push(ProtocolAnomaly::InvalidJson(e.to_string()));
+            return ParsedRequest { request: None, anomalies };
+        }
+    };
+
+    for field in object.keys().filter(|field| !KNOWN_FIELDS.contains(&field.as_str())) {
+        anomalies.push(ProtocolAnomaly::UnknownField(field.clone()));
+    }
+
+    let finish = match object.get("finish") {
+        Some(Value::Bool(finish)) => Some(*finish),
+        Some(_) => {
+            anomalies.push(ProtocolAnomaly::InvalidType("finish"));
+            None
+        }
+        None => {
+            anomalies.push(ProtocolAnomaly::MissingField("finish"));
+            None
+        }
+    };
+
+    // The APDU command is not needed for the finishing request
+    let payload = match object.get("payload") {
+        Some(Value::String(payload)) => Some(payload.clone()),
+        Some(_) => {
+            anomalies.push(ProtocolAnomaly::InvalidType("payload"));
+            None
+        }
+        None if finish == Some(false) => {
+            anomalies.push(ProtocolAnomaly::MissingField("payload"));
+            None
+        }
+        None => None,
+    };
+
+    let request = match finish {
+        Some(_) if mode == ProtocolMode::Strict && !anomalies.is_empty() => None,
+        Some(finish) => Some(ApduRequest { finish, payload }),
+        None => None,
+    };
+
+    ParsedRequest { request, anomalies }
+}
+
+/// Card which executes the APDU commands of the requests.
+pub trait ApduTransport {
+    type Error;
+
+    /// Sends the APDU command in hex to the card and returns the response in hex.
+    fn transmit_hex(&self, apdu_hex: &str) -> Result<String, Self::Error>;
+}
+
+/// Maximum number of the GET RESPONSE commands for one command, in case the card keeps answering 61xx.
+const MAX_RESPONSE_CHAINING: usize = 32;
+
+/// Sends the APDU command and completes the response of the T=0 card: the command answered with `6Cxx`
+/// (wrong Le, xx is the exact length) is repeated with Le = xx, and the data announced with `61xx`
+/// (xx bytes are available) is collected with GET RESPONSE. The status word of the last response is returned.
+pub fn transmit_chained<T: ApduTransport>(card: &T, apdu_hex: &str) -> Result<String, T::Error> {
+    let mut response = card.transmit_hex(apdu_hex)?.to_lowercase();
+    if let Some(le) = status_parameter(&response, "6c") {
+        response = card.transmit_hex(&with_le(apdu_hex, le))?.to_lowercase();
+    }
+
+    let mut data = String::new();
+    for _ in 0..MAX_RESPONSE_CHAINING {
+        let available = match status_parameter(&response, "61") {
+            Some(available) => available,
+            None => break,
+        };
+        data.push_str(&response[..response.len() - 4]);
+        response = card.transmit_hex(&format!("00c00000{}", available))?.to_lowercase();
+    }
+    data.push_str(&response);
+    Ok(data)
+}
+
+/// Returns the second byte of the status word (in hex) if the first one is `sw1`.
+fn status_parameter<'a>(response: &'a str, sw1: &str) -> Option<&'a str> {
+    let status = response.get(response.len().checked_sub(4)?..)?;
+    status.strip_prefix(sw1)
+}
+
+/// Sets Le of the command: replaces it in the commands which have it (case 2 and 4), otherwise appends it.
+fn with_le(apdu_hex: &str, le: &str) -> String {
+    let apdu = apdu_hex.to_lowercase();
+    let length = apdu.len() / 2;
+    let lc = u8::from_str_radix(apdu.get(8..10).unwrap_or("00"), 16).unwrap_or(0) as usize;
+    if length == 5 || length == 5 + lc + 1 {
+        format!("{}{}", &apdu[..apdu.len() - 2], le)
+    } else {
+        format!("{}{}", apdu, le)
+    }
+}
+
+/// Gets the R-APDU for the payload of the request: the ATR for the empty payload, otherwise the response of the card.
+///
+/// # Arguments
+///
+/// * `payload` - The APDU command in hex from the request.
+/// * `atr` - The ATR of the card in hex.
+/// * `card` - The card.
+pub fn request_rapdu<T: ApduTransport>(payload: &str, atr: &str, card: &T) -> Result<String, T::Error> {
+    if payload.is_empty() {
+        return Ok(atr.to_string());
+    }
+    transmit_chained(card, payload)
+}
+
+/// Creates the response with the R-APDU in hex. The empty R-APDU is the response to the finishing request.
+pub fn apdu_response(rapdu_hex: &str) -> String {
+    serde_json::json!({
+        "payload": rapdu_hex,
+    })
+    .to_string()
+}
+
+/// Creates the response for the rejected request: the protocol error in the strict mode, no response in the lenient mode.
+pub fn rejected_response(anomalies: &[ProtocolAnomaly], mode: ProtocolMode) -> Option<String> {
+    match mode {
+        ProtocolMode::Strict => Some(protocol_error_response(anomalies)),
+        ProtocolMode::Lenient => None,
+    }
+}
+
+/// Creates the response for the request rejected because of the protocol anomalies.
+pub fn protocol_error_response(anomalies: &[ProtocolAnomaly]) -> String {
+    let details: Vec<String> = anomalies.iter().map(|anomaly| anomaly.to_string()).collect();
+    serde_json::json!({
+        "payload": "",
+        "error": "protocol_error",
+        "details": details,
+    })
+    .to_string()
+}
+
+/// Reply of the card to the command of the request (see `RequestCard`).
+pub enum CardReply {
+    /// The R-APDU in hex, the empty one if the command has failed.
+    Rapdu(String),
+    /// The complete response instead of the R-APDU, e.g. the card is not present.
+    Response(String),
+    /// There is no response now: the request waits for the card or is dropped.
+    Deferred,
+}
+
+impl CardReply {
+    fn into_response(self) -> Option<String> {
+        match self {
+            CardReply::Rapdu(rapdu_hex) => Some(apdu_response(&rapdu_hex)),
+            CardReply::Response(response) => Some(response),
+            CardReply::Deferred => None,
+        }
+    }
+}
+
+/// Card side of the request handling: the card connection in `mqtt.rs` and the simulator card of the conformance tests.
+pub trait RequestCard {
+    /// Reports the deviation of the request from the protocol.
+    fn anomaly(&mut self, _anomaly: &ProtocolAnomaly) {}
+
+    /// Reports the rejected request.
+    fn rejected(&mut self, _anomalies: &[ProtocolAnomaly]) {}
+
+    /// Prepares the card for the accepted request. The returned reply is sent instead of handling the request.
+    fn accept(&mut self, _request: &ApduRequest) -> Option<CardReply> {
+        None
+    }
+
+    /// Finishes the authentication, the card is reset.
+    fn finish(&mut self);
+
+    /// Returns the ATR of the card, which the server asks for with the empty payload.
+    fn atr(&mut self) -> String;
+
+    /// Sends the APDU command of the request to the card.
+    fn transmit(&mut self, apdu_hex: &str) -> CardReply;
+}
+
+/// Handles the request of the server and creates its response.
+///
+/// # Arguments
+///
+/// * `payload` - The MQTT message payload.
+/// * `mode` - The protocol mode from the configuration.
+/// * `card` - The card of the request.
+///
+/// # Returns
+///
+/// * `Option<String>` - The response, `None` if the request is not answered (now).
+pub fn handle_request<C: RequestCard>(payload: &[u8], mode: ProtocolMode, card: &mut C) -> Option<String> {
+    let parsed = parse_apdu_request(payload, mode);
+    for anomaly in parsed.anomalies.iter() {
+        card.anomaly(anomaly);
+    }
+    let request = match parsed.request {
+        Some(request) => request,
+        None => {
+            card.rejected(&parsed.anomalies);
+            return rejected_response(&parsed.anomalies, mode);
+        }
+    };
+    if let Some(reply) = card.accept(&request) {
+        return reply.into_response();
+    }
+    if request.finish {
+        card.finish();
+        return Some(apdu_response(""));
+    }
+    match request.payload.as_deref() {
+        Some("") => Some(apdu_response(&card.atr())),
+        Some(apdu_hex) => card.transmit(apdu_hex).into_response(),
+        // There is nothing to send to the card (lenient mode only)
+        None => Some(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use serde::Deserialize;
+
+    /// Simulator of the company card: the recorded responses by the APDU command.
+    /// The commands which are not recorded get "INS not supported", like on the real card.
+    struct SimulatorCard {
+        atr: String,
+        responses: HashMap<String, String>,
+    }
+
+    impl ApduTransport for SimulatorCard {
+        type Error = String;
+
+        fn transmit_hex(&self, apdu_hex: &str) -> Result<String, Self::Error> {
+            Ok(self
+                .responses
+                .get(&apdu_hex.to_lowercase())
+                .cloned()
+                .unwrap_or_else(|| "6d00".to_string()))
+        }
+    }
+
+    impl RequestCard for SimulatorCard {
+        fn finish(&mut self) {}
+
+        fn atr(&mut self) -> String {
+            self.atr.clone()
+        }
+
+        fn transmit(&mut self, apdu_hex: &str) -> CardReply {
+            // The failed command gets the empty payload
+            CardReply::Rapdu(transmit_chained(&*self, apdu_hex).unwrap_or_default())
+        }
+    }
+
+    /// Recorded sequence of the server requests with the expected responses.
+    #[derive(Deserialize)]
+    struct Fixture {
+        description: String,
+        #[serde(default)]
+        mode: ProtocolMode,
+        atr: String,
+        /// Responses of the simulator card by the APDU command.
+        card: HashMap<String, String>,
+        exchange: Vec<Exchange>,
+    }
+
+    #[derive(Deserialize)]
+    struct Exchange {
+        /// The request as JSON, or the raw payload if it is a string.
+        request: Value,
+        /// The expected response, `null` if there must be no response.
+        response: Option<String>,
+    }
+
+    fn load_fixtures() -> Vec<(PathBuf, Fixture)> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("conformance");
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| {
+                let contents = std::fs::read_to_string(&path).unwrap();
+                let fixture = serde_json::from_str(&contents)
+                    .unwrap_or_else(|e| panic!("{}: invalid fixture: {}", path.display(), e));
+                (path, fixture)
+            })
+            .collect()
+    }
+
+    /// The responses are compared as JSON, so the order of the fields doesn't matter.
+    fn same_response(actual: &Option<String>, expected: &Option<String>) -> bool {
+        match (actual, expected) {
+            (Some(actual), Some(expected)) => {
+                match (serde_json::from_str::<Value>(actual), serde_json::from_str::<Value>(expected)) {
+                    (Ok(actual), Ok(expected)) => actual == expected,
+                    _ => actual == expected,
+                }
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn fixtures_are_present() {
+        assert!(!load_fixtures().is_empty());
+    }
+
+    #[test]
+    fn recorded_traffic_conforms() {
+        for (path, fixture) in load_fixtures() {
+            let mut card = SimulatorCard {
+                atr: fixture.atr,
+                responses: fixture.card,
+            };
+            for (step, exchange) in fixture.exchange.iter().enumerate() {
+                let payload = match &exchange.request {
+                    Value::String(raw) => raw.clone().into_bytes(),
+                    request => serde_json::to_vec(request).unwrap(),
+                };
+                let response = handle_request(&payload, fixture.mode, &mut card);
+                assert!(
+                    same_response(&response, &exchange.response),
+                    "{} ({}), step {}: expected {:?}, got {:?}",
+                    path.display(),
+                    fixture.description,
+                    step + 1,
+                    exchange.response,
+                    response
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn anomalies_are_reported_in_lenient_mode() {
+        let parsed = parse_apdu_request(br#"{"finish": false, "payload": "", "parcel": 1}"#, ProtocolMode::Lenient);
+        assert!(parsed.request.is_some());
+        assert_eq!(parsed.anomalies, vec![ProtocolAnomaly::UnknownField("parcel".to_string())]);
+    }
+
+    #[test]
+    fn payload_is_optional_for_finishing_request() {
+        let parsed = parse_apdu_request(br#"{"finish": true}"#, ProtocolMode::Strict);
+        assert_eq!(
+            parsed.request,
+            Some(ApduRequest {
+                finish: true,
+                payload: None
+            })
+        );
+        assert!(parsed.anomalies.is_empty());
+    }
+}
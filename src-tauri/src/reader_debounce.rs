@@ -0,0 +1,246 @@
+//! Module for the debouncing of the reader state changes.
+//!
+//! Faulty USB cables make the readers appear and disappear several times per second, and the card wiggled
+//! in the reader is inserted and removed within milliseconds, and every change would connect or disconnect the card.
+//! So the change of the reader is processed only after the reader state is stable for `stable_secs` or `stable_ms`
+//! (see `ReaderDebounceConfig`), and the reader with too many changes within `flapping_window_secs` is considered
+//! flapping: it is paused for `pause_secs` and a single alert is raised.
+//! The EMPTY reader is processed only after `removal_grace_secs`, as the card reset empties the reader for a moment
+//! and the removal of the card would break its session.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::config::ReaderDebounceConfig;
+
+/// Recent changes of the reader.
+#[derive(Default)]
+struct ReaderActivity {
+    /// Times of the changes within the flapping window.
+    changes: VecDeque<Instant>,
+    /// Time of the last change which is not processed yet.
+    pending_since: Option<Instant>,
+    /// Whether the last change has emptied the reader.
+    pending_removal: bool,
+    paused_until: Option<Instant>,
+}
+
+impl ReaderActivity {
+    fn is_paused(&self, now: Instant) -> bool {
+        self.paused_until.map(|until| now < until).unwrap_or(false)
+    }
+
+    fn forget_changes_before(&mut self, now: Instant, window: Duration) {
+        while self
+            .changes
+            .front()
+            .map(|change| now.duration_since(*change) > window)
+            .unwrap_or(false)
+        {
+            self.changes.pop_front();
+        }
+    }
+
+    /// Time when the pending change has to be processed.
+    fn due_at(&self, config: &ReaderDebounceConfig) -> Option<Instant> {
+        let mut delay = config.stable_period();
+        if self.pending_removal {
+            delay = delay.max(Duration::from_secs(config.removal_grace_secs));
+        }
+        let stable_at = self.pending_since? + delay;
+        Some(match self.paused_until {
+            Some(until) if until > stable_at => until,
+            _ => stable_at,
+        })
+    }
+}
+
+/// Debouncer of the reader state changes, by the reader name.
+#[derive(Default)]
+pub struct ReaderDebouncer {
+    readers: HashMap<String, ReaderActivity>,
+}
+
+impl ReaderDebouncer {
+    /// Records the change of the reader state.
+    ///
+    /// # Arguments
+    ///
+    /// * `removal` - Whether the reader is EMPTY after the change.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the reader has just been paused as flapping, so the alert has to be raised.
+    pub fn record_change(&mut self, reader: &str, removal: bool, now: Instant, config: &ReaderDebounceConfig) -> bool {
+        let activity = self.readers.entry(reader.to_string()).or_default();
+        activity.pending_since = Some(now);
+        activity.pending_removal = removal;
+        if activity.is_paused(now) {
+            return false;
+        }
+
+        activity.changes.push_back(now);
+        activity.forget_changes_before(now, Duration::from_secs(config.flapping_window_secs));
+
+        if config.flapping_threshold > 0 && activity.changes.len() >= config.flapping_threshold as usize {
+            activity.paused_until = Some(now + Duration::from_secs(config.pause_secs));
+            activity.changes.clear();
+            return true;
+        }
+        false
+    }
+
+    /// Takes the readers whose state is stable, so their latest state has to be processed.
+    pub fn take_stable(&mut self, now: Instant, config: &ReaderDebounceConfig) -> Vec<String> {
+        let window = Duration::from_secs(config.flapping_window_secs);
+        let mut readers = Vec::new();
+        for (reader, activity) in self.readers.iter_mut() {
+            activity.forget_changes_before(now, window);
+            if activity.due_at(config).map(|due| due <= now).unwrap_or(false) {
+                activity.pending_since = None;
+                readers.push(reader.clone());
+            }
+        }
+        // The quiet readers are forgotten, so the unplugged ones don't stay here forever
+        self.readers
+            .retain(|_, activity| activity.pending_since.is_some() || activity.is_paused(now) || !activity.changes.is_empty());
+        readers
+    }
+
+    /// Returns the time until the next pending change has to be processed, `None` if there are no pending changes.
+    pub fn next_deadline(&self, now: Instant, config: &ReaderDebounceConfig) -> Option<Duration> {
+        self.readers
+            .values()
+            .filter_map(|activity| activity.due_at(config))
+            .min()
+            .map(|due| due.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ReaderDebounceConfig {
+        ReaderDebounceConfig {
+            stable_secs: 1,
+            stable_ms: None,
+            flapping_threshold: 4,
+            flapping_window_secs: 10,
+            pause_secs: 30,
+            removal_grace_secs: 3,
+        }
+    }
+
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn change_is_processed_when_stable() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        assert!(!debouncer.record_change("Reader 1", false, start, &config));
+
+        assert!(debouncer.take_stable(at(start, 500), &config).is_empty());
+        assert_eq!(debouncer.next_deadline(at(start, 500), &config), Some(Duration::from_millis(500)));
+        assert_eq!(debouncer.take_stable(at(start, 1000), &config), vec!["Reader 1".to_string()]);
+        // The change is processed once
+        assert!(debouncer.take_stable(at(start, 2000), &config).is_empty());
+        assert_eq!(debouncer.next_deadline(at(start, 2000), &config), None);
+    }
+
+    #[test]
+    fn stable_ms_overrides_stable_secs() {
+        let (mut config, start) = (config(), Instant::now());
+        config.stable_ms = Some(200);
+        let mut debouncer = ReaderDebouncer::default();
+        debouncer.record_change("Reader 1", false, start, &config);
+        assert_eq!(debouncer.take_stable(at(start, 200), &config), vec!["Reader 1".to_string()]);
+    }
+
+    #[test]
+    fn new_change_restarts_stable_period() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        debouncer.record_change("Reader 1", false, start, &config);
+        debouncer.record_change("Reader 1", false, at(start, 800), &config);
+
+        assert!(debouncer.take_stable(at(start, 1000), &config).is_empty());
+        assert_eq!(debouncer.take_stable(at(start, 1800), &config), vec!["Reader 1".to_string()]);
+    }
+
+    #[test]
+    fn removal_waits_for_grace_period() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        debouncer.record_change("Reader 1", true, start, &config);
+
+        assert!(debouncer.take_stable(at(start, 1000), &config).is_empty());
+        assert_eq!(debouncer.next_deadline(at(start, 1000), &config), Some(Duration::from_secs(2)));
+        assert_eq!(debouncer.take_stable(at(start, 3000), &config), vec!["Reader 1".to_string()]);
+    }
+
+    #[test]
+    fn card_reset_is_not_removal() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        // The reset empties the reader for a moment
+        debouncer.record_change("Reader 1", true, start, &config);
+        debouncer.record_change("Reader 1", false, at(start, 100), &config);
+
+        assert_eq!(debouncer.take_stable(at(start, 1100), &config), vec!["Reader 1".to_string()]);
+    }
+
+    #[test]
+    fn flapping_reader_is_paused_with_single_alert() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        let alerts: Vec<bool> = (0..6)
+            .map(|change| debouncer.record_change("Reader 1", change % 2 == 1, at(start, change * 100), &config))
+            .collect();
+        assert_eq!(alerts, vec![false, false, false, true, false, false]);
+
+        // The latest state is processed only after the pause
+        assert!(debouncer.take_stable(at(start, 10_000), &config).is_empty());
+        assert_eq!(debouncer.next_deadline(at(start, 10_000), &config), Some(Duration::from_millis(20_300)));
+        assert_eq!(debouncer.take_stable(at(start, 30_300), &config), vec!["Reader 1".to_string()]);
+
+        // The changes during the pause are not counted after it
+        let alerts: Vec<bool> = (0..3)
+            .map(|change| debouncer.record_change("Reader 1", false, at(start, 31_000 + change * 100), &config))
+            .collect();
+        assert_eq!(alerts, vec![false, false, false]);
+    }
+
+    #[test]
+    fn changes_outside_flapping_window_are_forgotten() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        let alerts: Vec<bool> = (0..6)
+            .map(|change| debouncer.record_change("Reader 1", false, at(start, change * 4000), &config))
+            .collect();
+        assert!(alerts.iter().all(|alert| !alert));
+    }
+
+    #[test]
+    fn flapping_detection_can_be_disabled() {
+        let (mut config, start) = (config(), Instant::now());
+        config.flapping_threshold = 0;
+        let mut debouncer = ReaderDebouncer::default();
+        assert!((0..20).all(|change| !debouncer.record_change("Reader 1", false, at(start, change * 10), &config)));
+    }
+
+    #[test]
+    fn readers_are_debounced_separately() {
+        let (config, start) = (config(), Instant::now());
+        let mut debouncer = ReaderDebouncer::default();
+        debouncer.record_change("Reader 1", true, start, &config);
+        debouncer.record_change("Reader 2", false, at(start, 500), &config);
+
+        assert_eq!(debouncer.next_deadline(start, &config), Some(Duration::from_millis(1500)));
+        assert_eq!(debouncer.take_stable(at(start, 1500), &config), vec!["Reader 2".to_string()]);
+        assert_eq!(debouncer.next_deadline(at(start, 1500), &config), Some(Duration::from_millis(1500)));
+        assert_eq!(debouncer.take_stable(at(start, 3000), &config), vec!["Reader 1".to_string()]);
+    }
+}
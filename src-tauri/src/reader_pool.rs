@@ -0,0 +1,423 @@
+//! Module for the pool of the readers and the cards inserted into them.
+//!
+//! The pool is updated by the smart card monitor and shared through the watch channel,
+//! so the other parts of the application always see the latest state of the readers.
+//!
+//! Invariants of the pool:
+//! * one card per reader: a new card in the reader replaces the previous one;
+//! * one reader per card: a card found in another reader is removed from the previous one.
+
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::card_identification::TachographCardType;
+use crate::config::ReadersConfig;
+use crate::known_cards::CardGeneration;
+use crate::smart_card::ReaderId;
+
+/// Card inserted into the reader.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ReaderEntry {
+    /// State of the reader from the PC/SC event (e.g. "CHANGED | PRESENT").
+    pub card_state: String,
+    /// Company card number.
+    pub card_number: String,
+    /// ICCID of the card, the identity of the physical card. `None` until it is read by the card initialization.
+    pub iccid: Option<String>,
+    /// Type of the card detected by the card initialization, `None` until it is detected.
+    pub card_type: Option<TachographCardType>,
+    /// Generation of the card detected by the card initialization, `None` until it is detected.
+    pub card_generation: Option<CardGeneration>,
+}
+
+/// Readers with the cards, by the reader.
+#[derive(Clone, Debug, Default)]
+pub struct ReaderPool {
+    entries: HashMap<ReaderId, ReaderEntry>,
+}
+
+impl ReaderPool {
+    /// Updates the pool with the state of the reader.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader_id` - The reader.
+    /// * `card_state` - The state of the reader.
+    /// * `card_number` - The number of the card in the reader, empty if there is no (known) card.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - The numbers of the cards which are not in their readers anymore, so their connections have to be removed.
+    pub fn update(&mut self, reader_id: &ReaderId, card_state: &str, card_number: &str) -> Vec<String> {
+        let mut removed_cards = Vec::new();
+        if reader_id.name.is_empty() {
+            log::debug!("Reader name is empty. No action taken.");
+            return removed_cards;
+        }
+
+        if card_number.is_empty() {
+            if let Some(entry) = self.entries.remove(reader_id) {
+                log::debug!("The card {} is removed from the reader '{}'", entry.card_number, reader_id);
+                removed_cards.push(entry.card_number);
+            }
+            return removed_cards;
+        }
+
+        // One reader per card: the card has been moved from another reader
+        let other_readers: Vec<ReaderId> = self
+            .entries
+            .iter()
+            .filter(|(reader, entry)| *reader != reader_id && entry.card_number == card_number)
+            .map(|(reader, _)| reader.clone())
+            .collect();
+        for reader in other_readers {
+            log::debug!("The card {} is moved from the reader '{}' to '{}'", card_number, reader, reader_id);
+            self.entries.remove(&reader);
+            removed_cards.push(card_number.to_string());
+        }
+
+        // One card per reader: the new card replaces the previous one.
+        // The ICCID, the type and the generation of the same card in the reader are kept, the ones of the new card
+        // are read by its initialization.
+        let previous = self
+            .entries
+            .get(reader_id)
+            .filter(|previous| previous.card_number == card_number);
+        let entry = ReaderEntry {
+            card_state: card_state.to_string(),
+            card_number: card_number.to_string(),
+            iccid: previous.and_then(|previous| previous.iccid.clone()),
+            card_type: previous.and_then(|previous| previous.card_type),
+            card_generation: previous.and_then(|previous| previous.card_generation),
+        };
+        if let Some(previous) = self.entries.insert(reader_id.clone(), entry) {
+            if previous.card_number != card_number {
+                log::debug!(
+                    "The card {} in the reader '{}' is replaced by {}",
+                    previous.card_number,
+                    reader_id,
+                    card_number
+                );
+                removed_cards.push(previous.card_number);
+            }
+        }
+
+        removed_cards
+    }
+
+    /// Sets the ICCID of the card in the reader, if the card is still there.
+    pub fn set_iccid(&mut self, reader_id: &ReaderId, card_number: &str, iccid: &str) {
+        if let Some(entry) = self.entries.get_mut(reader_id).filter(|entry| entry.card_number == card_number) {
+            entry.iccid = Some(iccid.to_string());
+        }
+    }
+
+    /// Sets the type of the card in the reader, if the card is still there.
+    pub fn set_card_type(&mut self, reader_id: &ReaderId, card_number: &str, card_type: TachographCardType) {
+        if let Some(entry) = self.entries.get_mut(reader_id).filter(|entry| entry.card_number == card_number) {
+            entry.card_type = Some(card_type);
+        }
+    }
+
+    /// Sets the generation of the card in the reader, if the card is still there.
+    pub fn set_card_generation(&mut self, reader_id: &ReaderId, card_number: &str, card_generation: CardGeneration) {
+        if let Some(entry) = self.entries.get_mut(reader_id).filter(|entry| entry.card_number == card_number) {
+            entry.card_generation = Some(card_generation);
+        }
+    }
+
+    /// Returns the readers with the cards.
+    pub fn entries(&self) -> &HashMap<ReaderId, ReaderEntry> {
+        &self.entries
+    }
+}
+
+lazy_static! {
+    /// The pool of the readers shared through the watch channel.
+    static ref READER_POOL: watch::Sender<ReaderPool> = watch::channel(ReaderPool::default()).0;
+}
+
+/// Updates the shared pool with the state of the reader (see `ReaderPool::update`).
+///
+/// # Returns
+///
+/// * `Vec<String>` - The numbers of the cards whose connections have to be removed.
+pub fn update_reader(reader_id: &ReaderId, card_state: &str, card_number: &str) -> Vec<String> {
+    let mut removed_cards = Vec::new();
+    READER_POOL.send_modify(|pool| {
+        removed_cards = pool.update(reader_id, card_state, card_number);
+        log::debug!("Final state of the reader pool: {:?}", pool.entries());
+    });
+    removed_cards
+}
+
+/// Sets the ICCID of the card in the shared pool (see `ReaderPool::set_iccid`).
+pub fn set_card_iccid(reader_id: &ReaderId, card_number: &str, iccid: &str) {
+    READER_POOL.send_modify(|pool| pool.set_iccid(reader_id, card_number, iccid));
+}
+
+/// Sets the type of the card in the shared pool (see `ReaderPool::set_card_type`).
+pub fn set_card_type(reader_id: &ReaderId, card_number: &str, card_type: TachographCardType) {
+    READER_POOL.send_modify(|pool| pool.set_card_type(reader_id, card_number, card_type));
+}
+
+/// Sets the generation of the card in the shared pool (see `ReaderPool::set_card_generation`).
+pub fn set_card_generation(reader_id: &ReaderId, card_number: &str, card_generation: CardGeneration) {
+    READER_POOL.send_modify(|pool| pool.set_card_generation(reader_id, card_number, card_generation));
+}
+
+/// Returns the generation of the card in the reader, `None` if there is no card or its generation is not detected.
+pub fn find_card_generation(reader_name: &str) -> Option<CardGeneration> {
+    READER_POOL
+        .borrow()
+        .entries()
+        .get(&ReaderId::from_name(reader_name))
+        .and_then(|entry| entry.card_generation)
+}
+
+/// Returns the type of the card in the reader, `None` if there is no card or its type is not detected.
+pub fn find_card_type(reader_name: &str) -> Option<TachographCardType> {
+    READER_POOL
+        .borrow()
+        .entries()
+        .get(&ReaderId::from_name(reader_name))
+        .and_then(|entry| entry.card_type)
+}
+
+/// Returns the reader the card is inserted to, or `None` if the card is not in any reader.
+pub fn find_card_reader(card_number: &str) -> Option<ReaderId> {
+    find_card(card_number).map(|info| info.reader)
+}
+
+/// Returns the reader with the card, or `None` if the card is not in any reader.
+pub fn find_card(card_number: &str) -> Option<ReaderInfo> {
+    let pool = READER_POOL.borrow();
+    pool.entries()
+        .iter()
+        .find(|(_, entry)| entry.card_number == card_number)
+        .map(|(reader, entry)| ReaderInfo {
+            reader: reader.clone(),
+            reader_label: reader.label(),
+            entry: entry.clone(),
+        })
+}
+
+/// Checks if the name matches the pattern of the reader filter: `*` matches any text and `?` any character,
+/// the case is ignored.
+fn matches_reader_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    // The position after the last `*` in the pattern and the name it matches up to, to backtrack to
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Checks if the reader is used by the bridge by the `allow` and `deny` patterns of the configuration,
+/// which match the full PC/SC name or the label of the reader. The virtual readers are not used either,
+/// unless the reader is force-enabled in its settings.
+pub fn is_reader_allowed(readers: &ReadersConfig, reader_name: &str) -> bool {
+    let label = ReaderId::from_name(reader_name).slot_label();
+    let force_enabled = readers
+        .settings
+        .iter()
+        .any(|(name, settings)| settings.force_enable && (name == reader_name || *name == label));
+    if force_enabled {
+        return true;
+    }
+    let matches = |pattern: &String| matches_reader_pattern(pattern, reader_name) || matches_reader_pattern(pattern, &label);
+    if readers.deny.iter().any(matches) || readers.virtual_regexes.iter().any(|regex| regex.is_match(reader_name)) {
+        return false;
+    }
+    readers.allow.is_empty() || readers.allow.iter().any(matches)
+}
+
+/// Reader with the card for the frontend.
+#[derive(Serialize, Clone, Debug)]
+pub struct ReaderInfo {
+    pub reader: ReaderId,
+    pub reader_label: String,
+    #[serde(flatten)]
+    pub entry: ReaderEntry,
+}
+
+/// Public function to get the readers with the cards.
+/// This function is a Tauri command that is called from the diagnostics view of the frontend.
+#[tauri::command]
+pub fn get_readers() -> Vec<ReaderInfo> {
+    let pool = READER_POOL.borrow();
+    pool.entries()
+        .iter()
+        .map(|(reader, entry)| ReaderInfo {
+            reader: reader.clone(),
+            reader_label: reader.label(),
+            entry: entry.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(name: &str) -> ReaderId {
+        ReaderId::from_name(name)
+    }
+
+    #[test]
+    fn inserted_card_is_added() {
+        let mut pool = ReaderPool::default();
+        let removed = pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+
+        assert!(removed.is_empty());
+        assert_eq!(pool.entries().len(), 1);
+        assert_eq!(pool.entries()[&reader("Reader A")].card_number, "1111");
+    }
+
+    #[test]
+    fn state_of_the_same_card_is_updated() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        let removed = pool.update(&reader("Reader A"), "CHANGED | PRESENT | INUSE", "1111");
+
+        assert!(removed.is_empty());
+        assert_eq!(pool.entries()[&reader("Reader A")].card_state, "CHANGED | PRESENT | INUSE");
+    }
+
+    #[test]
+    fn removed_card_is_returned() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        let removed = pool.update(&reader("Reader A"), "CHANGED | EMPTY", "");
+
+        assert_eq!(removed, vec!["1111".to_string()]);
+        assert!(pool.entries().is_empty());
+    }
+
+    #[test]
+    fn removal_from_empty_reader_does_nothing() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        let removed = pool.update(&reader("Reader B"), "CHANGED | EMPTY", "");
+
+        assert!(removed.is_empty());
+        assert_eq!(pool.entries().len(), 1);
+    }
+
+    #[test]
+    fn new_card_replaces_previous_one_in_the_reader() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        let removed = pool.update(&reader("Reader A"), "CHANGED | PRESENT", "2222");
+
+        assert_eq!(removed, vec!["1111".to_string()]);
+        assert_eq!(pool.entries().len(), 1);
+        assert_eq!(pool.entries()[&reader("Reader A")].card_number, "2222");
+    }
+
+    #[test]
+    fn card_moved_to_another_reader_is_removed_from_the_previous_one() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        let removed = pool.update(&reader("Reader B"), "CHANGED | PRESENT", "1111");
+
+        assert_eq!(removed, vec!["1111".to_string()]);
+        assert_eq!(pool.entries().len(), 1);
+        assert!(pool.entries().contains_key(&reader("Reader B")));
+    }
+
+    #[test]
+    fn slots_of_dual_slot_reader_are_separate_readers() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader [CCID] 00 00"), "CHANGED | PRESENT", "1111");
+        pool.update(&reader("Reader [CCID] 00 01"), "CHANGED | PRESENT", "2222");
+        let removed = pool.update(&reader("Reader [CCID] 00 01"), "CHANGED | EMPTY", "");
+
+        assert_eq!(removed, vec!["2222".to_string()]);
+        assert_eq!(pool.entries()[&reader("Reader [CCID] 00 00")].card_number, "1111");
+    }
+
+    #[test]
+    fn iccid_is_kept_for_the_same_card_only() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        pool.set_iccid(&reader("Reader A"), "1111", "89001");
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT | INUSE", "1111");
+        assert_eq!(pool.entries()[&reader("Reader A")].iccid.as_deref(), Some("89001"));
+
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "2222");
+        assert_eq!(pool.entries()[&reader("Reader A")].iccid, None);
+    }
+
+    #[test]
+    fn card_type_is_kept_for_the_same_card_only() {
+        let mut pool = ReaderPool::default();
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "1111");
+        pool.set_card_type(&reader("Reader A"), "1111", TachographCardType::Company);
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT | INUSE", "1111");
+        assert_eq!(pool.entries()[&reader("Reader A")].card_type, Some(TachographCardType::Company));
+
+        pool.update(&reader("Reader A"), "CHANGED | PRESENT", "2222");
+        assert_eq!(pool.entries()[&reader("Reader A")].card_type, None);
+    }
+
+    #[test]
+    fn readers_are_filtered_by_the_patterns() {
+        let readers = ReadersConfig {
+            allow: vec!["ACS*".to_string(), "*Identiv*".to_string()],
+            deny: vec!["acs acr39u ICC Reader (slot ?)".to_string()],
+            ..Default::default()
+        };
+        assert!(is_reader_allowed(&readers, "ACS ACR38U-CCID 00 00"));
+        assert!(is_reader_allowed(&readers, "Identiv uTrust 2700 R 01 00"));
+        assert!(!is_reader_allowed(&readers, "ACS ACR39U ICC Reader 00 01"));
+        assert!(!is_reader_allowed(&readers, "Built-in Smart Card Reader"));
+        assert!(is_reader_allowed(&ReadersConfig::default(), "Built-in Smart Card Reader"));
+    }
+
+    #[test]
+    fn virtual_readers_are_skipped_unless_force_enabled() {
+        let mut readers = ReadersConfig {
+            virtual_regexes: vec![regex::Regex::new("(?i)remote").unwrap()],
+            ..Default::default()
+        };
+        assert!(!is_reader_allowed(&readers, "Remote Reader Co. CR100 00 00"));
+        assert!(is_reader_allowed(&readers, "ACS ACR38U-CCID 00 00"));
+
+        readers.settings.insert(
+            "Remote Reader Co. CR100 (slot 0)".to_string(),
+            crate::config::ReaderConfig {
+                force_enable: true,
+                ..Default::default()
+            },
+        );
+        assert!(is_reader_allowed(&readers, "Remote Reader Co. CR100 00 00"));
+    }
+
+    #[test]
+    fn empty_reader_name_is_ignored() {
+        let mut pool = ReaderPool::default();
+        let removed = pool.update(&reader(""), "CHANGED | PRESENT", "1111");
+
+        assert!(removed.is_empty());
+        assert!(pool.entries().is_empty());
+    }
+}
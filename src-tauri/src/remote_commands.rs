@@ -0,0 +1,478 @@
+//! Module for the remote commands of the application connection.
+//!
+//! The bridges are installed in the remote offices, where nobody can open the application when the cards stop
+//! working. The server side manages them with the commands published to `<ident>/commands`, the application
+//! connection of the account with the ident is subscribed to it. The command is a JSON object with the optional `id`,
+//! which is returned in the response, and the `command`:
+//!
+//! * `restart_sessions` - Reconnects the card connections of the account, the `cards` of them if they are set.
+//! * `sync_cards` - Synchronizes the cards in the readers with the connections (see `smart_card::manual_sync_cards`).
+//! * `report_status` - Reports the heartbeat of the account right away (see `heartbeat`).
+//! * `upload_logs` - Sends the end of the log file, at most `max_bytes` (`DEFAULT_LOG_UPLOAD_BYTES` by default).
+//! * `apply_config` - Pairs the `cards` and changes the `server` address and ident (see `apply_remote_config`),
+//!   so the new company cards are provisioned centrally.
+//!
+//! The result of every command is published to `<ident>/commands/response`. Every message of the commands topic
+//! is recorded to the security log with the summary of the command (see `RemoteCommand::summary`), not in full:
+//! the cards and the server settings of `apply_config` are recorded with their own configuration changes.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::{get_accounts, get_appearance_config, get_card_account, get_log_dir, LOG_FILE_NAME};
+use crate::config_writer::ConfigMutation;
+use crate::mqtt_client::{MqttClient, QoS};
+use crate::security_log::SecurityEvent;
+
+/// Size of the end of the log file sent by `upload_logs` if `max_bytes` is not set.
+const DEFAULT_LOG_UPLOAD_BYTES: u64 = 64 * 1024;
+/// Maximum size of the end of the log file sent by `upload_logs`, so the response fits the packet size of the broker.
+const MAX_LOG_UPLOAD_BYTES: u64 = 256 * 1024;
+
+/// Command from the server.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum RemoteCommand {
+    RestartSessions {
+        #[serde(default)]
+        cards: Option<Vec<String>>,
+    },
+    SyncCards,
+    ReportStatus,
+    UploadLogs {
+        #[serde(default)]
+        max_bytes: Option<u64>,
+    },
+    ApplyConfig {
+        #[serde(default)]
+        server: Option<RemoteServerConfig>,
+        #[serde(default)]
+        cards: Vec<RemoteCardConfig>,
+    },
+}
+
+impl RemoteCommand {
+    /// Returns the summary of the command for the security log: the command and the number of its cards.
+    fn summary(&self) -> String {
+        match self {
+            RemoteCommand::RestartSessions { cards: Some(cards) } => format!("restart_sessions of {} card(s)", cards.len()),
+            RemoteCommand::RestartSessions { cards: None } => "restart_sessions of all the cards".to_string(),
+            RemoteCommand::SyncCards => "sync_cards".to_string(),
+            RemoteCommand::ReportStatus => "report_status".to_string(),
+            RemoteCommand::UploadLogs { max_bytes } => match max_bytes {
+                Some(max_bytes) => format!("upload_logs of at most {} bytes", max_bytes),
+                None => "upload_logs".to_string(),
+            },
+            RemoteCommand::ApplyConfig { server, cards } => {
+                let mut changes = Vec::new();
+                if let Some(server) = server {
+                    if server.host.is_some() {
+                        changes.push("server host".to_string());
+                    }
+                    if server.ident.is_some() {
+                        changes.push("ident".to_string());
+                    }
+                }
+                if !cards.is_empty() {
+                    changes.push(format!("{} card(s)", cards.len()));
+                }
+                if changes.is_empty() {
+                    "apply_config without changes".to_string()
+                } else {
+                    format!("apply_config of {}", changes.join(", "))
+                }
+            }
+        }
+    }
+}
+
+/// Server settings of `apply_config`, the missing ones are kept.
+#[derive(Deserialize, Debug, PartialEq)]
+struct RemoteServerConfig {
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    ident: Option<String>,
+}
+
+/// Card paired by `apply_config`, the same settings as the card paired in the UI.
+#[derive(Deserialize, Debug, PartialEq)]
+struct RemoteCardConfig {
+    cardnumber: String,
+    atr: String,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    iccid: Option<String>,
+}
+
+/// Message with the command.
+#[derive(Deserialize, Debug)]
+struct CommandRequest {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(flatten)]
+    command: RemoteCommand,
+}
+
+/// Result of the command for the server.
+#[derive(Serialize, Debug)]
+struct CommandResponse {
+    id: Option<Value>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    result: Value,
+}
+
+/// Outcome of the command.
+#[derive(Debug)]
+struct CommandOutcome {
+    /// The result, also the part of the command which is done before it has failed.
+    result: Value,
+    error: Option<String>,
+    /// The configuration is changed, the connections are re-established after the response.
+    changes_config: bool,
+}
+
+impl From<Result<Value, String>> for CommandOutcome {
+    fn from(result: Result<Value, String>) -> Self {
+        match result {
+            Ok(result) => CommandOutcome {
+                result,
+                error: None,
+                changes_config: false,
+            },
+            Err(e) => CommandOutcome {
+                result: Value::Null,
+                error: Some(e),
+                changes_config: false,
+            },
+        }
+    }
+}
+
+/// Topic of the commands to the application connection with the ident.
+pub fn commands_topic(ident: &str) -> String {
+    format!("{}/commands", ident)
+}
+
+fn response_topic(ident: &str) -> String {
+    format!("{}/commands/response", ident)
+}
+
+/// Handles the command received by the application connection and publishes its result.
+///
+/// # Arguments
+///
+/// * `client` - The client of the application connection.
+/// * `ident` - The ident of the account.
+/// * `payload` - The message with the command.
+pub async fn handle_command(client: MqttClient, ident: String, payload: Vec<u8>) {
+    let mut changes_config = false;
+    let response = match serde_json::from_slice::<CommandRequest>(&payload) {
+        Ok(request) => {
+            log::info!("{} | Remote command: {:?}", ident, request.command);
+            let id = request.id.as_ref().map(|id| format!(", id {}", id)).unwrap_or_default();
+            crate::security_log::record(
+                SecurityEvent::RemoteCommand,
+                None,
+                &commands_topic(&ident),
+                &format!("{}{}", request.command.summary(), id),
+            );
+            let outcome = run_command(&ident, request.command).await;
+            changes_config = outcome.changes_config;
+            CommandResponse {
+                id: request.id,
+                ok: outcome.error.is_none(),
+                error: outcome.error,
+                result: outcome.result,
+            }
+        }
+        Err(e) => {
+            log::warn!("{} | Invalid remote command: {}", ident, e);
+            crate::security_log::record(
+                SecurityEvent::RemoteCommand,
+                None,
+                &commands_topic(&ident),
+                &format!("invalid command of {} bytes: {}", payload.len(), e),
+            );
+            // The ID is returned even if the rest of the command is invalid
+            let id = serde_json::from_slice::<Value>(&payload)
+                .ok()
+                .and_then(|value| value.get("id").cloned());
+            CommandResponse {
+                id,
+                ok: false,
+                error: Some(format!("Invalid command: {}", e)),
+                result: Value::Null,
+            }
+        }
+    };
+
+    let payload = match serde_json::to_string(&response) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("{} | Failed to serialize the result of the remote command: {}", ident, e);
+            return;
+        }
+    };
+    if let Err(e) = client.publish(response_topic(&ident), QoS::AtLeastOnce, false, payload).await {
+        log::warn!("{} | Failed to publish the result of the remote command: {:?}", ident, e);
+    }
+
+    // The connections are re-established after the confirmation, as the change of the server or the ident
+    // closes this connection
+    if changes_config {
+        crate::config::apply_changed_config().await;
+    }
+}
+
+async fn run_command(ident: &str, command: RemoteCommand) -> CommandOutcome {
+    let result = match command {
+        RemoteCommand::RestartSessions { cards } => restart_sessions(ident, cards).await,
+        RemoteCommand::SyncCards => sync_cards(ident).await,
+        RemoteCommand::ReportStatus => serde_json::to_value(crate::heartbeat::heartbeat(ident)).map_err(|e| e.to_string()),
+        RemoteCommand::UploadLogs { max_bytes } => upload_logs(max_bytes),
+        RemoteCommand::ApplyConfig { server, cards } => return apply_remote_config(ident, server, cards).await,
+    };
+    result.into()
+}
+
+async fn restart_sessions(ident: &str, cards: Option<Vec<String>>) -> Result<Value, String> {
+    let account_cards = account_cards(ident).await;
+    let cards = match cards {
+        Some(cards) => {
+            if let Some(unknown) = cards.iter().find(|card| !account_cards.contains(card)) {
+                return Err(format!("The card {} is not connected with the account", unknown));
+            }
+            cards
+        }
+        None => account_cards,
+    };
+    if !cards.is_empty() {
+        crate::mqtt::remove_connections(cards.clone()).await;
+        crate::smart_card::manual_sync_cards().await?;
+    }
+    Ok(json!({ "cards": cards }))
+}
+
+async fn sync_cards(ident: &str) -> Result<Value, String> {
+    crate::smart_card::manual_sync_cards().await?;
+    Ok(json!({ "cards": account_cards(ident).await }))
+}
+
+fn upload_logs(max_bytes: Option<u64>) -> Result<Value, String> {
+    let max_bytes = max_bytes.unwrap_or(DEFAULT_LOG_UPLOAD_BYTES).min(MAX_LOG_UPLOAD_BYTES);
+    let (log, size) = read_log_tail(max_bytes).map_err(|e| format!("Failed to read the log file: {}", e))?;
+    Ok(json!({ "size": size, "log": log }))
+}
+
+/// Applies the configuration sent by the server through the same changes as the UI makes. The whole document
+/// is validated before any change, the server settings are changed only by the command to the default account.
+///
+/// # Returns
+///
+/// * `CommandOutcome` - Whether the server settings are changed and the paired cards. If some changes have failed,
+///   the error lists them and the result has the changes which are applied.
+async fn apply_remote_config(ident: &str, server: Option<RemoteServerConfig>, cards: Vec<RemoteCardConfig>) -> CommandOutcome {
+    if let Err(e) = validate_remote_config(server.as_ref(), &cards) {
+        return Err(e).into();
+    }
+    // The default account goes first
+    let (_, default_account) = get_accounts().swap_remove(0);
+    if server.is_some() && default_account.ident != ident {
+        return Err("The server settings are changed only by the command to the default account".to_string()).into();
+    }
+
+    let source = format!("{}/commands", ident);
+    let mut errors: Vec<String> = Vec::new();
+    let mut server_changed = false;
+    if let Some(server) = server {
+        let host = server.host.unwrap_or(default_account.host);
+        let new_ident = server.ident.unwrap_or(default_account.ident);
+        let mutation = ConfigMutation::UpdateServer {
+            host: host.clone(),
+            ident: new_ident.clone(),
+            theme: get_appearance_config()
+                .map(|appearance| format!("{:?}", appearance.dark_theme))
+                .unwrap_or_default(),
+        };
+        match crate::config_writer::apply(mutation).await {
+            Ok(_) => {
+                server_changed = true;
+                crate::security_log::record(SecurityEvent::ConfigChange, None, &source, &format!("host: {}, ident: {}", host, new_ident));
+            }
+            Err(e) => errors.push(format!("server: {}", e)),
+        }
+    }
+
+    let mut paired: Vec<String> = Vec::new();
+    for card in cards {
+        let mutation = ConfigMutation::UpdateCard {
+            atr: card.atr.to_lowercase(),
+            cardnumber: card.cardnumber.clone(),
+            label: card.label,
+            notes: card.notes,
+            iccid: card.iccid,
+        };
+        match crate::config_writer::apply(mutation).await {
+            Ok(_) => {
+                crate::security_log::record(SecurityEvent::ConfigChange, Some(&card.cardnumber), &source, &format!("atr: {}", card.atr));
+                paired.push(card.cardnumber);
+            }
+            Err(e) => errors.push(format!("card {}: {}", card.cardnumber, e)),
+        }
+    }
+
+    let changes_config = server_changed || !paired.is_empty();
+    if changes_config {
+        log::info!("{} | The configuration is changed remotely, the paired cards: {:?}", ident, paired);
+    }
+    CommandOutcome {
+        changes_config,
+        result: json!({ "server": server_changed, "cards": paired }),
+        error: (!errors.is_empty())
+            .then(|| format!("Failed to apply the changes ({}), the other changes are applied", errors.join("; "))),
+    }
+}
+
+/// Checks the configuration sent by the server before it is applied.
+fn validate_remote_config(server: Option<&RemoteServerConfig>, cards: &[RemoteCardConfig]) -> Result<(), String> {
+    if let Some(server) = server {
+        if let Some(host) = &server.host {
+            crate::diagnostics::check_host_format(host).map_err(|e| format!("Invalid server address '{}': {}", host, e))?;
+        }
+        if server.ident.as_deref().map_or(false, |ident| ident.trim().is_empty()) {
+            return Err("The ident is empty".to_string());
+        }
+    }
+    let mut cardnumbers: Vec<&str> = Vec::new();
+    let mut atrs: Vec<String> = Vec::new();
+    for card in cards {
+        if card.cardnumber.trim().is_empty() {
+            return Err("The card number is empty".to_string());
+        }
+        if card.atr.is_empty() || hex::decode(&card.atr).is_err() {
+            return Err(format!("Invalid ATR '{}' of the card {}", card.atr, card.cardnumber));
+        }
+        if cardnumbers.contains(&card.cardnumber.as_str()) {
+            return Err(format!("The card {} is listed twice", card.cardnumber));
+        }
+        if atrs.contains(&card.atr.to_lowercase()) {
+            return Err(format!("The ATR '{}' is listed for two cards", card.atr));
+        }
+        cardnumbers.push(&card.cardnumber);
+        atrs.push(card.atr.to_lowercase());
+    }
+    Ok(())
+}
+
+/// Numbers of the connected cards of the account with the ident.
+async fn account_cards(ident: &str) -> Vec<String> {
+    let connected: Vec<String> = crate::smart_card::TASK_POOL
+        .lock()
+        .await
+        .iter()
+        .map(|task| task.client_id.clone())
+        .collect();
+    connected
+        .into_iter()
+        .filter(|cardnumber| get_card_account(cardnumber).ident == ident)
+        .collect()
+}
+
+/// Reads the end of the log file.
+///
+/// # Returns
+///
+/// * `std::io::Result<(String, u64)>` - The end of the log file and the size of the whole file.
+fn read_log_tail(max_bytes: u64) -> std::io::Result<(String, u64)> {
+    let mut file = File::open(get_log_dir()?.join(LOG_FILE_NAME))?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(size.saturating_sub(max_bytes)))?;
+    let mut tail = Vec::new();
+    file.take(max_bytes).read_to_end(&mut tail)?;
+    Ok((String::from_utf8_lossy(&tail).into_owned(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        let request: CommandRequest =
+            serde_json::from_str(r#"{"id": 7, "command": "restart_sessions", "cards": ["D000000012345600"]}"#).unwrap();
+        assert_eq!(request.id, Some(json!(7)));
+        assert_eq!(
+            request.command,
+            RemoteCommand::RestartSessions {
+                cards: Some(vec!["D000000012345600".to_string()])
+            }
+        );
+
+        let request: CommandRequest = serde_json::from_str(r#"{"command": "upload_logs"}"#).unwrap();
+        assert_eq!(request.id, None);
+        assert_eq!(request.command, RemoteCommand::UploadLogs { max_bytes: None });
+
+        assert!(serde_json::from_str::<CommandRequest>(r#"{"command": "format_disk"}"#).is_err());
+    }
+
+    #[test]
+    fn commands_are_summarized_without_their_data() {
+        let summary = |payload: &str| serde_json::from_str::<CommandRequest>(payload).unwrap().command.summary();
+        assert_eq!(summary(r#"{"command": "restart_sessions", "cards": ["D000000012345600"]}"#), "restart_sessions of 1 card(s)");
+        assert_eq!(summary(r#"{"command": "upload_logs", "max_bytes": 1024}"#), "upload_logs of at most 1024 bytes");
+        assert_eq!(summary(r#"{"command": "sync_cards"}"#), "sync_cards");
+        let summary = summary(
+            r#"{"command": "apply_config", "server": {"ident": "secret-ident"},
+                "cards": [{"cardnumber": "D000000012345600", "atr": "3b9f96"}]}"#,
+        );
+        assert_eq!(summary, "apply_config of ident, 1 card(s)");
+    }
+
+    #[test]
+    fn remote_config_is_validated() {
+        let card = |cardnumber: &str, atr: &str| RemoteCardConfig {
+            cardnumber: cardnumber.to_string(),
+            atr: atr.to_string(),
+            label: None,
+            notes: None,
+            iccid: None,
+        };
+        let server = RemoteServerConfig {
+            host: Some("mqtt.flespi.io:8883".to_string()),
+            ident: None,
+        };
+        assert!(validate_remote_config(Some(&server), &[card("D000000012345600", "3b9f96")]).is_ok());
+        assert!(validate_remote_config(None, &[card("D000000012345600", "not hex")]).is_err());
+        assert!(validate_remote_config(None, &[card("D000000012345600", "3b9f96"), card("D000000012345601", "3B9F96")]).is_err());
+
+        let server = RemoteServerConfig {
+            host: Some("mqtt.flespi.io".to_string()),
+            ident: None,
+        };
+        assert!(validate_remote_config(Some(&server), &[]).is_err());
+    }
+
+    #[test]
+    fn rejected_config_changes_nothing() {
+        let cards = vec![RemoteCardConfig {
+            cardnumber: "D000000012345600".to_string(),
+            atr: "not hex".to_string(),
+            label: None,
+            notes: None,
+            iccid: None,
+        }];
+        let outcome = tauri::async_runtime::block_on(apply_remote_config("ident", None, cards));
+        assert!(outcome.error.is_some());
+        assert!(!outcome.changes_config);
+    }
+}
@@ -0,0 +1,171 @@
+//! Module for the periodic jobs.
+//!
+//! The jobs (store compaction, security log retention, etc.) are registered by name with the default interval
+//! at the start and run by the single scheduler task, instead of a separate timer loop for every job.
+//! The interval of a job can be changed or the job can be disabled in the `scheduler` section of the configuration.
+//! The time of the last run is kept in the data folder, so a daily job is not run at every start of the application.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::config::{get_data_dir, get_scheduled_job_config, retry_io};
+use crate::timestamp::Timestamp;
+
+/// Name of the file with the time of the last runs of the jobs in the data folder.
+const SCHEDULER_STATE_FILE_NAME: &str = "scheduler.json";
+/// Maximum time between the checks of the jobs, so the configuration changes are applied without a restart.
+const MAX_CHECK_INTERVAL_SECS: i64 = 60;
+
+/// Registered job.
+struct Job {
+    name: &'static str,
+    default_interval_secs: u64,
+    run: fn(),
+    /// Unix time of the last run, `None` if the job has never been run.
+    last_run: Option<i64>,
+}
+
+impl Job {
+    /// The interval from the configuration, or the default one. `None` if the job is disabled.
+    fn interval_secs(&self) -> Option<u64> {
+        match get_scheduled_job_config(self.name) {
+            Some(config) if !config.enabled => None,
+            Some(config) => Some(config.interval_secs.unwrap_or(self.default_interval_secs)),
+            None => Some(self.default_interval_secs),
+        }
+    }
+
+    /// Unix time of the next run, `None` if the job is disabled. The job which has never been run is due right away.
+    fn next_run(&self) -> Option<i64> {
+        let interval = self.interval_secs()?;
+        Some(self.last_run.map(|last_run| last_run + interval as i64).unwrap_or(0))
+    }
+}
+
+lazy_static! {
+    static ref JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+}
+
+fn get_state_path() -> std::io::Result<PathBuf> {
+    Ok(get_data_dir()?.join(SCHEDULER_STATE_FILE_NAME))
+}
+
+/// Reads the time of the last runs of the jobs, by the job name.
+fn load_last_runs() -> HashMap<String, i64> {
+    let path = match get_state_path() {
+        Ok(path) => path,
+        Err(_) => return HashMap::new(),
+    };
+    match retry_io(|| fs::read_to_string(&path)) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Invalid scheduler state in {}, the jobs are run as new: {}", path.display(), e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_last_runs(jobs: &[Job]) {
+    let last_runs: HashMap<&str, i64> = jobs
+        .iter()
+        .filter_map(|job| job.last_run.map(|last_run| (job.name, last_run)))
+        .collect();
+    let result = get_state_path().and_then(|path| {
+        let json = serde_json::to_string_pretty(&last_runs).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        retry_io(|| fs::write(&path, &json))
+    });
+    if let Err(e) = result {
+        log::error!("Failed to save the scheduler state: {}", e);
+    }
+}
+
+/// Registers the periodic job. The job is run by the scheduler task (see `start_scheduler`).
+///
+/// # Arguments
+///
+/// * `name` - The name of the job in the configuration and in the diagnostics.
+/// * `default_interval_secs` - The interval between the runs if it is not configured.
+/// * `run` - The function of the job.
+pub fn register_job(name: &'static str, default_interval_secs: u64, run: fn()) {
+    let last_run = load_last_runs().get(name).copied();
+    let mut jobs = JOBS.lock().unwrap();
+    if jobs.iter().any(|job| job.name == name) {
+        log::warn!("Job '{}' is already registered", name);
+        return;
+    }
+    jobs.push(Job {
+        name,
+        default_interval_secs,
+        run,
+        last_run,
+    });
+}
+
+/// Runs the registered jobs when they are due. This function will run forever with the loop.
+pub async fn start_scheduler() {
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        let due: Vec<(&'static str, fn())> = JOBS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| job.next_run().map(|next_run| next_run <= now).unwrap_or(false))
+            .map(|job| (job.name, job.run))
+            .collect();
+
+        for (name, run) in due {
+            log::debug!("Running the scheduled job '{}'", name);
+            run();
+            let mut jobs = JOBS.lock().unwrap();
+            if let Some(job) = jobs.iter_mut().find(|job| job.name == name) {
+                job.last_run = Some(chrono::Utc::now().timestamp());
+            }
+            save_last_runs(&jobs);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let sleep_secs = JOBS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|job| job.next_run())
+            .map(|next_run| next_run - now)
+            .min()
+            .unwrap_or(MAX_CHECK_INTERVAL_SECS)
+            .clamp(1, MAX_CHECK_INTERVAL_SECS);
+        tokio::time::sleep(Duration::from_secs(sleep_secs as u64)).await;
+    }
+}
+
+/// Scheduled job for the diagnostics.
+#[derive(Serialize, Clone, Debug)]
+pub struct ScheduledJobInfo {
+    pub name: String,
+    /// The interval between the runs, `None` if the job is disabled.
+    pub interval_secs: Option<u64>,
+    pub last_run: Option<Timestamp>,
+    pub next_run: Option<Timestamp>,
+}
+
+/// Public function to get the scheduled jobs.
+/// This function is a Tauri command that is called from the diagnostics view of the frontend.
+#[tauri::command]
+pub fn list_scheduled_jobs() -> Vec<ScheduledJobInfo> {
+    let now = chrono::Utc::now().timestamp();
+    JOBS.lock()
+        .unwrap()
+        .iter()
+        .map(|job| ScheduledJobInfo {
+            name: job.name.to_string(),
+            interval_secs: job.interval_secs(),
+            last_run: job.last_run.map(Timestamp::from_epoch),
+            next_run: job.next_run().map(|next_run| Timestamp::from_epoch(next_run.max(now))),
+        })
+        .collect()
+}
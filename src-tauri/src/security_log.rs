@@ -0,0 +1,389 @@
+//! Module for the security log of the remote interactions.
+//!
+//! Every remote command, every configuration change that comes from outside of the application UI and every
+//! authentication session with a company card is written to a separate log for the customers' audit (SOC).
+//! The log is tamper-evident: every record contains the hash of the previous one, so a removed or modified
+//! record breaks the chain, which is detected by `verify_security_log`.
+//!
+//! The records are kept in monthly files `security/security-YYYY-MM.log` in the data folder, the chain continues
+//! from one file to the next. The files older than the retention period (`security_log.retention_days`) are removed.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{get_data_dir, get_security_log_config, retry_io};
+use crate::timestamp::Timestamp;
+
+/// Name of the folder with the security log files inside the data folder.
+const SECURITY_LOG_DIR_NAME: &str = "security";
+/// Previous hash of the very first record of the chain.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// Default interval of the retention job.
+pub const RETENTION_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Kind of the recorded interaction.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEvent {
+    /// A command received from the server on the application connection.
+    RemoteCommand,
+    /// A configuration change that is not made in the application UI.
+    ConfigChange,
+    /// The server started the authentication with the company card.
+    AuthenticationStarted,
+    /// The server finished the authentication with the company card.
+    AuthenticationFinished,
+    /// The ident of the account is changed, the connections are re-established with the new ident.
+    IdentRotated,
+    /// The connections follow the redirect of the broker to another server (see the server_redirect module).
+    ServerRedirected,
+}
+
+/// One record of the security log.
+///
+/// `hash` is the SHA-256 of the record serialized with the empty `hash` field, in hex.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SecurityRecord {
+    seq: u64,
+    timestamp: Timestamp,
+    event: SecurityEvent,
+    /// Company card number, if the interaction is related to the card.
+    card: Option<String>,
+    /// Who initiated the interaction (MQTT topic, ident, deep link, etc.).
+    source: String,
+    details: String,
+    prev_hash: String,
+    hash: String,
+}
+
+impl SecurityRecord {
+    fn compute_hash(&self) -> String {
+        let unsigned = SecurityRecord {
+            hash: String::new(),
+            ..self.clone()
+        };
+        // Serialization of the plain structure can't fail
+        let json = serde_json::to_string(&unsigned).unwrap_or_default();
+        hex::encode(Sha256::digest(json.as_bytes()))
+    }
+}
+
+/// The end of the chain: sequence number and hash of the last written record.
+struct ChainHead {
+    seq: u64,
+    hash: String,
+}
+
+lazy_static! {
+    /// Loaded lazily from the existing files on the first record.
+    static ref CHAIN_HEAD: Mutex<Option<ChainHead>> = Mutex::new(None);
+}
+
+fn get_security_log_dir() -> io::Result<PathBuf> {
+    let mut path = get_data_dir()?;
+    path.push(SECURITY_LOG_DIR_NAME);
+    Ok(path)
+}
+
+/// Returns the security log files sorted from the oldest to the newest.
+fn list_log_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("security-") && name.ends_with(".log"))
+                .unwrap_or(false)
+        })
+        .collect();
+    // The month in the name is zero-padded, so the names are sorted chronologically
+    files.sort();
+    Ok(files)
+}
+
+fn read_records(path: &Path) -> io::Result<Vec<SecurityRecord>> {
+    let contents = retry_io(|| fs::read_to_string(path))?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+        .collect()
+}
+
+/// Finds the last record of the chain in the existing files.
+fn load_chain_head(dir: &Path) -> io::Result<ChainHead> {
+    for path in list_log_files(dir)?.iter().rev() {
+        if let Some(last) = read_records(path)?.pop() {
+            return Ok(ChainHead {
+                seq: last.seq,
+                hash: last.hash,
+            });
+        }
+    }
+    Ok(ChainHead {
+        seq: 0,
+        hash: GENESIS_HASH.to_string(),
+    })
+}
+
+/// Writes the interaction to the security log.
+///
+/// Failures are logged to the application log and don't interrupt the interaction itself.
+///
+/// # Arguments
+///
+/// * `event` - Kind of the interaction.
+/// * `card` - Company card number, if the interaction is related to the card.
+/// * `source` - Who initiated the interaction.
+/// * `details` - Free-form description (command payload, changed values, etc.).
+pub fn record(event: SecurityEvent, card: Option<&str>, source: &str, details: &str) {
+    if let Err(e) = append_record(event, card, source, details) {
+        log::error!("Failed to write the security log record {:?}: {}", event, e);
+    }
+}
+
+fn append_record(event: SecurityEvent, card: Option<&str>, source: &str, details: &str) -> io::Result<()> {
+    let dir = get_security_log_dir()?;
+    fs::create_dir_all(&dir)?;
+
+    let mut head = CHAIN_HEAD.lock().unwrap();
+    if head.is_none() {
+        *head = Some(load_chain_head(&dir)?);
+    }
+    let chain = head.as_mut().unwrap();
+
+    let now = chrono::Utc::now();
+    let mut record = SecurityRecord {
+        seq: chain.seq + 1,
+        timestamp: Timestamp::from_datetime(now),
+        event,
+        card: card.map(|card| card.to_string()),
+        source: source.to_string(),
+        details: details.to_string(),
+        prev_hash: chain.hash.clone(),
+        hash: String::new(),
+    };
+    record.hash = record.compute_hash();
+
+    let line = serde_json::to_string(&record).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let path = dir.join(format!("security-{}.log", now.format("%Y-%m")));
+    retry_io(|| {
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", line)
+    })?;
+
+    chain.seq = record.seq;
+    chain.hash = record.hash;
+    Ok(())
+}
+
+/// Removes the security log files which are older than the retention period from the configuration.
+/// The file of the month is removed when the whole month is out of the retention period.
+pub fn apply_retention() {
+    let retention_days = get_security_log_config().retention_days;
+    let dir = match get_security_log_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::error!("Failed to get the security log folder: {}", e);
+            return;
+        }
+    };
+    let files = match list_log_files(&dir) {
+        Ok(files) => files,
+        Err(e) => {
+            log::error!("Failed to read the security log folder: {}", e);
+            return;
+        }
+    };
+
+    let oldest_kept = (chrono::Utc::now() - chrono::Duration::days(retention_days as i64))
+        .format("%Y-%m")
+        .to_string();
+    for path in files {
+        let month = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix("security-"))
+            .unwrap_or_default()
+            .to_string();
+        if month < oldest_kept {
+            match fs::remove_file(&path) {
+                Ok(_) => log::info!("Security log {} is removed by the retention policy", path.display()),
+                Err(e) => log::warn!("Failed to remove the security log {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Checks that the chain of the security log records is not broken.
+///
+/// The first remaining record is trusted as is, because the older records may have been removed by the retention policy.
+///
+/// # Returns
+///
+/// * `Result<u64, String>` - The number of checked records, or the description of the first broken record.
+pub fn verify_chain() -> Result<u64, String> {
+    let dir = get_security_log_dir().map_err(|e| e.to_string())?;
+    verify_chain_in(&dir)
+}
+
+fn verify_chain_in(dir: &Path) -> Result<u64, String> {
+    let mut checked = 0;
+    let mut prev: Option<SecurityRecord> = None;
+
+    for path in list_log_files(dir).map_err(|e| e.to_string())? {
+        let records = read_records(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for record in records {
+            if record.hash != record.compute_hash() {
+                return Err(format!("{}: record {} is modified", path.display(), record.seq));
+            }
+            if let Some(prev) = &prev {
+                if record.prev_hash != prev.hash || record.seq != prev.seq + 1 {
+                    return Err(format!(
+                        "{}: the chain is broken between the records {} and {}",
+                        path.display(),
+                        prev.seq,
+                        record.seq
+                    ));
+                }
+            }
+            checked += 1;
+            prev = Some(record);
+        }
+    }
+
+    Ok(checked)
+}
+
+/// Public function to check the integrity of the security log.
+/// This function is a Tauri command that is called from the frontend.
+///
+/// # Returns
+///
+/// * `Result<u64, String>` - The number of checked records, or the file and the record where the chain is broken.
+#[tauri::command]
+pub fn verify_security_log() -> Result<u64, String> {
+    match verify_chain() {
+        Ok(checked) => {
+            log::info!("Security log is verified: {} record(s)", checked);
+            Ok(checked)
+        }
+        Err(e) => {
+            log::error!("Security log verification failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the record which continues the chain after `prev`.
+    fn next_record(prev: Option<&SecurityRecord>, details: &str) -> SecurityRecord {
+        let mut record = SecurityRecord {
+            seq: prev.map_or(1, |prev| prev.seq + 1),
+            timestamp: Timestamp::from_datetime(chrono::Utc::now()),
+            event: SecurityEvent::RemoteCommand,
+            card: None,
+            source: "test".to_string(),
+            details: details.to_string(),
+            prev_hash: prev.map_or(GENESIS_HASH.to_string(), |prev| prev.hash.clone()),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        record
+    }
+
+    fn chain(length: usize) -> Vec<SecurityRecord> {
+        let mut records: Vec<SecurityRecord> = Vec::new();
+        for i in 0..length {
+            let record = next_record(records.last(), &format!("command {}", i));
+            records.push(record);
+        }
+        records
+    }
+
+    fn write_file(dir: &Path, month: &str, records: &[SecurityRecord]) {
+        let lines: Vec<String> = records.iter().map(|record| serde_json::to_string(record).unwrap()).collect();
+        fs::write(dir.join(format!("security-{}.log", month)), lines.join("\n") + "\n").unwrap();
+    }
+
+    fn temp_log_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tba-security-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hash_covers_every_field() {
+        let record = next_record(None, "command");
+        assert_eq!(record.hash, record.compute_hash());
+        let modified = SecurityRecord {
+            details: "another command".to_string(),
+            ..record.clone()
+        };
+        assert_ne!(modified.compute_hash(), record.hash);
+        let modified = SecurityRecord {
+            card: Some("RUD0000000000000".to_string()),
+            ..record.clone()
+        };
+        assert_ne!(modified.compute_hash(), record.hash);
+    }
+
+    #[test]
+    fn intact_chain_is_verified() {
+        let dir = temp_log_dir();
+        write_file(&dir, "2024-05", &chain(3));
+        assert_eq!(verify_chain_in(&dir), Ok(3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn modified_record_is_detected() {
+        let dir = temp_log_dir();
+        let mut records = chain(3);
+        records[1].details = "forged command".to_string();
+        write_file(&dir, "2024-05", &records);
+        let error = verify_chain_in(&dir).unwrap_err();
+        assert!(error.contains("record 2 is modified"), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removed_middle_record_is_detected() {
+        let dir = temp_log_dir();
+        let mut records = chain(3);
+        records.remove(1);
+        write_file(&dir, "2024-05", &records);
+        let error = verify_chain_in(&dir).unwrap_err();
+        assert!(error.contains("between the records 1 and 3"), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chain_continues_across_monthly_files() {
+        let dir = temp_log_dir();
+        let records = chain(4);
+        write_file(&dir, "2024-12", &records[..2]);
+        write_file(&dir, "2025-01", &records[2..]);
+        assert_eq!(verify_chain_in(&dir), Ok(4));
+        assert_eq!(load_chain_head(&dir).unwrap().seq, 4);
+
+        // The first record of the next month must continue the last one of the previous month
+        let mut restarted = records[2..].to_vec();
+        restarted[0] = next_record(None, "restarted chain");
+        write_file(&dir, "2025-01", &restarted);
+        assert!(verify_chain_in(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
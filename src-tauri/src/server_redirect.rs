@@ -0,0 +1,372 @@
+//! Module for the redirects of the MQTT 5 broker to another server.
+//!
+//! The broker being migrated or balanced refuses the connection (or disconnects it) with the "Use another server"
+//! or "Server moved" reason code and the Server Reference property with the new address. The MQTT client reports
+//! only the reason code, so the bridge reads the reference itself: it sends the CONNECT to the configured server
+//! and parses the Server Reference of the CONNACK. The connection is moved to the referenced server only if it is
+//! in the `allowed_redirect_hosts` of the server (or of the account), so a compromised or misconfigured broker can't
+//! send the company card traffic anywhere else. Without the allow-list the redirect is only reported to the user.
+//!
+//! The followed redirect is kept until the restart of the application, the other connections to the same server
+//! are established with the referenced server right away (see `current_host`).
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::config::{split_host_to_parts, AccountConfig};
+use crate::global_app_handle::emit_notification;
+use crate::mqtt_client::MqttVersion;
+
+/// Timeout of the connection to the server and of its CONNACK when the Server Reference is read.
+const PROBE_TIMEOUT_SECS: u64 = 10;
+
+/// Maximum size of the CONNACK read from the server.
+const MAX_CONNACK_SIZE: usize = 4096;
+
+/// CONNECT packet type of the fixed header.
+const CONNECT_PACKET: u8 = 0x10;
+/// CONNACK packet type of the fixed header.
+const CONNACK_PACKET: u8 = 0x20;
+/// Identifier of the Server Reference property.
+const SERVER_REFERENCE_PROPERTY: u8 = 0x1C;
+
+lazy_static! {
+    /// Followed redirects: the referenced server by the configured one.
+    static ref REDIRECTS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    /// Hosts which have asked to connect to another server, so the user is notified only once.
+    static ref MOVED_SERVERS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Returns the server the connections to the configured server are established with: the referenced server
+/// if the redirect has been followed, otherwise the configured one.
+pub fn current_host(configured: &str) -> String {
+    REDIRECTS
+        .lock()
+        .unwrap()
+        .get(configured)
+        .cloned()
+        .unwrap_or_else(|| configured.to_string())
+}
+
+/// Returns the referenced servers of the followed redirects, 'host:port'.
+pub fn followed_hosts() -> Vec<String> {
+    REDIRECTS.lock().unwrap().values().cloned().collect()
+}
+
+/// Follows the redirect of the server of the account, called when the connection reports the moved server.
+///
+/// The Server Reference is read from the server the connection is established with, and the referenced server
+/// is checked against the allow-list of the account. The redirect which is not followed is reported to the user.
+///
+/// # Arguments
+///
+/// * `account` - The account of the connection.
+/// * `log_header` - The header of the log messages of the connection.
+///
+/// # Returns
+///
+/// * `Option<String>` - The referenced server, 'host:port', which the connection is moved to.
+pub async fn follow_redirect(account: &AccountConfig, log_header: &str) -> Option<String> {
+    let host = current_host(&account.host);
+    if account.mqtt_version != MqttVersion::V5 {
+        report_server_moved(&host, log_header);
+        return None;
+    }
+    let probe_account = account.clone();
+    let probe_host = host.clone();
+    let reference = tokio::task::spawn_blocking(move || read_server_reference(&probe_account, &probe_host))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    let reference = match reference {
+        Ok(Some(reference)) => reference,
+        Ok(None) => {
+            log::warn!("{} The server {} has not sent the Server Reference", log_header, host);
+            report_server_moved(&host, log_header);
+            return None;
+        }
+        Err(e) => {
+            log::warn!("{} Failed to read the Server Reference of {}: {}", log_header, host, e);
+            report_server_moved(&host, log_header);
+            return None;
+        }
+    };
+    match allowed_reference(&reference, &host, &account.allowed_redirect_hosts) {
+        Some(target) => {
+            log::warn!("{} The server {} has moved to {}, the connection follows the redirect", log_header, host, target);
+            let previous = REDIRECTS.lock().unwrap().insert(account.host.clone(), target.clone());
+            if previous.as_deref() != Some(target.as_str()) {
+                emit_notification("info", &format!("The server {} has moved to {}, the bridge is connected to it.", account.host, target));
+                crate::security_log::record(
+                    crate::security_log::SecurityEvent::ServerRedirected,
+                    None,
+                    &host,
+                    &format!("redirected to {}", target),
+                );
+            }
+            Some(target)
+        }
+        None => {
+            log::warn!(
+                "{} The server {} refers to {}, which is not in allowed_redirect_hosts. The redirect is not followed.",
+                log_header,
+                host,
+                reference
+            );
+            report_server_moved(&host, log_header);
+            None
+        }
+    }
+}
+
+/// Reports that the server has asked to connect to another server, and the redirect is not followed.
+/// The connection keeps retrying the server and the user is notified (once per host) to update the server address.
+fn report_server_moved(host: &str, log_header: &str) {
+    log::warn!(
+        "{} The server {} has asked to connect to another server. Update the server address in the configuration.",
+        log_header,
+        host
+    );
+    if MOVED_SERVERS.lock().unwrap().insert(host.to_string()) {
+        emit_notification(
+            "warning",
+            &format!("The server {} has moved. Update the server address in the settings.", host),
+        );
+    }
+}
+
+/// Picks the first server of the Server Reference which is allowed for the redirect.
+///
+/// The reference is a space separated list of 'host[:port]', the port of the current server is used
+/// if the reference has none.
+///
+/// # Returns
+///
+/// * `Option<String>` - The allowed server, 'host:port', or `None` if none of the referenced servers is allowed.
+fn allowed_reference(reference: &str, current: &str, allowed_hosts: &[String]) -> Option<String> {
+    let current_port = split_host_to_parts(current).map(|(_, port)| port).ok()?;
+    reference.split_whitespace().find_map(|server| {
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().ok()?),
+            None => (server, current_port),
+        };
+        allowed_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, host))
+            .then(|| format!("{}:{}", host, port))
+    })
+}
+
+/// Checks the host against the pattern of the allow-list: the host name or `*.domain` for its subdomains.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let (pattern, host) = (pattern.trim().to_lowercase(), host.to_lowercase());
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => !pattern.is_empty() && pattern == host,
+    }
+}
+
+/// Sends the CONNECT with the settings of the account to the server and reads the Server Reference of its CONNACK.
+///
+/// # Returns
+///
+/// * `Result<Option<String>, String>` - The Server Reference, `None` if the CONNACK has none.
+fn read_server_reference(account: &AccountConfig, host: &str) -> Result<Option<String>, String> {
+    let (name, port) = split_host_to_parts(host)?;
+    let timeout = Duration::from_secs(PROBE_TIMEOUT_SECS);
+    let address = (name.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", name))?;
+    let stream = TcpStream::connect_timeout(&address, timeout).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+    let client_id = format!("tba-redirect-{}", crate::installation::installation_id());
+    let connect = connect_packet(&client_id, account.username.as_deref(), account.password.as_deref());
+    let connack = match crate::client_tls::load_tls(account)? {
+        Some(config) => {
+            let server_name = rustls::ServerName::try_from(name.as_str()).map_err(|e| e.to_string())?;
+            let connection = rustls::ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?;
+            exchange(&mut rustls::StreamOwned::new(connection, stream), &connect)?
+        }
+        None => exchange(&mut { stream }, &connect)?,
+    };
+    parse_server_reference(&connack)
+}
+
+/// Sends the CONNECT and reads the CONNACK packet.
+fn exchange<S: Read + Write>(stream: &mut S, connect: &[u8]) -> Result<Vec<u8>, String> {
+    stream.write_all(connect).map_err(|e| e.to_string())?;
+    stream.flush().map_err(|e| e.to_string())?;
+    let mut header = [0u8; 1];
+    stream.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if header[0] != CONNACK_PACKET {
+        return Err(format!("The server has responded with the packet 0x{:02x} instead of CONNACK", header[0]));
+    }
+    let mut length = 0usize;
+    for shift in (0..28).step_by(7) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).map_err(|e| e.to_string())?;
+        length |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_CONNACK_SIZE {
+        return Err(format!("The CONNACK of {} bytes is too large", length));
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(body)
+}
+
+/// Encodes the remaining length or the property length as the variable byte integer.
+fn write_variable_length(buffer: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// Encodes the MQTT 5 CONNECT packet with the clean start and without the properties.
+fn connect_packet(client_id: &str, username: Option<&str>, password: Option<&str>) -> Vec<u8> {
+    let mut flags = 0x02; // clean start
+    if username.is_some() {
+        flags |= 0x80 | 0x40; // the broker with the token authentication gets the empty password
+    }
+    let mut body = Vec::new();
+    write_string(&mut body, "MQTT");
+    body.push(5); // protocol version
+    body.push(flags);
+    body.extend_from_slice(&(PROBE_TIMEOUT_SECS as u16).to_be_bytes()); // keep-alive
+    write_variable_length(&mut body, 0); // no properties
+    write_string(&mut body, client_id);
+    if let Some(username) = username {
+        write_string(&mut body, username);
+        write_string(&mut body, password.unwrap_or_default());
+    }
+    let mut packet = vec![CONNECT_PACKET];
+    write_variable_length(&mut packet, body.len());
+    packet.extend(body);
+    packet
+}
+
+/// Parses the body of the CONNACK (after the fixed header) and returns its Server Reference property.
+fn parse_server_reference(body: &[u8]) -> Result<Option<String>, String> {
+    let truncated = || "The CONNACK is truncated".to_string();
+    // The acknowledge flags and the reason code go before the properties
+    let mut position = 2;
+    let mut properties_length = 0usize;
+    for shift in (0..28).step_by(7) {
+        let byte = *body.get(position).ok_or_else(truncated)?;
+        position += 1;
+        properties_length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let properties = body.get(position..position + properties_length).ok_or_else(truncated)?;
+    let read_u16 = |at: usize| -> Result<usize, String> {
+        let bytes = properties.get(at..at + 2).ok_or_else(truncated)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    };
+    let mut at = 0;
+    while at < properties.len() {
+        let property = properties[at];
+        at += 1;
+        at += match property {
+            // Strings and binary data: the length and the value
+            0x12 | 0x15 | 0x16 | 0x1A | 0x1C | 0x1F => {
+                let length = read_u16(at)?;
+                if property == SERVER_REFERENCE_PROPERTY {
+                    let value = properties.get(at + 2..at + 2 + length).ok_or_else(truncated)?;
+                    return Ok(Some(String::from_utf8_lossy(value).into_owned()));
+                }
+                2 + length
+            }
+            // User property: the pair of strings
+            0x26 => {
+                let key = read_u16(at)?;
+                2 + key + 2 + read_u16(at + 2 + key)?
+            }
+            // Four byte integers
+            0x11 | 0x27 => 4,
+            // Two byte integers
+            0x13 | 0x21 | 0x22 => 2,
+            // Bytes
+            0x24 | 0x25 | 0x28 | 0x29 | 0x2A => 1,
+            _ => return Err(format!("Unknown CONNACK property 0x{:02x}", property)),
+        };
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_packet_has_the_credentials() {
+        let packet = connect_packet("c", Some("token"), None);
+        let expected = vec![
+            0x10, 23, // CONNECT, remaining length
+            0, 4, b'M', b'Q', b'T', b'T', 5, 0xC2, 0, 10, 0, // protocol, version, flags, keep-alive, no properties
+            0, 1, b'c', // client ID
+            0, 5, b't', b'o', b'k', b'e', b'n', // username
+            0, 0, // empty password
+        ];
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn server_reference_is_parsed() {
+        // Reason code "Server moved", the reason string and the Server Reference
+        let mut body = vec![0x00, 0x9D];
+        let mut properties = vec![0x1F];
+        write_string(&mut properties, "moved");
+        properties.push(0x26);
+        write_string(&mut properties, "k");
+        write_string(&mut properties, "v");
+        properties.push(0x21);
+        properties.extend_from_slice(&[0, 10]);
+        properties.push(SERVER_REFERENCE_PROPERTY);
+        write_string(&mut properties, "mqtt2.flespi.io:8883");
+        write_variable_length(&mut body, properties.len());
+        body.extend(properties);
+        assert_eq!(parse_server_reference(&body), Ok(Some("mqtt2.flespi.io:8883".to_string())));
+
+        assert_eq!(parse_server_reference(&[0x00, 0x00, 0x00]), Ok(None));
+        assert!(parse_server_reference(&[0x00, 0x9D, 0x05, SERVER_REFERENCE_PROPERTY]).is_err());
+    }
+
+    #[test]
+    fn only_allowed_hosts_are_followed() {
+        let allowed = vec!["*.flespi.io".to_string(), "broker.example.com".to_string()];
+        assert_eq!(allowed_reference("mqtt2.flespi.io", "mqtt.flespi.io:8883", &allowed), Some("mqtt2.flespi.io:8883".to_string()));
+        assert_eq!(allowed_reference("BROKER.example.com:1883", "mqtt.flespi.io:8883", &allowed), Some("BROKER.example.com:1883".to_string()));
+        assert_eq!(
+            allowed_reference("evil.com mqtt3.flespi.io:443", "mqtt.flespi.io:8883", &allowed),
+            Some("mqtt3.flespi.io:443".to_string())
+        );
+        assert_eq!(allowed_reference("flespi.io.evil.com", "mqtt.flespi.io:8883", &allowed), None);
+        assert_eq!(allowed_reference("mqtt2.flespi.io", "mqtt.flespi.io:8883", &[]), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Module for the simulated reader with the simulated company card.
+//!
+//! The developers and the frontend are tested without the hardware: in the builds with the `simulated-card` feature
+//! the monitor reports the simulated reader with the card inserted at the start. The card has the ATR of the company
+//! card, answers the SELECT and READ BINARY commands of the ICCID, the type and the identification of the card,
+//! and returns the canned responses to the commands of the authentication. The card is paired with the number
+//! in the UI the same as the real card. In the other builds the reader is never reported.
+
+use std::cell::Cell;
+use std::error::Error;
+
+use crate::protocol::ApduTransport;
+
+/// Name of the simulated reader, it has no slot index, so its label is the same.
+pub const SIMULATED_READER_NAME: &str = "Tacho Bridge Simulated Reader";
+/// ATR of the simulated card, it matches the known company card ATRs (see `known_cards`).
+pub const SIMULATED_ATR: &str = "3bff9600008131fe4380318065b0846566fb12017882900085";
+
+/// Content of EF ICC, the ICCID of the simulated card.
+const SIMULATED_ICC: &str = "8900000000000000000153494d554c41544544000000000001";
+/// Type of the card in EF Application_Identification: the company card.
+const SIMULATED_CARD_TYPE: u8 = 4;
+/// Card number in EF Identification.
+const SIMULATED_CARD_NUMBER: &[u8; 16] = b"SIM0000000000001";
+/// 2020-01-01 and 2035-01-01, the issue and the expiry dates of the card.
+const SIMULATED_ISSUE_DATE: u32 = 1_577_836_800;
+const SIMULATED_EXPIRY_DATE: u32 = 2_051_222_400;
+
+/// File selected on the simulated card.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SelectedFile {
+    None,
+    Icc,
+    ApplicationIdentification,
+    Identification,
+}
+
+/// Simulated company card.
+pub struct SimulatedCard {
+    selected: Cell<SelectedFile>,
+}
+
+impl Default for SimulatedCard {
+    fn default() -> Self {
+        SimulatedCard {
+            selected: Cell::new(SelectedFile::None),
+        }
+    }
+}
+
+/// Checks if the reader is the simulated one. It is never the case in the builds without the `simulated-card` feature.
+pub fn is_simulated_reader(reader_name: &str) -> bool {
+    cfg!(feature = "simulated-card") && reader_name == SIMULATED_READER_NAME
+}
+
+/// Checks if the simulated reader has to be reported by the monitor.
+pub fn is_enabled() -> bool {
+    cfg!(feature = "simulated-card")
+}
+
+impl SimulatedCard {
+    /// Responds to the command with the data and the status word.
+    fn respond(&self, apdu: &[u8]) -> Vec<u8> {
+        const OK: [u8; 2] = [0x90, 0x00];
+        if apdu.len() < 4 {
+            return vec![0x67, 0x00];
+        }
+        // Le of the short command without the data
+        let le = if apdu.len() == 5 { apdu[4] as usize } else { 0 };
+        let data = if apdu.len() > 5 { &apdu[5..] } else { &[][..] };
+        let mut response = match apdu[1] {
+            // SELECT: the files of the ICCID, the type and the identification are known, the other files
+            // (e.g. the certificates) are not found, the applications and the MF are accepted
+            0xA4 => {
+                let selected = match data {
+                    [0x00, 0x02] => SelectedFile::Icc,
+                    [0x05, 0x01] => SelectedFile::ApplicationIdentification,
+                    [0x05, 0x20] => SelectedFile::Identification,
+                    _ if apdu[2] == 0x02 => return vec![0x6A, 0x82],
+                    _ => SelectedFile::None,
+                };
+                self.selected.set(selected);
+                Vec::new()
+            }
+            // READ BINARY
+            0xB0 => {
+                let mut content = match self.selected.get() {
+                    SelectedFile::Icc => hex::decode(SIMULATED_ICC).unwrap_or_default(),
+                    SelectedFile::ApplicationIdentification => vec![SIMULATED_CARD_TYPE],
+                    SelectedFile::Identification => identification(),
+                    SelectedFile::None => Vec::new(),
+                };
+                content.resize(le, 0x00);
+                content
+            }
+            // VERIFY: the card has no PIN
+            0x20 => return vec![0x6A, 0x88],
+            // GET CHALLENGE: the random number is requested by the authentication
+            0x84 => (0..le.max(8)).map(|i| (i as u8).wrapping_mul(37).wrapping_add(11)).collect(),
+            // INTERNAL AUTHENTICATE: the signature of the challenge
+            0x88 => vec![0x5A; if le == 0 { 128 } else { le }],
+            // MSE, PSO, EXTERNAL AUTHENTICATE and the others are accepted
+            _ => Vec::new(),
+        };
+        response.extend_from_slice(&OK);
+        response
+    }
+}
+
+impl ApduTransport for SimulatedCard {
+    type Error = Box<dyn Error>;
+
+    fn transmit_hex(&self, apdu_hex: &str) -> Result<String, Self::Error> {
+        let apdu = hex::decode(apdu_hex).map_err(|err| format!("Failed to decode tracker's APDU HEX: {}", err))?;
+        let response = hex::encode(self.respond(&apdu));
+        log::debug!("Simulated card: {} -> {}", apdu_hex, response);
+        Ok(response)
+    }
+}
+
+/// Encodes the name: the code page (Latin-1) and the text padded with the spaces.
+fn name(text: &str) -> Vec<u8> {
+    let mut name = vec![0x01];
+    name.extend(text.bytes().chain(std::iter::repeat(b' ')).take(35));
+    name
+}
+
+/// Content of EF Identification of the company card: CardIdentification and CompanyCardHolderIdentification.
+fn identification() -> Vec<u8> {
+    let mut data = vec![0x11];
+    data.extend_from_slice(SIMULATED_CARD_NUMBER);
+    data.extend(name("Simulated Issuing Authority"));
+    data.extend_from_slice(&SIMULATED_ISSUE_DATE.to_be_bytes());
+    data.extend_from_slice(&SIMULATED_ISSUE_DATE.to_be_bytes());
+    data.extend_from_slice(&SIMULATED_EXPIRY_DATE.to_be_bytes());
+    data.extend(name("Simulated Transport Company"));
+    data.extend(name("Simulated Street 1"));
+    data.extend_from_slice(b"en");
+    data
+}
@@ -0,0 +1,199 @@
+//! Module for the single instance of the application.
+//!
+//! The operating system starts a new instance of the application for every `tba://` link, while the cards must be
+//! served by one bridge only. The running instance listens on the loopback interface and keeps the port and a random
+//! secret in the data folder. The started instance reads them, sends the secret and its command line to the running
+//! one and exits, the running instance handles the forwarded command line (see `deep_link::handle_forwarded_args`).
+//!
+//! The file of the closed (or crashed) instance is left in the data folder, so the started instance which can't
+//! reach the running one goes on as the running instance itself and replaces the file.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::config::{get_data_dir, retry_io};
+
+/// Name of the file with the port and the secret of the running instance in the data folder.
+const INSTANCE_FILE_NAME: &str = "instance";
+
+/// Timeout of the connection to the running instance and of its reply.
+const FORWARD_TIMEOUT_MILLIS: u64 = 2000;
+
+/// Upper limit of the forwarded command line.
+const MAX_FORWARDED_SIZE: u64 = 64 * 1024;
+
+/// Reply of the running instance which has accepted the forwarded command line.
+const ACCEPTED_REPLY: &[u8] = b"ok\n";
+
+/// Forwards the command line of this instance to the running one.
+///
+/// # Returns
+///
+/// * `bool` - Whether the running instance has accepted the command line, this instance must exit then.
+pub fn forward_to_running_instance() -> bool {
+    let (port, secret) = match read_instance_file() {
+        Some(instance) => instance,
+        None => return false,
+    };
+    let args: Vec<String> = std::env::args().collect();
+    match forward_args(port, &secret, &args) {
+        Ok(()) => true,
+        Err(e) => {
+            log::info!("No running instance of the application is reachable: {}", e);
+            false
+        }
+    }
+}
+
+fn forward_args(port: u16, secret: &str, args: &[String]) -> Result<(), String> {
+    let timeout = Duration::from_millis(FORWARD_TIMEOUT_MILLIS);
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let mut stream = TcpStream::connect_timeout(&address, timeout).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    let args = serde_json::to_string(args).map_err(|e| e.to_string())?;
+    stream
+        .write_all(format!("{}\n{}", secret, args).as_bytes())
+        .and_then(|_| stream.shutdown(std::net::Shutdown::Write))
+        .map_err(|e| e.to_string())?;
+    let mut reply = Vec::new();
+    stream
+        .take(ACCEPTED_REPLY.len() as u64)
+        .read_to_end(&mut reply)
+        .map_err(|e| e.to_string())?;
+    if reply != ACCEPTED_REPLY {
+        return Err("the command line is not accepted".to_string());
+    }
+    Ok(())
+}
+
+/// Starts to accept the command lines of the instances started while the application is running.
+/// Called once the application is set up, the accepted command lines are handled by the deep links.
+///
+/// # Arguments
+///
+/// * `app` - The handle of the running application.
+pub fn listen(app: tauri::AppHandle) {
+    let listener = match std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to listen for the started instances of the application: {}", e);
+            return;
+        }
+    };
+    let port = match listener.local_addr() {
+        Ok(address) => address.port(),
+        Err(e) => {
+            log::error!("Failed to listen for the started instances of the application: {}", e);
+            return;
+        }
+    };
+    let mut random = [0u8; 32];
+    if SystemRandom::new().fill(&mut random).is_err() {
+        log::error!("Failed to generate the secret of the running instance");
+        return;
+    }
+    let secret = hex::encode(random);
+    if let Err(e) = write_instance_file(port, &secret) {
+        log::error!("Failed to save the running instance: {}", e);
+        return;
+    }
+    tauri::async_runtime::spawn(accept_instances(listener, secret, app));
+}
+
+async fn accept_instances(listener: std::net::TcpListener, secret: String, app: tauri::AppHandle) {
+    let listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to listen for the started instances of the application: {}", e);
+            return;
+        }
+    };
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept the started instance of the application: {}", e);
+                continue;
+            }
+        };
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(
+            Duration::from_millis(FORWARD_TIMEOUT_MILLIS),
+            (&mut stream).take(MAX_FORWARDED_SIZE).read_to_end(&mut received),
+        )
+        .await;
+        if !matches!(read, Ok(Ok(_))) {
+            log::warn!("The started instance of the application has not sent its command line");
+            continue;
+        }
+        match parse_forwarded(&received, &secret) {
+            Ok(args) => {
+                let _ = stream.write_all(ACCEPTED_REPLY).await;
+                log::info!("The application is started again, its command line is handled by the running instance");
+                crate::deep_link::handle_forwarded_args(&app, args);
+            }
+            Err(e) => log::warn!("The forwarded command line is refused: {}", e),
+        }
+    }
+}
+
+/// Returns the command line sent by the started instance: the secret of the running instance on the first line,
+/// then the arguments as the JSON array.
+fn parse_forwarded(received: &[u8], secret: &str) -> Result<Vec<String>, String> {
+    let received = std::str::from_utf8(received).map_err(|_| "it is not valid text".to_string())?;
+    let (received_secret, args) = received.split_once('\n').ok_or_else(|| "the secret is missing".to_string())?;
+    let matches = received_secret.len() == secret.len()
+        && received_secret
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return Err("the secret does not match".to_string());
+    }
+    serde_json::from_str(args).map_err(|e| format!("invalid arguments: {}", e))
+}
+
+fn read_instance_file() -> Option<(u16, String)> {
+    let path = get_data_dir().ok()?.join(INSTANCE_FILE_NAME);
+    let contents = retry_io(|| fs::read_to_string(&path)).ok()?;
+    let mut lines = contents.lines();
+    let port = lines.next()?.trim().parse::<u16>().ok()?;
+    let secret = lines.next()?.trim().to_string();
+    Some((port, secret))
+}
+
+fn write_instance_file(port: u16, secret: &str) -> Result<(), String> {
+    let path = get_data_dir().map_err(|e| e.to_string())?.join(INSTANCE_FILE_NAME);
+    retry_io(|| fs::write(&path, format!("{}\n{}\n", port, secret))).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_forwarded_command_line() {
+        let received = b"secret\n[\"tba.exe\",\"tba://resync\"]";
+        assert_eq!(
+            parse_forwarded(received, "secret"),
+            Ok(vec!["tba.exe".to_string(), "tba://resync".to_string()])
+        );
+    }
+
+    #[test]
+    fn refuses_wrong_secret() {
+        assert!(parse_forwarded(b"guess\n[\"tba.exe\"]", "secret").is_err());
+        assert!(parse_forwarded(b"[\"tba.exe\"]", "secret").is_err());
+        assert!(parse_forwarded(b"secret\nnot json", "secret").is_err());
+    }
+}
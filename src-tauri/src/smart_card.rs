@@ -1,7 +1,10 @@
 use std::error::Error;
 use std::error::Error as StdError;
-use std::ffi::CStr;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use pcsc::*; // Importing pcsc module for smart card reader operations.
 
@@ -10,19 +13,64 @@ use tauri::async_runtime::Mutex;
 // use tauri::Manager; // Tauri application manager for app lifecycle and window management. // There is a Mutex implementation for the standard from the std lib, but it blocks the current thread and is not integrated with the Tauri async framework we are using, so we will use what is intended: Tauri mutex.
 
 use hex::{decode, encode}; // Hexadecimal encoding and decoding utilities.
+use once_cell::unsync::OnceCell; // Lazily read card data.
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender}; // Reader changes from the monitor thread.
 
 // Importing specific functionality from local modules
-use crate::config::get_from_cache; // Function to get data from cache for syncing cards.
-use crate::config::CacheSection;
-use crate::global_app_handle::emit_event;
+use crate::config::get_card_by_atr; // Function to get the paired card from cache for syncing cards.
+use crate::config::get_reader_debounce_config; // Debouncing of the reader state changes.
+use crate::config::{get_reader_alias, get_readers_config}; // Filter and aliases of the readers.
+use crate::config::{get_card_force_protocol, ForceProtocol}; // Protocol override of the card.
+use crate::global_app_handle::{emit_card_state, emit_notification, CardStatePayload, StateReason};
+use crate::timestamp::Timestamp;
 // Enum for cache sections for getting data from cache.
-use crate::mqtt::{ensure_connection, remove_connections}; // MQTT module functions for managing connections with the readers.
+use crate::mqtt::remove_connections; // MQTT module functions for managing connections with the readers.
+use crate::mqtt_client::MqttClient; // Client of the connection for the graceful disconnect.
+use crate::card_init; // Initialization of the inserted cards out of the monitor loop.
+use crate::reader_pool::{is_reader_allowed, update_reader}; // Pool of the readers with the cards.
+use crate::reader_debounce::ReaderDebouncer; // Protection against the flapping readers.
+use crate::apdu_trace::TracedCard; // Trace of the commands sent to the card.
+use crate::atr::{parse_atr_and_get_protocol, AtrSummary}; // Parsing of the card ATRs.
+use crate::known_cards::{find_known_card, warn_if_unknown_card, CardGeneration, KnownCard}; // Known tachograph card ATRs.
+use crate::card_identification::TachographCardType; // Type of the tachograph card.
+use crate::protocol::ApduTransport; // Commands to the card of any kind.
+use crate::simulated_card::{is_simulated_reader, SimulatedCard, SIMULATED_ATR, SIMULATED_READER_NAME}; // Simulated reader without the hardware.
 
 // import set for async task_pool under mutex
 use lazy_static::lazy_static; // Importing the lazy_static macro
-use rumqttc::v5::AsyncClient;
 
-const MAX_BUFFER_SIZE: usize = 260; // Example buffer size for smart card communication.
+pub const MAX_BUFFER_SIZE: usize = 260; // Example buffer size for smart card communication.
+
+/// Maximum waiting of the monitor thread for the reader changes, the readers are listed again after it.
+const READER_WAIT_TIMEOUT_SECS: u64 = 5;
+/// State of the reader filtered out by the `readers.allow`/`readers.deny` settings, shown in the UI.
+const READER_IGNORED_STATE: &str = "IGNORED";
+/// Delay before the PC/SC context is established again after the failure.
+const CONTEXT_RETRY_SECS: u64 = 5;
+
+/// Active MQTT connection of a card.
+///
+/// The physical card is identified by its ICCID: when the OS renames the reader (e.g. after the replug),
+/// the card in the new reader is the same card, and its connection is moved to the new reader.
+/// The reader name is kept for the display only.
+pub struct ConnectionTask {
+    /// Client ID of the connection, the company card number.
+    pub client_id: String,
+    /// ICCID of the card, `None` if it couldn't be read.
+    pub iccid: Option<String>,
+    /// Reader the card is connected through.
+    pub reader_name: CString,
+    /// Client of the connection, to disconnect it gracefully.
+    pub mqtt_client: MqttClient,
+    /// Topic filter the card is subscribed to, `None` if the broker routes the requests without the subscription.
+    pub subscription: Option<String>,
+    /// The card uses the connection shared with the other cards (see the multiplex module).
+    pub shared: bool,
+    /// The task that runs the connection.
+    pub handle: JoinHandle<()>,
+    /// Requests waiting for the card, they are answered when the connection is removed.
+    pub waiting_requests: crate::mqtt::WaitingRequests,
+}
 
 lazy_static! {
     /// Global static vector to store active MQTT client connections and their associated tasks.
@@ -34,41 +82,94 @@ lazy_static! {
     /// safely among multiple tasks. Each task can clone the `Arc`, increasing the reference count,
     /// and decrement it when done, ensuring the memory is cleaned up when no longer in use.
     ///
-    /// The vector stores tuples of three elements:
-    /// - `String`: The client ID, a unique identifier for each MQTT client connection.
-    /// - `AsyncClient`: The MQTT client instance, which handles the actual communication with the MQTT broker.
-    /// - `JoinHandle<usize>`: A handle to the asynchronous task associated with this client. The task runs in the
-    ///    background, handling incoming MQTT messages and other asynchronous operations.
-    pub static ref TASK_POOL: Arc<Mutex<Vec<(String, AsyncClient, JoinHandle<()>)>>> = Arc::new(Mutex::new(Vec::new()));
+    /// The vector stores the connections of the cards (see `ConnectionTask`): the client ID, a unique identifier
+    /// for each MQTT client connection, and a handle to the asynchronous task which runs in the background,
+    /// handling incoming MQTT messages and other asynchronous operations.
+    pub static ref TASK_POOL: Arc<Mutex<Vec<ConnectionTask>>> = Arc::new(Mutex::new(Vec::new()));
+    /// Context the monitor thread waits for the reader changes with, to cancel the waiting (see `stop_monitor`).
+    static ref MONITOR_CONTEXT: std::sync::Mutex<Option<Context>> = std::sync::Mutex::new(None);
 }
 
-/// Represents the state of a tachograph card.
-///
-/// This structure holds information about a tachograph card currently being
-/// interacted with through a smart card reader.
+/// The monitor of the readers is stopped, the application is closing.
+static MONITOR_STOPPED: AtomicBool = AtomicBool::new(false);
+/// The restart of the PC/SC service has been noticed by a card connection (see `report_service_lost`).
+static SERVICE_LOST: AtomicBool = AtomicBool::new(false);
+
+/// Identification of a reader slot.
 ///
-/// # Fields
+/// Dual-slot readers are enumerated by PC/SC as two readers with nearly identical names.
+/// On PC/SC lite the name ends with the reader and slot indexes (e.g. "Reader [CCID] 00 01"),
+/// so the slot index is parsed from the name to distinguish the slots in the pool and in the UI.
 ///
-/// * `atr` - A string representing the Answer To Reset (ATR) of the card. The ATR is a sequence
-///   of bytes returned by the card upon reset, identifying the card's communication parameters.
-/// * `reader_name` - The name of the smart card reader through which the card is being accessed.
-/// * `card_state` - A string describing the current state of the card (e.g., "Inserted", "Removed").
-/// * `card_number` - The identification number of the tachograph card.
-#[derive(Clone, serde::Serialize)]
-pub struct TachoState {
-    pub atr: String,
-    pub reader_name: String,
-    pub card_state: String,
-    pub card_number: String,
-    pub online: Option<bool>,
-    pub authentication: Option<bool>,
+/// Limitation: the slot is taken from the name only. PC/SC has no portable attribute with the slot of the reader
+/// (the slot attributes need a connection to the reader and depend on the driver), and the Windows names have no
+/// slot index ("Reader 0", "Reader 1" are the instances of the reader), so there the slot is always 0. The slots
+/// are still separate readers, as the full name is a part of the identification, only their labels are the names.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct ReaderId {
+    pub name: String, // full PC/SC name of the reader
+    pub slot: u8,     // slot index within the physical reader
 }
 
+impl ReaderId {
+    pub fn from_name(name: &str) -> Self {
+        let slot = Self::split_slot(name).map(|(_, slot)| slot).unwrap_or(0);
+        ReaderId {
+            name: name.to_string(),
+            slot,
+        }
+    }
+
+    /// Splits the PC/SC lite reader name "<name> <reader index> <slot index>" into the base name and the slot index.
+    fn split_slot(name: &str) -> Option<(&str, u8)> {
+        let mut parts = name.rsplitn(3, ' ');
+        let slot = parts.next()?;
+        let index = parts.next()?;
+        let base = parts.next()?;
+        let is_index = |part: &str| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit());
+        if !is_index(slot) || !is_index(index) {
+            return None;
+        }
+        u8::from_str_radix(slot, 16).ok().map(|slot| (base, slot))
+    }
+
+    /// Human readable label of the reader slot for the UI: the alias of the reader if it is configured,
+    /// otherwise the label derived from the PC/SC name (see `slot_label`).
+    pub fn label(&self) -> String {
+        get_reader_alias(&self.name).unwrap_or_else(|| self.slot_label())
+    }
+
+    /// Label of the reader slot derived from the PC/SC name. The readers are configured by it or by the full name.
+    pub fn slot_label(&self) -> String {
+        match Self::split_slot(&self.name) {
+            Some((base, slot)) => format!("{} (slot {})", base, slot),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ReaderId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.label())
+    }
+}
+
+/// Updates the readers to wait on: the disconnected readers are removed and the connected ones are added.
+/// The readers filtered out by the configuration (see `is_reader_allowed`) are not monitored.
+///
+/// # Arguments
+///
+/// * `ignored` - The readers which are filtered out, to report only the newly filtered ones.
+///
+/// # Returns
+///
+/// * `Result<Vec<CString>, Box<dyn Error>>` - The readers which are filtered out since the previous call.
 fn setup_reader_states(
     ctx: &Context,
     readers_buf: &mut [u8],
     reader_states: &mut Vec<ReaderState>,
-) -> Result<(), Box<dyn Error>> {
+    ignored: &mut HashSet<CString>,
+) -> Result<Vec<CString>, Box<dyn Error>> {
     // Remove dead readers.
     fn is_dead(rs: &ReaderState) -> bool {
         rs.event_state().intersects(State::UNKNOWN | State::IGNORE)
@@ -90,7 +191,25 @@ fn setup_reader_states(
         }
     };
 
+    let names: Vec<&CStr> = names.collect();
+
+    // The settings may have been changed, so the monitored readers are filtered too
+    let readers_config = get_readers_config();
+    let is_allowed = |name: &CStr| name == PNP_NOTIFICATION() || is_reader_allowed(&readers_config, &name.to_string_lossy());
+    reader_states.retain(|rs| is_allowed(rs.name()));
+    // The reader connected again is reported again
+    ignored.retain(|name| names.contains(&name.as_c_str()));
+
+    let mut newly_ignored = Vec::new();
     for name in names {
+        if !is_allowed(name) {
+            if ignored.insert(name.to_owned()) {
+                log::info!("Reader {:?} is ignored by the configuration", name);
+                newly_ignored.push(name.to_owned());
+            }
+            continue;
+        }
+        ignored.remove(name);
         if !reader_states.iter().any(|rs| rs.name() == name) {
             log::info!("Reader {:?} has been connected to the computer", name);
             reader_states.push(ReaderState::new(name, State::UNAWARE));
@@ -102,78 +221,36 @@ fn setup_reader_states(
         rs.sync_current_state();
     }
 
-    Ok(())
+    Ok(newly_ignored)
 }
 
-async fn process_reader_states(
-    ctx: &Context,
-    reader_states: &mut [ReaderState],
-    reader_cards_pool: &mut Vec<(String, String, String)>,
-) -> Result<(), Box<dyn Error>> {
-    match ctx.get_status_change(None, reader_states) {
-        Ok(status) => status,
-        Err(e) => {
-            log::error!("Failed to get reader status change: {:?}", e);
-        }
-    }
-
-    for rs in reader_states {
-        if rs.name() != PNP_NOTIFICATION() {
-            // convert ATR to hex string value
-            let atr = hex::encode(rs.atr());
-            // Checking if card number is in the cache
-            let card_number = get_from_cache(CacheSection::Cards, &atr);
-            let card_number_clone = card_number.clone();
-
-            // convert reader name to string
-            let reader_name_string: &str = rs.name().to_str().unwrap(); // convert reader name(&CStr) to string
-            /*
-                This is a CRUTCH!!! Need to find a better way to convert card_state to string
-                The meaning of the card_state is in the pcsc module with the their own state enum.
-                The card_state is a bit mask and it is not clear how to convert it to a human readable string properly
-            */
-            let card_state_string = format!("{:?}", rs.event_state());
-
-            // If the card state has not 'CHANGED' state, then we skip the processing of this card
-            // Due to the specifics of the library, the map can be initialized in several stages,
-            // But we only need the final result with the value changed
-            if !card_state_string.contains("CHANGED") {
-                continue;
-            }
-
-            //  Trace status of the reader & card
-            log::info!(
-                "{:?} {:?} {:?}, {:?}",
-                rs.name(),
-                rs.event_state(),
-                atr,
-                card_number
-            );
-
-            // launches async task with a card and mqtt connection.
-            ensure_connection(rs.name(), card_number.clone(), atr.clone()).await;
-
-            // find cards that have been ejected and return as a vector
-            let readers_list = reader_cards_pool_update(
-                reader_cards_pool,
-                reader_name_string,
-                &card_state_string,
-                &card_number,
-            );
-            // check the inserted cards and their connections. If the card is removed, it deletes the task in which the mqtt connection is running.
-            remove_connections(readers_list).await;
-
-            // send an event to the frontend to update the state of the card
-            emit_event("global-cards-sync", atr.into(), reader_name_string.into(), card_state_string.into(), card_number_clone.into(), None, None);
-        };
-    }
+/// State of the reader reported by the monitor thread.
+struct ReaderSnapshot {
+    name: CString,
+    event_state: State,
+    atr: Vec<u8>,
+}
 
-    Ok(())
+/// Event of the monitor thread.
+enum MonitorEvent {
+    /// The states of the changed readers.
+    Changes(Vec<ReaderSnapshot>),
+    /// The readers which are filtered out by the configuration.
+    Ignored(Vec<CString>),
+    /// The PC/SC service has been stopped or restarted, the cards have to be connected again.
+    ServiceLost,
 }
 
-// Automatically sync cards
-pub async fn sc_monitor() -> ! {
-    loop {
+/// Waits for the changes of the readers on the monitor thread and sends them to `sc_monitor`.
+///
+/// The waiting for the changes blocks the thread, so it must not run on the async runtime. The waiting is
+/// interrupted by `stop_monitor` with the cancellation of the context, and by the timeout, so the readers
+/// connected to the computer are found also where the PnP notifications are not supported.
+///
+/// When the PC/SC service is stopped or restarted, `MonitorEvent::ServiceLost` is sent and the context is
+/// established again once the service is back, so all the readers are reported as changed with the new context.
+fn watch_readers(events: UnboundedSender<MonitorEvent>) {
+    while !MONITOR_STOPPED.load(Ordering::SeqCst) {
         let ctx = match Context::establish(Scope::User) {
             Ok(ctx) => ctx,
             Err(e) => {
@@ -181,10 +258,11 @@ pub async fn sc_monitor() -> ! {
                     "Failed to establish context: {:?}. Try to reinit in a 5 seconds.",
                     e
                 );
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                std::thread::sleep(std::time::Duration::from_secs(CONTEXT_RETRY_SECS));
                 continue;
             }
         };
+        *MONITOR_CONTEXT.lock().unwrap() = Some(ctx.clone());
 
         let mut readers_buf = [0; 2048];
         let mut reader_states = vec![
@@ -192,98 +270,309 @@ pub async fn sc_monitor() -> ! {
             ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
         ];
 
-        // Vector that stores the connected states of the reader + card (so that it would be possible to understand that the card has been removed)
-        let mut reader_cards_pool = Vec::new();
-
-        loop {
-            if let Err(e) = setup_reader_states(&ctx, &mut readers_buf, &mut reader_states) {
-                log::error!("Failed to setup_reader_states: {:?}", e);
-                break; // Exit the inner loop to re-establish context
+        let mut ignored = HashSet::new();
+        let mut service_lost = false;
+        while !MONITOR_STOPPED.load(Ordering::SeqCst) {
+            // The restart noticed by a card connection, the handle of this context is most likely invalid too
+            if SERVICE_LOST.swap(false, Ordering::SeqCst) {
+                service_lost = true;
+                break;
             }
-            if let Err(e) =
-                process_reader_states(&ctx, &mut reader_states, &mut reader_cards_pool).await
-            {
-                log::error!("Failed to process reader states: {:?}", e);
-                break; // Exit the inner loop to re-establish context
+            match setup_reader_states(&ctx, &mut readers_buf, &mut reader_states, &mut ignored) {
+                Ok(newly_ignored) => {
+                    if !newly_ignored.is_empty() && events.send(MonitorEvent::Ignored(newly_ignored)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to setup_reader_states: {:?}", e);
+                    service_lost = e.downcast_ref::<pcsc::Error>().map_or(false, |e| is_service_lost(*e));
+                    break; // Exit the inner loop to re-establish context
+                }
             }
             log::debug!("Waiting for the next status change...");
-            tokio::task::yield_now().await;
+            match ctx.get_status_change(std::time::Duration::from_secs(READER_WAIT_TIMEOUT_SECS), &mut reader_states) {
+                Ok(()) => {}
+                // The stop and the lost service are checked by the loop
+                Err(pcsc::Error::Timeout) | Err(pcsc::Error::Cancelled) => continue,
+                Err(e) => {
+                    log::error!("Failed to get reader status change: {:?}", e);
+                    service_lost = is_service_lost(e);
+                    break; // Exit the inner loop to re-establish context
+                }
+            }
+
+            // If the card state has not 'CHANGED' state, then we skip the processing of this card
+            // Due to the specifics of the library, the map can be initialized in several stages,
+            // But we only need the final result with the value changed
+            let snapshots: Vec<ReaderSnapshot> = reader_states
+                .iter()
+                .filter(|rs| rs.name() != PNP_NOTIFICATION() && rs.event_state().contains(State::CHANGED))
+                .map(|rs| ReaderSnapshot {
+                    name: rs.name().to_owned(),
+                    event_state: rs.event_state(),
+                    atr: rs.atr().to_vec(),
+                })
+                .collect();
+            if !snapshots.is_empty() && events.send(MonitorEvent::Changes(snapshots)).is_err() {
+                // The monitor is gone, nobody needs the changes
+                return;
+            }
         }
 
-        log::debug!("Re-establishing context...");
+        *MONITOR_CONTEXT.lock().unwrap() = None;
+        if service_lost && events.send(MonitorEvent::ServiceLost).is_err() {
+            return;
+        }
+        if !MONITOR_STOPPED.load(Ordering::SeqCst) {
+            log::debug!("Re-establishing context...");
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
     }
+    log::info!("The reader monitor thread is stopped");
 }
 
-pub fn reader_cards_pool_update(
-    reader_cards_pool: &mut Vec<(String, String, String)>,
-    reader_name: &str,
-    card_state: &str,
-    card_number: &str,
-) -> Vec<String> {
-    let mut company_card_numbers = Vec::new();
+/// Stops the monitor of the readers: the waiting for the changes is cancelled and the PC/SC context is released.
+pub fn stop_monitor() {
+    MONITOR_STOPPED.store(true, Ordering::SeqCst);
+    if let Some(ctx) = MONITOR_CONTEXT.lock().unwrap().as_ref() {
+        if let Err(e) = ctx.cancel() {
+            log::debug!("Failed to cancel the waiting for the reader changes: {:?}", e);
+        }
+    }
+}
+
+/// Processes the stable state of the reader: connects the inserted card and disconnects the removed one.
+async fn apply_reader_state(reader_name: &CStr, atr: &[u8], card_state_string: String) {
+    // convert ATR to hex string value
+    let atr = hex::encode(atr);
+    // Checking if card number is in the cache
+    let paired = get_card_by_atr(&atr);
+    let card_number = paired.as_ref().map(|(card_number, _)| card_number.clone()).unwrap_or_default();
+    let card_number_clone = card_number.clone();
 
-    println!(
-        "Updating reader cards pool. Reader name: '{}', Card state: '{}', Card number: '{}'",
-        reader_name, card_state, card_number
+    // convert reader name to string
+    let reader_name_string = reader_name.to_string_lossy().to_string();
+    let reader_id = ReaderId::from_name(&reader_name_string);
+
+    // The model of the card is identified by the ATR
+    let card_model = find_known_card(&atr).map(|known| known.model());
+
+    //  Trace status of the reader & card
+    log::info!(
+        "{} {} {:?}, {:?}, {:?}",
+        reader_id,
+        card_state_string,
+        atr,
+        card_number,
+        card_model
     );
 
-    if !reader_name.is_empty() && !card_number.is_empty() {
-        let exists = reader_cards_pool
-            .iter()
-            .any(|(reader, _, _)| reader == reader_name);
-        if !exists {
-            println!(
-                "Reader '{}' does not exist in the pool. Adding new entry.",
-                reader_name
-            );
-            reader_cards_pool.push((
-                reader_name.to_string(),
-                card_state.to_string(),
-                card_number.to_string(),
-            ));
-        }
-    } else if !reader_name.is_empty() && card_number.is_empty() {
-        let entries_to_remove: Vec<_> = reader_cards_pool
-            .iter()
-            .enumerate()
-            .filter(|(_, (reader, _, _))| reader == reader_name)
-            .map(|(i, (_, _, card))| {
-                company_card_numbers.push(card.clone());
-                i
-            })
-            .collect();
-
-        if !entries_to_remove.is_empty() {
-            println!(
-                "Removing {} entries for reader '{}'.",
-                entries_to_remove.len(),
-                reader_name
-            );
-            for i in entries_to_remove.into_iter().rev() {
-                reader_cards_pool.remove(i);
-            }
-        }
+    // The unpaired card which doesn't look like a tachograph card is most likely inserted by mistake
+    if card_number.is_empty() {
+        warn_if_unknown_card(&atr, &reader_id.label());
+    }
+
+    // find cards that have been ejected (or replaced, or moved to another reader) and return as a vector
+    let removed_cards = update_reader(&reader_id, &card_state_string, &card_number);
+    // If the card is removed, it deletes the task in which the mqtt connection is running.
+    // This is done before the new connection is ensured, so the card moved to another reader is connected again.
+    remove_connections(removed_cards).await;
+
+    // The identification of the removed card is not shown anymore, the inserted card is read again
+    crate::card_identification::forget(&reader_name_string);
+    // The card is initialized in its own task, then it is connected by the monitor (see `card_init`).
+    card_init::start(reader_name, card_number.clone(), atr.clone());
+
+    // The reader which is disconnected from the computer is reported in the unknown or ignored state
+    let reason = if card_state_string == READER_IGNORED_STATE {
+        Some(StateReason::ReaderIgnored)
     } else {
-        println!(
-            "Reader name is empty or both reader name and card number are empty. No action taken."
-        );
+        (card_state_string.contains("UNKNOWN") || card_state_string.contains("IGNORE")).then_some(StateReason::ReaderRemoved)
+    };
+    // The card paused or disabled by the user is shown with the reason it doesn't serve the requests
+    let (reason, detail) = match reason {
+        Some(reason) => (Some(reason), None),
+        None => crate::mqtt::availability_reason(paired.as_ref().map(|(_, card_config)| card_config)),
+    };
+
+    // send an event to the frontend to update the state of the card
+    if let Err(e) = emit_card_state(CardStatePayload {
+        atr,
+        reader_name: reader_name_string.clone(),
+        reader_label: reader_id.label(),
+        card_state: card_state_string,
+        card_number: card_number_clone,
+        online: None,
+        authentication: None,
+        updated_at: Timestamp::now(),
+        reason,
+        detail,
+        card_type: None,
+        card_model,
+        card_generation: None,
+    }) {
+        log::warn!("Failed to emit card state for the reader {}: {}", reader_id, e);
     }
+}
 
-    println!("Final state of reader_cards_pool: {:?}", reader_cards_pool);
+/// Resyncs the reader the same way as the manual sync: the card is removed from the pool with its connection,
+/// then the state of the reader is read again, so the inserted card is connected from scratch.
+pub async fn resync_reader(reader_name: &CStr) {
+    let reader_id = ReaderId::from_name(&reader_name.to_string_lossy());
+    log::info!("Resyncing the reader {}", reader_id);
+    let removed_cards = update_reader(&reader_id, "RESYNC", "");
+    remove_connections(removed_cards).await;
 
-    company_card_numbers
+    // The error is converted before the await, as the boxed error can't be held across it
+    let state = read_reader_state(reader_name).map_err(|e| e.to_string());
+    match state {
+        Ok((atr, card_state_string)) => apply_reader_state(reader_name, &atr, card_state_string).await,
+        Err(e) => log::error!("Failed to read the state of the reader {}: {}", reader_id, e),
+    }
+}
+
+/// Reads the current ATR and state of the reader.
+fn read_reader_state(reader_name: &CStr) -> Result<(Vec<u8>, String), Box<dyn Error>> {
+    if is_simulated_reader(&reader_name.to_string_lossy()) {
+        return Ok((decode(SIMULATED_ATR)?, format!("{:?}", State::CHANGED | State::PRESENT)));
+    }
+    let ctx = Context::establish(Scope::User)?;
+    let mut reader_states = [ReaderState::new(reader_name.to_owned(), State::UNAWARE)];
+    ctx.get_status_change(std::time::Duration::from_secs(1), &mut reader_states)?;
+    let rs = &reader_states[0];
+    Ok((rs.atr().to_vec(), format!("{:?}", rs.event_state())))
+}
+
+// Automatically sync cards
+//
+// The changes of the readers come from the monitor thread (see `watch_readers`), the monitor waits for them
+// without blocking the runtime and wakes up only when the debounced changes or the initialized cards are due.
+pub async fn sc_monitor() {
+    let (sender, mut events) = unbounded_channel();
+    if crate::simulated_card::is_enabled() {
+        // The simulated card is inserted into the simulated reader at the start
+        let snapshot = ReaderSnapshot {
+            name: CString::new(SIMULATED_READER_NAME).expect("The name of the simulated reader has no NUL"),
+            event_state: State::CHANGED | State::PRESENT,
+            atr: decode(SIMULATED_ATR).unwrap_or_default(),
+        };
+        let _ = sender.send(MonitorEvent::Changes(vec![snapshot]));
+    }
+    if let Err(e) = std::thread::Builder::new()
+        .name("reader-monitor".to_string())
+        .spawn(move || watch_readers(sender))
+    {
+        log::error!("Failed to start the reader monitor thread: {}", e);
+        return;
+    }
+
+    // The latest states of the readers, by the reader name
+    let mut readers: HashMap<String, ReaderSnapshot> = HashMap::new();
+    let mut debouncer = ReaderDebouncer::default();
+    loop {
+        let config = get_reader_debounce_config();
+        // The waiting is interrupted when the pending change of a reader has to be processed
+        let mut timeout = debouncer.next_deadline(Instant::now(), &config);
+        // and when the results of the card initializations are expected
+        if card_init::in_progress() {
+            let poll = std::time::Duration::from_millis(card_init::CARD_INIT_POLL_MS);
+            timeout = Some(timeout.map_or(poll, |timeout| timeout.min(poll)));
+        }
+        card_init::connect_initialized_cards().await;
+
+        let received = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, events.recv()).await.ok(),
+            None => Some(events.recv().await),
+        };
+        let now = Instant::now();
+        match received {
+            Some(Some(MonitorEvent::Changes(snapshots))) => {
+                for snapshot in snapshots {
+                    let reader_name = snapshot.name.to_string_lossy().to_string();
+                    let removal = snapshot.event_state.contains(State::EMPTY);
+                    if debouncer.record_change(&reader_name, removal, now, &config) {
+                        let reader_label = ReaderId::from_name(&reader_name).label();
+                        log::warn!("Reader {} is flapping, it is paused for {} seconds", reader_label, config.pause_secs);
+                        emit_notification(
+                            "warning",
+                            &format!(
+                                "The reader {} is connected and disconnected too often. Check the USB cable of the reader. \
+                                 The reader is paused for {} minutes.",
+                                reader_label,
+                                config.pause_secs / 60
+                            ),
+                        );
+                    }
+                    readers.insert(reader_name, snapshot);
+                }
+            }
+            Some(Some(MonitorEvent::Ignored(reader_names))) => {
+                // The card in the reader is disconnected and the reader is shown as ignored
+                for reader_name in reader_names {
+                    readers.remove(reader_name.to_string_lossy().as_ref());
+                    apply_reader_state(&reader_name, &[], READER_IGNORED_STATE.to_string()).await;
+                }
+                continue;
+            }
+            Some(Some(MonitorEvent::ServiceLost)) => {
+                log::warn!("The PC/SC service has been stopped or restarted, the cards are connected again when it is back");
+                emit_notification(
+                    "warning",
+                    "The smart card service of the computer has been restarted. The cards are connected again when it is back.",
+                );
+                // The readers are processed as disconnected, so their cards are connected from scratch
+                // when the new context reports them
+                let reader_names: Vec<CString> = readers.drain().map(|(_, snapshot)| snapshot.name).collect();
+                debouncer = ReaderDebouncer::default();
+                for reader_name in reader_names {
+                    apply_reader_state(&reader_name, &[], format!("{:?}", State::CHANGED | State::UNKNOWN)).await;
+                }
+                // The connections whose cards are not in the known readers hold the dead card handles too
+                let cards: Vec<String> = TASK_POOL.lock().await.iter().map(|task| task.client_id.clone()).collect();
+                remove_connections(cards).await;
+                continue;
+            }
+            Some(None) => {
+                log::info!("The reader monitor is stopped");
+                return;
+            }
+            // The timeout, the pending changes are processed below
+            None => {}
+        }
+
+        for reader_name in debouncer.take_stable(now, &config) {
+            match readers.get(&reader_name) {
+                Some(snapshot) => {
+                    let card_state_string = format!("{:?}", snapshot.event_state);
+                    apply_reader_state(&snapshot.name, &snapshot.atr, card_state_string).await;
+                    // The reader disconnected from the computer is not reported anymore
+                    if snapshot.event_state.intersects(State::UNKNOWN | State::IGNORE) {
+                        readers.remove(&reader_name);
+                    }
+                }
+                None => match CString::new(reader_name) {
+                    Ok(name) => apply_reader_state(&name, &[], format!("{:?}", State::CHANGED | State::UNKNOWN)).await,
+                    Err(e) => log::error!("Invalid reader name: {}", e),
+                },
+            }
+        }
+    }
 }
 
 pub fn send_apdu_to_card_command(card: &Card, apdu_hex: &str) -> Result<String, Box<dyn Error>> {
+    crate::fault_injection::before_apdu(card);
+
     // Convert HEX string to bytes
     let apdu =
         decode(apdu_hex).map_err(|err| format!("Failed to decode tracker's APDU HEX: {}", err))?;
 
     println!("Sending APDU: {:?}", apdu);
     let mut rapdu_buf = [0; MAX_BUFFER_SIZE];
+    // The PC/SC error is kept as is, so the caller can find out why the transmission failed (e.g. the card is removed)
     let rapdu = card.transmit(&apdu, &mut rapdu_buf).map_err(|err| {
         log::error!("Failed to transmit APDU command to card: {}", err);
-        format!("Failed to transmit APDU command to card: {}", err)
+        Box::new(err) as Box<dyn Error>
     })?;
 
     // Decoding response from binary array to HEX string
@@ -293,25 +582,524 @@ pub fn send_apdu_to_card_command(card: &Card, apdu_hex: &str) -> Result<String,
     Ok(rapdu_hex)
 }
 
-pub fn create_card_object(reader_name: &CStr) -> Result<Card, Box<dyn StdError>> {
+impl crate::protocol::ApduTransport for Card {
+    type Error = Box<dyn Error>;
+
+    fn transmit_hex(&self, apdu_hex: &str) -> Result<String, Self::Error> {
+        send_apdu_to_card_command(self, apdu_hex)
+    }
+}
+
+/// Card the `ManagedCard` is connected to: the card in the PC/SC reader or the simulated one (see `simulated_card`).
+enum CardHandle {
+    Pcsc(Card),
+    Simulated(SimulatedCard),
+}
+
+impl crate::protocol::ApduTransport for CardHandle {
+    type Error = Box<dyn Error>;
+
+    fn transmit_hex(&self, apdu_hex: &str) -> Result<String, Self::Error> {
+        match self {
+            CardHandle::Pcsc(card) => send_apdu_to_card_command(card, apdu_hex),
+            CardHandle::Simulated(card) => card.transmit_hex(apdu_hex),
+        }
+    }
+}
+
+/// Checks if the PC/SC error means that the PC/SC service (pcscd or the Windows Smart Card service) is stopped
+/// or has been restarted. The handles of the contexts and the cards established before the restart are invalid then.
+fn is_service_lost(err: pcsc::Error) -> bool {
+    matches!(
+        err,
+        pcsc::Error::NoService | pcsc::Error::ServiceStopped | pcsc::Error::InvalidHandle
+    )
+}
+
+/// Checks if the error returned by the card operations means that the PC/SC service has been stopped or restarted,
+/// so the card has to be connected again (see `report_service_lost`).
+pub fn is_service_error(err: &(dyn StdError + 'static)) -> bool {
+    err.downcast_ref::<pcsc::Error>().map_or(false, |err| is_service_lost(*err))
+}
+
+/// Reports the PC/SC service restart noticed by the card connection: the monitor tears down the card connections
+/// and connects the cards again with the new context, the same as when it notices the restart itself.
+pub fn report_service_lost() {
+    SERVICE_LOST.store(true, Ordering::SeqCst);
+    if let Some(ctx) = MONITOR_CONTEXT.lock().unwrap().as_ref() {
+        // The handle of the context may be invalid as well, then the waiting fails with the error anyway
+        let _ = ctx.cancel();
+    }
+}
+
+/// Checks if the error returned by the card operations means that the card has been reset by another application
+/// sharing it (SCARD_W_RESET_CARD). The card is still in the reader, the connection has to be reconnected.
+pub fn is_card_reset_error(err: &(dyn StdError + 'static)) -> bool {
+    matches!(err.downcast_ref::<pcsc::Error>(), Some(pcsc::Error::ResetCard))
+}
+
+/// Checks if the error returned by the card operations means that there is no card in the reader.
+pub fn is_card_absent_error(err: &(dyn StdError + 'static)) -> bool {
+    matches!(
+        err.downcast_ref::<pcsc::Error>(),
+        Some(pcsc::Error::RemovedCard) | Some(pcsc::Error::NoSmartcard)
+    )
+}
+
+/// APDU commands to read the EF ICC file (card identification) of the tachograph card:
+/// select the MF, select the EF ICC (FID 0002) and read its 25 bytes.
+const SELECT_MF_APDU: &str = "00a4000c023f00";
+const SELECT_EF_ICC_APDU: &str = "00a4020c020002";
+const READ_EF_ICC_APDU: &str = "00b0000019";
+/// Length of the EF ICC file content in bytes.
+const EF_ICC_LENGTH: usize = 25;
+/// Select the tachograph application (DF Tachograph) by its name "TACHO".
+const SELECT_DF_TACHOGRAPH_APDU: &str = "00a4040c06ff544143484f";
+/// Select the application of the second generation (DF Tachograph_G2) by its name "SMRDT".
+/// The Gen2 cards have both applications, the Gen1 cards only DF Tachograph.
+const SELECT_DF_TACHOGRAPH_G2_APDU: &str = "00a4040c06ff534d524454";
+/// Select EF Application_Identification (FID 0501) and read its first byte, the type of the card.
+const SELECT_EF_APPLICATION_ID_APDU: &str = "00a4020c020501";
+const READ_CARD_TYPE_APDU: &str = "00b0000001";
+
+lazy_static! {
+    /// Cards (by the card number) whose ICCID has to be re-read before it is used next time.
+    static ref ICCID_REFRESH_REQUESTS: std::sync::Mutex<HashSet<String>> = std::sync::Mutex::new(HashSet::new());
+    /// ICCIDs read from the cards since the start, by the card number.
+    static ref KNOWN_ICCIDS: std::sync::Mutex<HashMap<String, String>> = std::sync::Mutex::new(HashMap::new());
+    /// ICCIDs of the inserted cards which are not paired, by the ATR. The pairing checks that the card
+    /// is not paired with another number (see `config::update_card_config`).
+    static ref UNPAIRED_ICCIDS: std::sync::Mutex<HashMap<String, String>> = std::sync::Mutex::new(HashMap::new());
+}
+
+/// Card connection with the lazily read ICCID (content of the EF ICC file in hex) and type of the card.
+///
+/// The ICCID and the type are read on the first use and cached only if they are valid, so a failed read is retried next time.
+/// The cache lives as long as the card connection, a new card in the reader always gets a new `ManagedCard`.
+///
+/// The idle card can be powered off (see `power_off`), then it must be connected again with `create_card`
+/// before it is used.
+pub struct ManagedCard {
+    card: Option<CardHandle>,
+    iccid: OnceCell<String>,
+    card_type: OnceCell<TachographCardType>,
+    card_generation: OnceCell<CardGeneration>,
+    /// Protocols the card is connected with, also when it is reconnected.
+    protocols: Protocols,
+    /// Protocol negotiated with the card, the card reset by another application is reconnected with it.
+    negotiated_protocols: Protocols,
+    /// Share mode of the connection, it is kept when the card reset by another application is reconnected.
+    share_mode: ShareMode,
+}
+
+impl ManagedCard {
+    pub fn new(card: Card, protocols: Protocols) -> Self {
+        ManagedCard::with_handle(CardHandle::Pcsc(card), protocols)
+    }
+
+    fn with_handle(card: CardHandle, protocols: Protocols) -> Self {
+        let negotiated_protocols = match &card {
+            CardHandle::Pcsc(card) => negotiated_protocols(card).unwrap_or(protocols),
+            CardHandle::Simulated(_) => protocols,
+        };
+        ManagedCard {
+            card: Some(card),
+            iccid: OnceCell::new(),
+            card_type: OnceCell::new(),
+            card_generation: OnceCell::new(),
+            protocols,
+            negotiated_protocols,
+            share_mode: ShareMode::Shared,
+        }
+    }
+
+    /// Returns the connection to the card, the card which is powered off (see `power_off`) fails with `UnpoweredCard`.
+    fn card(&self) -> Result<&CardHandle, pcsc::Error> {
+        self.card.as_ref().ok_or(pcsc::Error::UnpoweredCard)
+    }
+
+    fn card_mut(&mut self) -> Result<&mut CardHandle, pcsc::Error> {
+        self.card.as_mut().ok_or(pcsc::Error::UnpoweredCard)
+    }
+
+    /// Checks if the card is connected and powered.
+    pub fn is_powered(&self) -> bool {
+        self.card.is_some()
+    }
+
+    /// Disconnects from the card and powers it off, if no other application uses it.
+    pub fn power_off(&mut self) -> Result<(), pcsc::Error> {
+        match self.card.take() {
+            Some(CardHandle::Pcsc(card)) => card.disconnect(Disposition::UnpowerCard).map_err(|(card, e)| {
+                self.card = Some(CardHandle::Pcsc(card));
+                e
+            }),
+            Some(CardHandle::Simulated(_)) | None => Ok(()),
+        }
+    }
+
+    /// Connects to the card in the reader in the shared mode, with the protocol override of the card if it is set.
+    /// The card is opened exclusively only for the authentication session (see `set_share_mode`).
+    pub fn create_card(reader_name: &CStr, cardnumber: &str) -> Result<Self, Box<dyn StdError>> {
+        if is_simulated_reader(&reader_name.to_string_lossy()) {
+            log::debug!("Connecting to the simulated card");
+            return Ok(ManagedCard::with_handle(CardHandle::Simulated(SimulatedCard::default()), Protocols::ANY));
+        }
+        let force_protocol = get_card_force_protocol(cardnumber);
+        let protocols = force_protocol.map(force_protocols).unwrap_or(Protocols::ANY);
+        let card = create_card_object(reader_name, protocols)?;
+        if let Some(force_protocol) = force_protocol {
+            log_protocol_override(&card, cardnumber, force_protocol);
+        }
+        Ok(ManagedCard::new(card, protocols))
+    }
+
+    /// Reconnects to the card with the other share mode without resetting it.
+    /// Fails with `SharingViolation` if the exclusive access is requested while the card is used by another application.
+    pub fn set_share_mode(&mut self, share_mode: ShareMode) -> Result<(), pcsc::Error> {
+        self.reconnect(share_mode, Disposition::LeaveCard)
+    }
+
+    /// Returns the cached ICCID or reads it from the card.
+    pub fn iccid(&self) -> Result<&str, Box<dyn Error>> {
+        self.iccid
+            .get_or_try_init(|| read_iccid(self.card()?))
+            .map(|iccid| iccid.as_str())
+    }
+
+    /// Returns the ICCID if it has already been read.
+    pub fn cached_iccid(&self) -> Option<&str> {
+        self.iccid.get().map(|iccid| iccid.as_str())
+    }
+
+    /// Returns the cached type of the card or detects it (see `detect_card_type`).
+    pub fn card_type(&self) -> Result<TachographCardType, Box<dyn Error>> {
+        self.card_type.get_or_try_init(|| detect_card_type(self.card()?)).copied()
+    }
+
+    /// Returns the type of the card if it has already been detected.
+    pub fn cached_card_type(&self) -> Option<TachographCardType> {
+        self.card_type.get().copied()
+    }
+
+    /// Returns the cached generation of the card or detects it (see `detect_card_generation`).
+    pub fn card_generation(&self) -> Result<CardGeneration, Box<dyn Error>> {
+        self.card_generation.get_or_try_init(|| detect_card_generation(self.card()?)).copied()
+    }
+
+    /// Returns the generation of the card if it has already been detected.
+    pub fn cached_card_generation(&self) -> Option<CardGeneration> {
+        self.card_generation.get().copied()
+    }
+
+    /// Reconnects to the card with its protocols. The ICCID is kept, as it is the same card.
+    pub fn reconnect(&mut self, share_mode: ShareMode, disposition: Disposition) -> Result<(), pcsc::Error> {
+        let protocols = self.protocols;
+        match self.card_mut()? {
+            CardHandle::Pcsc(card) => card.reconnect(share_mode, protocols, disposition)?,
+            CardHandle::Simulated(_) => {}
+        }
+        self.share_mode = share_mode;
+        Ok(())
+    }
+
+    /// Reconnects to the card reset by another application with the negotiated protocol and the same share mode.
+    /// The card is left as is, it has just been reset anyway.
+    fn recover_from_reset(&mut self) -> Result<(), pcsc::Error> {
+        let share_mode = self.share_mode;
+        let protocols = self.negotiated_protocols;
+        match self.card_mut()? {
+            CardHandle::Pcsc(card) => card.reconnect(share_mode, protocols, Disposition::LeaveCard),
+            CardHandle::Simulated(_) => Ok(()),
+        }
+    }
+
+    /// Sends the command of the server request to the card and returns the response (see `protocol::request_rapdu`).
+    ///
+    /// The exchange holds the PC/SC transaction, so the other applications on the computer (e.g. the card managers
+    /// of the vendors) can't send their commands in the middle of it, e.g. between the command and its GET RESPONSE.
+    /// The transaction is released when the response is received, so the card is not locked between the requests.
+    /// The commands are recorded into the APDU trace of the card (see `apdu_trace`).
+    ///
+    /// If another application sharing the card has reset it, the command fails with SCARD_W_RESET_CARD: the card is
+    /// reconnected and the command is sent once again, so the server doesn't get the error for the card which is fine.
+    pub fn exchange(&mut self, payload: &str, atr: &str, cardnumber: &str) -> Result<String, Box<dyn Error>> {
+        if payload.is_empty() {
+            return Ok(atr.to_string());
+        }
+        match self.transmit_request(payload, atr, cardnumber) {
+            Err(err) if is_card_reset_error(err.as_ref()) => {
+                log::warn!("{} | The card has been reset by another application, reconnecting", cardnumber);
+                self.recover_from_reset().map_err(|err| {
+                    log::error!("{} | Failed to reconnect the reset card: {}", cardnumber, err);
+                    Box::new(err) as Box<dyn Error>
+                })?;
+                self.transmit_request(payload, atr, cardnumber)
+            }
+            result => result,
+        }
+    }
+
+    /// Sends the command to the card within the transaction (see `exchange`).
+    fn transmit_request(&mut self, payload: &str, atr: &str, cardnumber: &str) -> Result<String, Box<dyn Error>> {
+        let card = match self.card_mut()? {
+            CardHandle::Pcsc(card) => card,
+            CardHandle::Simulated(card) => {
+                return crate::protocol::request_rapdu(payload, atr, &TracedCard::new(&*card, cardnumber))
+            }
+        };
+        let transaction = card.transaction().map_err(|err| {
+            log::error!("Failed to begin the transaction with the card: {}", err);
+            Box::new(err) as Box<dyn Error>
+        })?;
+        // The transaction is ended when it is dropped, the card is left as is
+        crate::protocol::request_rapdu(payload, atr, &TracedCard::new(&*transaction, cardnumber))
+    }
+
+    /// Reads the identification of the card from the chip (see `card_identification`).
+    pub fn identification(&self) -> Result<crate::card_identification::CardIdentification, String> {
+        crate::card_identification::read(self.card().map_err(|e| e.to_string())?)
+    }
+
+    /// Reads the certificates of the card and checks them (see `card_certificates`).
+    pub fn check_certificates(&self, cardnumber: &str) -> Result<crate::card_certificates::CertificateCheck, String> {
+        let generation = self.card_generation().map_err(|e| e.to_string())?;
+        crate::card_certificates::check(self.card().map_err(|e| e.to_string())?, generation, cardnumber)
+    }
+
+    /// Clears the cached ICCID and reads it from the card again.
+    pub fn refresh_iccid(&mut self) -> Result<&str, Box<dyn Error>> {
+        self.iccid = OnceCell::new();
+        self.iccid()
+    }
+}
+
+/// Reads the EF ICC file and checks that the content looks like a real card identification.
+fn read_iccid<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> Result<String, Box<dyn Error>> {
+    for apdu in [SELECT_MF_APDU, SELECT_EF_ICC_APDU] {
+        let response = card.transmit_hex(apdu)?;
+        if !response.ends_with("9000") {
+            return Err(format!("EF ICC selection failed with the status {}", response).into());
+        }
+    }
+
+    let response = card.transmit_hex(READ_EF_ICC_APDU)?;
+    let data = match response.strip_suffix("9000") {
+        Some(data) => data,
+        None => return Err(format!("EF ICC reading failed with the status {}", response).into()),
+    };
+    if data.len() != EF_ICC_LENGTH * 2 {
+        return Err(format!("EF ICC has unexpected length: {} bytes", data.len() / 2).into());
+    }
+    if data.chars().all(|c| c == '0') || data.chars().all(|c| c == 'f') {
+        return Err("EF ICC content is empty".into());
+    }
+
+    Ok(data.to_string())
+}
+
+/// Detects the type of the tachograph card: selects the tachograph application and reads the equipment type
+/// from EF Application_Identification. The tachograph application is left selected.
+pub fn detect_card_type<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> Result<TachographCardType, Box<dyn Error>> {
+    for apdu in [SELECT_DF_TACHOGRAPH_APDU, SELECT_EF_APPLICATION_ID_APDU] {
+        let response = card.transmit_hex(apdu)?;
+        if !response.ends_with("9000") {
+            return Err(format!("EF Application_Identification selection failed with the status {}", response).into());
+        }
+    }
+
+    let response = card.transmit_hex(READ_CARD_TYPE_APDU)?;
+    let data = match response.strip_suffix("9000") {
+        Some(data) => decode(data)?,
+        None => return Err(format!("EF Application_Identification reading failed with the status {}", response).into()),
+    };
+    let card_type = data.first().copied().ok_or("EF Application_Identification is empty")?;
+    TachographCardType::from_byte(card_type).ok_or_else(|| format!("Unknown type of the card: {}", card_type).into())
+}
+
+/// Detects the generation of the tachograph card by its applications: only the Gen2 cards have DF Tachograph_G2.
+/// The ATR only tells the generation of the cards in the table of the known ATRs (see `known_cards`).
+pub fn detect_card_generation<T: ApduTransport<Error = Box<dyn Error>>>(card: &T) -> Result<CardGeneration, Box<dyn Error>> {
+    if card.transmit_hex(SELECT_DF_TACHOGRAPH_G2_APDU)?.ends_with("9000") {
+        return Ok(CardGeneration::Gen2);
+    }
+    let response = card.transmit_hex(SELECT_DF_TACHOGRAPH_APDU)?;
+    if response.ends_with("9000") {
+        Ok(CardGeneration::Gen1)
+    } else {
+        Err(format!("DF Tachograph selection failed with the status {}", response).into())
+    }
+}
+
+/// Remembers the ICCID read from the card, so the card can be found by it (see `card_lookup`).
+pub fn remember_iccid(cardnumber: &str, iccid: &str) {
+    KNOWN_ICCIDS.lock().unwrap().insert(cardnumber.to_string(), iccid.to_string());
+}
+
+/// Remembers the ICCID read from the unpaired card, for its pairing.
+pub fn remember_unpaired_iccid(atr: &str, iccid: &str) {
+    UNPAIRED_ICCIDS.lock().unwrap().insert(atr.to_lowercase(), iccid.to_string());
+}
+
+/// Returns the ICCID of the unpaired card with the ATR, `None` if it has not been read.
+pub fn unpaired_iccid(atr: &str) -> Option<String> {
+    UNPAIRED_ICCIDS.lock().unwrap().get(&atr.to_lowercase()).cloned()
+}
+
+/// Returns the last ICCID read from the card, `None` if it has not been read since the start.
+pub fn known_iccid(cardnumber: &str) -> Option<String> {
+    KNOWN_ICCIDS.lock().unwrap().get(cardnumber).cloned()
+}
+
+/// Returns the ICCIDs read from the cards since the start, by the card number.
+pub fn known_iccids() -> HashMap<String, String> {
+    KNOWN_ICCIDS.lock().unwrap().clone()
+}
+
+/// Returns the number of the card with the ICCID, `None` if there is no such card among the read ones
+/// and the paired ones (see `config::get_card_by_iccid`).
+pub fn find_card_by_iccid(iccid: &str) -> Option<String> {
+    KNOWN_ICCIDS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, known)| known.eq_ignore_ascii_case(iccid))
+        .map(|(cardnumber, _)| cardnumber.clone())
+        .or_else(|| crate::config::get_card_by_iccid(iccid).map(|(cardnumber, _)| cardnumber))
+}
+
+/// Takes the request to re-read the ICCID of the card (see `refresh_iccid`).
+///
+/// # Returns
+///
+/// * `bool` - `true` if the ICCID has been requested to be re-read since the last call.
+pub fn take_iccid_refresh_request(cardnumber: &str) -> bool {
+    ICCID_REFRESH_REQUESTS.lock().unwrap().remove(cardnumber)
+}
+
+/// Public function to re-read the ICCID of the card.
+/// This function is a Tauri command that is called from the frontend.
+/// The ICCID is re-read by the connection of the card before it is used next time.
+///
+/// # Arguments
+///
+/// * `cardnumber` - The company card number.
+///
+/// # Returns
+///
+/// * `bool` - Returns `true` if the card has an active connection, otherwise `false`.
+#[tauri::command]
+pub async fn refresh_iccid(cardnumber: String) -> bool {
+    let connected = TASK_POOL.lock().await.iter().any(|task| task.client_id == cardnumber);
+    if !connected {
+        log::warn!("ICCID refresh is requested for the card {} without connection", cardnumber);
+        return false;
+    }
+    log::info!("ICCID refresh is requested for the card {}", cardnumber);
+    ICCID_REFRESH_REQUESTS.lock().unwrap().insert(cardnumber);
+    true
+}
+
+pub fn create_card_object(reader_name: &CStr, protocols: Protocols) -> Result<Card, Box<dyn StdError>> {
     // Establish a PC/SC context.
-    let ctx = Context::establish(Scope::User).expect("Failed to establish context");
+    let ctx = Context::establish(Scope::User)?;
 
     // Directly use the reader name to connect to the card.
-    ctx.connect(reader_name, ShareMode::Shared, Protocols::ANY)
-        .map_err(|err| {
-            log::error!("Failed to connect to card: {}", err);
-            Box::new(err) as Box<dyn StdError>
-        })
+    // The error is logged by the caller, as the function is also used to check if the card is back in the reader.
+    ctx.connect(reader_name, ShareMode::Shared, protocols)
+        .map_err(|err| Box::new(err) as Box<dyn StdError>)
+}
+
+fn force_protocols(force_protocol: ForceProtocol) -> Protocols {
+    match force_protocol {
+        ForceProtocol::T0 => Protocols::T0,
+        ForceProtocol::T1 => Protocols::T1,
+        ForceProtocol::Any => Protocols::ANY,
+    }
+}
+
+/// Returns the protocol negotiated with the card, `None` if the card doesn't report it.
+fn negotiated_protocols(card: &Card) -> Option<Protocols> {
+    let protocol = card.status2_owned().ok()?.protocol2()?;
+    Some(match protocol {
+        Protocol::T0 => Protocols::T0,
+        Protocol::T1 => Protocols::T1,
+        Protocol::RAW => Protocols::RAW,
+    })
+}
+
+/// Logs the protocol override of the card which differs from the default protocol of its ATR.
+fn log_protocol_override(card: &Card, cardnumber: &str, force_protocol: ForceProtocol) {
+    let forced = match force_protocol {
+        ForceProtocol::T0 => "T=0",
+        ForceProtocol::T1 => "T=1",
+        ForceProtocol::Any => return,
+    };
+    let detected = card
+        .status2_owned()
+        .map_err(|e| e.to_string())
+        .and_then(|status| parse_atr_and_get_protocol(status.atr()).map(|(protocol, _)| protocol));
+    match detected {
+        Ok(detected) if detected != forced => {
+            log::warn!("{} | The protocol is forced to {}, the ATR of the card suggests {}", cardnumber, forced, detected)
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("{} | The protocol is forced to {}, the ATR of the card can't be parsed: {}", cardnumber, forced, e),
+    }
+}
+
+/// ATR and protocol of the card in the reader, for the diagnostics view.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ReaderAtrInfo {
+    pub reader: String,
+    pub atr: String,
+    pub protocol: String,
+    pub summary: AtrSummary,
+    /// The tachograph card the ATR belongs to, `None` if the ATR is unknown.
+    pub known_card: Option<KnownCard>,
+}
+
+/// Public function to get the ATR of the card in the reader.
+/// This function is a Tauri command that is called from the diagnostics view of the frontend.
+/// The ATR is taken from the reader state, so the card is not connected and the running sessions are not affected.
+///
+/// # Arguments
+///
+/// * `reader` - The full PC/SC name of the reader.
+///
+/// # Returns
+///
+/// * `Result<ReaderAtrInfo, String>` - The ATR, the default protocol and the decoded ATR, or the error message.
+#[tauri::command]
+pub fn get_reader_atr(reader: String) -> Result<ReaderAtrInfo, String> {
+    let reader_name = std::ffi::CString::new(reader.clone()).map_err(|e| e.to_string())?;
+    let (atr, card_state) = read_reader_state(&reader_name).map_err(|e| format!("Failed to get the reader state: {}", e))?;
+    if !card_state.contains("PRESENT") {
+        return Err(format!("There is no card in the reader {}", reader));
+    }
+
+    let (protocol, summary) = parse_atr_and_get_protocol(&atr)?;
+    let atr = encode(atr);
+    Ok(ReaderAtrInfo {
+        reader,
+        known_card: find_known_card(&atr),
+        atr,
+        protocol,
+        summary,
+    })
 }
 
 // Manual card sync function. ////////////
 // This function is used to manually sync cards from anywhere in the program.
 // Manually sync cards. Clicking on the button in the frontend will trigger this function
+// The errors of the PC/SC service (e.g. it is stopped) are logged and returned to the caller.
 #[tauri::command]
-pub async fn manual_sync_cards() -> () {
+pub async fn manual_sync_cards() -> Result<(), String> {
     log::debug!("Manual sync cards function is called");
-    let ctx = Context::establish(Scope::User).expect("failed to establish context");
+    let ctx = Context::establish(Scope::User).map_err(|e| {
+        log::error!("The cards are not synced, failed to establish the PC/SC context: {}", e);
+        format!("Failed to establish the PC/SC context: {}", e)
+    })?;
 
     let mut readers_buf = [0; 2048];
     let mut reader_states = vec![
@@ -319,51 +1107,68 @@ pub async fn manual_sync_cards() -> () {
         ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
     ];
 
-    // setup readers states. Getting changes and other inits
-    if let Err(e) = setup_reader_states(&ctx, &mut readers_buf, &mut reader_states) {
+    // setup readers states. Getting changes and other inits. The ignored readers are reported by the monitor.
+    if let Err(e) = setup_reader_states(&ctx, &mut readers_buf, &mut reader_states, &mut HashSet::new()) {
         log::error!("Failed to setup reader states: {:?}", e);
     }
     // waiting fot the status change
-    ctx.get_status_change(None, &mut reader_states)
-        .expect("failed to get status change");
-
-    for rs in reader_states {
-        if rs.name() != PNP_NOTIFICATION() {
-            // convert ATR to hex string value
-            let atr = hex::encode(rs.atr());
-            // Checking if card number is in the cache
-            let card_number = get_from_cache(CacheSection::Cards, &atr);
-            /*
-                This is a CRUTCH!!! Need to find a better way to convert card_state to string
-                The meaning of the card_state is in the pcsc module with the their own state enum.
-                The card_state is a bit mask and it is not clear how to convert it to a human readable string properly
-            */
-            let card_state_string = format!("{:?}", rs.event_state());
-            // If the card state has not 'CHANGED' state, then we skip the processing of this card
-            // Due to the specifics of the library, the map can be initialized in several stages,
-            // But we only need the final result with the value changed
-            if !card_state_string.contains("CHANGED") {
-                continue;
-            }
+    ctx.get_status_change(None, &mut reader_states).map_err(|e| {
+        log::error!("The cards are not synced, failed to get the states of the readers: {}", e);
+        format!("Failed to get the states of the readers: {}", e)
+    })?;
 
-            //  Trace status of the reader & card
-            log::info!(
-                "{:?} {:?} {:?}, {:?}",
-                rs.name(),
-                rs.event_state(),
-                atr,
-                card_number
-            );
+    // The changed readers are collected first, the states of the readers can't be held across the await
+    let changed: Vec<(CString, Vec<u8>, String)> = reader_states
+        .iter()
+        .filter(|rs| rs.name() != PNP_NOTIFICATION())
+        .map(|rs| (rs.name().to_owned(), rs.atr().to_vec(), format!("{:?}", rs.event_state())))
+        // If the card state has not 'CHANGED' state, then we skip the processing of this card
+        // Due to the specifics of the library, the map can be initialized in several stages,
+        // But we only need the final result with the value changed
+        .filter(|(_, _, card_state_string)| card_state_string.contains("CHANGED"))
+        .collect();
+    drop(reader_states);
 
-            // launches async task with a card and mqtt connection.
-            ensure_connection(rs.name(), card_number.clone(), atr.clone()).await;
+    // The readers are processed the same way as the stable changes of the monitor:
+    // the pool of the readers is updated, the removed cards are disconnected and the inserted ones are connected.
+    for (reader_name, atr, card_state_string) in changed {
+        apply_reader_state(&reader_name, &atr, card_state_string).await;
+    }
+    Ok(())
+}
 
-            // convert reader name to string
-            let reader_name_string: &str = rs.name().to_str().unwrap(); // convert reader name(&CStr) to string
-            let card_number_clone = card_number.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // send an event to the frontend to update the state of the card
-            emit_event("global-cards-sync", atr.into(), reader_name_string.into(), card_state_string.into(), card_number_clone.into(), None, None);
-        };
+    #[test]
+    fn slot_is_parsed_from_pcsc_lite_name() {
+        let first = ReaderId::from_name("Gemalto Prox Dual USB PC LinkReader [CCID] 00 00");
+        let second = ReaderId::from_name("Gemalto Prox Dual USB PC LinkReader [CCID] 00 01");
+        assert_eq!(first.slot, 0);
+        assert_eq!(second.slot, 1);
+        assert_ne!(first, second);
+        assert_eq!(second.slot_label(), "Gemalto Prox Dual USB PC LinkReader [CCID] (slot 1)");
+    }
+
+    #[test]
+    fn name_without_slot_index_is_slot_zero() {
+        // The Windows names of the slots differ by the instance, the slot is not known
+        let first = ReaderId::from_name("Identiv uTrust 2700 R Smart Card Reader 0");
+        let second = ReaderId::from_name("Identiv uTrust 2700 R Smart Card Reader 1");
+        assert_eq!((first.slot, second.slot), (0, 0));
+        assert_ne!(first, second);
+        assert_eq!(second.slot_label(), "Identiv uTrust 2700 R Smart Card Reader 1");
+
+        let simulated = ReaderId::from_name(SIMULATED_READER_NAME);
+        assert_eq!(simulated.slot, 0);
+        assert_eq!(simulated.slot_label(), SIMULATED_READER_NAME);
+    }
+
+    #[test]
+    fn only_hex_indexes_are_slots() {
+        assert_eq!(ReaderId::from_name("Reader [CCID] 00 0a").slot, 10);
+        assert_eq!(ReaderId::from_name("Reader 2019 01").slot, 0);
+        assert_eq!(ReaderId::from_name("00 01").slot, 0);
     }
 }
@@ -0,0 +1,74 @@
+//! Module for the staggering of the card connections.
+//!
+//! When the bridge with many readers starts, all the MQTT clients connect at once and some brokers rate-limit
+//! the burst. So every new connection takes the next free slot at least `interval_ms` after the previous one,
+//! and the connections made within `startup_window_ms` after the start of the application are also delayed
+//! by a random time up to the window (see `ConnectionStaggerConfig`).
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::config::get_connection_stagger_config;
+
+lazy_static! {
+    /// Time of the start of the application, the startup window is counted from it.
+    static ref STARTED_AT: Instant = Instant::now();
+    /// Time of the last reserved connection slot.
+    static ref LAST_SLOT: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Marks the start of the application. Called once, before the cards are connected.
+pub fn mark_startup() {
+    lazy_static::initialize(&STARTED_AT);
+}
+
+/// Time since the start of the application.
+pub fn uptime() -> Duration {
+    STARTED_AT.elapsed()
+}
+
+/// Random time up to the window, different for every call.
+fn random_delay(client_id: &str, window: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    client_id.hash(&mut hasher);
+    Instant::now().hash(&mut hasher);
+    Duration::from_millis(hasher.finish() % window.as_millis().max(1) as u64)
+}
+
+/// Reserves the connection slot of the card.
+///
+/// # Arguments
+///
+/// * `client_id` - The card number, for the log.
+///
+/// # Returns
+///
+/// * `Duration` - How long the connection has to wait for its slot, zero if the staggering is not configured.
+pub fn connection_delay(client_id: &str) -> Duration {
+    let config = get_connection_stagger_config();
+    let interval = Duration::from_millis(config.interval_ms);
+    let window = Duration::from_millis(config.startup_window_ms);
+    let now = Instant::now();
+
+    let mut earliest = now;
+    if !window.is_zero() && now.duration_since(*STARTED_AT) < window {
+        earliest += random_delay(client_id, window);
+    }
+
+    let mut last_slot = LAST_SLOT.lock().unwrap();
+    let slot = match *last_slot {
+        Some(last) if last + interval > earliest => last + interval,
+        _ => earliest,
+    };
+    *last_slot = Some(slot);
+
+    let delay = slot.duration_since(now);
+    if !delay.is_zero() {
+        log::info!("Card {}. The connection is staggered by {} ms", client_id, delay.as_millis());
+    }
+    delay
+}
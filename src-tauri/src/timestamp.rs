@@ -0,0 +1,48 @@
+//! Module for the time values in the emitted payloads.
+//!
+//! Every date and time sent to the frontend or to the external consumers (LAN broadcast, exports, logs for the audit)
+//! is represented by the same `Timestamp` object with both the ISO-8601 string and the Unix time,
+//! so the consumers don't have to guess the format.
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Point in time in the emitted payloads.
+///
+/// Serialized as `{"iso": "2024-05-01T12:00:00Z", "epoch": 1714564800}`.
+///
+/// # Fields
+///
+/// * `iso` - ISO-8601 (RFC 3339) string in UTC.
+/// * `epoch` - Unix time in seconds.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub iso: String,
+    pub epoch: i64,
+}
+
+impl Timestamp {
+    /// The current time.
+    pub fn now() -> Self {
+        Self::from_datetime(Utc::now())
+    }
+
+    /// Creates the timestamp from the Unix time in seconds.
+    /// Out of range values are clamped to the Unix epoch.
+    pub fn from_epoch(epoch: i64) -> Self {
+        let datetime = Utc.timestamp_opt(epoch, 0).single().unwrap_or_default();
+        Self::from_datetime(datetime)
+    }
+
+    /// The date in UTC as "YYYY-MM-DD", the format of the dates in the configuration.
+    pub fn date(&self) -> &str {
+        self.iso.get(..10).unwrap_or(&self.iso)
+    }
+
+    pub fn from_datetime(datetime: DateTime<Utc>) -> Self {
+        Timestamp {
+            iso: datetime.to_rfc3339_opts(SecondsFormat::Secs, true),
+            epoch: datetime.timestamp(),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Module for the MQTT topics of the card connections.
+//!
+//! By default the card client gets the requests the broker (flespi) routes to it without the subscription,
+//! and the response topic is the request topic with `request` replaced by `response`. The other brokers are
+//! used with the `topics` templates from the configuration: the card client subscribes to the `request` template
+//! and responds to the `response` template. The placeholders of the templates:
+//!
+//! * `{ident}` - The ident of the account of the card.
+//! * `{cardnumber}` - The number of the card.
+//! * `{suffix}` - Only in the `response` template: the levels of the request topic matched by the wildcards
+//!   (`+`, `#`) of the `request` template, e.g. the ID of the request.
+
+use crate::config::{get_topics_config, TopicsConfig};
+
+/// Topics of the card connection.
+pub struct CardTopics {
+    templates: Option<TopicsConfig>,
+    ident: String,
+    cardnumber: String,
+}
+
+impl CardTopics {
+    /// Creates the topics of the card with the templates from the configuration.
+    pub fn new(ident: &str, cardnumber: &str) -> Self {
+        CardTopics {
+            templates: get_topics_config(),
+            ident: ident.to_string(),
+            cardnumber: cardnumber.to_string(),
+        }
+    }
+
+    fn render(&self, template: &str) -> String {
+        template.replace("{ident}", &self.ident).replace("{cardnumber}", &self.cardnumber)
+    }
+
+    /// Returns the topic filter of the requests, `None` if the broker routes the requests without the subscription.
+    pub fn subscription(&self) -> Option<String> {
+        self.templates.as_ref().map(|templates| self.render(&templates.request))
+    }
+
+    /// Returns the topic of the response to the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_topic` - The topic of the request.
+    pub fn response_topic(&self, request_topic: &str) -> String {
+        match &self.templates {
+            Some(templates) => {
+                let suffix = wildcard_levels(&self.render(&templates.request), request_topic).join("/");
+                self.render(&templates.response).replace("{suffix}", &suffix)
+            }
+            // The contents of response and request are the same.
+            // Card number and parcel ID. So we just change the initial topic
+            None => request_topic.replace("request", "response"),
+        }
+    }
+}
+
+/// Checks if the topic matches the topic filter with the wildcards (`+`, `#`).
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        if filter_level == "#" {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(topic_level) if filter_level == "+" || filter_level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Returns the levels of the topic matched by the wildcards of the filter.
+fn wildcard_levels<'a>(filter: &str, topic: &'a str) -> Vec<&'a str> {
+    let mut topic_levels = topic.split('/');
+    let mut matched = Vec::new();
+    for filter_level in filter.split('/') {
+        match filter_level {
+            "#" => {
+                matched.extend(topic_levels.by_ref());
+                break;
+            }
+            "+" => matched.extend(topic_levels.next()),
+            _ => {
+                topic_levels.next();
+            }
+        }
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics(request: &str, response: &str) -> CardTopics {
+        CardTopics {
+            templates: Some(TopicsConfig {
+                request: request.to_string(),
+                response: response.to_string(),
+            }),
+            ident: "bridge".to_string(),
+            cardnumber: "C123".to_string(),
+        }
+    }
+
+    #[test]
+    fn default_response_topic_replaces_request() {
+        let topics = CardTopics {
+            templates: None,
+            ident: "bridge".to_string(),
+            cardnumber: "C123".to_string(),
+        };
+        assert_eq!(topics.subscription(), None);
+        assert_eq!(topics.response_topic("tacho/C123/request/7"), "tacho/C123/response/7");
+    }
+
+    #[test]
+    fn templates_are_rendered() {
+        let topics = topics("{ident}/{cardnumber}/request/#", "{ident}/{cardnumber}/response/{suffix}");
+        assert_eq!(topics.subscription().as_deref(), Some("bridge/C123/request/#"));
+        assert_eq!(topics.response_topic("bridge/C123/request/42/a"), "bridge/C123/response/42/a");
+    }
+
+    #[test]
+    fn single_level_wildcards_are_collected() {
+        let topics = topics("req/+/{cardnumber}/+", "resp/{cardnumber}/{suffix}");
+        assert_eq!(topics.response_topic("req/t1/C123/42"), "resp/C123/t1/42");
+    }
+
+    #[test]
+    fn topics_are_matched_by_filters() {
+        assert!(topic_matches("bridge/C123/request/#", "bridge/C123/request/42/a"));
+        assert!(topic_matches("req/+/C123/+", "req/t1/C123/42"));
+        assert!(!topic_matches("req/+/C123/+", "req/t1/C456/42"));
+        assert!(!topic_matches("req/+/C123", "req/t1/C123/42"));
+        assert!(!topic_matches("req/+/C123/+", "req/t1/C123"));
+    }
+}
@@ -0,0 +1,93 @@
+#!/usr/bin/env python3
+"""Generates the test chains of the tachograph card certificates in the formats of the ERCA test certificates.
+
+Gen1 (Annex 1B): EUR.PK of the test root, the member state and the card certificates, RSA-1024 with the message
+recovery (ISO/IEC 9796-2, SHA-1). Gen2 (Annex 1C): the root, the member state and the card verifiable
+certificates on NIST P-256.
+
+The keys are generated on every run, so the output differs between the runs: regenerate all the files together.
+Requires the `cryptography` package.
+"""
+import hashlib
+import os
+import struct
+
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec, rsa
+from cryptography.hazmat.primitives.asymmetric.utils import decode_dss_signature
+
+OUT = os.path.dirname(os.path.abspath(__file__))
+
+# 2020-01-01 and 2035-01-01
+VALID_FROM = 1577836800
+VALID_UNTIL = 2051222400
+
+EUR_KEY_ID = bytes.fromhex("fd45432001ffff01")
+MS_KEY_ID = bytes.fromhex("fd54535401ffff01")
+CARD_KEY_ID = bytes.fromhex("0d12345678900501")
+
+
+def write(name, data):
+    with open(os.path.join(OUT, name), "wb") as f:
+        f.write(data)
+
+
+def rsa_key():
+    key = rsa.generate_private_key(public_exponent=65537, key_size=1024)
+    numbers = key.private_numbers()
+    return numbers.public_numbers.n, numbers.public_numbers.e, numbers.d
+
+
+def gen1_certificate(signer, car, cha, chr_, public):
+    n, e, _ = public
+    content = bytes([0x01]) + car + cha + struct.pack(">I", VALID_UNTIL) + chr_ + n.to_bytes(128, "big") + e.to_bytes(8, "big")
+    assert len(content) == 164
+    recoverable, non_recoverable = content[:106], content[106:]
+    block = bytes([0x6A]) + recoverable + hashlib.sha1(content).digest() + bytes([0xBC])
+    signer_n, _, signer_d = signer
+    signature = pow(int.from_bytes(block, "big"), signer_d, signer_n).to_bytes(128, "big")
+    return signature + non_recoverable + car
+
+
+def tlv(tag, value):
+    length = len(value)
+    if length < 0x80:
+        header = bytes([length])
+    elif length < 0x100:
+        header = bytes([0x81, length])
+    else:
+        header = bytes([0x82]) + length.to_bytes(2, "big")
+    return tag + header + value
+
+
+def gen2_certificate(signer, car, cha, chr_, public):
+    point = public.public_key().public_bytes(serialization.Encoding.X962, serialization.PublicFormat.UncompressedPoint)
+    public_key = tlv(b"\x7f\x49", tlv(b"\x06", bytes.fromhex("2a8648ce3d030107")) + tlv(b"\x86", point))
+    body = tlv(
+        b"\x7f\x4e",
+        tlv(b"\x5f\x29", b"\x00")
+        + tlv(b"\x42", car)
+        + tlv(b"\x5f\x4c", cha)
+        + public_key
+        + tlv(b"\x5f\x20", chr_)
+        + tlv(b"\x5f\x25", struct.pack(">I", VALID_FROM))
+        + tlv(b"\x5f\x24", struct.pack(">I", VALID_UNTIL)),
+    )
+    r, s = decode_dss_signature(signer.sign(body, ec.ECDSA(hashes.SHA256())))
+    return tlv(b"\x7f\x21", body + tlv(b"\x5f\x37", r.to_bytes(32, "big") + s.to_bytes(32, "big")))
+
+
+def main():
+    eur, ms, card = rsa_key(), rsa_key(), rsa_key()
+    write("gen1_eur.pk", EUR_KEY_ID + eur[0].to_bytes(128, "big") + eur[1].to_bytes(8, "big"))
+    write("gen1_ms.crt", gen1_certificate(eur, EUR_KEY_ID, bytes.fromhex("ffffffffffffff"), MS_KEY_ID, ms))
+    write("gen1_card.crt", gen1_certificate(ms, MS_KEY_ID, bytes.fromhex("ff544143484f04"), CARD_KEY_ID, card))
+
+    root, ms, card = (ec.generate_private_key(ec.SECP256R1()) for _ in range(3))
+    write("gen2_root.crt", gen2_certificate(root, EUR_KEY_ID, bytes.fromhex("ff544143484f01"), EUR_KEY_ID, root))
+    write("gen2_ms.crt", gen2_certificate(root, EUR_KEY_ID, bytes.fromhex("ff544143484f02"), MS_KEY_ID, ms))
+    write("gen2_card.crt", gen2_certificate(ms, MS_KEY_ID, bytes.fromhex("ff544143484f04"), CARD_KEY_ID, card))
+
+
+if __name__ == "__main__":
+    main()